@@ -0,0 +1,75 @@
+use std::{
+    env, fs,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    emit_build_info();
+    #[cfg(feature = "grpc")]
+    generate_grpc_service();
+}
+
+// For /version: the commit built (GIT_COMMIT wins, for builds outside a checkout) and when
+fn emit_build_info() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // HEAD names the branch; the branch's ref file changes with every commit
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(head_ref) = fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        println!("cargo:rerun-if-changed=.git/{}", head_ref);
+    }
+
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short=12", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|commit| commit.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+}
+
+// Generates the server stubs without protoc; messages are derived in src/grpc.rs
+#[cfg(feature = "grpc")]
+fn generate_grpc_service() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    println!("cargo:rerun-if-changed=proto/publisher.proto");
+
+    let service = Service::builder()
+        .name("Publisher")
+        .package("rustin_bot_publisher.v1")
+        .method(
+            Method::builder()
+                .name("publish_message")
+                .route_name("PublishMessage")
+                .input_type("crate::grpc::PublishRequest")
+                .output_type("crate::grpc::PublishResponse")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .build();
+
+    Builder::new().build_client(false).compile(&[service]);
+}
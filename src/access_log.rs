@@ -0,0 +1,75 @@
+use std::{cell::RefCell, future::Future, time::Instant};
+
+use axum::{
+    body::HttpBody, extract::Request, http::header::CONTENT_LENGTH, middleware::Next,
+    response::Response,
+};
+use log::info;
+
+tokio::task_local! {
+    // The command the request's update resolved to, filled in by the dispatcher
+    static COMMAND: RefCell<Option<String>>;
+}
+
+// Noted for the access log line of the request being handled, if any; updates fetched by
+// polling have no request and are only logged by the dispatcher
+pub fn record_command(command: &str) {
+    let _ = COMMAND.try_with(|slot| *slot.borrow_mut() = Some(command.to_string()));
+}
+
+// Runs `future` with room for the command it resolves, and returns both
+async fn with_command_slot<F: Future>(future: F) -> (F::Output, Option<String>) {
+    COMMAND
+        .scope(RefCell::new(None), async {
+            let output = future.await;
+            (output, COMMAND.with(|slot| slot.borrow_mut().take()))
+        })
+        .await
+}
+
+// Middleware writing one structured line per request; bodies are never logged, they carry
+// users' messages
+pub async fn log_request(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let request_bytes = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or_default();
+
+    let (response, command) = with_command_slot(next.run(request)).await;
+
+    let status = response.status().as_u16();
+    let response_bytes = response.body().size_hint().exact().unwrap_or_default();
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let command = command.unwrap_or_default();
+    info!(
+        method = method.as_str(), path = path.as_str(), status, latency_ms, request_bytes,
+        response_bytes, command = command.as_str();
+        "{} {} {} in {}ms", method, path, status, latency_ms
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn commands_are_recorded_for_the_enclosing_request_only() {
+        record_command("/ignored");
+
+        let (output, command) = with_command_slot(async {
+            record_command("/songlinks");
+            7
+        })
+        .await;
+
+        assert_eq!(output, 7);
+        assert_eq!(command.as_deref(), Some("/songlinks"));
+        assert_eq!(with_command_slot(async {}).await.1, None);
+    }
+}
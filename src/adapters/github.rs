@@ -0,0 +1,59 @@
+use std::{env, sync::Arc};
+
+use axum::{
+    debug_handler,
+    http::{HeaderMap, StatusCode},
+    Extension, Json,
+};
+use serde_json::Value;
+
+use super::publish_event;
+use crate::publisher::{MessagePublisher, RabbitMessage};
+
+pub const SOURCE: &str = "github";
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+const EVENT_HEADER: &str = "X-GitHub-Event";
+
+pub struct GithubAdapter {
+    pub queue: String,
+}
+
+impl GithubAdapter {
+    pub fn from_env() -> Self {
+        Self {
+            queue: env::var("GITHUB_QUEUE").unwrap_or_else(|_| "GitHub".to_string()),
+        }
+    }
+}
+
+// Receives GitHub webhooks; the signature is checked by the HMAC layer on this route
+#[debug_handler]
+pub async fn receive_github_event(
+    Extension(publisher): Extension<Arc<dyn MessagePublisher>>,
+    Extension(adapter): Extension<Arc<GithubAdapter>>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> Result<StatusCode, StatusCode> {
+    let event = headers
+        .get(EVENT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let message = normalize(event, payload);
+    publish_event(&adapter.queue, message, publisher.as_ref()).await
+}
+
+// Turn a GitHub payload into an event like "github.pull_request.opened" with a short summary
+fn normalize(event: &str, payload: Value) -> RabbitMessage {
+    let event_name = match payload["action"].as_str() {
+        Some(action) => format!("{}.{}.{}", SOURCE, event, action),
+        None => format!("{}.{}", SOURCE, event),
+    };
+    let repository = payload["repository"]["full_name"]
+        .as_str()
+        .unwrap_or("unknown repository");
+    let sender = payload["sender"]["login"].as_str().unwrap_or("unknown");
+    let summary = format!("{} on {} by {}", event, repository, sender);
+
+    RabbitMessage::event(SOURCE, &event_name, summary, payload)
+}
@@ -0,0 +1,25 @@
+pub mod github;
+pub mod stripe;
+
+use axum::http::StatusCode;
+use log::{error, info};
+
+use crate::publisher::{MessagePublisher, RabbitMessage};
+
+// Publish a normalized adapter event, mapping broker failures to a 500 so the producer retries
+async fn publish_event(
+    queue_name: &str,
+    message: RabbitMessage,
+    publisher: &dyn MessagePublisher,
+) -> Result<StatusCode, StatusCode> {
+    publisher.publish(queue_name, &message).await.map_err(|e| {
+        error!("Publishing to {} failed: {}", queue_name, e);
+        e.status_code()
+    })?;
+    info!(
+        "Published '{}' event to {} queue.",
+        message.event.as_deref().unwrap_or_default(),
+        queue_name
+    );
+    Ok(StatusCode::OK)
+}
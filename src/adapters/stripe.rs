@@ -0,0 +1,109 @@
+use std::{
+    env,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    debug_handler,
+    http::{HeaderMap, StatusCode},
+    Extension,
+};
+use log::info;
+use serde_json::Value;
+
+use super::publish_event;
+use crate::{
+    publisher::{MessagePublisher, RabbitMessage},
+    signature::HmacVerifier,
+};
+
+pub const SOURCE: &str = "stripe";
+const SIGNATURE_HEADER: &str = "Stripe-Signature";
+// Stripe's recommended maximum age of a signed event
+const TIMESTAMP_TOLERANCE_SECS: u64 = 300;
+
+pub struct StripeAdapter {
+    pub queue: String,
+    verifier: HmacVerifier,
+}
+
+impl StripeAdapter {
+    // Enabled only when STRIPE_WEBHOOK_SECRET is set
+    pub fn from_env() -> Option<Self> {
+        let secret = env::var("STRIPE_WEBHOOK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())?;
+        Some(Self {
+            queue: env::var("STRIPE_QUEUE").unwrap_or_else(|_| "Stripe".to_string()),
+            verifier: HmacVerifier::new(SIGNATURE_HEADER, secret.as_bytes()),
+        })
+    }
+
+    // Stripe signs "<timestamp>.<body>" and sends "t=<timestamp>,v1=<hex>[,v1=...]"
+    fn verify(&self, body: &[u8], header: &str, now: u64) -> bool {
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+                Some(("v1", value)) => signatures.push(value),
+                _ => {}
+            }
+        }
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+        if now.abs_diff(timestamp) > TIMESTAMP_TOLERANCE_SECS {
+            return false;
+        }
+
+        let mut signed_payload = format!("{}.", timestamp).into_bytes();
+        signed_payload.extend_from_slice(body);
+        signatures
+            .iter()
+            .any(|signature| self.verifier.verify_hex(&signed_payload, signature))
+    }
+}
+
+#[debug_handler]
+pub async fn receive_stripe_event(
+    Extension(publisher): Extension<Arc<dyn MessagePublisher>>,
+    Extension(adapter): Extension<Arc<StripeAdapter>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if !adapter.verify(&body, signature, now) {
+        info!("Rejected Stripe event: signature mismatch.");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let payload: Value = serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let message = normalize(payload).ok_or(StatusCode::BAD_REQUEST)?;
+    publish_event(&adapter.queue, message, publisher.as_ref()).await
+}
+
+// Turn a Stripe event into "stripe.<type>" carrying the event's data.object
+fn normalize(mut payload: Value) -> Option<RabbitMessage> {
+    let event_type = payload["type"].as_str()?.to_string();
+    let object = payload["data"]["object"].take();
+    let summary = match payload["id"].as_str() {
+        Some(id) => format!("{} ({})", event_type, id),
+        None => event_type.clone(),
+    };
+    Some(RabbitMessage::event(
+        SOURCE,
+        &format!("{}.{}", SOURCE, event_type),
+        summary,
+        object,
+    ))
+}
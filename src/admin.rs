@@ -0,0 +1,123 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use axum::{body::Bytes, extract::Path, Extension, Json};
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use crate::errors::WebhookError;
+
+// The admin API is signed with HMAC_ADMIN_SECRET
+pub const SOURCE: &str = "admin";
+
+// Commands switched off at runtime, e.g. /readimage while the OCR worker is down
+#[derive(Debug, Default)]
+pub struct CommandSwitches {
+    disabled: RwLock<HashSet<String>>,
+}
+
+impl CommandSwitches {
+    pub fn new(disabled: HashSet<String>) -> Self {
+        Self {
+            disabled: RwLock::new(disabled),
+        }
+    }
+
+    pub fn is_enabled(&self, command: &str) -> bool {
+        !self
+            .disabled
+            .read()
+            .expect("command switches lock poisoned")
+            .contains(command)
+    }
+
+    pub fn set_enabled(&self, command: &str, enabled: bool) {
+        let mut disabled = self
+            .disabled
+            .write()
+            .expect("command switches lock poisoned");
+        if enabled {
+            disabled.remove(command);
+        } else {
+            disabled.insert(command.to_string());
+        }
+    }
+
+    pub fn disabled(&self) -> Vec<String> {
+        let mut disabled: Vec<String> = self
+            .disabled
+            .read()
+            .expect("command switches lock poisoned")
+            .iter()
+            .cloned()
+            .collect();
+        disabled.sort();
+        disabled
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct CommandSwitch {
+    enabled: bool,
+}
+
+// GET /admin/commands
+#[utoipa::path(
+    get,
+    path = "/admin/commands",
+    tag = "admin",
+    security(("hmac" = [])),
+    responses(
+        (status = 200, description = "The commands switched off", body = Object,
+            example = json!({ "disabled": ["/readimage"] })),
+        (status = 401, description = "Missing or wrong signature", body = WebhookError),
+    )
+)]
+pub async fn list_commands(Extension(switches): Extension<Arc<CommandSwitches>>) -> Json<Value> {
+    Json(json!({ "disabled": switches.disabled() }))
+}
+
+// POST /admin/commands/readimage with {"enabled": false}; the leading slash is optional
+#[utoipa::path(
+    post,
+    path = "/admin/commands/{command}",
+    tag = "admin",
+    security(("hmac" = [])),
+    params(("command" = String, Path, description = "The command, with or without its slash")),
+    request_body = CommandSwitch,
+    responses(
+        (status = 200, description = "The command's new state", body = Object,
+            example = json!({ "command": "/readimage", "enabled": false, "disabled": ["/readimage"] })),
+        (status = 400, description = "Not a switch", body = WebhookError),
+        (status = 401, description = "Missing or wrong signature", body = WebhookError),
+    )
+)]
+pub async fn switch_command(
+    Path(command): Path<String>,
+    Extension(switches): Extension<Arc<CommandSwitches>>,
+    body: Bytes,
+) -> Result<Json<Value>, WebhookError> {
+    let switch: CommandSwitch =
+        serde_json::from_slice(&body).map_err(|e| WebhookError::invalid_json(e.to_string()))?;
+    let command = format!("/{}", command.trim_start_matches('/'));
+    switches.set_enabled(&command, switch.enabled);
+    info!(
+        "{} {} through the admin API.",
+        if switch.enabled {
+            "Enabled"
+        } else {
+            "Disabled"
+        },
+        command
+    );
+    Ok(Json(json!({
+        "command": command,
+        "enabled": switch.enabled,
+        "disabled": switches.disabled(),
+    })))
+}
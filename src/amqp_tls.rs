@@ -0,0 +1,220 @@
+use std::{fmt, fs, io::BufReader, path::PathBuf};
+
+use lapin::{
+    tcp::{OwnedIdentity, OwnedTLSConfig},
+    Connection, ConnectionProperties,
+};
+use p12_keystore::{Certificate, KeyStore, KeyStoreEntry, PrivateKeyChain};
+use sha2::{Digest, Sha256};
+
+// lapin only accepts client identities as PKCS#12, so PEM inputs are repacked in memory
+const IDENTITY_PASSWORD: &str = "rustin_bot_publisher";
+
+#[derive(Debug)]
+pub enum AmqpTlsError {
+    ReadFile { path: PathBuf, reason: String },
+    InvalidPem { path: PathBuf, reason: String },
+    Pkcs12(String),
+    InsecureScheme,
+}
+
+impl fmt::Display for AmqpTlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmqpTlsError::ReadFile { path, reason } => {
+                write!(f, "cannot read {}: {}", path.display(), reason)
+            }
+            AmqpTlsError::InvalidPem { path, reason } => {
+                write!(f, "invalid PEM in {}: {}", path.display(), reason)
+            }
+            AmqpTlsError::Pkcs12(reason) => {
+                write!(f, "cannot build client identity: {}", reason)
+            }
+            AmqpTlsError::InsecureScheme => write!(
+                f,
+                "AMQP TLS settings are configured but the address does not use amqps://"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AmqpTlsError {}
+
+// TLS material for brokers that require amqps:// with mutual authentication
+pub struct AmqpTlsSettings {
+    ca_bundle_path: Option<PathBuf>,
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+}
+
+impl AmqpTlsSettings {
+    // None when no path is set
+    pub fn new(
+        ca_bundle_path: Option<PathBuf>,
+        client_cert_path: Option<PathBuf>,
+        client_key_path: Option<PathBuf>,
+    ) -> Option<Self> {
+        if ca_bundle_path.is_none() && client_cert_path.is_none() && client_key_path.is_none() {
+            return None;
+        }
+        Some(Self {
+            ca_bundle_path,
+            client_cert_path,
+            client_key_path,
+        })
+    }
+
+    pub fn load(&self) -> Result<OwnedTLSConfig, AmqpTlsError> {
+        let cert_chain = match &self.ca_bundle_path {
+            Some(path) => Some(read_to_string(path)?),
+            None => None,
+        };
+        let identity = match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(build_identity(cert_path, key_path)?),
+            (None, None) => None,
+            (Some(path), None) | (None, Some(path)) => {
+                return Err(AmqpTlsError::InvalidPem {
+                    path: path.clone(),
+                    reason: "amqp_client_cert and amqp_client_key must be set together".into(),
+                })
+            }
+        };
+        Ok(OwnedTLSConfig {
+            identity,
+            cert_chain,
+        })
+    }
+}
+
+fn read_to_string(path: &PathBuf) -> Result<String, AmqpTlsError> {
+    fs::read_to_string(path).map_err(|e| AmqpTlsError::ReadFile {
+        path: path.clone(),
+        reason: e.to_string(),
+    })
+}
+
+// Packs the PEM certificate chain and PKCS#8 key into an in-memory PKCS#12 identity
+fn build_identity(cert_path: &PathBuf, key_path: &PathBuf) -> Result<OwnedIdentity, AmqpTlsError> {
+    let invalid = |path: &PathBuf, reason: String| AmqpTlsError::InvalidPem {
+        path: path.clone(),
+        reason,
+    };
+
+    let cert_pem = read_to_string(cert_path)?;
+    let chain = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_bytes()))
+        .map(|cert| {
+            let cert = cert.map_err(|e| invalid(cert_path, e.to_string()))?;
+            Certificate::from_der(&cert).map_err(|e| invalid(cert_path, e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(leaf) = chain.first() else {
+        return Err(invalid(cert_path, "no certificate found".into()));
+    };
+    let local_key_id = Sha256::digest(leaf.as_der());
+
+    let key_pem = read_to_string(key_path)?;
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(key_pem.as_bytes()))
+        .next()
+        .ok_or_else(|| {
+            invalid(
+                key_path,
+                "no PKCS#8 private key found (convert with `openssl pkcs8 -topk8 -nocrypt`)".into(),
+            )
+        })?
+        .map_err(|e| invalid(key_path, e.to_string()))?;
+
+    let mut keystore = KeyStore::new();
+    keystore.add_entry(
+        "client",
+        KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(
+            key.secret_pkcs8_der(),
+            local_key_id,
+            chain,
+        )),
+    );
+    let der = keystore
+        .writer(IDENTITY_PASSWORD)
+        .write()
+        .map_err(|e| AmqpTlsError::Pkcs12(e.to_string()))?;
+
+    Ok(OwnedIdentity {
+        der,
+        password: IDENTITY_PASSWORD.to_string(),
+    })
+}
+
+// Connection parameters resolved once, when the config is loaded, so TLS misconfiguration is
+// reported with the rest of it
+pub struct AmqpConnector {
+    address: String,
+    tls: Option<OwnedTLSConfig>,
+}
+
+// OwnedTLSConfig is neither Clone nor Debug
+fn copy_tls(tls: &OwnedTLSConfig) -> OwnedTLSConfig {
+    OwnedTLSConfig {
+        identity: tls.identity.as_ref().map(|identity| OwnedIdentity {
+            der: identity.der.clone(),
+            password: identity.password.clone(),
+        }),
+        cert_chain: tls.cert_chain.clone(),
+    }
+}
+
+impl Clone for AmqpConnector {
+    fn clone(&self) -> Self {
+        Self {
+            address: self.address.clone(),
+            tls: self.tls.as_ref().map(copy_tls),
+        }
+    }
+}
+
+// The address may hold credentials and the identity a private key, so neither is shown
+impl fmt::Debug for AmqpConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AmqpConnector")
+            .field("tls", &self.tls.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AmqpConnector {
+    pub fn try_new(address: &str, tls: Option<&AmqpTlsSettings>) -> Result<Self, AmqpTlsError> {
+        let tls = match tls {
+            Some(_) if !address.starts_with("amqps://") => {
+                return Err(AmqpTlsError::InsecureScheme)
+            }
+            Some(tls) => Some(tls.load()?),
+            None => None,
+        };
+        Ok(Self {
+            address: address.to_string(),
+            tls,
+        })
+    }
+
+    // A single connection attempt; callers decide whether and when to retry
+    pub async fn connect(&self) -> Result<Connection, String> {
+        let Some(tls) = &self.tls else {
+            return Connection::connect(&self.address, ConnectionProperties::default())
+                .await
+                .map_err(|e| e.to_string());
+        };
+
+        // Connecting takes the config, so it is copied for every attempt
+        Connection::connect_with_config(
+            &self.address,
+            ConnectionProperties::default(),
+            copy_tls(tls),
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "TLS connection failed: {}. Check that amqp_ca_bundle trusts the broker \
+                     certificate and that the broker accepts the amqp_client_cert identity.",
+                e
+            )
+        })
+    }
+}
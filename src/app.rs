@@ -0,0 +1,586 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    middleware,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Router,
+};
+use log::info;
+use tokio::sync::Semaphore;
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
+
+use crate::{
+    access_log::log_request,
+    adapters::{
+        github::{self, receive_github_event, GithubAdapter},
+        stripe::{receive_stripe_event, StripeAdapter},
+    },
+    admin::{self, CommandSwitches},
+    bots::BotRegistry,
+    broadcast::{Broadcaster, ChatDirectory, InMemoryChatDirectory},
+    broker::{readyz, require_broker, Broker, PoolOptions},
+    circuit_breaker::CircuitBreakerPublisher,
+    config::{Config, ConfigArgs, ConfigError},
+    cooldowns::CommandCooldowns,
+    deletion::UserRecords,
+    flood::FloodGuard,
+    i18n::Translations,
+    ip_filter::filter_source_ip,
+    limits::reject_overload,
+    metrics::{MeteredPublisher, Metrics},
+    openapi,
+    polling::{UpdateMode, UpdatePoller},
+    publisher::{Backend, MessagePublisher, RabbitPublisher},
+    rate_limit::{limit_source_ip, IpRateLimiter},
+    reminders::ReminderScheduler,
+    routing::{Routes, RoutingTable},
+    rpc::{self, RpcClient},
+    scrubbing::ScrubbingPublisher,
+    sessions::{InMemorySessionStore, SessionStore},
+    settings::{FileSettingsStore, SettingsPublisher, SettingsStore},
+    shadow::ShadowPublisher,
+    shared_state::{InMemorySharedState, SharedState},
+    signature::verify_hmac,
+    statsd::StatsdExporter,
+    telegram_api::WebhookRegistrar,
+    validation::validate_update,
+    version::version,
+    webhook_handler::{self, receive_bot_message, receive_message, Dispatcher, UNKNOWN_COMMAND},
+    websocket::{self, WsIngest},
+};
+
+// The webhook server with its background tasks, for the binary or for embedding in another
+// service; everything not given to the builder comes from the config and the environment
+#[derive(Default)]
+pub struct AppBuilder {
+    config: Option<Config>,
+    publisher: Option<Arc<dyn MessagePublisher>>,
+    routes: Option<RoutingTable>,
+    bots: Option<BotRegistry>,
+}
+
+impl AppBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // Publishes instead of the configured backend, still behind the circuit breaker, metrics and
+    // audit log; no broker is connected, so RPC is not served
+    pub fn publisher(mut self, publisher: Arc<dyn MessagePublisher>) -> Self {
+        self.publisher = Some(publisher);
+        self
+    }
+
+    // The command registry: which queue each command is published to, and its aliases. Given
+    // here, it is no longer reloaded from the config file on SIGHUP
+    pub fn routes(mut self, routes: RoutingTable) -> Self {
+        self.routes = Some(routes);
+        self
+    }
+
+    // The bots served, instead of those in the config
+    pub fn bots(mut self, bots: BotRegistry) -> Self {
+        self.bots = Some(bots);
+        self
+    }
+
+    // Connects the backend and starts the background tasks (cron jobs, reminders, polling)
+    pub async fn build(self) -> Result<App, ConfigError> {
+        let config = match self.config {
+            Some(config) => config,
+            None => Config::load(ConfigArgs::default())?,
+        };
+        let metrics = Arc::new(Metrics::default());
+        let broker = match config.backend {
+            _ if self.publisher.is_some() => Arc::new(Broker::unused()),
+            Backend::RabbitMq => {
+                // Connect in the background so the server is up (and reports not-ready) while
+                // RabbitMQ starts
+                let broker = Arc::new(Broker::default());
+                broker.spawn_connect(
+                    config.rabbit_connector.clone(),
+                    PoolOptions::from_config(&config, Arc::clone(&metrics)),
+                );
+                broker
+            }
+            #[cfg(feature = "sqs")]
+            Backend::Sqs => Arc::new(Broker::unused()),
+            #[cfg(feature = "mqtt")]
+            Backend::Mqtt => Arc::new(Broker::unused()),
+        };
+
+        let rabbit_publisher = Arc::new(
+            RabbitPublisher::new(
+                Arc::clone(&broker),
+                config.queues.clone(),
+                Arc::clone(&metrics),
+            )
+            .with_unroutable_queue(config.unroutable_queue.clone())
+            .with_timeout_fallback_queues(config.timeout_fallback_queues.clone())
+            .with_publish_timeout(config.publish_timeout)
+            .with_cipher(config.payload_cipher.clone())
+            .with_signer(config.message_signer.clone()),
+        );
+        let serves_rpc = self.publisher.is_none() && config.backend == Backend::RabbitMq;
+        let backend_publisher: Arc<dyn MessagePublisher> = match (self.publisher, config.backend) {
+            (Some(publisher), _) => publisher,
+            (None, Backend::RabbitMq) => Arc::clone(&rabbit_publisher) as _,
+            #[cfg(feature = "sqs")]
+            (None, Backend::Sqs) => Arc::new(crate::sqs::SqsPublisher::from_config(&config).await),
+            #[cfg(feature = "mqtt")]
+            (None, Backend::Mqtt) => Arc::new(
+                crate::mqtt::MqttPublisher::spawn(&config)
+                    .expect("mqtt_address was validated with the config"),
+            ),
+        };
+        let publisher: Arc<dyn MessagePublisher> = Arc::new(MeteredPublisher::new(
+            Arc::new(CircuitBreakerPublisher::new(
+                backend_publisher,
+                config.circuit_breaker.clone(),
+            )),
+            Arc::clone(&metrics),
+        ));
+        // Mirrors to a second cluster, e.g. during a migration. The shadow has its own
+        // connection and circuit breaker, and its own metrics so the primary's stay true
+        let publisher: Arc<dyn MessagePublisher> = match &config.shadow_rabbit_connector {
+            Some(shadow_connector) => {
+                let shadow_metrics = Arc::new(Metrics::default());
+                let shadow_broker = Arc::new(Broker::default());
+                shadow_broker.spawn_connect(
+                    shadow_connector.clone(),
+                    PoolOptions::from_config(&config, Arc::clone(&shadow_metrics)),
+                );
+                let shadow =
+                    RabbitPublisher::new(shadow_broker, config.queues.clone(), shadow_metrics)
+                        .with_unroutable_queue(config.unroutable_queue.clone())
+                        .with_timeout_fallback_queues(config.timeout_fallback_queues.clone())
+                        .with_publish_timeout(config.publish_timeout)
+                        .with_cipher(config.payload_cipher.clone())
+                        .with_signer(config.message_signer.clone());
+                Arc::new(ShadowPublisher::new(
+                    publisher,
+                    Arc::new(CircuitBreakerPublisher::new(
+                        Arc::new(shadow),
+                        config.circuit_breaker.clone(),
+                    )),
+                    Arc::clone(&metrics),
+                ))
+            }
+            None => publisher,
+        };
+        // Outermost, so the audit log records the outcome callers saw
+        #[cfg(feature = "audit")]
+        let audit_log = match &config.audit_database_url {
+            Some(url) => Some(Arc::new(crate::audit::init(
+                crate::audit::connect_options(url)
+                    .map_err(|e| ConfigError(vec![format!("audit_database_url: {}", e)]))?,
+            ))),
+            None => None,
+        };
+        #[cfg(feature = "audit")]
+        let publisher: Arc<dyn MessagePublisher> = match &audit_log {
+            Some(audit_log) => Arc::new(crate::audit::AuditPublisher::new(
+                publisher,
+                audit_log.records.clone(),
+            )),
+            None => publisher,
+        };
+        // Every message for a chat carries the chat's /settings
+        let chat_settings: Arc<dyn SettingsStore> =
+            Arc::new(FileSettingsStore::load(config.settings_file.clone()));
+        let publisher: Arc<dyn MessagePublisher> = Arc::new(SettingsPublisher::new(
+            publisher,
+            Arc::clone(&chat_settings),
+        ));
+        // Around the audit log too, so personal data is not kept there either
+        let publisher: Arc<dyn MessagePublisher> = match &config.scrubber {
+            Some(scrubber) => Arc::new(ScrubbingPublisher::new(publisher, scrubber.clone())),
+            None => publisher,
+        };
+
+        // Internal producers can publish over gRPC through the same publisher and metrics
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_address) = config.grpc_address {
+            tokio::spawn(crate::grpc::serve(grpc_address, Arc::clone(&publisher)));
+        }
+
+        if let Some(statsd) = config.statsd.clone() {
+            StatsdExporter::new(statsd, Arc::clone(&metrics)).spawn();
+        }
+
+        // The routing table can be swapped at runtime by sending SIGHUP after editing the config
+        // file
+        let routes = Arc::new(Routes::new(
+            self.routes.clone().unwrap_or_else(|| config.routes.clone()),
+        ));
+        #[cfg(unix)]
+        if let (None, Some(config_file)) = (&self.routes, config.config_file.clone()) {
+            routes.spawn_reload_on_sighup(config_file);
+        }
+
+        let bots = Arc::new(
+            self.bots
+                .unwrap_or_else(|| BotRegistry::new(config.bots.clone())),
+        );
+        // The audit log remembers chats across restarts; without it only chats seen since start
+        // count
+        let known_chats: Arc<dyn ChatDirectory> = Arc::new(InMemoryChatDirectory::default());
+        #[cfg(feature = "audit")]
+        let known_chats: Arc<dyn ChatDirectory> = match &audit_log {
+            Some(audit_log) => Arc::clone(audit_log) as _,
+            None => known_chats,
+        };
+        // Only the audit log keeps anything about users for /deleteme to purge
+        let user_records: Option<Arc<dyn UserRecords>> = None;
+        #[cfg(feature = "audit")]
+        let user_records: Option<Arc<dyn UserRecords>> = match &audit_log {
+            Some(audit_log) => Some(Arc::clone(audit_log) as _),
+            None => user_records,
+        };
+        config.cron_jobs.spawn(
+            Arc::clone(&publisher),
+            Arc::clone(&bots),
+            Arc::clone(&metrics),
+        );
+        // Pending reminders are reloaded from the file, so a restart does not lose them
+        let reminders = Arc::new(ReminderScheduler::load(config.reminders_file.clone()));
+        reminders.spawn(Arc::clone(&publisher), Arc::clone(&bots));
+        let command_switches = Arc::new(CommandSwitches::new(config.disabled_commands.clone()));
+        // Kept per process unless replicas share them through Redis
+        let shared_state: Arc<dyn SharedState> = Arc::new(InMemorySharedState::default());
+        let sessions: Arc<dyn SessionStore> =
+            Arc::new(InMemorySessionStore::new(config.session_ttl));
+        #[cfg(feature = "redis")]
+        let (shared_state, sessions) = match &config.redis_url {
+            Some(url) => {
+                let connection = crate::redis_state::connect(url)
+                    .await
+                    .map_err(|e| ConfigError(vec![format!("redis_url: {}", e)]))?;
+                info!("Sharing sessions, cooldowns and rate limits through Redis.");
+                (
+                    Arc::new(crate::redis_state::RedisSharedState::new(
+                        connection.clone(),
+                    )) as _,
+                    Arc::new(crate::redis_state::RedisSessionStore::new(
+                        connection,
+                        config.session_ttl,
+                    )) as _,
+                )
+            }
+            None => (shared_state, sessions),
+        };
+        let dispatcher = Arc::new(Dispatcher {
+            publisher: Arc::clone(&publisher),
+            bots: Arc::clone(&bots),
+            translations: Arc::new(Translations::bundled()),
+            sessions,
+            cooldowns: Arc::new(
+                CommandCooldowns::new(config.cooldowns.clone())
+                    .with_state(Arc::clone(&shared_state)),
+            ),
+            flood: Arc::new(
+                FloodGuard::new(config.flood.clone()).with_state(Arc::clone(&shared_state)),
+            ),
+            metrics: Arc::clone(&metrics),
+            routes,
+            tiers: Arc::new(config.tiers.clone()),
+            admin_chat_ids: Arc::new(config.admin_chat_ids.clone()),
+            broadcaster: Arc::new(Broadcaster {
+                chats: known_chats,
+                interval: config.broadcast_interval,
+            }),
+            reminders,
+            command_switches: Arc::clone(&command_switches),
+            unknown_command_replies: Arc::new(
+                CommandCooldowns::single(UNKNOWN_COMMAND, config.unknown_command_interval)
+                    .with_state(Arc::clone(&shared_state)),
+            ),
+            user_records,
+            settings: chat_settings,
+        });
+
+        // Updates go through the same dispatcher whichever way they arrive
+        if config.mode == UpdateMode::Polling {
+            UpdatePoller {
+                dispatcher: Arc::clone(&dispatcher),
+                broker: Arc::clone(&broker),
+                timeout: config.polling_timeout,
+            }
+            .spawn(&bots);
+        }
+
+        let mut webhook_routes = Router::new()
+            .route("/webhook", post(receive_message))
+            .route("/webhook/:bot_id", post(receive_bot_message))
+            .route_layer(middleware::from_fn_with_state(
+                config.webhook_max_body_bytes,
+                validate_update,
+            ));
+        // Producers that sign their bodies can be verified before reaching the handlers
+        if let Some(verifier) = config.hmac_verifier(webhook_handler::SOURCE) {
+            webhook_routes = webhook_routes.route_layer(middleware::from_fn_with_state(
+                (Arc::new(verifier), config.webhook_max_body_bytes),
+                verify_hmac,
+            ));
+        }
+        // Telegram retries on 5xx, so updates arriving before the broker is up are not lost
+        let mut webhook_routes = webhook_routes.route_layer(middleware::from_fn_with_state(
+            Arc::clone(&broker),
+            require_broker,
+        ));
+        // One permit pool for both webhook routes; requests beyond it are shed, not queued
+        let webhook_permits = Arc::new(Semaphore::new(config.webhook_concurrency_limit));
+        webhook_routes = webhook_routes.route_layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(reject_overload))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(webhook_permits))
+                .timeout(config.webhook_timeout),
+        );
+        // Checked before everything but the rate limit, so unknown senders learn nothing about
+        // the broker or signatures
+        if let Some(ip_filter) = config.webhook_ip_filter.clone() {
+            webhook_routes = webhook_routes.route_layer(middleware::from_fn_with_state(
+                Arc::new(ip_filter),
+                filter_source_ip,
+            ));
+        }
+        // Outermost, so a client flooding the webhook is turned away before any other work;
+        // chat-level flood limits only apply once an update is parsed
+        if let Some(limit) = config.webhook_ip_rate_limit.clone() {
+            webhook_routes = webhook_routes.route_layer(middleware::from_fn_with_state(
+                Arc::new(IpRateLimiter::new(
+                    limit,
+                    shared_state,
+                    Arc::clone(&metrics),
+                )),
+                limit_source_ip,
+            ));
+        }
+
+        let mut router = Router::new()
+            .route("/", get(hello))
+            .route("/readyz", get(readyz))
+            .route("/version", get(version))
+            .merge(openapi::routes())
+            .merge(webhook_routes);
+
+        // Adapters for non-Telegram producers are only exposed when their secret is configured
+        if let Some(verifier) = config.hmac_verifier(github::SOURCE) {
+            let github_routes = Router::new()
+                .route("/ingest/github", post(receive_github_event))
+                .route_layer(middleware::from_fn_with_state(
+                    (Arc::new(verifier), config.webhook_max_body_bytes),
+                    verify_hmac,
+                ))
+                .layer(Extension(Arc::new(GithubAdapter::from_env())));
+            router = router.merge(github_routes);
+        }
+        if let Some(adapter) = StripeAdapter::from_env() {
+            let stripe_routes = Router::new()
+                .route("/ingest/stripe", post(receive_stripe_event))
+                .layer(Extension(Arc::new(adapter)));
+            router = router.merge(stripe_routes);
+        }
+
+        // Synchronous request/response over the broker, for callers holding the RPC secret;
+        // replies come back over RabbitMQ, so other backends go without
+        if let Some(verifier) = config.hmac_verifier(rpc::SOURCE).filter(|_| serves_rpc) {
+            let rpc_client =
+                RpcClient::new(Arc::clone(&broker), rabbit_publisher, config.rpc_timeout);
+            let rpc_routes = Router::new()
+                .route("/rpc/:queue", post(rpc::call_queue))
+                .route_layer(middleware::from_fn_with_state(
+                    (Arc::new(verifier), config.webhook_max_body_bytes),
+                    verify_hmac,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    Arc::clone(&broker),
+                    require_broker,
+                ))
+                .layer(Extension(Arc::new(rpc_client)));
+            router = router.merge(rpc_routes);
+        }
+
+        // Event streams from internal services, for producers holding the WebSocket secret
+        if let Some(verifier) = config.hmac_verifier(websocket::SOURCE) {
+            let ingest = WsIngest::new(verifier, config.ws_allowed_queues.clone());
+            let ws_routes = Router::new()
+                .route("/ws", get(websocket::upgrade))
+                .route_layer(middleware::from_fn_with_state(
+                    Arc::clone(&broker),
+                    require_broker,
+                ))
+                .layer(Extension(Arc::new(ingest)));
+            router = router.merge(ws_routes);
+        }
+
+        // Switching commands off and on, for operators holding the admin secret
+        if let Some(verifier) = config.hmac_verifier(admin::SOURCE) {
+            let admin_routes = Router::new()
+                .route("/admin/commands", get(admin::list_commands))
+                .route("/admin/commands/:command", post(admin::switch_command))
+                .route_layer(middleware::from_fn_with_state(
+                    (Arc::new(verifier), config.webhook_max_body_bytes),
+                    verify_hmac,
+                ))
+                .layer(Extension(command_switches));
+            router = router.merge(admin_routes);
+        }
+
+        // Re-publishing from the audit log, for operators holding the admin secret
+        #[cfg(feature = "audit")]
+        if let (Some(audit_log), Some(verifier)) = (audit_log, config.hmac_verifier(admin::SOURCE))
+        {
+            let admin_routes = Router::new()
+                .route("/admin/replay", post(crate::replay::replay))
+                .route_layer(middleware::from_fn_with_state(
+                    (Arc::new(verifier), config.webhook_max_body_bytes),
+                    verify_hmac,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    Arc::clone(&broker),
+                    require_broker,
+                ))
+                .layer(Extension(audit_log));
+            router = router.merge(admin_routes);
+        }
+
+        #[cfg(feature = "otel")]
+        let router = router.layer(middleware::from_fn(crate::telemetry::trace_request));
+        // Outside every other layer, so shed, throttled and rejected requests are logged too
+        let router = router.layer(middleware::from_fn(log_request));
+
+        let router = router
+            .layer(Extension(publisher))
+            .layer(Extension(Arc::clone(&dispatcher)))
+            .layer(Extension(Arc::clone(&metrics)))
+            .layer(Extension(Arc::clone(&broker)))
+            .layer(Extension(config.backend));
+
+        let webhooks = config
+            .webhook
+            .clone()
+            .map(|settings| Arc::new(WebhookRegistrar::new(settings, &bots)));
+        Ok(App {
+            config,
+            router,
+            webhooks,
+            dispatcher,
+            broker,
+            metrics,
+        })
+    }
+}
+
+pub struct App {
+    config: Config,
+    router: Router,
+    // Registered with Telegram while serving, if a public URL is configured
+    webhooks: Option<Arc<WebhookRegistrar>>,
+    dispatcher: Arc<Dispatcher>,
+    broker: Arc<Broker>,
+    metrics: Arc<Metrics>,
+}
+
+impl App {
+    pub fn builder() -> AppBuilder {
+        AppBuilder::default()
+    }
+
+    // Every route with its layers, to be merged into another service's router; the webhook IP
+    // filter needs it served with `into_make_service_with_connect_info::<SocketAddr>()`
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+
+    // Where updates go after the HTTP layers, for feeding them in without a request
+    pub fn dispatcher(&self) -> &Arc<Dispatcher> {
+        &self.dispatcher
+    }
+
+    pub fn broker(&self) -> &Arc<Broker> {
+        &self.broker
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    // Serves on the configured address until Ctrl-C or SIGTERM, registering the webhooks
+    // meanwhile
+    pub async fn serve(self) {
+        // Telegram retries failed deliveries, so registering while the listener binds is harmless
+        if let Some(webhooks) = &self.webhooks {
+            let webhooks = Arc::clone(webhooks);
+            tokio::spawn(async move { webhooks.register().await });
+        }
+
+        let server = async {
+            #[cfg(feature = "tls")]
+            if let Some(settings) = self.config.tls.clone() {
+                crate::tls::serve(
+                    &self.config.server_address,
+                    self.router.clone(),
+                    settings,
+                    shutdown_signal(),
+                )
+                .await;
+                return;
+            }
+
+            let listener = tokio::net::TcpListener::bind(&self.config.server_address)
+                .await
+                .expect("Could not bind to address");
+
+            println!("Listening on {}", listener.local_addr().unwrap());
+
+            // Client addresses are needed by the webhook IP filter
+            let app = self
+                .router
+                .clone()
+                .into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .expect("Error serving application");
+        };
+        server.await;
+
+        if let Some(webhooks) = self.webhooks {
+            webhooks.unregister().await;
+        }
+    }
+}
+
+// Resolves on Ctrl-C, or SIGTERM from an orchestrator
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .expect("Could not listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down.");
+}
+
+async fn hello() -> impl IntoResponse {
+    "Hello"
+}
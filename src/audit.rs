@@ -0,0 +1,436 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    PgPool, Postgres, QueryBuilder,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    broadcast::ChatDirectory,
+    deletion::UserRecords,
+    publisher::{MessagePublisher, PublishError, RabbitMessage},
+};
+
+// Records waiting to be written; when the database falls this far behind, new ones are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+const BATCH_SIZE: usize = 500;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS publish_audit (
+    id BIGSERIAL PRIMARY KEY,
+    published_at TIMESTAMPTZ NOT NULL,
+    update_id BIGINT,
+    bot_id TEXT,
+    chat_id BIGINT,
+    command TEXT,
+    destination TEXT NOT NULL,
+    payload_sha256 TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    error TEXT,
+    payload JSONB
+)";
+// Columns added after the table was first released
+const ADD_COLUMNS: &str = "ALTER TABLE publish_audit ADD COLUMN IF NOT EXISTS payload JSONB, \
+                           ADD COLUMN IF NOT EXISTS bot_id TEXT";
+
+// One row of publish_audit
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub published_at: SystemTime,
+    pub update_id: Option<i64>,
+    pub bot_id: Option<String>,
+    pub chat_id: Option<i64>,
+    pub command: Option<String>,
+    pub destination: String,
+    pub payload_sha256: String,
+    pub error: Option<String>,
+    // The message as JSON, so it can be replayed
+    pub payload: String,
+}
+
+impl AuditRecord {
+    fn new(destination: &str, message: &RabbitMessage, result: &Result<(), PublishError>) -> Self {
+        // The JSON form, so the hash does not depend on the queue's encoding
+        let payload = serde_json::to_string(message).unwrap_or_default();
+        Self {
+            published_at: SystemTime::now(),
+            update_id: message.metadata.update_id,
+            bot_id: message.bot_id.clone(),
+            chat_id: message.chat_id,
+            command: message.metadata.command.clone(),
+            destination: destination.to_string(),
+            payload_sha256: hex::encode(Sha256::digest(&payload)),
+            error: result.as_ref().err().map(ToString::to_string),
+            payload,
+        }
+    }
+
+    fn outcome(&self) -> &'static str {
+        if self.error.is_some() {
+            "failed"
+        } else {
+            "published"
+        }
+    }
+}
+
+// What the writer does with the database, in the order it was queued
+#[derive(Debug)]
+pub enum AuditWrite {
+    Record(AuditRecord),
+    // Deletes a user's records once every record queued before has been written
+    Purge {
+        bot_id: String,
+        user_id: i64,
+        done: oneshot::Sender<Result<u64, String>>,
+    },
+}
+
+// The audit database, and the queue of writes waiting for it
+#[derive(Clone)]
+pub struct AuditLog {
+    pub pool: PgPool,
+    pub records: mpsc::Sender<AuditWrite>,
+}
+
+// Where the writer puts records; the database, or memory in tests
+#[async_trait]
+trait AuditStore: Send + Sync {
+    async fn insert(&self, records: &[AuditRecord]) -> Result<(), String>;
+    // A user's records are those of their private chat (whose id is theirs) and those their
+    // updates produced elsewhere
+    async fn purge(&self, bot_id: &str, user_id: i64) -> Result<u64, String>;
+}
+
+// Checked when the config is loaded, so a URL the driver rejects never reaches startup
+pub fn connect_options(url: &str) -> Result<PgConnectOptions, String> {
+    url.parse().map_err(|e: sqlx::Error| e.to_string())
+}
+
+// Starts the writer; the database is connected to lazily, so an unreachable one only delays
+// the records
+pub fn init(options: PgConnectOptions) -> AuditLog {
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect_lazy_with(options);
+    let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+    tokio::spawn(write_records(pool.clone(), receiver));
+    info!("Recording published messages in the audit log.");
+    AuditLog {
+        pool,
+        records: sender,
+    }
+}
+
+async fn write_records(pool: PgPool, receiver: mpsc::Receiver<AuditWrite>) {
+    while let Err(e) = create_table(&pool).await {
+        warn!("Could not create the audit table, retrying: {}", e);
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+    drain(&pool, receiver).await;
+}
+
+// Writes records in batches, off the publish path. Purges run in queue order, so no record
+// queued before one can be written after it.
+async fn drain(store: &impl AuditStore, mut receiver: mpsc::Receiver<AuditWrite>) {
+    let mut writes = Vec::with_capacity(BATCH_SIZE);
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while receiver.recv_many(&mut writes, BATCH_SIZE).await > 0 {
+        for write in writes.drain(..) {
+            match write {
+                AuditWrite::Record(record) => batch.push(record),
+                AuditWrite::Purge {
+                    bot_id,
+                    user_id,
+                    done,
+                } => {
+                    flush(store, &mut batch).await;
+                    let _ = done.send(store.purge(&bot_id, user_id).await);
+                }
+            }
+        }
+        flush(store, &mut batch).await;
+    }
+}
+
+// A failed batch is retried until it is written
+async fn flush(store: &impl AuditStore, batch: &mut Vec<AuditRecord>) {
+    if batch.is_empty() {
+        return;
+    }
+    while let Err(e) = store.insert(batch).await {
+        warn!(
+            "Could not write {} audit records, retrying: {}",
+            batch.len(),
+            e
+        );
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+    batch.clear();
+}
+
+async fn create_table(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query(CREATE_TABLE).execute(pool).await?;
+    sqlx::query(ADD_COLUMNS).execute(pool).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl AuditStore for PgPool {
+    async fn insert(&self, records: &[AuditRecord]) -> Result<(), String> {
+        insert(self, records).await.map_err(|e| e.to_string())
+    }
+
+    async fn purge(&self, bot_id: &str, user_id: i64) -> Result<u64, String> {
+        sqlx::query(
+            "DELETE FROM publish_audit WHERE bot_id = $1 AND (chat_id = $2 OR payload->>'from_id' = $3)",
+        )
+        .bind(bot_id)
+        .bind(user_id)
+        .bind(user_id.to_string())
+        .execute(self)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(|e| e.to_string())
+    }
+}
+
+async fn insert(pool: &PgPool, records: &[AuditRecord]) -> Result<(), sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new(
+        "INSERT INTO publish_audit (published_at, update_id, bot_id, chat_id, command, destination, \
+         payload_sha256, outcome, error, payload) ",
+    );
+    query.push_values(records, |mut row, record| {
+        let published_at = record
+            .published_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        row.push("to_timestamp(")
+            .push_bind_unseparated(published_at)
+            .push_unseparated(")")
+            .push_bind(record.update_id)
+            .push_bind(record.bot_id.clone())
+            .push_bind(record.chat_id)
+            .push_bind(record.command.clone())
+            .push_bind(record.destination.clone())
+            .push_bind(record.payload_sha256.clone())
+            .push_bind(record.outcome())
+            .push_bind(record.error.clone())
+            .push_bind(record.payload.clone())
+            .push_unseparated("::jsonb");
+    });
+    query.build().execute(pool).await?;
+    Ok(())
+}
+
+// Every chat a bot has published to is a chat it knows
+#[async_trait]
+impl ChatDirectory for AuditLog {
+    async fn record(&self, _bot_id: &str, _chat_id: i64) {}
+
+    async fn chats(&self, bot_id: &str) -> Result<Vec<i64>, String> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT chat_id FROM publish_audit WHERE bot_id = $1 AND chat_id IS NOT NULL",
+        )
+        .bind(bot_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+}
+
+// Queued behind the records waiting to be written, so those are purged too
+#[async_trait]
+impl UserRecords for AuditLog {
+    async fn purge_user(&self, bot_id: &str, user_id: i64) -> Result<u64, String> {
+        let stopped = || "the audit writer has stopped".to_string();
+        let (done, purged) = oneshot::channel();
+        self.records
+            .send(AuditWrite::Purge {
+                bot_id: bot_id.to_string(),
+                user_id,
+                done,
+            })
+            .await
+            .map_err(|_| stopped())?;
+        purged.await.map_err(|_| stopped())?
+    }
+}
+
+// Wraps another publisher and queues an audit record for every publish, without waiting for it
+pub struct AuditPublisher {
+    inner: Arc<dyn MessagePublisher>,
+    records: mpsc::Sender<AuditWrite>,
+}
+
+impl AuditPublisher {
+    pub fn new(inner: Arc<dyn MessagePublisher>, records: mpsc::Sender<AuditWrite>) -> Self {
+        Self { inner, records }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for AuditPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        let result = self.inner.publish(destination, message).await;
+        let record = AuditRecord::new(destination, message, &result);
+        if let Err(e) = self.records.try_send(AuditWrite::Record(record)) {
+            warn!(
+                "Dropped the audit record of a publish to {}: {}",
+                destination, e
+            );
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use axum::{http::HeaderMap, Extension};
+
+    use crate::{
+        publisher::MessageMetadata,
+        testing::{dispatcher, fixtures, RecordingPublisher},
+        webhook_handler::receive_message,
+    };
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<Vec<AuditRecord>>);
+
+    #[async_trait]
+    impl AuditStore for MemoryStore {
+        async fn insert(&self, records: &[AuditRecord]) -> Result<(), String> {
+            self.0.lock().unwrap().extend_from_slice(records);
+            Ok(())
+        }
+
+        async fn purge(&self, bot_id: &str, user_id: i64) -> Result<u64, String> {
+            let mut records = self.0.lock().unwrap();
+            let before = records.len();
+            records.retain(|record| {
+                let from_id = serde_json::from_str::<serde_json::Value>(&record.payload).unwrap()
+                    ["from_id"]
+                    .as_i64();
+                record.bot_id.as_deref() != Some(bot_id)
+                    || (record.chat_id != Some(user_id) && from_id != Some(user_id))
+            });
+            Ok((before - records.len()) as u64)
+        }
+    }
+
+    fn received(write: Option<AuditWrite>) -> AuditRecord {
+        match write {
+            Some(AuditWrite::Record(record)) => record,
+            other => panic!("expected a record, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_publish_is_recorded_with_its_outcome() {
+        let inner = Arc::new(RecordingPublisher::default());
+        let (sender, mut receiver) = mpsc::channel(8);
+        let publisher = AuditPublisher::new(Arc::clone(&inner) as _, sender);
+        let message = RabbitMessage::chat("default", 42, "hi").with_metadata(MessageMetadata {
+            command: Some("/help".to_string()),
+            update_id: Some(9),
+            ..MessageMetadata::default()
+        });
+
+        publisher.publish("Reply", &message).await.unwrap();
+        inner.fail_with(|| PublishError::NotConnected);
+        publisher.publish("Reply", &message).await.unwrap_err();
+
+        let published = received(receiver.recv().await);
+        assert_eq!(published.update_id, Some(9));
+        assert_eq!(published.chat_id, Some(42));
+        assert_eq!(published.command.as_deref(), Some("/help"));
+        assert_eq!(published.outcome(), "published");
+        let failed = received(receiver.recv().await);
+        assert_eq!(failed.outcome(), "failed");
+        assert_eq!(failed.payload_sha256, published.payload_sha256);
+    }
+
+    #[tokio::test]
+    async fn purges_also_remove_records_queued_before_them() {
+        let store = Arc::new(MemoryStore::default());
+        let (sender, receiver) = mpsc::channel(8);
+        let writer = tokio::spawn({
+            let store = Arc::clone(&store);
+            async move { drain(store.as_ref(), receiver).await }
+        });
+        let publisher =
+            AuditPublisher::new(Arc::new(RecordingPublisher::default()), sender.clone());
+        let log = AuditLog {
+            pool: PgPoolOptions::new()
+                .connect_lazy("postgres://localhost/audit")
+                .unwrap(),
+            records: sender,
+        };
+
+        for (chat_id, queue) in [(42, "Deletion"), (7, "Music"), (42, "Reply")] {
+            let message = RabbitMessage::chat("default", chat_id, "hi");
+            publisher.publish(queue, &message).await.unwrap();
+        }
+        assert_eq!(log.purge_user("default", 42).await, Ok(2));
+        drop((publisher, log));
+        writer.await.unwrap();
+
+        let left = store.0.lock().unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].chat_id, Some(7));
+    }
+
+    #[tokio::test]
+    async fn no_record_of_the_user_survives_deleteme() {
+        let store = Arc::new(MemoryStore::default());
+        let (sender, receiver) = mpsc::channel(8);
+        let writer = tokio::spawn({
+            let store = Arc::clone(&store);
+            async move { drain(store.as_ref(), receiver).await }
+        });
+        let recorded = Arc::new(RecordingPublisher::default());
+        let mut dispatcher = dispatcher(Arc::clone(&recorded));
+        dispatcher.publisher = Arc::new(AuditPublisher::new(
+            Arc::clone(&recorded) as _,
+            sender.clone(),
+        ));
+        dispatcher.user_records = Some(Arc::new(AuditLog {
+            pool: PgPoolOptions::new()
+                .connect_lazy("postgres://localhost/audit")
+                .unwrap(),
+            records: sender,
+        }));
+        let dispatcher = Arc::new(dispatcher);
+
+        for text in ["/help", "/deleteme"] {
+            let update = fixtures::text_message(text);
+            receive_message(
+                Extension(Arc::clone(&dispatcher)),
+                HeaderMap::new(),
+                fixtures::to_body(&update),
+            )
+            .await
+            .unwrap();
+        }
+        drop(dispatcher);
+        writer.await.unwrap();
+
+        assert_eq!(recorded.queues(), vec!["Reply", "Deletion", "Reply"]);
+        assert!(store.0.lock().unwrap().is_empty());
+    }
+}
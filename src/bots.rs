@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+pub const DEFAULT_BOT_ID: &str = "default";
+
+// How much of a /songlinks request is forwarded to the Music queue
+#[derive(Debug, Clone, PartialEq)]
+pub struct SonglinksLimits {
+    pub max_lines: usize,
+    pub max_line_chars: usize,
+}
+
+impl Default for SonglinksLimits {
+    fn default() -> Self {
+        Self {
+            max_lines: 10,
+            max_line_chars: 50,
+        }
+    }
+}
+
+// Per-bot settings for one Telegram bot served by this deployment
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub id: String,
+    pub secret_token: Option<String>,
+    // Bot API token, needed only to register the webhook with Telegram
+    pub api_token: Option<String>,
+    pub queue_prefix: String,
+    // None means every command is enabled
+    pub enabled_commands: Option<HashSet<String>>,
+    pub songlinks_limits: SonglinksLimits,
+    // Only forward stickers that someone replies to with /stickerinfo
+    pub stickers_on_command: bool,
+    // Commands answered with an immediate "working on it" reply once their work is queued
+    pub acknowledged_commands: HashSet<String>,
+    // Replaces the translated acknowledgment text
+    pub acknowledgment_text: Option<String>,
+    // Telegram username without the "@", to recognise "/help@username" in groups
+    pub username: Option<String>,
+    // Chat types ("group", "supergroup", ...) where commands are only handled when addressed to
+    // the bot: "/help@username", a mention of it or a reply to one of its messages
+    pub addressed_chat_types: HashSet<String>,
+}
+
+impl BotConfig {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            secret_token: None,
+            api_token: None,
+            queue_prefix: String::new(),
+            enabled_commands: None,
+            songlinks_limits: SonglinksLimits::default(),
+            stickers_on_command: false,
+            acknowledged_commands: HashSet::new(),
+            acknowledgment_text: None,
+            username: None,
+            addressed_chat_types: HashSet::new(),
+        }
+    }
+
+    pub fn command_enabled(&self, command: &str) -> bool {
+        self.enabled_commands
+            .as_ref()
+            .is_none_or(|commands| commands.contains(command))
+    }
+
+    pub fn queue_name(&self, queue: &str) -> String {
+        format!("{}{}", self.queue_prefix, queue)
+    }
+
+    // "/help@username" -> Some("/help") when the username is this bot's, None when the command
+    // is addressed to another bot (or the bot's username is not configured)
+    pub fn own_command<'a>(&self, command: &'a str) -> Option<&'a str> {
+        let Some((command, username)) = command.split_once('@') else {
+            return Some(command);
+        };
+        self.username
+            .as_deref()
+            .filter(|own| own.eq_ignore_ascii_case(username))
+            .map(|_| command)
+    }
+
+    // Whether commands in this type of chat must be addressed to the bot
+    pub fn requires_address(&self, chat_type: Option<&str>) -> bool {
+        chat_type.is_some_and(|chat_type| self.addressed_chat_types.contains(chat_type))
+    }
+
+    // Compared in constant time, so the token cannot be guessed from response times
+    pub fn verify_secret(&self, provided: Option<&str>) -> bool {
+        match &self.secret_token {
+            Some(expected) => provided
+                .is_some_and(|provided| provided.as_bytes().ct_eq(expected.as_bytes()).into()),
+            None => true,
+        }
+    }
+}
+
+// Settings of one bot, or of every bot when set in bot_defaults; fields left unset fall through
+// like the config layers'
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BotOverrides {
+    pub secret_token: Option<String>,
+    pub api_token: Option<String>,
+    pub queue_prefix: Option<String>,
+    // Comma separated; when set only these commands are enabled
+    pub commands: Option<String>,
+    pub songlinks_max_lines: Option<usize>,
+    pub songlinks_max_chars: Option<usize>,
+    pub stickers_on_command: Option<bool>,
+    pub ack_commands: Option<String>,
+    pub ack_text: Option<String>,
+    pub username: Option<String>,
+    pub addressed_chat_types: Option<String>,
+}
+
+impl BotOverrides {
+    // Values set in `over` win
+    pub fn merge(self, over: Self) -> Self {
+        Self {
+            secret_token: over.secret_token.or(self.secret_token),
+            api_token: over.api_token.or(self.api_token),
+            queue_prefix: over.queue_prefix.or(self.queue_prefix),
+            commands: over.commands.or(self.commands),
+            songlinks_max_lines: over.songlinks_max_lines.or(self.songlinks_max_lines),
+            songlinks_max_chars: over.songlinks_max_chars.or(self.songlinks_max_chars),
+            stickers_on_command: over.stickers_on_command.or(self.stickers_on_command),
+            ack_commands: over.ack_commands.or(self.ack_commands),
+            ack_text: over.ack_text.or(self.ack_text),
+            username: over.username.or(self.username),
+            addressed_chat_types: over.addressed_chat_types.or(self.addressed_chat_types),
+        }
+    }
+
+    pub fn into_bot(self, id: &str) -> Result<BotConfig, Vec<String>> {
+        let mut problems = Vec::new();
+        let defaults = SonglinksLimits::default();
+        let mut limit = |name: &str, value: Option<usize>, default: usize| match value {
+            Some(0) => {
+                problems.push(format!("bots.{}.{}: must be greater than zero", id, name));
+                default
+            }
+            value => value.unwrap_or(default),
+        };
+        let songlinks_limits = SonglinksLimits {
+            max_lines: limit(
+                "songlinks_max_lines",
+                self.songlinks_max_lines,
+                defaults.max_lines,
+            ),
+            max_line_chars: limit(
+                "songlinks_max_chars",
+                self.songlinks_max_chars,
+                defaults.max_line_chars,
+            ),
+        };
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Ok(BotConfig {
+            id: id.to_string(),
+            secret_token: self.secret_token.filter(|t| !t.is_empty()),
+            api_token: self.api_token.filter(|t| !t.is_empty()),
+            queue_prefix: self.queue_prefix.unwrap_or_default(),
+            enabled_commands: self.commands.map(|commands| parse_commands(&commands)),
+            songlinks_limits,
+            stickers_on_command: self.stickers_on_command.unwrap_or_default(),
+            acknowledged_commands: self
+                .ack_commands
+                .map(|commands| parse_commands(&commands))
+                .unwrap_or_default(),
+            acknowledgment_text: self.ack_text.filter(|t| !t.is_empty()),
+            username: self
+                .username
+                .map(|name| name.trim_start_matches('@').to_string())
+                .filter(|name| !name.is_empty()),
+            addressed_chat_types: self
+                .addressed_chat_types
+                .map(|types| {
+                    types
+                        .split(',')
+                        .map(|chat_type| chat_type.trim().to_lowercase())
+                        .filter(|chat_type| !chat_type.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+// "readimage, /songlinks" -> {"/readimage", "/songlinks"}
+fn parse_commands(commands: &str) -> HashSet<String> {
+    commands
+        .split(',')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .map(|c| format!("/{}", c.trim_start_matches('/')))
+        .collect()
+}
+
+pub struct BotRegistry {
+    bots: HashMap<String, BotConfig>,
+}
+
+impl BotRegistry {
+    pub fn new(bots: Vec<BotConfig>) -> Self {
+        Self {
+            bots: bots.into_iter().map(|bot| (bot.id.clone(), bot)).collect(),
+        }
+    }
+
+    pub fn get(&self, bot_id: &str) -> Option<&BotConfig> {
+        self.bots.get(bot_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BotConfig> {
+        self.bots.values()
+    }
+
+    // The given queues with every registered bot's prefix applied
+    pub fn queue_names(&self, queues: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .bots
+            .values()
+            .flat_map(|bot| queues.iter().map(|queue| bot.queue_name(queue)))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
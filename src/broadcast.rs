@@ -0,0 +1,132 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{info, warn};
+
+use crate::{
+    bots::BotConfig,
+    publisher::{MessagePublisher, RabbitMessage},
+    routing::REPLY_QUEUE,
+};
+
+// Where /broadcast finds the chats a bot has talked to
+#[async_trait]
+pub trait ChatDirectory: Send + Sync {
+    // Called for every update that belongs to a chat
+    async fn record(&self, bot_id: &str, chat_id: i64);
+    async fn chats(&self, bot_id: &str) -> Result<Vec<i64>, String>;
+}
+
+// Chats seen since this process started
+#[derive(Default)]
+pub struct InMemoryChatDirectory {
+    chats: Mutex<HashMap<String, HashSet<i64>>>,
+}
+
+#[async_trait]
+impl ChatDirectory for InMemoryChatDirectory {
+    async fn record(&self, bot_id: &str, chat_id: i64) {
+        let mut chats = self.chats.lock().expect("chat directory lock poisoned");
+        if let Some(known) = chats.get_mut(bot_id) {
+            known.insert(chat_id);
+        } else {
+            chats.insert(bot_id.to_string(), HashSet::from([chat_id]));
+        }
+    }
+
+    async fn chats(&self, bot_id: &str) -> Result<Vec<i64>, String> {
+        let chats = self.chats.lock().expect("chat directory lock poisoned");
+        Ok(chats
+            .get(bot_id)
+            .map(|known| known.iter().copied().collect())
+            .unwrap_or_default())
+    }
+}
+
+// Sends an announcement to every known chat of a bot, one Reply message per chat
+pub struct Broadcaster {
+    pub chats: Arc<dyn ChatDirectory>,
+    // Pause between two messages, so the Reply consumer stays under Telegram's rate limits
+    pub interval: Duration,
+}
+
+impl Broadcaster {
+    // Starts the broadcast in the background and returns how many chats it will reach
+    pub async fn start(
+        &self,
+        publisher: Arc<dyn MessagePublisher>,
+        bot: &BotConfig,
+        text: &str,
+    ) -> Result<usize, String> {
+        let mut chats = self.chats.chats(&bot.id).await?;
+        chats.sort_unstable();
+        let count = chats.len();
+        let queue = bot.queue_name(REPLY_QUEUE);
+        let bot_id = bot.id.clone();
+        let text = text.to_string();
+        let interval = self.interval;
+        tokio::spawn(async move {
+            let mut failed = 0;
+            for (index, chat_id) in chats.into_iter().enumerate() {
+                if index > 0 {
+                    tokio::time::sleep(interval).await;
+                }
+                let message =
+                    RabbitMessage::chat(&bot_id, chat_id, text.as_str()).with_event("broadcast");
+                if let Err(e) = publisher.publish(&queue, &message).await {
+                    warn!("Could not broadcast to chat {}: {}", chat_id, e);
+                    failed += 1;
+                }
+            }
+            info!(
+                "Broadcast of bot '{}' reached {} of {} chats.",
+                bot_id,
+                count - failed,
+                count
+            );
+        });
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingPublisher;
+
+    #[tokio::test]
+    async fn broadcasts_reach_every_known_chat_of_the_bot() {
+        let chats = Arc::new(InMemoryChatDirectory::default());
+        chats.record("default", 1).await;
+        chats.record("default", 2).await;
+        chats.record("default", 1).await;
+        chats.record("music", 3).await;
+        let broadcaster = Broadcaster {
+            chats,
+            interval: Duration::ZERO,
+        };
+        let publisher = Arc::new(RecordingPublisher::default());
+
+        let count = broadcaster
+            .start(
+                Arc::clone(&publisher) as _,
+                &BotConfig::new("default"),
+                "Maintenance at noon",
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(count, 2);
+        let chat_ids: Vec<_> = publisher
+            .published()
+            .into_iter()
+            .map(|(_, message)| message.chat_id)
+            .collect();
+        assert_eq!(chat_ids, vec![Some(1), Some(2)]);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use lapin::{Channel, Connection};
+use log::{info, warn};
+
+use crate::{
+    amqp_tls::AmqpConnector,
+    channel_pool::{open_channel, Autoscale, ChannelPool, ChannelSelection},
+    config::Config,
+    errors::WebhookError,
+    metrics::Metrics,
+};
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const AUTOSCALE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How the channel pool is built once the broker is reachable
+pub struct PoolOptions {
+    pub channels: usize,
+    pub selection: ChannelSelection,
+    pub health_check: Option<Duration>,
+    pub max_retry_delay: Duration,
+    // Queue -> number of channels reserved for it
+    pub dedicated: HashMap<String, usize>,
+    // Set when the pool may grow beyond its initial size
+    pub autoscale: Option<Autoscale>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl PoolOptions {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let autoscale = (config.channel_pool_max > config.channel_pool_size).then_some(Autoscale {
+            min: config.channel_pool_size,
+            max: config.channel_pool_max,
+            grow_after: config.channel_pool_grow_after,
+            interval: AUTOSCALE_INTERVAL,
+        });
+        Self {
+            channels: config.channel_pool_size,
+            selection: config.channel_selection,
+            health_check: config.channel_health_check,
+            max_retry_delay: config.broker_retry_max_delay,
+            dedicated: config.queues.dedicated_channels(),
+            autoscale,
+            metrics,
+        }
+    }
+}
+
+// The broker connection, established in the background so the HTTP server can start first
+#[derive(Default)]
+pub struct Broker {
+    pool: OnceLock<Arc<ChannelPool>>,
+    // Set when another backend publishes, so nothing waits for a RabbitMQ that never comes
+    unused: bool,
+}
+
+impl Broker {
+    // Connects right away, for one-shot commands that cannot wait in the background
+    pub async fn connect(connector: &AmqpConnector, channels: usize) -> Result<Self, String> {
+        let broker = Self::default();
+        let _ = broker.pool.set(Arc::new(
+            open_pool(
+                connector,
+                channels,
+                ChannelSelection::default(),
+                &HashMap::new(),
+            )
+            .await?,
+        ));
+        Ok(broker)
+    }
+
+    pub async fn close(&self) {
+        if let Some(pool) = self.pool.get() {
+            if let Err(e) = pool.close().await {
+                warn!("Could not close the RabbitMQ connection cleanly: {}", e);
+            }
+        }
+    }
+
+    pub fn channel_pool(&self) -> Option<&Arc<ChannelPool>> {
+        self.pool.get()
+    }
+
+    pub fn unused() -> Self {
+        Self {
+            unused: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.unused || self.pool.get().is_some()
+    }
+
+    // Keeps trying to connect with exponential backoff until the channel pool is up
+    pub fn spawn_connect(self: &Arc<Self>, connector: AmqpConnector, options: PoolOptions) {
+        let broker = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut delay = INITIAL_RETRY_DELAY;
+            loop {
+                let pool = open_pool(
+                    &connector,
+                    options.channels,
+                    options.selection,
+                    &options.dedicated,
+                );
+                match pool.await {
+                    Ok(pool) => {
+                        let pool = Arc::new(pool);
+                        if let Some(interval) = options.health_check {
+                            pool.spawn_health_check(interval);
+                        }
+                        options
+                            .metrics
+                            .channel_pool_size
+                            .store(pool.size() as u64, Ordering::Relaxed);
+                        if let Some(autoscale) = options.autoscale {
+                            pool.spawn_autoscale(autoscale, Arc::clone(&options.metrics));
+                        }
+                        let _ = broker.pool.set(pool);
+                        info!("Connected to RabbitMQ.");
+                        return;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Could not connect to RabbitMQ ({}); retrying in {}s.",
+                            e,
+                            delay.as_secs()
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(options.max_retry_delay);
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn open_pool(
+    connector: &AmqpConnector,
+    channels: usize,
+    selection: ChannelSelection,
+    dedicated: &HashMap<String, usize>,
+) -> Result<ChannelPool, String> {
+    let connection = Arc::new(connector.connect().await?);
+    let mut pool = ChannelPool::new(
+        Arc::clone(&connection),
+        open_channels(&connection, channels).await?,
+        selection,
+    );
+    for (queue, &channels) in dedicated {
+        info!("Dedicating {} channels to {}.", channels, queue);
+        let channels = open_channels(&connection, channels).await?;
+        pool = pool.with_dedicated(queue.clone(), channels, selection);
+    }
+    Ok(pool)
+}
+
+async fn open_channels(connection: &Connection, count: usize) -> Result<Vec<Arc<Channel>>, String> {
+    let mut channels = Vec::with_capacity(count);
+    for _ in 0..count {
+        let channel = open_channel(connection).await.map_err(|e| e.to_string())?;
+        channels.push(Arc::new(channel));
+    }
+    Ok(channels)
+}
+
+// Readiness probe for orchestrators; the process is live well before this turns 200
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Connected to the broker", body = String, example = "ready"),
+        (status = 503, description = "Still connecting", body = String),
+    )
+)]
+pub async fn readyz(Extension(broker): Extension<Arc<Broker>>) -> impl IntoResponse {
+    if broker.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "waiting for RabbitMQ")
+    }
+}
+
+// Middleware answering 503 until the broker is connected, so producers retry later
+pub async fn require_broker(
+    State(broker): State<Arc<Broker>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, WebhookError> {
+    if !broker.is_ready() {
+        return Err(WebhookError::broker_unavailable());
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,445 @@
+use arc_swap::ArcSwap;
+use lapin::{options::ConfirmSelectOptions, Channel, Connection};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use crate::metrics::Metrics;
+
+// Channels run in confirm mode so a publish learns whether the broker returned the message
+pub async fn open_channel(connection: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = connection.create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    Ok(channel)
+}
+
+// How the next channel is picked from the pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelSelection {
+    #[default]
+    RoundRobin,
+    // The channel with the fewest publishes still waiting for their confirm
+    LeastInFlight,
+}
+
+impl FromStr for ChannelSelection {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(ChannelSelection::RoundRobin),
+            "least-in-flight" => Ok(ChannelSelection::LeastInFlight),
+            other => Err(format!(
+                "unknown channel selection {:?}, expected round-robin or least-in-flight",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChannelSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelSelection::RoundRobin => write!(f, "round-robin"),
+            ChannelSelection::LeastInFlight => write!(f, "least-in-flight"),
+        }
+    }
+}
+
+impl ChannelSelection {
+    fn selector(self) -> Box<dyn ChannelSelector> {
+        match self {
+            ChannelSelection::RoundRobin => Box::<RoundRobin>::default(),
+            ChannelSelection::LeastInFlight => Box::<LeastInFlight>::default(),
+        }
+    }
+}
+
+// Picks a slot index out of `slots`, given each slot's number of in-flight checkouts
+pub trait ChannelSelector: Send + Sync {
+    fn select(&self, slots: usize, in_flight: &dyn Fn(usize) -> usize) -> usize;
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl ChannelSelector for RoundRobin {
+    fn select(&self, slots: usize, _in_flight: &dyn Fn(usize) -> usize) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % slots
+    }
+}
+
+#[derive(Default)]
+pub struct LeastInFlight {
+    // Where the scan starts, rotated so ties (e.g. an idle pool) are spread over the slots
+    start: AtomicUsize,
+}
+
+impl ChannelSelector for LeastInFlight {
+    fn select(&self, slots: usize, in_flight: &dyn Fn(usize) -> usize) -> usize {
+        let start = self.start.fetch_add(1, Ordering::Relaxed);
+        (0..slots)
+            .map(|offset| (start + offset) % slots)
+            .min_by_key(|&index| in_flight(index))
+            .unwrap_or_default()
+    }
+}
+
+// One pooled channel; the lock is only taken to replace a closed channel, never to check out
+struct Slot {
+    channel: ArcSwap<Channel>,
+    in_flight: AtomicUsize,
+    replacing: Mutex<()>,
+}
+
+impl Slot {
+    fn new(channel: Arc<Channel>) -> Arc<Self> {
+        Arc::new(Self {
+            channel: ArcSwap::new(channel),
+            in_flight: AtomicUsize::new(0),
+            replacing: Mutex::new(()),
+        })
+    }
+}
+
+// How long checkouts were held (publish and confirm) since the autoscaler last looked
+#[derive(Default)]
+struct CheckoutStats {
+    checkouts: AtomicU64,
+    held_micros: AtomicU64,
+    // Since the pool was created; never reset
+    contention: ChannelContention,
+}
+
+// Checkouts, and those that got a channel another publish was still holding
+#[derive(Default)]
+struct ChannelContention {
+    checkouts: AtomicU64,
+    contended: AtomicU64,
+}
+
+impl CheckoutStats {
+    fn record(&self, held: Duration) {
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.held_micros
+            .fetch_add(held.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn record_checkout(&self, in_flight_before: usize) {
+        self.contention.checkouts.fetch_add(1, Ordering::Relaxed);
+        if in_flight_before > 0 {
+            self.contention.contended.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // The number of checkouts and their average hold time, resetting both
+    fn take(&self) -> (u64, Duration) {
+        let checkouts = self.checkouts.swap(0, Ordering::Relaxed);
+        let held_micros = self.held_micros.swap(0, Ordering::Relaxed);
+        let average = held_micros.checked_div(checkouts).unwrap_or_default();
+        (checkouts, Duration::from_micros(average))
+    }
+}
+
+// A checked-out channel; it counts as in flight on its slot until dropped
+pub struct PooledChannel {
+    channel: Arc<Channel>,
+    slot: Arc<Slot>,
+    stats: Arc<CheckoutStats>,
+    checked_out_at: Instant,
+}
+
+impl Deref for PooledChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        &self.channel
+    }
+}
+
+impl Drop for PooledChannel {
+    fn drop(&mut self) {
+        self.slot.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.stats.record(self.checked_out_at.elapsed());
+    }
+}
+
+// Resizing bounds; the pool starts at `min` channels
+#[derive(Debug, Clone, Copy)]
+pub struct Autoscale {
+    pub min: usize,
+    pub max: usize,
+    // Grow when checkouts are held longer than this on average
+    pub grow_after: Duration,
+    pub interval: Duration,
+}
+
+// Channels sharing one selection strategy: the shared pool, or those dedicated to a queue
+struct SlotGroup {
+    // Swapped as a whole when the pool is resized; checkouts work on the snapshot they loaded
+    slots: ArcSwap<Vec<Arc<Slot>>>,
+    selector: Box<dyn ChannelSelector>,
+    stats: Arc<CheckoutStats>,
+}
+
+impl SlotGroup {
+    fn new(channels: Vec<Arc<Channel>>, selection: ChannelSelection) -> Self {
+        Self {
+            slots: ArcSwap::from_pointee(channels.into_iter().map(Slot::new).collect()),
+            selector: selection.selector(),
+            stats: Arc::default(),
+        }
+    }
+}
+
+pub struct ChannelPool {
+    connection: Arc<Connection>,
+    shared: SlotGroup,
+    dedicated: HashMap<String, SlotGroup>,
+}
+
+impl ChannelPool {
+    pub fn new(
+        connection: Arc<Connection>,
+        channels: Vec<Arc<Channel>>,
+        selection: ChannelSelection,
+    ) -> Self {
+        assert!(!channels.is_empty(), "Channel pool should never be empty");
+        Self {
+            connection,
+            shared: SlotGroup::new(channels, selection),
+            dedicated: HashMap::new(),
+        }
+    }
+
+    // Publishes to `queue` go over `channels` of its own instead of the shared ones
+    pub fn with_dedicated(
+        mut self,
+        queue: String,
+        channels: Vec<Arc<Channel>>,
+        selection: ChannelSelection,
+    ) -> Self {
+        assert!(
+            !channels.is_empty(),
+            "Dedicated channels should never be empty"
+        );
+        self.dedicated
+            .insert(queue, SlotGroup::new(channels, selection));
+        self
+    }
+
+    // Size of the shared pool
+    pub fn size(&self) -> usize {
+        self.shared.slots.load().len()
+    }
+
+    // Checkouts since the pool was created and how many of them shared a busy channel, over the
+    // shared and dedicated channels; a high share means publishes queue behind each other
+    pub fn contention(&self) -> (u64, u64) {
+        std::iter::once(&self.shared)
+            .chain(self.dedicated.values())
+            .map(|group| &group.stats.contention)
+            .fold((0, 0), |(checkouts, contended), contention| {
+                (
+                    checkouts + contention.checkouts.load(Ordering::Relaxed),
+                    contended + contention.contended.load(Ordering::Relaxed),
+                )
+            })
+    }
+
+    // Wait-free checkout from the shared pool
+    pub async fn get_next_channel(&self) -> Result<PooledChannel, lapin::Error> {
+        self.checkout(&self.shared).await
+    }
+
+    // Checkout for a publish to `queue`, from its dedicated channels if it has any
+    pub async fn get_channel_for(&self, queue: &str) -> Result<PooledChannel, lapin::Error> {
+        self.checkout(self.dedicated.get(queue).unwrap_or(&self.shared))
+            .await
+    }
+
+    // A channel the broker has closed is replaced first
+    async fn checkout(&self, group: &SlotGroup) -> Result<PooledChannel, lapin::Error> {
+        let slots = group.slots.load();
+        let index = group.selector.select(slots.len(), &|index| {
+            slots[index].in_flight.load(Ordering::Relaxed)
+        });
+        let slot = Arc::clone(&slots[index]);
+        group
+            .stats
+            .record_checkout(slot.in_flight.fetch_add(1, Ordering::Relaxed));
+        // From here on the guard gives the in-flight count back, even if replacing fails
+        let mut checked_out = PooledChannel {
+            channel: slot.channel.load_full(),
+            slot: Arc::clone(&slot),
+            stats: Arc::clone(&group.stats),
+            checked_out_at: Instant::now(),
+        };
+        if !checked_out.channel.status().connected() {
+            checked_out.channel = self.replace_channel(&slot).await?;
+        }
+        Ok(checked_out)
+    }
+
+    // Replaces every closed channel, shared and dedicated; used by the background prober
+    pub async fn replace_closed_channels(&self) {
+        let groups = std::iter::once(&self.shared).chain(self.dedicated.values());
+        for group in groups {
+            for slot in group.slots.load().iter() {
+                if slot.channel.load().status().connected() {
+                    continue;
+                }
+                if let Err(e) = self.replace_channel(slot).await {
+                    warn!(
+                        "Could not replace closed channel {}: {}",
+                        slot.channel.load().id(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    // Concurrent callers for the same slot wait for the first one instead of each opening a channel
+    async fn replace_channel(&self, slot: &Slot) -> Result<Arc<Channel>, lapin::Error> {
+        let _replacing = slot.replacing.lock().await;
+        let current = slot.channel.load_full();
+        if current.status().connected() {
+            return Ok(current);
+        }
+        let channel = Arc::new(open_channel(&self.connection).await?);
+        info!(
+            "Replaced closed channel {} with channel {}.",
+            current.id(),
+            channel.id()
+        );
+        slot.channel.store(Arc::clone(&channel));
+        Ok(channel)
+    }
+
+    // A channel outside the round-robin, for consumers that must own theirs
+    pub async fn dedicated_channel(&self) -> Result<Channel, lapin::Error> {
+        self.connection.create_channel().await
+    }
+
+    // Closes the connection, flushing anything still buffered for the broker
+    pub async fn close(&self) -> Result<(), lapin::Error> {
+        self.connection.close(200, "shutting down").await
+    }
+
+    async fn grow(&self) -> Result<(), lapin::Error> {
+        let slot = Slot::new(Arc::new(open_channel(&self.connection).await?));
+        let mut slots = Vec::clone(&self.shared.slots.load());
+        slots.push(slot);
+        self.shared.slots.store(Arc::new(slots));
+        Ok(())
+    }
+
+    // The removed channel closes once its last in-flight publish drops it
+    fn shrink(&self) {
+        let mut slots = Vec::clone(&self.shared.slots.load());
+        slots.pop();
+        self.shared.slots.store(Arc::new(slots));
+    }
+
+    // Adds a shared channel while checkouts are slow and removes one while they are fast (or there are
+    // none), one step per interval and within the bounds; only this task resizes the pool
+    pub fn spawn_autoscale(self: &Arc<Self>, autoscale: Autoscale, metrics: Arc<Metrics>) {
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(autoscale.interval);
+            loop {
+                ticker.tick().await;
+                let (checkouts, average_held) = pool.shared.stats.take();
+                let size = pool.size();
+                if checkouts > 0 && average_held > autoscale.grow_after && size < autoscale.max {
+                    match pool.grow().await {
+                        Ok(()) => info!(
+                            "Grew the channel pool to {} channels (publishes took {}ms).",
+                            size + 1,
+                            average_held.as_millis()
+                        ),
+                        Err(e) => warn!("Could not grow the channel pool: {}", e),
+                    }
+                } else if average_held < autoscale.grow_after / 2 && size > autoscale.min {
+                    pool.shrink();
+                    info!("Shrank the channel pool to {} channels.", size - 1);
+                }
+                metrics
+                    .channel_pool_size
+                    .store(pool.size() as u64, Ordering::Relaxed);
+            }
+        });
+    }
+
+    // Periodically probes the pool so idle closed channels are fixed before the next request
+    pub fn spawn_health_check(self: &Arc<Self>, interval: Duration) {
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                pool.replace_closed_channels().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_in_flight_picks_the_idlest_slot() {
+        let loads = [3, 1, 2];
+        let selector = LeastInFlight::default();
+
+        for _ in 0..loads.len() {
+            assert_eq!(selector.select(loads.len(), &|index| loads[index]), 1);
+        }
+    }
+
+    #[test]
+    fn least_in_flight_spreads_ties() {
+        let selector = LeastInFlight::default();
+
+        let picks: Vec<usize> = (0..3).map(|_| selector.select(3, &|_| 0)).collect();
+
+        assert_eq!(picks, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn checkout_stats_average_and_reset() {
+        let stats = CheckoutStats::default();
+        stats.record(Duration::from_millis(10));
+        stats.record(Duration::from_millis(30));
+
+        assert_eq!(stats.take(), (2, Duration::from_millis(20)));
+        assert_eq!(stats.take(), (0, Duration::ZERO));
+    }
+
+    #[test]
+    fn busy_checkouts_count_as_contended() {
+        let stats = CheckoutStats::default();
+        stats.record_checkout(0);
+        stats.record_checkout(2);
+        stats.take();
+
+        assert_eq!(stats.contention.checkouts.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.contention.contended.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use log::{info, warn};
+
+use crate::publisher::{MessagePublisher, PublishError, RabbitMessage};
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    // Failure ratio (0.0..=1.0) within a window that opens the circuit
+    pub failure_rate: f64,
+    // Minimum publishes in a window before the ratio is evaluated
+    pub min_requests: u32,
+    pub window: Duration,
+    // How long the circuit stays open before a probe publish is let through
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.5,
+            min_requests: 10,
+            window: Duration::from_secs(30),
+            open_for: Duration::from_secs(15),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    Open { since: Instant },
+    // A single probe publish is in flight; `opened_at` is when the circuit last opened
+    HalfOpen { opened_at: Instant },
+}
+
+struct Breaker {
+    state: State,
+    window_start: Instant,
+    successes: u32,
+    failures: u32,
+}
+
+// Short-circuits publishes while the broker is failing instead of waiting for each timeout
+pub struct CircuitBreakerPublisher {
+    inner: Arc<dyn MessagePublisher>,
+    config: CircuitBreakerConfig,
+    breaker: Mutex<Breaker>,
+}
+
+// The half-open probe in flight. It must end with `finish` or `release`; dropped without an
+// outcome, e.g. when a timeout cancels the publish, it re-opens the circuit so the breaker
+// cannot stay half-open with no probe left to close it.
+struct Probe<'a> {
+    publisher: &'a CircuitBreakerPublisher,
+    settled: bool,
+}
+
+impl Probe<'_> {
+    fn finish(mut self, success: bool) {
+        self.settled = true;
+        self.publisher.record(success, true);
+    }
+
+    // For outcomes that say nothing about the broker: the next publish probes again
+    fn release(mut self) {
+        self.settled = true;
+        let mut breaker = self.publisher.breaker();
+        if let State::HalfOpen { opened_at } = breaker.state {
+            breaker.state = State::Open { since: opened_at };
+        }
+    }
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        let mut breaker = self.publisher.breaker();
+        if matches!(breaker.state, State::HalfOpen { .. }) {
+            warn!("Circuit breaker probe was cancelled, re-opening.");
+            breaker.state = State::Open {
+                since: Instant::now(),
+            };
+        }
+    }
+}
+
+impl CircuitBreakerPublisher {
+    pub fn new(inner: Arc<dyn MessagePublisher>, config: CircuitBreakerConfig) -> Self {
+        Self {
+            inner,
+            config,
+            breaker: Mutex::new(Breaker {
+                state: State::Closed,
+                window_start: Instant::now(),
+                successes: 0,
+                failures: 0,
+            }),
+        }
+    }
+
+    fn breaker(&self) -> std::sync::MutexGuard<'_, Breaker> {
+        self.breaker.lock().expect("circuit breaker lock poisoned")
+    }
+
+    // Decides whether a publish may go through, moving Open -> HalfOpen once the cool-down ends;
+    // the publish that does so is the probe
+    fn acquire(&self) -> Result<Option<Probe<'_>>, PublishError> {
+        let mut breaker = self.breaker();
+        match breaker.state {
+            State::Closed => Ok(None),
+            State::Open { since } if since.elapsed() >= self.config.open_for => {
+                info!("Circuit breaker half-open, sending probe publish.");
+                breaker.state = State::HalfOpen { opened_at: since };
+                Ok(Some(Probe {
+                    publisher: self,
+                    settled: false,
+                }))
+            }
+            State::Open { .. } | State::HalfOpen { .. } => Err(PublishError::CircuitOpen),
+        }
+    }
+
+    fn record(&self, success: bool, probe: bool) {
+        let mut breaker = self.breaker();
+        let now = Instant::now();
+
+        if probe {
+            if success {
+                info!("Circuit breaker closed after successful probe.");
+                breaker.state = State::Closed;
+            } else {
+                warn!("Circuit breaker probe failed, re-opening.");
+                breaker.state = State::Open { since: now };
+            }
+            breaker.window_start = now;
+            breaker.successes = 0;
+            breaker.failures = 0;
+            return;
+        }
+
+        if now.duration_since(breaker.window_start) >= self.config.window {
+            breaker.window_start = now;
+            breaker.successes = 0;
+            breaker.failures = 0;
+        }
+        if success {
+            breaker.successes += 1;
+        } else {
+            breaker.failures += 1;
+        }
+
+        let total = breaker.successes + breaker.failures;
+        if breaker.state == State::Closed
+            && total >= self.config.min_requests
+            && f64::from(breaker.failures) / f64::from(total) >= self.config.failure_rate
+        {
+            warn!(
+                "Circuit breaker opened: {} of {} publishes failed.",
+                breaker.failures, total
+            );
+            breaker.state = State::Open { since: now };
+        }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for CircuitBreakerPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        let probe = self.acquire()?;
+        let result = self.inner.publish(destination, message).await;
+        // Invalid input or a missing queue says nothing about broker health, and a pending connect
+        // is not a failure
+        let counted = !matches!(
+            result,
+            Err(PublishError::InvalidDestination(_))
+                | Err(PublishError::Serialization(_))
+                | Err(PublishError::NotConnected)
+                | Err(PublishError::Unroutable(_))
+        );
+        match probe {
+            Some(probe) if counted => probe.finish(result.is_ok()),
+            Some(probe) => probe.release(),
+            None if counted => self.record(result.is_ok(), false),
+            None => {}
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingPublisher;
+
+    // A publisher whose publishes never finish, for cancelling probes
+    struct Hanging;
+
+    #[async_trait]
+    impl MessagePublisher for Hanging {
+        async fn publish(&self, _: &str, _: &RabbitMessage) -> Result<(), PublishError> {
+            std::future::pending().await
+        }
+    }
+
+    fn config(open_for: Duration) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failure_rate: 0.5,
+            min_requests: 2,
+            window: Duration::from_secs(60),
+            open_for,
+        }
+    }
+
+    fn message() -> RabbitMessage {
+        RabbitMessage::chat("default", 42, "Bohemian Rhapsody")
+    }
+
+    async fn open(breaker: &CircuitBreakerPublisher, inner: &RecordingPublisher) {
+        inner.fail_with(|| PublishError::Broker("down".to_string()));
+        for _ in 0..2 {
+            let _ = breaker.publish("Music", &message()).await;
+        }
+    }
+
+    fn state(breaker: &CircuitBreakerPublisher) -> State {
+        breaker.breaker().state
+    }
+
+    #[tokio::test]
+    async fn opens_on_failures_and_short_circuits_during_the_cool_down() {
+        let inner = Arc::new(RecordingPublisher::default());
+        let breaker =
+            CircuitBreakerPublisher::new(Arc::clone(&inner) as _, config(Duration::from_secs(60)));
+
+        breaker.publish("Music", &message()).await.unwrap();
+        assert_eq!(state(&breaker), State::Closed);
+        open(&breaker, &inner).await;
+        assert!(matches!(state(&breaker), State::Open { .. }));
+
+        inner.succeed();
+        assert!(matches!(
+            breaker.publish("Music", &message()).await,
+            Err(PublishError::CircuitOpen)
+        ));
+        assert_eq!(inner.queues(), vec!["Music"]);
+    }
+
+    #[tokio::test]
+    async fn probe_outcomes_close_or_reopen_the_circuit() {
+        let inner = Arc::new(RecordingPublisher::default());
+        let breaker = CircuitBreakerPublisher::new(Arc::clone(&inner) as _, config(Duration::ZERO));
+
+        open(&breaker, &inner).await;
+        // The failing probe re-opens the circuit
+        assert!(matches!(
+            breaker.publish("Music", &message()).await,
+            Err(PublishError::Broker(_))
+        ));
+        assert!(matches!(state(&breaker), State::Open { .. }));
+
+        // A probe that says nothing about the broker only gives the slot back
+        inner.fail_with(|| PublishError::Unroutable("Music".to_string()));
+        let _ = breaker.publish("Music", &message()).await;
+        assert!(matches!(state(&breaker), State::Open { .. }));
+
+        inner.succeed();
+        breaker.publish("Music", &message()).await.unwrap();
+        assert_eq!(state(&breaker), State::Closed);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_probe_reopens_the_circuit() {
+        let inner = Arc::new(RecordingPublisher::default());
+        let breaker = CircuitBreakerPublisher::new(Arc::clone(&inner) as _, config(Duration::ZERO));
+        open(&breaker, &inner).await;
+        let breaker = CircuitBreakerPublisher {
+            inner: Arc::new(Hanging),
+            ..breaker
+        };
+
+        let message = message();
+        let probe = breaker.publish("Music", &message);
+        assert!(tokio::time::timeout(Duration::from_millis(10), probe)
+            .await
+            .is_err());
+
+        assert!(matches!(state(&breaker), State::Open { .. }));
+    }
+}
@@ -0,0 +1,406 @@
+use std::{sync::Arc, time::Duration};
+
+use clap::{Parser, Subcommand};
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    types::FieldTable,
+    ExchangeKind,
+};
+use url::Url;
+
+use crate::{
+    adapters::{
+        github::{self, GithubAdapter},
+        stripe::StripeAdapter,
+    },
+    app::App,
+    bots::{BotRegistry, DEFAULT_BOT_ID},
+    broker::Broker,
+    config::{Config, ConfigArgs},
+    encryption,
+    loadtest::LoadTest,
+    metrics::Metrics,
+    polling::UpdateMode,
+    publisher::{validate_destination, Backend, MessagePublisher, RabbitMessage, RabbitPublisher},
+    signing,
+};
+
+// One-shot commands give up on an unreachable broker instead of retrying forever
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Publishes Telegram bot updates to RabbitMQ, SQS or MQTT"
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    // Without a subcommand the server is started, as before the CLI existed
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Run the webhook server (the default)")]
+    Serve,
+    #[command(about = "Validate the configuration and check that RabbitMQ is reachable")]
+    CheckConfig,
+    #[command(about = "Declare the queues the bots and adapters publish to")]
+    DeclareTopology {
+        #[arg(long, help = "Declare every queue durable, whatever the config says")]
+        durable: bool,
+    },
+    #[command(about = "Publish a synthetic message to a queue")]
+    SendTest {
+        #[arg(help = "Exact queue name (bot queue prefixes are not applied)")]
+        queue: String,
+        #[arg(long, default_value = "send-test message from rustin_bot_publisher")]
+        text: String,
+        #[arg(long, default_value = DEFAULT_BOT_ID)]
+        bot_id: String,
+        #[arg(long, default_value_t = 0)]
+        chat_id: i64,
+    },
+    #[command(
+        about = "Feed synthetic updates to the handler at a steady rate and report publish throughput"
+    )]
+    Loadtest {
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..), help = "Updates per second")]
+        rate: u32,
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+        duration_secs: u64,
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(i64).range(1..), help = "Distinct chats the updates are spread over")]
+        chats: i64,
+        #[arg(long, default_value = "/songlinks")]
+        command: String,
+        #[arg(long, default_value = DEFAULT_BOT_ID)]
+        bot_id: String,
+    },
+}
+
+async fn connect(config: &Config) -> Result<Broker, String> {
+    tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        Broker::connect(&config.rabbit_connector, 1),
+    )
+    .await
+    .map_err(|_| {
+        format!(
+            "RabbitMQ did not answer within {}s",
+            CONNECT_TIMEOUT.as_secs()
+        )
+    })?
+    .map_err(|e| format!("cannot connect to RabbitMQ: {}", e))
+}
+
+// The configuration itself was already validated when it was loaded
+pub async fn check_config(config: &Config) -> Result<(), String> {
+    println!("Configuration is valid:");
+    println!("  server_address         = {}", config.server_address);
+    println!("  backend                = {}", config.backend);
+    println!("  mode                   = {}", config.mode);
+    println!("  channel_pool_size      = {}", config.channel_pool_size);
+    println!("  channel_pool_max       = {}", config.channel_pool_max);
+    println!("  channel_selection      = {}", config.channel_selection);
+    println!(
+        "  webhook_max_body_bytes = {}",
+        config.webhook_max_body_bytes
+    );
+    println!(
+        "  session_ttl_secs       = {}",
+        config.session_ttl.as_secs()
+    );
+    println!(
+        "  payload_encoding       = {}",
+        config.queues.default_options().encoding
+    );
+    println!(
+        "  persistent_messages    = {}",
+        config.queues.default_options().persistent
+    );
+    println!(
+        "  durable_queues         = {}",
+        config.queues.default_options().durable
+    );
+    if let Some(grpc_address) = config.grpc_address {
+        println!("  grpc_address           = {}", grpc_address);
+    }
+    if let Some(webhook) = &config.webhook {
+        println!("  webhook_public_url     = {}", webhook.public_url);
+    }
+    if let Some(limit) = &config.webhook_ip_rate_limit {
+        println!(
+            "  webhook_ip_rate_limit  = {}/s per address (burst {})",
+            limit.per_second, limit.burst
+        );
+    }
+    if let Some(cipher) = &config.payload_cipher {
+        println!(
+            "  payload_encryption     = {} ({})",
+            encryption::ALGORITHM,
+            cipher.key_id()
+        );
+    }
+    if let Some(signer) = &config.message_signer {
+        println!(
+            "  message_signing        = {} ({}), public key {}",
+            signing::ALGORITHM,
+            signer.key_id(),
+            signer.public_key()
+        );
+    }
+    if let Some(scrubber) = &config.scrubber {
+        println!(
+            "  pii_scrubbing          = {}",
+            scrubber.rule_names().collect::<Vec<_>>().join(", ")
+        );
+    }
+    if let Some(shadow_rabbit_address) = &config.shadow_rabbit_address {
+        println!("  shadow_rabbit_address  = {}", shadow_rabbit_address);
+    }
+    if let Some(statsd) = &config.statsd {
+        println!(
+            "  statsd_address         = {} (every {}s)",
+            statsd.address,
+            statsd.interval.as_secs()
+        );
+    }
+    if let Some(redis_url) = &config.redis_url {
+        println!("  redis_url              = {}", redis_url);
+    }
+    if let Some(url) = &config.audit_database_url {
+        println!("  audit_database_url     = {}", without_password(url));
+    }
+    if let Some(unroutable_queue) = &config.unroutable_queue {
+        println!("  unroutable_queue       = {}", unroutable_queue);
+    }
+    let mut timeout_fallback_queues: Vec<_> = config.timeout_fallback_queues.iter().collect();
+    timeout_fallback_queues.sort();
+    for (command, queue) in timeout_fallback_queues {
+        println!("  timeout_fallback_queues.{} = {}", command, queue);
+    }
+    let queue_names = config.queues.broker_name("<queue>");
+    if queue_names != "<queue>" {
+        println!("  queue names            = {}", queue_names);
+    }
+    for job in config.cron_jobs.iter() {
+        let state = if job.enabled { "" } else { " (disabled)" };
+        println!(
+            "  cron.{} = {:?} -> {}{}",
+            job.name, job.schedule_spec, job.queue, state
+        );
+    }
+
+    if let Some(prefix) = &config.sqs_queue_url_prefix {
+        println!("  sqs_queue_url_prefix   = {}", prefix);
+    }
+    #[cfg(feature = "mqtt")]
+    if config.backend == Backend::Mqtt {
+        println!(
+            "  mqtt_address           = {}",
+            config.mqtt_address.as_deref().unwrap_or_default()
+        );
+        println!("  mqtt_topic_prefix      = {}", config.mqtt_topic_prefix);
+        println!("  mqtt_qos               = {}", config.mqtt_qos);
+    }
+    let mut sqs_queue_urls: Vec<_> = config.sqs_queue_urls.iter().collect();
+    sqs_queue_urls.sort();
+    for (queue, url) in sqs_queue_urls {
+        println!("  sqs_queue_urls.{} = {}", queue, url);
+    }
+
+    if config.backend != Backend::RabbitMq {
+        return Ok(());
+    }
+    let broker = connect(config).await?;
+    broker.close().await;
+    println!("RabbitMQ is reachable.");
+    Ok(())
+}
+
+fn without_password(url: &str) -> String {
+    let mut url = Url::parse(url).expect("URLs are validated with the config");
+    if url.password().is_some() {
+        let _ = url.set_password(Some("***"));
+    }
+    url.to_string()
+}
+
+// Queues the running server would publish to with the current configuration
+fn topology_queues(config: &Config) -> Vec<String> {
+    let mut queues = BotRegistry::new(config.bots.clone()).queue_names(&config.routes.queues());
+    if config.hmac_verifier(github::SOURCE).is_some() {
+        queues.push(GithubAdapter::from_env().queue);
+    }
+    if let Some(adapter) = StripeAdapter::from_env() {
+        queues.push(adapter.queue);
+    }
+    queues.extend(config.unroutable_queue.clone());
+    for queue in config.timeout_fallback_queues.values() {
+        if !queues.contains(queue) {
+            queues.push(queue.clone());
+        }
+    }
+    queues
+}
+
+// Everything is published through the default exchange, so only queues and the dead-letter
+// exchanges they name need declaring
+pub async fn declare_topology(config: &Config, durable: bool) -> Result<(), String> {
+    if config.backend != Backend::RabbitMq {
+        return Err(format!(
+            "declare-topology only applies to RabbitMQ, the backend is {}",
+            config.backend
+        ));
+    }
+    let broker = connect(config).await?;
+    let channel = broker
+        .channel_pool()
+        .expect("connected broker has a channel pool")
+        .get_next_channel()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    for queue in topology_queues(config) {
+        let queue_options = config.queues.for_queue(&queue);
+        let queue = config.queues.broker_name(&queue);
+        let options = QueueDeclareOptions {
+            durable: durable || queue_options.durable,
+            auto_delete: queue_options.auto_delete,
+            ..QueueDeclareOptions::default()
+        };
+        let declared = channel
+            .queue_declare(&queue, options, queue_options.declare_arguments())
+            .await
+            .map_err(|e| format!("cannot declare queue {}: {}", queue, e))?;
+        println!(
+            "Declared {} queue {} ({} messages ready)",
+            if options.durable {
+                "durable"
+            } else {
+                "transient"
+            },
+            queue,
+            declared.message_count()
+        );
+        if queue_options.persistent && !options.durable {
+            println!(
+                "  Persistent messages to {} are still lost on a broker restart.",
+                queue
+            );
+        }
+    }
+    for route in topology_queues(config)
+        .iter()
+        .filter_map(|queue| config.queues.dead_letter_route(queue))
+    {
+        let durable = ExchangeDeclareOptions {
+            durable: true,
+            ..ExchangeDeclareOptions::default()
+        };
+        channel
+            .exchange_declare(
+                &route.exchange,
+                ExchangeKind::Direct,
+                durable,
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|e| format!("cannot declare exchange {}: {}", route.exchange, e))?;
+        let options = QueueDeclareOptions {
+            durable: true,
+            ..QueueDeclareOptions::default()
+        };
+        channel
+            .queue_declare(&route.queue, options, FieldTable::default())
+            .await
+            .map_err(|e| format!("cannot declare queue {}: {}", route.queue, e))?;
+        channel
+            .queue_bind(
+                &route.queue,
+                &route.exchange,
+                &route.routing_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|e| format!("cannot bind queue {}: {}", route.queue, e))?;
+        println!(
+            "Declared dead-letter queue {} on exchange {} ({})",
+            route.queue, route.exchange, route.routing_key
+        );
+    }
+    broker.close().await;
+    Ok(())
+}
+
+// Publishes for real, so point it at a test broker; the server's other background tasks (cron
+// jobs, reminders) run meanwhile as they would in production
+pub async fn load_test(mut config: Config, load_test: LoadTest) -> Result<(), String> {
+    // Updates come from the generator only
+    config.mode = UpdateMode::Webhook;
+    let app = App::builder()
+        .config(config)
+        .build()
+        .await
+        .map_err(|e| e.to_string())?;
+    let ready = async {
+        while !app.broker().is_ready() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::time::timeout(CONNECT_TIMEOUT, ready)
+        .await
+        .map_err(|_| {
+            format!(
+                "the broker was not ready within {}s",
+                CONNECT_TIMEOUT.as_secs()
+            )
+        })?;
+    println!(
+        "Sending {} updates/s for {}s...",
+        load_test.rate,
+        load_test.duration.as_secs()
+    );
+    load_test.run(&app).await.print();
+    Ok(())
+}
+
+pub async fn send_test(config: &Config, queue: &str, message: RabbitMessage) -> Result<(), String> {
+    // Fail on a bad queue name before spending time on the connection
+    validate_destination(queue).map_err(|e| e.to_string())?;
+    #[cfg(feature = "sqs")]
+    if config.backend == Backend::Sqs {
+        let publisher = crate::sqs::SqsPublisher::from_config(config).await;
+        publisher
+            .publish(queue, &message)
+            .await
+            .map_err(|e| e.to_string())?;
+        println!("Published test message to {}.", queue);
+        return Ok(());
+    }
+    // The MQTT client publishes from a background event loop this command would exit before
+    #[cfg(feature = "mqtt")]
+    if config.backend == Backend::Mqtt {
+        return Err("send-test does not support the mqtt backend".to_string());
+    }
+    let broker = Arc::new(connect(config).await?);
+    // No redirect here: a returned test message should be reported, not rerouted
+    let publisher = RabbitPublisher::new(
+        Arc::clone(&broker),
+        config.queues.clone(),
+        Arc::new(Metrics::default()),
+    )
+    .with_cipher(config.payload_cipher.clone())
+    .with_signer(config.message_signer.clone());
+    let result = publisher
+        .publish(queue, &message)
+        .await
+        .map_err(|e| e.to_string());
+    broker.close().await;
+    result?;
+    println!("Published test message to {}.", queue);
+    Ok(())
+}
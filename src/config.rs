@@ -0,0 +1,2194 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::HeaderName;
+use clap::Args;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    adapters::github,
+    admin,
+    amqp_tls::{AmqpConnector, AmqpTlsSettings},
+    bots::{BotConfig, BotOverrides, DEFAULT_BOT_ID},
+    channel_pool::ChannelSelection,
+    circuit_breaker::CircuitBreakerConfig,
+    cron::{CronJobConfig, CronJobs},
+    encoding::PayloadEncoding,
+    encryption::PayloadCipher,
+    flood::FloodConfig,
+    ip_filter::{parse_ranges, IpFilter, TELEGRAM_RANGES},
+    polling::UpdateMode,
+    publisher::{validate_destination, Backend, DEFAULT_PUBLISH_TIMEOUT},
+    queues::{QueueOptions, QueueOverrides, QueueSettings},
+    rate_limit::IpRateLimit,
+    routing::RoutingTable,
+    rpc,
+    scrubbing::{Scrubber, ScrubbingConfig},
+    signature::{HmacSettings, HmacVerifier, DEFAULT_SIGNATURE_HEADER},
+    signing::MessageSigner,
+    statsd::StatsdConfig,
+    telegram_api::WebhookSettings,
+    tiers::{TierConfig, UserTiers},
+    webhook_handler, websocket,
+};
+
+// Everything whose requests can be signed, by the name its [hmac.<name>] settings go under
+const HMAC_SOURCES: [&str; 5] = [
+    webhook_handler::SOURCE,
+    github::SOURCE,
+    rpc::SOURCE,
+    websocket::SOURCE,
+    admin::SOURCE,
+];
+
+// One source of settings; fields left unset fall through to the layer below it
+#[derive(Args, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    #[arg(
+        long,
+        global = true,
+        help = "Address the HTTP server listens on [env: SERVER_ADDRESS]"
+    )]
+    pub server_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "amqp:// or amqps:// broker URL [env: RABBIT_ADDRESS]"
+    )]
+    pub rabbit_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Second amqp:// or amqps:// broker every publish is mirrored to, e.g. during a migration [env: SHADOW_RABBIT_ADDRESS]"
+    )]
+    pub shadow_rabbit_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "PEM bundle of the CAs trusted to sign the broker's certificate [env: AMQP_CA_BUNDLE]"
+    )]
+    pub amqp_ca_bundle: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "PEM client certificate, for brokers that require mutual TLS [env: AMQP_CLIENT_CERT]"
+    )]
+    pub amqp_client_cert: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "PKCS#8 PEM key of amqp_client_cert [env: AMQP_CLIENT_KEY]"
+    )]
+    pub amqp_client_key: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "PEM certificate HTTPS is served with, instead of plain HTTP [env: TLS_CERT_PATH]"
+    )]
+    pub tls_cert_path: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "PEM key of tls_cert_path [env: TLS_KEY_PATH]"
+    )]
+    pub tls_key_path: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Domain or IP a self-signed certificate is generated for when tls_cert_path does not exist [env: TLS_SELF_SIGNED_DOMAIN]"
+    )]
+    pub tls_self_signed_domain: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "redis:// or rediss:// server replicas share sessions, cooldowns and rate limits through; each replica keeps its own when unset [env: REDIS_URL]"
+    )]
+    pub redis_url: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "postgres:// database every published message is recorded in [env: AUDIT_DATABASE_URL]"
+    )]
+    pub audit_database_url: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Where messages are published: rabbitmq, sqs or mqtt [env: BACKEND]"
+    )]
+    pub backend: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "SQS queue URL that queue names are appended to, e.g. https://sqs.eu-west-1.amazonaws.com/123456789012/ [env: SQS_QUEUE_URL_PREFIX]"
+    )]
+    pub sqs_queue_url_prefix: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "mqtt:// or mqtts:// broker URL, optionally with ?client_id= [env: MQTT_ADDRESS]"
+    )]
+    pub mqtt_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "First level of every MQTT topic, as in bot/commands/readimage [env: MQTT_TOPIC_PREFIX]"
+    )]
+    pub mqtt_topic_prefix: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "MQTT quality of service: 0, 1 or 2 [env: MQTT_QOS]"
+    )]
+    pub mqtt_qos: Option<u8>,
+    #[arg(
+        long,
+        global = true,
+        help = "Address of the gRPC publish API [env: GRPC_ADDRESS]"
+    )]
+    pub grpc_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Number of pooled AMQP channels [env: CHANNEL_POOL_SIZE]"
+    )]
+    pub channel_pool_size: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Upper bound the pool may grow to under load; defaults to the pool size [env: CHANNEL_POOL_MAX]"
+    )]
+    pub channel_pool_max: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Average publish time above which the pool grows [env: CHANNEL_POOL_GROW_AFTER_MS]"
+    )]
+    pub channel_pool_grow_after_ms: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Interval of the closed-channel prober [env: CHANNEL_HEALTH_CHECK_SECS]"
+    )]
+    pub channel_health_check_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "How publishes pick a pooled channel: round-robin or least-in-flight [env: CHANNEL_SELECTION]"
+    )]
+    pub channel_selection: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Upper bound of the broker reconnect backoff [env: BROKER_RETRY_MAX_SECS]"
+    )]
+    pub broker_retry_max_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "How updates arrive: webhook, or polling with getUpdates [env: MODE]"
+    )]
+    pub mode: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "How long one getUpdates call waits for updates in polling mode [env: POLLING_TIMEOUT_SECS]"
+    )]
+    pub polling_timeout_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Largest accepted webhook body [env: WEBHOOK_MAX_BODY_BYTES]"
+    )]
+    pub webhook_max_body_bytes: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Public https:// base URL to register with Telegram on startup [env: WEBHOOK_PUBLIC_URL]"
+    )]
+    pub webhook_public_url: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Webhook requests handled at once; more are answered 503 [env: WEBHOOK_CONCURRENCY_LIMIT]"
+    )]
+    pub webhook_concurrency_limit: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Time a webhook request may take before it is answered 503 [env: WEBHOOK_TIMEOUT_SECS]"
+    )]
+    pub webhook_timeout_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Time a publish may wait for the broker to confirm it [env: PUBLISH_TIMEOUT_SECS]"
+    )]
+    pub publish_timeout_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Concurrent webhook connections Telegram may open, 1-100 [env: WEBHOOK_MAX_CONNECTIONS]"
+    )]
+    pub webhook_max_connections: Option<u8>,
+    #[arg(
+        long,
+        global = true,
+        help = "Drop updates queued at Telegram when registering [env: WEBHOOK_DROP_PENDING_UPDATES]"
+    )]
+    pub webhook_drop_pending_updates: Option<bool>,
+    #[arg(
+        long,
+        global = true,
+        help = "Only accept webhooks from allowed source addresses [env: WEBHOOK_IP_FILTER]"
+    )]
+    pub webhook_ip_filter: Option<bool>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma separated CIDR ranges allowed to post webhooks, Telegram's by default [env: WEBHOOK_ALLOWED_IPS]"
+    )]
+    pub webhook_allowed_ips: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma separated CIDR ranges of proxies whose X-Forwarded-For is trusted [env: TRUSTED_PROXIES]"
+    )]
+    pub trusted_proxies: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Webhook requests per second each source address may make; unlimited when unset [env: WEBHOOK_IP_RATE_LIMIT]"
+    )]
+    pub webhook_ip_rate_limit: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        help = "Webhook requests an idle source address may send at once, the rate limit by default [env: WEBHOOK_IP_RATE_BURST]"
+    )]
+    pub webhook_ip_rate_burst: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma separated chat ids allowed to use admin commands such as /stats [env: ADMIN_CHAT_IDS]"
+    )]
+    pub admin_chat_ids: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma separated commands that start switched off, e.g. /readimage [env: DISABLED_COMMANDS]"
+    )]
+    pub disabled_commands: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Lifetime of pending conversation steps [env: SESSION_TTL_SECS]"
+    )]
+    pub session_ttl_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Minimum time between two unknown-command replies to the same chat; 0 replies every time [env: UNKNOWN_COMMAND_INTERVAL_SECS]"
+    )]
+    pub unknown_command_interval_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "File pending /remind jobs are kept in across restarts; empty keeps them in memory only [env: REMINDERS_FILE]"
+    )]
+    pub reminders_file: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "File per-chat /settings are kept in across restarts; empty keeps them in memory only [env: SETTINGS_FILE]"
+    )]
+    pub settings_file: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "How long /rpc requests wait for a reply [env: RPC_TIMEOUT_SECS]"
+    )]
+    pub rpc_timeout_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Pause between the messages of a /broadcast [env: BROADCAST_INTERVAL_MS]"
+    )]
+    pub broadcast_interval_ms: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Queue payload format: json, protobuf or msgpack [env: PAYLOAD_ENCODING]"
+    )]
+    pub payload_encoding: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Publish messages with delivery mode 2 so they survive a broker restart [env: PERSISTENT_MESSAGES]"
+    )]
+    pub persistent_messages: Option<bool>,
+    #[arg(
+        long,
+        global = true,
+        help = "Declare queues durable in declare-topology [env: DURABLE_QUEUES]"
+    )]
+    pub durable_queues: Option<bool>,
+    #[arg(
+        long,
+        global = true,
+        help = "Queue that receives messages no queue was bound for [env: UNROUTABLE_QUEUE]"
+    )]
+    pub unroutable_queue: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Prepended to every queue name on the broker, e.g. staging. [env: QUEUE_NAME_PREFIX]"
+    )]
+    pub queue_name_prefix: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Appended to every queue name on the broker [env: QUEUE_NAME_SUFFIX]"
+    )]
+    pub queue_name_suffix: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "host:port of a StatsD server or Datadog agent metrics are pushed to [env: STATSD_ADDRESS]"
+    )]
+    pub statsd_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Put in front of every StatsD metric name [env: STATSD_PREFIX]"
+    )]
+    pub statsd_prefix: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma-separated DogStatsD tags added to every metric, e.g. env:prod [env: STATSD_TAGS]"
+    )]
+    pub statsd_tags: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "How often metrics are pushed to StatsD [env: STATSD_INTERVAL_SECS]"
+    )]
+    pub statsd_interval_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Share of failed publishes within a window that opens the circuit breaker, e.g. 0.5 [env: CIRCUIT_FAILURE_RATE]"
+    )]
+    pub circuit_failure_rate: Option<f64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Publishes a window needs before its failure rate counts [env: CIRCUIT_MIN_REQUESTS]"
+    )]
+    pub circuit_min_requests: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        help = "Length of the circuit breaker's window [env: CIRCUIT_WINDOW_SECS]"
+    )]
+    pub circuit_window_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "How long an open circuit rejects publishes before one is let through [env: CIRCUIT_OPEN_SECS]"
+    )]
+    pub circuit_open_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "More messages than this from one chat within flood_window_secs mutes it [env: FLOOD_MAX_MESSAGES]"
+    )]
+    pub flood_max_messages: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Window chat messages are counted over [env: FLOOD_WINDOW_SECS]"
+    )]
+    pub flood_window_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "How long a flooding chat is ignored [env: FLOOD_MUTE_SECS]"
+    )]
+    pub flood_mute_secs: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma separated ids of the bots served, each with its own /webhook/<id> [env: BOT_IDS]"
+    )]
+    pub bot_ids: Option<String>,
+    // Settings every bot falls back to; set in a config file or with SONGLINKS_MAX_LINES,
+    // SONGLINKS_MAX_CHARS, STICKERS_ON_COMMAND, ACK_COMMANDS, ACK_TEXT, USERNAME and
+    // ADDRESSED_CHAT_TYPES
+    #[arg(skip)]
+    pub bot_defaults: Option<BotOverrides>,
+    // Bot id -> its own settings; set in a config file or with BOT_<ID>_<SETTING>, e.g.
+    // BOT_MUSIC_SECRET_TOKEN, and kept off the command line for the tokens' sake
+    #[arg(skip)]
+    pub bots: Option<HashMap<String, BotOverrides>>,
+    // Signing producer -> its shared secret and signature header, e.g. [hmac.github]; set in a
+    // config file or with HMAC_<NAME>_SECRET and HMAC_<NAME>_HEADER, and kept off the command
+    // line for the secrets' sake
+    #[arg(skip)]
+    pub hmac: Option<HashMap<String, HmacSettings>>,
+    #[arg(
+        long,
+        global = true,
+        help = "Comma separated queues WebSocket producers may publish to; any when unset [env: WS_ALLOWED_QUEUES]"
+    )]
+    pub ws_allowed_queues: Option<String>,
+    // Command -> seconds between two uses by the same user; set in a config file or with
+    // COOLDOWN_<COMMAND>_SECS, e.g. COOLDOWN_READIMAGE_SECS=30
+    #[arg(skip)]
+    pub cooldowns: Option<HashMap<String, u64>>,
+    // Command -> queue overrides; only a config file can set these
+    #[arg(skip)]
+    pub routes: Option<HashMap<String, String>>,
+    // Alias -> command, reloaded with the routes; only a config file can set these
+    #[arg(skip)]
+    pub aliases: Option<HashMap<String, String>>,
+    // Language -> alias -> command, e.g. [localized_aliases.ro] "/ajutor" = "/help"; reloaded
+    // with the routes, only a config file can set these
+    #[arg(skip)]
+    pub localized_aliases: Option<HashMap<String, HashMap<String, String>>>,
+    // Per-queue publish settings; only a config file can set these
+    #[arg(skip)]
+    pub queues: Option<HashMap<String, QueueOverrides>>,
+    // User tiers with their own limits; only a config file can set these
+    #[arg(skip)]
+    pub tiers: Option<HashMap<String, TierConfig>>,
+    // Messages published on a schedule; only a config file can set these
+    #[arg(skip)]
+    pub cron: Option<HashMap<String, CronJobConfig>>,
+    // Queue -> SQS queue URL, for queues not under the prefix; only a config file can set these
+    #[arg(skip)]
+    pub sqs_queue_urls: Option<HashMap<String, String>>,
+    // Command -> queue its messages go to when a publish times out; only a config file can set
+    // these
+    #[arg(skip)]
+    pub timeout_fallback_queues: Option<HashMap<String, String>>,
+    // Personal data redacted from messages before they are published; only a config file can
+    // set this
+    #[arg(skip)]
+    pub pii_scrubbing: Option<ScrubbingConfig>,
+    // AES-256-GCM key (64 hex characters) published bodies are encrypted with, and the name
+    // consumers know it by; kept off the command line, where other users could read it
+    #[arg(skip)]
+    pub payload_encryption_key: Option<String>,
+    #[arg(skip)]
+    pub payload_encryption_key_id: Option<String>,
+    // Ed25519 private key seed (64 hex characters) published bodies are signed with, and the name
+    // consumers know its public half by; kept off the command line like the encryption key
+    #[arg(skip)]
+    pub message_signing_key: Option<String>,
+    #[arg(skip)]
+    pub message_signing_key_id: Option<String>,
+}
+
+impl ConfigLayer {
+    fn defaults() -> Self {
+        Self {
+            server_address: Some("0.0.0.0:8080".to_string()),
+            rabbit_address: None,
+            shadow_rabbit_address: None,
+            amqp_ca_bundle: None,
+            amqp_client_cert: None,
+            amqp_client_key: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_self_signed_domain: None,
+            redis_url: None,
+            audit_database_url: None,
+            backend: None,
+            sqs_queue_url_prefix: None,
+            mqtt_address: None,
+            mqtt_topic_prefix: Some("bot".to_string()),
+            mqtt_qos: Some(1),
+            grpc_address: None,
+            channel_pool_size: Some(5),
+            channel_pool_max: None,
+            channel_pool_grow_after_ms: Some(50),
+            channel_health_check_secs: None,
+            channel_selection: None,
+            broker_retry_max_secs: Some(30),
+            mode: None,
+            polling_timeout_secs: Some(30),
+            // Telegram updates are a few kilobytes at most
+            webhook_max_body_bytes: Some(256 * 1024),
+            webhook_public_url: None,
+            webhook_concurrency_limit: Some(128),
+            webhook_timeout_secs: Some(10),
+            publish_timeout_secs: Some(DEFAULT_PUBLISH_TIMEOUT.as_secs()),
+            webhook_max_connections: None,
+            webhook_drop_pending_updates: Some(false),
+            webhook_ip_filter: Some(false),
+            webhook_allowed_ips: Some(TELEGRAM_RANGES.join(",")),
+            trusted_proxies: None,
+            webhook_ip_rate_limit: None,
+            webhook_ip_rate_burst: None,
+            admin_chat_ids: None,
+            disabled_commands: None,
+            session_ttl_secs: Some(300),
+            unknown_command_interval_secs: Some(60),
+            reminders_file: Some("reminders.json".to_string()),
+            settings_file: Some("settings.json".to_string()),
+            rpc_timeout_secs: Some(10),
+            // Telegram allows about 30 messages per second across chats
+            broadcast_interval_ms: Some(50),
+            payload_encoding: None,
+            persistent_messages: Some(true),
+            durable_queues: Some(true),
+            unroutable_queue: None,
+            queue_name_prefix: None,
+            queue_name_suffix: None,
+            statsd_address: None,
+            statsd_prefix: Some("rustin_bot_publisher.".to_string()),
+            statsd_tags: None,
+            statsd_interval_secs: Some(10),
+            circuit_failure_rate: Some(CircuitBreakerConfig::default().failure_rate),
+            circuit_min_requests: Some(CircuitBreakerConfig::default().min_requests),
+            circuit_window_secs: Some(CircuitBreakerConfig::default().window.as_secs()),
+            circuit_open_secs: Some(CircuitBreakerConfig::default().open_for.as_secs()),
+            flood_max_messages: Some(FloodConfig::default().max_messages),
+            flood_window_secs: Some(FloodConfig::default().window.as_secs()),
+            flood_mute_secs: Some(FloodConfig::default().mute_for.as_secs()),
+            bot_ids: Some(DEFAULT_BOT_ID.to_string()),
+            bot_defaults: None,
+            bots: None,
+            hmac: None,
+            ws_allowed_queues: None,
+            cooldowns: None,
+            routes: None,
+            aliases: None,
+            localized_aliases: None,
+            queues: None,
+            tiers: None,
+            cron: None,
+            sqs_queue_urls: None,
+            timeout_fallback_queues: None,
+            pii_scrubbing: None,
+            payload_encryption_key: None,
+            payload_encryption_key_id: None,
+            message_signing_key: None,
+            message_signing_key_id: None,
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("config file {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("config file {}: {}", path.display(), e))
+    }
+
+    // Unparseable values are collected into `problems` instead of aborting on the first one
+    fn from_env(problems: &mut Vec<String>) -> Self {
+        Self {
+            server_address: env_string("SERVER_ADDRESS"),
+            rabbit_address: env_string("RABBIT_ADDRESS"),
+            shadow_rabbit_address: env_string("SHADOW_RABBIT_ADDRESS"),
+            amqp_ca_bundle: env_string("AMQP_CA_BUNDLE"),
+            amqp_client_cert: env_string("AMQP_CLIENT_CERT"),
+            amqp_client_key: env_string("AMQP_CLIENT_KEY"),
+            tls_cert_path: env_string("TLS_CERT_PATH"),
+            tls_key_path: env_string("TLS_KEY_PATH"),
+            tls_self_signed_domain: env_string("TLS_SELF_SIGNED_DOMAIN"),
+            redis_url: env_string("REDIS_URL"),
+            audit_database_url: env_string("AUDIT_DATABASE_URL"),
+            backend: env_string("BACKEND"),
+            sqs_queue_url_prefix: env_string("SQS_QUEUE_URL_PREFIX"),
+            mqtt_address: env_string("MQTT_ADDRESS"),
+            mqtt_topic_prefix: env_string("MQTT_TOPIC_PREFIX"),
+            mqtt_qos: env_parse("MQTT_QOS", problems),
+            grpc_address: env_string("GRPC_ADDRESS"),
+            channel_pool_size: env_parse("CHANNEL_POOL_SIZE", problems),
+            channel_pool_max: env_parse("CHANNEL_POOL_MAX", problems),
+            channel_pool_grow_after_ms: env_parse("CHANNEL_POOL_GROW_AFTER_MS", problems),
+            channel_health_check_secs: env_parse("CHANNEL_HEALTH_CHECK_SECS", problems),
+            channel_selection: env_string("CHANNEL_SELECTION"),
+            broker_retry_max_secs: env_parse("BROKER_RETRY_MAX_SECS", problems),
+            mode: env_string("MODE"),
+            polling_timeout_secs: env_parse("POLLING_TIMEOUT_SECS", problems),
+            webhook_max_body_bytes: env_parse("WEBHOOK_MAX_BODY_BYTES", problems),
+            webhook_public_url: env_string("WEBHOOK_PUBLIC_URL"),
+            webhook_concurrency_limit: env_parse("WEBHOOK_CONCURRENCY_LIMIT", problems),
+            webhook_timeout_secs: env_parse("WEBHOOK_TIMEOUT_SECS", problems),
+            publish_timeout_secs: env_parse("PUBLISH_TIMEOUT_SECS", problems),
+            webhook_max_connections: env_parse("WEBHOOK_MAX_CONNECTIONS", problems),
+            webhook_drop_pending_updates: env_parse("WEBHOOK_DROP_PENDING_UPDATES", problems),
+            webhook_ip_filter: env_parse("WEBHOOK_IP_FILTER", problems),
+            webhook_allowed_ips: env_string("WEBHOOK_ALLOWED_IPS"),
+            trusted_proxies: env_string("TRUSTED_PROXIES"),
+            webhook_ip_rate_limit: env_parse("WEBHOOK_IP_RATE_LIMIT", problems),
+            webhook_ip_rate_burst: env_parse("WEBHOOK_IP_RATE_BURST", problems),
+            admin_chat_ids: env_string("ADMIN_CHAT_IDS"),
+            disabled_commands: env_string("DISABLED_COMMANDS"),
+            session_ttl_secs: env_parse("SESSION_TTL_SECS", problems),
+            unknown_command_interval_secs: env_parse("UNKNOWN_COMMAND_INTERVAL_SECS", problems),
+            reminders_file: env::var("REMINDERS_FILE").ok(),
+            settings_file: env::var("SETTINGS_FILE").ok(),
+            rpc_timeout_secs: env_parse("RPC_TIMEOUT_SECS", problems),
+            broadcast_interval_ms: env_parse("BROADCAST_INTERVAL_MS", problems),
+            payload_encoding: env_string("PAYLOAD_ENCODING"),
+            persistent_messages: env_parse("PERSISTENT_MESSAGES", problems),
+            durable_queues: env_parse("DURABLE_QUEUES", problems),
+            unroutable_queue: env_string("UNROUTABLE_QUEUE"),
+            queue_name_prefix: env_string("QUEUE_NAME_PREFIX"),
+            queue_name_suffix: env_string("QUEUE_NAME_SUFFIX"),
+            statsd_address: env_string("STATSD_ADDRESS"),
+            statsd_prefix: env::var("STATSD_PREFIX").ok(),
+            statsd_tags: env_string("STATSD_TAGS"),
+            statsd_interval_secs: env_parse("STATSD_INTERVAL_SECS", problems),
+            circuit_failure_rate: env_parse("CIRCUIT_FAILURE_RATE", problems),
+            circuit_min_requests: env_parse("CIRCUIT_MIN_REQUESTS", problems),
+            circuit_window_secs: env_parse("CIRCUIT_WINDOW_SECS", problems),
+            circuit_open_secs: env_parse("CIRCUIT_OPEN_SECS", problems),
+            flood_max_messages: env_parse("FLOOD_MAX_MESSAGES", problems),
+            flood_window_secs: env_parse("FLOOD_WINDOW_SECS", problems),
+            flood_mute_secs: env_parse("FLOOD_MUTE_SECS", problems),
+            bot_ids: env_string("BOT_IDS"),
+            bot_defaults: Some(env_bot_settings("", problems))
+                .filter(|defaults| *defaults != BotOverrides::default()),
+            bots: env_bots(problems),
+            hmac: env_hmac(),
+            ws_allowed_queues: env_string("WS_ALLOWED_QUEUES"),
+            cooldowns: env_cooldowns(problems),
+            routes: None,
+            aliases: None,
+            localized_aliases: None,
+            queues: None,
+            tiers: None,
+            cron: None,
+            sqs_queue_urls: None,
+            timeout_fallback_queues: None,
+            pii_scrubbing: None,
+            payload_encryption_key: env_string("PAYLOAD_ENCRYPTION_KEY"),
+            payload_encryption_key_id: env_string("PAYLOAD_ENCRYPTION_KEY_ID"),
+            message_signing_key: env_string("MESSAGE_SIGNING_KEY"),
+            message_signing_key_id: env_string("MESSAGE_SIGNING_KEY_ID"),
+        }
+    }
+
+    // Values set in `over` win
+    fn merge(self, over: Self) -> Self {
+        Self {
+            server_address: over.server_address.or(self.server_address),
+            rabbit_address: over.rabbit_address.or(self.rabbit_address),
+            shadow_rabbit_address: over.shadow_rabbit_address.or(self.shadow_rabbit_address),
+            amqp_ca_bundle: over.amqp_ca_bundle.or(self.amqp_ca_bundle),
+            amqp_client_cert: over.amqp_client_cert.or(self.amqp_client_cert),
+            amqp_client_key: over.amqp_client_key.or(self.amqp_client_key),
+            tls_cert_path: over.tls_cert_path.or(self.tls_cert_path),
+            tls_key_path: over.tls_key_path.or(self.tls_key_path),
+            tls_self_signed_domain: over.tls_self_signed_domain.or(self.tls_self_signed_domain),
+            redis_url: over.redis_url.or(self.redis_url),
+            audit_database_url: over.audit_database_url.or(self.audit_database_url),
+            backend: over.backend.or(self.backend),
+            sqs_queue_url_prefix: over.sqs_queue_url_prefix.or(self.sqs_queue_url_prefix),
+            mqtt_address: over.mqtt_address.or(self.mqtt_address),
+            mqtt_topic_prefix: over.mqtt_topic_prefix.or(self.mqtt_topic_prefix),
+            mqtt_qos: over.mqtt_qos.or(self.mqtt_qos),
+            grpc_address: over.grpc_address.or(self.grpc_address),
+            channel_pool_size: over.channel_pool_size.or(self.channel_pool_size),
+            channel_pool_max: over.channel_pool_max.or(self.channel_pool_max),
+            channel_pool_grow_after_ms: over
+                .channel_pool_grow_after_ms
+                .or(self.channel_pool_grow_after_ms),
+            channel_health_check_secs: over
+                .channel_health_check_secs
+                .or(self.channel_health_check_secs),
+            channel_selection: over.channel_selection.or(self.channel_selection),
+            broker_retry_max_secs: over.broker_retry_max_secs.or(self.broker_retry_max_secs),
+            mode: over.mode.or(self.mode),
+            polling_timeout_secs: over.polling_timeout_secs.or(self.polling_timeout_secs),
+            webhook_max_body_bytes: over.webhook_max_body_bytes.or(self.webhook_max_body_bytes),
+            webhook_public_url: over.webhook_public_url.or(self.webhook_public_url),
+            webhook_concurrency_limit: over
+                .webhook_concurrency_limit
+                .or(self.webhook_concurrency_limit),
+            webhook_timeout_secs: over.webhook_timeout_secs.or(self.webhook_timeout_secs),
+            publish_timeout_secs: over.publish_timeout_secs.or(self.publish_timeout_secs),
+            webhook_max_connections: over
+                .webhook_max_connections
+                .or(self.webhook_max_connections),
+            webhook_drop_pending_updates: over
+                .webhook_drop_pending_updates
+                .or(self.webhook_drop_pending_updates),
+            webhook_ip_filter: over.webhook_ip_filter.or(self.webhook_ip_filter),
+            webhook_allowed_ips: over.webhook_allowed_ips.or(self.webhook_allowed_ips),
+            trusted_proxies: over.trusted_proxies.or(self.trusted_proxies),
+            webhook_ip_rate_limit: over.webhook_ip_rate_limit.or(self.webhook_ip_rate_limit),
+            webhook_ip_rate_burst: over.webhook_ip_rate_burst.or(self.webhook_ip_rate_burst),
+            admin_chat_ids: over.admin_chat_ids.or(self.admin_chat_ids),
+            disabled_commands: over.disabled_commands.or(self.disabled_commands),
+            session_ttl_secs: over.session_ttl_secs.or(self.session_ttl_secs),
+            unknown_command_interval_secs: over
+                .unknown_command_interval_secs
+                .or(self.unknown_command_interval_secs),
+            reminders_file: over.reminders_file.or(self.reminders_file),
+            settings_file: over.settings_file.or(self.settings_file),
+            rpc_timeout_secs: over.rpc_timeout_secs.or(self.rpc_timeout_secs),
+            broadcast_interval_ms: over.broadcast_interval_ms.or(self.broadcast_interval_ms),
+            payload_encoding: over.payload_encoding.or(self.payload_encoding),
+            persistent_messages: over.persistent_messages.or(self.persistent_messages),
+            durable_queues: over.durable_queues.or(self.durable_queues),
+            unroutable_queue: over.unroutable_queue.or(self.unroutable_queue),
+            queue_name_prefix: over.queue_name_prefix.or(self.queue_name_prefix),
+            queue_name_suffix: over.queue_name_suffix.or(self.queue_name_suffix),
+            statsd_address: over.statsd_address.or(self.statsd_address),
+            statsd_prefix: over.statsd_prefix.or(self.statsd_prefix),
+            statsd_tags: over.statsd_tags.or(self.statsd_tags),
+            statsd_interval_secs: over.statsd_interval_secs.or(self.statsd_interval_secs),
+            circuit_failure_rate: over.circuit_failure_rate.or(self.circuit_failure_rate),
+            circuit_min_requests: over.circuit_min_requests.or(self.circuit_min_requests),
+            circuit_window_secs: over.circuit_window_secs.or(self.circuit_window_secs),
+            circuit_open_secs: over.circuit_open_secs.or(self.circuit_open_secs),
+            flood_max_messages: over.flood_max_messages.or(self.flood_max_messages),
+            flood_window_secs: over.flood_window_secs.or(self.flood_window_secs),
+            flood_mute_secs: over.flood_mute_secs.or(self.flood_mute_secs),
+            bot_ids: over.bot_ids.or(self.bot_ids),
+            bot_defaults: match (self.bot_defaults, over.bot_defaults) {
+                (Some(under), Some(over)) => Some(under.merge(over)),
+                (under, over) => over.or(under),
+            },
+            bots: match (self.bots, over.bots) {
+                (Some(under), Some(over)) => Some(merge_named(under, over, BotOverrides::merge)),
+                (under, over) => over.or(under),
+            },
+            hmac: match (self.hmac, over.hmac) {
+                (Some(under), Some(over)) => Some(merge_named(under, over, HmacSettings::merge)),
+                (under, over) => over.or(under),
+            },
+            ws_allowed_queues: over.ws_allowed_queues.or(self.ws_allowed_queues),
+            cooldowns: over.cooldowns.or(self.cooldowns),
+            routes: over.routes.or(self.routes),
+            aliases: over.aliases.or(self.aliases),
+            localized_aliases: over.localized_aliases.or(self.localized_aliases),
+            queues: over.queues.or(self.queues),
+            tiers: over.tiers.or(self.tiers),
+            cron: over.cron.or(self.cron),
+            sqs_queue_urls: over.sqs_queue_urls.or(self.sqs_queue_urls),
+            timeout_fallback_queues: over
+                .timeout_fallback_queues
+                .or(self.timeout_fallback_queues),
+            pii_scrubbing: over.pii_scrubbing.or(self.pii_scrubbing),
+            payload_encryption_key: over.payload_encryption_key.or(self.payload_encryption_key),
+            payload_encryption_key_id: over
+                .payload_encryption_key_id
+                .or(self.payload_encryption_key_id),
+            message_signing_key: over.message_signing_key.or(self.message_signing_key),
+            message_signing_key_id: over.message_signing_key_id.or(self.message_signing_key_id),
+        }
+    }
+}
+
+// Only the routing table can be reloaded while running; other settings need a restart
+pub fn load_routes(path: &Path) -> Result<RoutingTable, Vec<String>> {
+    let layer = ConfigLayer::from_file(path).map_err(|problem| vec![problem])?;
+    routing_table(&layer)
+}
+
+fn routing_table(layer: &ConfigLayer) -> Result<RoutingTable, Vec<String>> {
+    RoutingTable::with_overrides(
+        layer.routes.clone().unwrap_or_default(),
+        layer.aliases.clone().unwrap_or_default(),
+    )?
+    .with_localized_aliases(layer.localized_aliases.clone().unwrap_or_default())
+}
+
+// A hex encoded key and the id consumers know it by, built into what uses it; None when no key
+// is set
+fn named_key<T>(
+    name: &str,
+    key: Option<String>,
+    key_id: Option<String>,
+    backend: Option<&Backend>,
+    build: impl FnOnce(&str, &[u8]) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    let Some(key) = key.filter(|key| !key.is_empty()) else {
+        return Ok(None);
+    };
+    // The other backends have no headers to name the key in
+    if let Some(backend) = backend.filter(|backend| **backend != Backend::RabbitMq) {
+        return Err(format!(
+            "{}: not supported by the {} backend",
+            name, backend
+        ));
+    }
+    let key = hex::decode(key.trim()).map_err(|e| format!("{}: not hex: {}", name, e))?;
+    let key_id = key_id.filter(|key_id| !key_id.is_empty()).ok_or_else(|| {
+        format!(
+            "{}_id: must name the key, so consumers can pick theirs",
+            name
+        )
+    })?;
+    build(&key_id, &key).map(Some)
+}
+
+// Names are matched case-insensitively, like the upper-cased ones in environment variables
+fn merge_named<T: Default>(
+    under: HashMap<String, T>,
+    over: HashMap<String, T>,
+    merge: impl Fn(T, T) -> T,
+) -> HashMap<String, T> {
+    let mut merged: HashMap<String, T> = under
+        .into_iter()
+        .map(|(name, value)| (name.to_lowercase(), value))
+        .collect();
+    for (name, value) in over {
+        let name = name.to_lowercase();
+        let under = merged.remove(&name).unwrap_or_default();
+        merged.insert(name, merge(under, value));
+    }
+    merged
+}
+
+// HMAC_<NAME>_SECRET and HMAC_<NAME>_HEADER for every producer that has one; None when none is set
+fn env_hmac() -> Option<HashMap<String, HmacSettings>> {
+    let mut hmac: HashMap<String, HmacSettings> = HashMap::new();
+    for (name, value) in env::vars().filter(|(_, value)| !value.is_empty()) {
+        let Some(rest) = name.strip_prefix("HMAC_") else {
+            continue;
+        };
+        if let Some(source) = rest.strip_suffix("_SECRET") {
+            hmac.entry(source.to_lowercase()).or_default().secret = Some(value);
+        } else if let Some(source) = rest.strip_suffix("_HEADER") {
+            hmac.entry(source.to_lowercase()).or_default().header = Some(value);
+        }
+    }
+    Some(hmac).filter(|hmac| !hmac.is_empty())
+}
+
+// Longer names first, so BOT_X_ACK_COMMANDS is not read as bot X_ACK's COMMANDS
+const BOT_ENV_SETTINGS: [&str; 11] = [
+    "SONGLINKS_MAX_LINES",
+    "SONGLINKS_MAX_CHARS",
+    "ADDRESSED_CHAT_TYPES",
+    "STICKERS_ON_COMMAND",
+    "SECRET_TOKEN",
+    "QUEUE_PREFIX",
+    "ACK_COMMANDS",
+    "API_TOKEN",
+    "ACK_TEXT",
+    "USERNAME",
+    "COMMANDS",
+];
+
+// BOT_<ID>_<SETTING> for every bot that has one; None when none is set
+fn env_bots(problems: &mut Vec<String>) -> Option<HashMap<String, BotOverrides>> {
+    let ids: HashSet<String> = env::vars()
+        .filter_map(|(name, _)| {
+            let rest = name.strip_prefix("BOT_")?;
+            BOT_ENV_SETTINGS.iter().find_map(|setting| {
+                let id = rest.strip_suffix(setting)?.strip_suffix('_')?;
+                Some(id.to_string()).filter(|id| !id.is_empty())
+            })
+        })
+        .collect();
+    let bots: HashMap<String, BotOverrides> = ids
+        .into_iter()
+        .map(|id| {
+            let prefix = format!("BOT_{}_", id);
+            let key = |setting: &str| format!("{}{}", prefix, setting);
+            let bot = BotOverrides {
+                secret_token: env_string(&key("SECRET_TOKEN")),
+                api_token: env_string(&key("API_TOKEN")),
+                // Set but empty still means no prefix, or no command enabled
+                queue_prefix: env::var(key("QUEUE_PREFIX")).ok(),
+                commands: env::var(key("COMMANDS")).ok(),
+                ..env_bot_settings(&prefix, problems)
+            };
+            (id, bot)
+        })
+        .collect();
+    Some(bots).filter(|bots| !bots.is_empty())
+}
+
+// The settings a bot can share with the others, read from <prefix><SETTING>
+fn env_bot_settings(prefix: &str, problems: &mut Vec<String>) -> BotOverrides {
+    let key = |setting: &str| format!("{}{}", prefix, setting);
+    let stickers_on_command = key("STICKERS_ON_COMMAND");
+    BotOverrides {
+        songlinks_max_lines: env_parse(&key("SONGLINKS_MAX_LINES"), problems),
+        songlinks_max_chars: env_parse(&key("SONGLINKS_MAX_CHARS"), problems),
+        stickers_on_command: env_string(&stickers_on_command).and_then(|value| {
+            match value.as_str() {
+                "1" | "true" => Some(true),
+                "0" | "false" => Some(false),
+                _ => {
+                    problems.push(format!(
+                        "{}: cannot parse {:?}: expected true, false, 1 or 0",
+                        stickers_on_command, value
+                    ));
+                    None
+                }
+            }
+        }),
+        ack_commands: env_string(&key("ACK_COMMANDS")),
+        ack_text: env_string(&key("ACK_TEXT")),
+        username: env_string(&key("USERNAME")),
+        addressed_chat_types: env_string(&key("ADDRESSED_CHAT_TYPES")),
+        ..BotOverrides::default()
+    }
+}
+
+// COOLDOWN_<COMMAND>_SECS for every command that has one; None when none is set
+fn env_cooldowns(problems: &mut Vec<String>) -> Option<HashMap<String, u64>> {
+    let cooldowns: HashMap<String, u64> = env::vars()
+        .filter_map(|(name, _)| {
+            let command = name.strip_prefix("COOLDOWN_")?.strip_suffix("_SECS")?;
+            let secs = env_parse(&name, problems)?;
+            Some((format!("/{}", command.to_lowercase()), secs))
+        })
+        .collect();
+    Some(cooldowns).filter(|cooldowns| !cooldowns.is_empty())
+}
+
+fn env_string(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T: FromStr>(name: &str, problems: &mut Vec<String>) -> Option<T>
+where
+    T::Err: fmt::Display,
+{
+    let value = env_string(name)?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            problems.push(format!("{}: cannot parse {:?}: {}", name, value, e));
+            None
+        }
+    }
+}
+
+// Command-line flags: an optional config file plus per-setting overrides
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    #[arg(
+        long,
+        global = true,
+        help = "TOML file with settings [env: CONFIG_FILE]"
+    )]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: ConfigLayer,
+}
+
+// Every problem found while loading, reported together
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Process-wide settings, resolved from defaults, config file, environment and flags (in that order)
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server_address: String,
+    // HTTPS is served in-process when set
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsSettings>,
+    pub backend: Backend,
+    // Empty unless the backend is RabbitMQ
+    pub rabbit_address: String,
+    // Connects to rabbit_address with the AMQP TLS settings; unused unless the backend is
+    // RabbitMQ
+    pub rabbit_connector: AmqpConnector,
+    // Publishes are mirrored here in the background, whatever the backend
+    pub shadow_rabbit_address: Option<String>,
+    pub shadow_rabbit_connector: Option<AmqpConnector>,
+    // Sessions, cooldowns and rate limits are shared through this server instead of kept per
+    // process
+    pub redis_url: Option<String>,
+    // Published messages are recorded here, for support and for /deleteme
+    pub audit_database_url: Option<String>,
+    pub sqs_queue_url_prefix: Option<String>,
+    pub sqs_queue_urls: HashMap<String, String>,
+    pub mqtt_address: Option<String>,
+    pub mqtt_topic_prefix: String,
+    pub mqtt_qos: u8,
+    pub grpc_address: Option<SocketAddr>,
+    pub channel_pool_size: usize,
+    // The pool autoscales between channel_pool_size and this when they differ
+    pub channel_pool_max: usize,
+    pub channel_pool_grow_after: Duration,
+    pub channel_health_check: Option<Duration>,
+    pub channel_selection: ChannelSelection,
+    pub broker_retry_max_delay: Duration,
+    pub mode: UpdateMode,
+    pub polling_timeout: Duration,
+    pub webhook_max_body_bytes: usize,
+    pub webhook_concurrency_limit: usize,
+    pub webhook_timeout: Duration,
+    pub publish_timeout: Duration,
+    // Set when the server should register its own webhook with Telegram
+    pub webhook: Option<WebhookSettings>,
+    pub webhook_ip_filter: Option<IpFilter>,
+    pub webhook_ip_rate_limit: Option<IpRateLimit>,
+    pub admin_chat_ids: HashSet<i64>,
+    // Switched on again at runtime through the admin API
+    pub disabled_commands: HashSet<String>,
+    pub session_ttl: Duration,
+    pub unknown_command_interval: Duration,
+    pub rpc_timeout: Duration,
+    pub broadcast_interval: Duration,
+    pub queues: QueueSettings,
+    pub tiers: UserTiers,
+    pub cron_jobs: CronJobs,
+    pub unroutable_queue: Option<String>,
+    // RabbitMQ only, like the unroutable queue
+    pub timeout_fallback_queues: HashMap<String, String>,
+    pub scrubber: Option<Scrubber>,
+    pub payload_cipher: Option<Arc<PayloadCipher>>,
+    pub message_signer: Option<Arc<MessageSigner>>,
+    // The process metrics are pushed here every interval when set; without it they are only
+    // readable in-process, through App::metrics
+    pub statsd: Option<StatsdConfig>,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub flood: FloodConfig,
+    // Command -> minimum time between two uses by the same user
+    pub cooldowns: HashMap<String, Duration>,
+    pub bots: Vec<BotConfig>,
+    // Signing producer -> the verifier of its requests, for those with a secret set
+    pub hmac_verifiers: HashMap<String, HmacVerifier>,
+    // None allows WebSocket producers to publish to any valid destination
+    pub ws_allowed_queues: Option<HashSet<String>>,
+    pub routes: RoutingTable,
+    // Kept so the routing table can be reloaded from it
+    pub config_file: Option<PathBuf>,
+    pub reminders_file: Option<PathBuf>,
+    pub settings_file: Option<PathBuf>,
+}
+
+impl Config {
+    // The verifier of the producer with this name, when its secret is set
+    pub fn hmac_verifier(&self, name: &str) -> Option<HmacVerifier> {
+        self.hmac_verifiers.get(name).cloned()
+    }
+
+    pub fn load(args: ConfigArgs) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        let mut layer = ConfigLayer::defaults();
+
+        let file = args
+            .config
+            .or_else(|| env_string("CONFIG_FILE").map(PathBuf::from));
+        if let Some(path) = &file {
+            match ConfigLayer::from_file(path) {
+                Ok(from_file) => layer = layer.merge(from_file),
+                Err(problem) => problems.push(problem),
+            }
+        }
+        layer = layer
+            .merge(ConfigLayer::from_env(&mut problems))
+            .merge(args.overrides);
+
+        let config = Self::validate(layer, file, &mut problems);
+        match config {
+            Some(config) if problems.is_empty() => Ok(config),
+            _ => Err(ConfigError(problems)),
+        }
+    }
+
+    fn validate(
+        layer: ConfigLayer,
+        config_file: Option<PathBuf>,
+        problems: &mut Vec<String>,
+    ) -> Option<Self> {
+        let routes = match routing_table(&layer) {
+            Ok(routes) => Some(routes),
+            Err(route_problems) => {
+                problems.extend(route_problems);
+                None
+            }
+        };
+        let mut problem = |message: String| problems.push(message);
+
+        let payload_encoding = match layer.payload_encoding.as_deref().map(str::parse) {
+            None => Some(PayloadEncoding::default()),
+            Some(Ok(encoding)) => Some(encoding),
+            Some(Err(e)) => {
+                problem(format!("payload_encoding: {}", e));
+                None
+            }
+        };
+        let mut affix = |name: &str, value: Option<String>| {
+            let value = value.unwrap_or_default();
+            if value.chars().any(char::is_whitespace) {
+                problem(format!("{}: must not contain whitespace", name));
+            }
+            value
+        };
+        let name_prefix = affix("queue_name_prefix", layer.queue_name_prefix);
+        let name_suffix = affix("queue_name_suffix", layer.queue_name_suffix);
+        let queues = payload_encoding.and_then(|encoding| {
+            let defaults = QueueOptions::default();
+            let default = QueueOptions {
+                encoding,
+                persistent: layer.persistent_messages.unwrap_or(defaults.persistent),
+                durable: layer.durable_queues.unwrap_or(defaults.durable),
+                ..defaults
+            };
+            match QueueSettings::new(default, layer.queues.unwrap_or_default()) {
+                Ok(queues) => Some(queues.with_name_affixes(name_prefix, name_suffix)),
+                Err(queue_problems) => {
+                    queue_problems.into_iter().for_each(&mut problem);
+                    None
+                }
+            }
+        });
+
+        let channel_selection = match layer.channel_selection.as_deref().map(str::parse) {
+            None => Some(ChannelSelection::default()),
+            Some(Ok(selection)) => Some(selection),
+            Some(Err(e)) => {
+                problem(format!("channel_selection: {}", e));
+                None
+            }
+        };
+
+        let server_address = layer.server_address.unwrap_or_default();
+        let has_port = server_address
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        if !has_port {
+            problem(format!(
+                "server_address: expected host:port, got {:?}",
+                server_address
+            ));
+        }
+
+        let path = |value: Option<String>| value.filter(|path| !path.is_empty()).map(PathBuf::from);
+        let tls_domain = layer
+            .tls_self_signed_domain
+            .filter(|domain| !domain.is_empty());
+        let tls_paths = match (path(layer.tls_cert_path), path(layer.tls_key_path)) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+            (None, None) => {
+                if tls_domain.is_some() {
+                    problem(
+                        "tls_self_signed_domain: needs tls_cert_path and tls_key_path".to_string(),
+                    );
+                }
+                None
+            }
+            _ => {
+                problem("tls_cert_path and tls_key_path: must be set together".to_string());
+                None
+            }
+        };
+        if tls_paths.is_some() {
+            if cfg!(not(feature = "tls")) {
+                problem("tls_cert_path: HTTPS needs the \"tls\" cargo feature".to_string());
+            } else if server_address.parse::<SocketAddr>().is_err() {
+                problem(
+                    "server_address: must be an IP address and port to serve HTTPS".to_string(),
+                );
+            }
+        }
+        #[cfg(feature = "tls")]
+        let tls = tls_paths.map(|(cert_path, key_path)| {
+            crate::tls::TlsSettings::new(cert_path, key_path, tls_domain)
+        });
+
+        let backend = match layer.backend.as_deref().map(str::parse) {
+            None => Some(Backend::default()),
+            Some(Ok(backend)) => Some(backend),
+            Some(Err(e)) => {
+                problem(format!("backend: {}", e));
+                None
+            }
+        };
+
+        // Other backends never connect to RabbitMQ
+        let rabbit_address = layer.rabbit_address.unwrap_or_default();
+        if backend == Some(Backend::RabbitMq) {
+            if rabbit_address.is_empty() {
+                problem("rabbit_address: must be set (e.g. amqp://localhost:5672)".to_string());
+            } else if !rabbit_address.starts_with("amqp://")
+                && !rabbit_address.starts_with("amqps://")
+            {
+                problem("rabbit_address: must start with amqp:// or amqps://".to_string());
+            }
+        }
+        let shadow_rabbit_address = layer
+            .shadow_rabbit_address
+            .filter(|address| !address.is_empty());
+        if let Some(address) = &shadow_rabbit_address {
+            if !address.starts_with("amqp://") && !address.starts_with("amqps://") {
+                problem("shadow_rabbit_address: must start with amqp:// or amqps://".to_string());
+            } else if *address == rabbit_address {
+                problem("shadow_rabbit_address: must not be rabbit_address".to_string());
+            }
+        }
+        let amqp_tls = AmqpTlsSettings::new(
+            path(layer.amqp_ca_bundle),
+            path(layer.amqp_client_cert),
+            path(layer.amqp_client_key),
+        );
+        let mut connector = |name: &str, address: &str| {
+            AmqpConnector::try_new(address, amqp_tls.as_ref())
+                .map_err(|e| problem(format!("{}: {}", name, e)))
+                .ok()
+        };
+        let rabbit_connector = if backend == Some(Backend::RabbitMq) {
+            connector("rabbit_address", &rabbit_address)
+        } else {
+            // Without TLS settings there is nothing to fail
+            AmqpConnector::try_new("", None).ok()
+        };
+        let shadow_rabbit_connector = match &shadow_rabbit_address {
+            Some(address) => connector("shadow_rabbit_address", address).map(Some),
+            None => Some(None),
+        };
+
+        let payload_cipher = named_key(
+            "payload_encryption_key",
+            layer.payload_encryption_key,
+            layer.payload_encryption_key_id,
+            backend.as_ref(),
+            |key_id, key| PayloadCipher::new(key_id, key).map(Arc::new),
+        )
+        .map_err(&mut problem)
+        .ok();
+        let message_signer = named_key(
+            "message_signing_key",
+            layer.message_signing_key,
+            layer.message_signing_key_id,
+            backend.as_ref(),
+            |key_id, seed| MessageSigner::new(key_id, seed).map(Arc::new),
+        )
+        .map_err(&mut problem)
+        .ok();
+
+        let redis_url = layer.redis_url.filter(|url| !url.is_empty());
+        if let Some(url) = &redis_url {
+            if !url.starts_with("redis://") && !url.starts_with("rediss://") {
+                problem("redis_url: must start with redis:// or rediss://".to_string());
+            } else if cfg!(not(feature = "redis")) {
+                problem("redis_url: shared state needs the \"redis\" cargo feature".to_string());
+            }
+        }
+        let audit_database_url = layer.audit_database_url.filter(|url| !url.is_empty());
+        if let Some(url) = &audit_database_url {
+            match Url::parse(url) {
+                Ok(parsed) if !matches!(parsed.scheme(), "postgres" | "postgresql") => problem(
+                    "audit_database_url: must start with postgres:// or postgresql://".to_string(),
+                ),
+                Ok(_) if cfg!(not(feature = "audit")) => problem(
+                    "audit_database_url: the audit log needs the \"audit\" cargo feature"
+                        .to_string(),
+                ),
+                #[cfg(feature = "audit")]
+                Ok(_) => {
+                    if let Err(e) = crate::audit::connect_options(url) {
+                        problem(format!("audit_database_url: {}", e));
+                    }
+                }
+                #[cfg(not(feature = "audit"))]
+                Ok(_) => {}
+                // The URL may hold a password, so only the error is shown
+                Err(e) => problem(format!("audit_database_url: {}", e)),
+            }
+        }
+
+        let sqs_queue_url_prefix = layer.sqs_queue_url_prefix;
+        let sqs_queue_urls = layer.sqs_queue_urls.unwrap_or_default();
+        for (name, url) in sqs_queue_url_prefix
+            .iter()
+            .map(|prefix| ("sqs_queue_url_prefix".to_string(), prefix))
+            .chain(
+                sqs_queue_urls
+                    .iter()
+                    .map(|(queue, url)| (format!("sqs_queue_urls.{}", queue), url)),
+            )
+        {
+            match Url::parse(url) {
+                Ok(url) if matches!(url.scheme(), "https" | "http") => {}
+                Ok(_) => problem(format!("{}: must be an https:// URL", name)),
+                Err(e) => problem(format!("{}: {:?}: {}", name, url, e)),
+            }
+        }
+        #[cfg(feature = "sqs")]
+        if backend == Some(Backend::Sqs)
+            && sqs_queue_url_prefix.is_none()
+            && sqs_queue_urls.is_empty()
+        {
+            problem(
+                "sqs_queue_url_prefix: must be set, or every queue listed in sqs_queue_urls"
+                    .to_string(),
+            );
+        }
+
+        let mqtt_qos = layer.mqtt_qos.unwrap_or_default();
+        if mqtt_qos > 2 {
+            problem("mqtt_qos: must be 0, 1 or 2".to_string());
+        }
+        let mqtt_topic_prefix = layer.mqtt_topic_prefix.unwrap_or_default();
+        if mqtt_topic_prefix.is_empty() || mqtt_topic_prefix.contains(['+', '#']) {
+            problem("mqtt_topic_prefix: must be set and contain no + or # wildcards".to_string());
+        }
+        #[cfg(feature = "mqtt")]
+        if backend == Some(Backend::Mqtt) {
+            match &layer.mqtt_address {
+                None => {
+                    problem("mqtt_address: must be set (e.g. mqtt://localhost:1883)".to_string())
+                }
+                Some(address) => {
+                    if let Err(e) = crate::mqtt::mqtt_options(address) {
+                        problem(format!("mqtt_address: {}", e));
+                    }
+                }
+            }
+        }
+
+        let grpc_address = layer
+            .grpc_address
+            .and_then(|address| match address.parse() {
+                Ok(address) => Some(address),
+                Err(e) => {
+                    problem(format!("grpc_address: {:?}: {}", address, e));
+                    None
+                }
+            });
+
+        let mode = match layer.mode.as_deref().map(str::parse) {
+            None => Some(UpdateMode::default()),
+            Some(Ok(mode)) => Some(mode),
+            Some(Err(e)) => {
+                problem(format!("mode: {}", e));
+                None
+            }
+        };
+        // A registered webhook would make Telegram refuse every getUpdates call
+        if mode == Some(UpdateMode::Polling) && layer.webhook_public_url.is_some() {
+            problem("webhook_public_url: cannot be used with mode polling".to_string());
+        }
+
+        if let Some(max_connections) = layer.webhook_max_connections {
+            if !(1..=100).contains(&max_connections) {
+                problem("webhook_max_connections: must be between 1 and 100".to_string());
+            }
+        }
+        let webhook = layer
+            .webhook_public_url
+            .and_then(|address| match Url::parse(&address) {
+                Ok(public_url) if public_url.scheme() == "https" => Some(WebhookSettings {
+                    public_url,
+                    max_connections: layer.webhook_max_connections,
+                    drop_pending_updates: layer.webhook_drop_pending_updates.unwrap_or_default(),
+                }),
+                Ok(_) => {
+                    problem(
+                        "webhook_public_url: Telegram only delivers to https:// URLs".to_string(),
+                    );
+                    None
+                }
+                Err(e) => {
+                    problem(format!("webhook_public_url: {:?}: {}", address, e));
+                    None
+                }
+            });
+
+        let mut ranges =
+            |name: &str, value: Option<String>| match parse_ranges(&value.unwrap_or_default()) {
+                Ok(ranges) => Some(ranges),
+                Err(e) => {
+                    problem(format!("{}: {}", name, e));
+                    None
+                }
+            };
+        let allowed_ips = ranges("webhook_allowed_ips", layer.webhook_allowed_ips);
+        let trusted_proxies = ranges("trusted_proxies", layer.trusted_proxies);
+        let webhook_ip_rate_limit = match (layer.webhook_ip_rate_limit, layer.webhook_ip_rate_burst)
+        {
+            (Some(0), _) => {
+                problem("webhook_ip_rate_limit: must be greater than zero".to_string());
+                None
+            }
+            (Some(_), Some(0)) => {
+                problem("webhook_ip_rate_burst: must be greater than zero".to_string());
+                None
+            }
+            (Some(per_second), burst) => {
+                trusted_proxies.clone().map(|trusted_proxies| IpRateLimit {
+                    per_second,
+                    burst: burst.unwrap_or(per_second),
+                    trusted_proxies,
+                })
+            }
+            (None, _) => None,
+        };
+        let webhook_ip_filter = match (layer.webhook_ip_filter, allowed_ips, trusted_proxies) {
+            (Some(true), Some(allowed), Some(trusted)) => Some(IpFilter::new(allowed, trusted)),
+            _ => None,
+        };
+
+        let tiers = match UserTiers::new(layer.tiers.unwrap_or_default()) {
+            Ok(tiers) => Some(tiers),
+            Err(tier_problems) => {
+                tier_problems.into_iter().for_each(&mut problem);
+                None
+            }
+        };
+
+        let cron_jobs = match CronJobs::new(layer.cron.unwrap_or_default()) {
+            Ok(cron_jobs) => Some(cron_jobs),
+            Err(cron_problems) => {
+                cron_problems.into_iter().for_each(&mut problem);
+                None
+            }
+        };
+
+        let scrubber = match layer.pii_scrubbing.map(Scrubber::new).transpose() {
+            Ok(scrubber) => Some(scrubber),
+            Err(scrubbing_problems) => {
+                scrubbing_problems.into_iter().for_each(&mut problem);
+                None
+            }
+        };
+
+        let mut admin_chat_ids = HashSet::new();
+        for id in layer
+            .admin_chat_ids
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+        {
+            let id = id.trim();
+            if id.is_empty() {
+                continue;
+            }
+            match id.parse() {
+                Ok(id) => {
+                    admin_chat_ids.insert(id);
+                }
+                Err(_) => problem(format!("admin_chat_ids: {:?} is not a chat id", id)),
+            }
+        }
+
+        let mut disabled_commands = HashSet::new();
+        for command in layer
+            .disabled_commands
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+        {
+            let command = command.trim();
+            if command.is_empty() {
+                continue;
+            }
+            if command.starts_with('/') {
+                disabled_commands.insert(command.to_string());
+            } else {
+                problem(format!(
+                    "disabled_commands: {:?} is not a /command",
+                    command
+                ));
+            }
+        }
+
+        let unroutable_queue = layer.unroutable_queue.filter(|queue| {
+            let valid = validate_destination(queue);
+            if let Err(e) = &valid {
+                problem(format!("unroutable_queue: {}", e));
+            }
+            valid.is_ok()
+        });
+        let mut timeout_fallback_queues = layer.timeout_fallback_queues.unwrap_or_default();
+        timeout_fallback_queues.retain(|command, queue| {
+            if !command.starts_with('/') {
+                problem(format!(
+                    "timeout_fallback_queues: {:?} is not a /command",
+                    command
+                ));
+                return false;
+            }
+            let valid = validate_destination(queue);
+            if let Err(e) = &valid {
+                problem(format!("timeout_fallback_queues.{:?}: {}", command, e));
+            }
+            valid.is_ok()
+        });
+
+        let mut positive = |name: &str, value: Option<u64>| match value {
+            Some(0) => {
+                problem(format!("{}: must be greater than zero", name));
+                None
+            }
+            other => other,
+        };
+        let channel_pool_size = positive("channel_pool_size", layer.channel_pool_size.map(as_u64));
+        let channel_pool_grow_after_ms = positive(
+            "channel_pool_grow_after_ms",
+            layer.channel_pool_grow_after_ms,
+        );
+        let channel_health_check =
+            positive("channel_health_check_secs", layer.channel_health_check_secs);
+        let broker_retry_max_secs = positive("broker_retry_max_secs", layer.broker_retry_max_secs);
+        let polling_timeout_secs = positive("polling_timeout_secs", layer.polling_timeout_secs);
+        let webhook_max_body_bytes = positive(
+            "webhook_max_body_bytes",
+            layer.webhook_max_body_bytes.map(as_u64),
+        );
+        let session_ttl_secs = positive("session_ttl_secs", layer.session_ttl_secs);
+        let webhook_concurrency_limit = positive(
+            "webhook_concurrency_limit",
+            layer.webhook_concurrency_limit.map(as_u64),
+        );
+        let webhook_timeout_secs = positive("webhook_timeout_secs", layer.webhook_timeout_secs);
+        let publish_timeout_secs = positive("publish_timeout_secs", layer.publish_timeout_secs);
+        let rpc_timeout_secs = positive("rpc_timeout_secs", layer.rpc_timeout_secs);
+        let statsd_interval_secs = positive("statsd_interval_secs", layer.statsd_interval_secs);
+        let circuit_min_requests = positive(
+            "circuit_min_requests",
+            layer.circuit_min_requests.map(u64::from),
+        );
+        let circuit_window_secs = positive("circuit_window_secs", layer.circuit_window_secs);
+        let circuit_open_secs = positive("circuit_open_secs", layer.circuit_open_secs);
+        let flood_max_messages =
+            positive("flood_max_messages", layer.flood_max_messages.map(as_u64));
+        let flood_window_secs = positive("flood_window_secs", layer.flood_window_secs);
+        let flood_mute_secs = positive("flood_mute_secs", layer.flood_mute_secs);
+
+        let circuit_failure_rate = layer.circuit_failure_rate.unwrap_or_default();
+        if !(circuit_failure_rate > 0.0 && circuit_failure_rate <= 1.0) {
+            problem("circuit_failure_rate: must be greater than 0 and at most 1".to_string());
+        }
+        let mut cooldowns = HashMap::new();
+        for (command, secs) in layer.cooldowns.unwrap_or_default() {
+            if command.starts_with('/') {
+                cooldowns.insert(command, Duration::from_secs(secs));
+            } else {
+                problem(format!("cooldowns: {:?} is not a /command", command));
+            }
+        }
+
+        let bot_defaults = layer.bot_defaults.unwrap_or_default();
+        let mut bot_overrides: HashMap<String, BotOverrides> = layer
+            .bots
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, bot)| (id.to_lowercase(), bot))
+            .collect();
+        let bot_ids: Vec<&str> = layer
+            .bot_ids
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .collect();
+        if bot_ids.is_empty() {
+            problem("bot_ids: must name at least one bot".to_string());
+        }
+        let mut bots = Vec::new();
+        for id in bot_ids {
+            let overrides = bot_overrides.remove(&id.to_lowercase()).unwrap_or_default();
+            match bot_defaults.clone().merge(overrides).into_bot(id) {
+                Ok(bot) => bots.push(bot),
+                Err(bot_problems) => bot_problems.into_iter().for_each(&mut problem),
+            }
+        }
+
+        let mut hmac_verifiers = HashMap::new();
+        for (name, settings) in layer.hmac.unwrap_or_default() {
+            let name = name.to_lowercase();
+            if !HMAC_SOURCES.contains(&name.as_str()) {
+                problem(format!(
+                    "hmac.{}: nothing is signed under this name, expected one of {}",
+                    name,
+                    HMAC_SOURCES.join(", ")
+                ));
+                continue;
+            }
+            let header = settings.header.filter(|header| !header.is_empty());
+            if let Some(header) = &header {
+                if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    problem(format!(
+                        "hmac.{}.header: {:?} is not a header name",
+                        name, header
+                    ));
+                    continue;
+                }
+            }
+            let Some(secret) = settings.secret.filter(|secret| !secret.is_empty()) else {
+                if header.is_some() {
+                    problem(format!("hmac.{}.header: set without a secret", name));
+                }
+                continue;
+            };
+            // GitHub picks its own header
+            let default_header = if name == github::SOURCE {
+                github::SIGNATURE_HEADER
+            } else {
+                DEFAULT_SIGNATURE_HEADER
+            };
+            let verifier = HmacVerifier::new(
+                header.as_deref().unwrap_or(default_header),
+                secret.as_bytes(),
+            );
+            hmac_verifiers.insert(name, verifier);
+        }
+        let ws_allowed_queues = layer.ws_allowed_queues.map(|queues| {
+            queues
+                .split(',')
+                .map(str::trim)
+                .filter(|queue| !queue.is_empty())
+                .filter(|queue| {
+                    let valid = validate_destination(queue);
+                    if let Err(e) = &valid {
+                        problem(format!("ws_allowed_queues: {}", e));
+                    }
+                    valid.is_ok()
+                })
+                .map(str::to_string)
+                .collect()
+        });
+
+        let statsd = match layer.statsd_address.filter(|address| !address.is_empty()) {
+            None => None,
+            Some(address) => match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Some(StatsdConfig {
+                        address,
+                        prefix: layer.statsd_prefix.unwrap_or_default(),
+                        tags: layer
+                            .statsd_tags
+                            .unwrap_or_default()
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|tag| !tag.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                        interval: Duration::from_secs(statsd_interval_secs?),
+                    })
+                }
+                _ => {
+                    problem(format!("statsd_address: {:?} is not host:port", address));
+                    None
+                }
+            },
+        };
+
+        let channel_pool_size = channel_pool_size? as usize;
+        let channel_pool_max = layer.channel_pool_max.unwrap_or(channel_pool_size);
+        if channel_pool_max < channel_pool_size {
+            problem(format!(
+                "channel_pool_max: must be at least channel_pool_size ({})",
+                channel_pool_size
+            ));
+            return None;
+        }
+
+        Some(Self {
+            server_address,
+            #[cfg(feature = "tls")]
+            tls,
+            backend: backend?,
+            rabbit_address,
+            rabbit_connector: rabbit_connector?,
+            shadow_rabbit_address,
+            shadow_rabbit_connector: shadow_rabbit_connector?,
+            redis_url,
+            audit_database_url,
+            sqs_queue_url_prefix,
+            sqs_queue_urls,
+            mqtt_address: layer.mqtt_address,
+            mqtt_topic_prefix,
+            mqtt_qos,
+            grpc_address,
+            channel_pool_size,
+            channel_pool_max,
+            channel_pool_grow_after: Duration::from_millis(channel_pool_grow_after_ms?),
+            channel_health_check: channel_health_check.map(Duration::from_secs),
+            channel_selection: channel_selection?,
+            broker_retry_max_delay: Duration::from_secs(broker_retry_max_secs?),
+            mode: mode?,
+            polling_timeout: Duration::from_secs(polling_timeout_secs?),
+            webhook_max_body_bytes: webhook_max_body_bytes? as usize,
+            webhook_concurrency_limit: webhook_concurrency_limit? as usize,
+            webhook_timeout: Duration::from_secs(webhook_timeout_secs?),
+            publish_timeout: Duration::from_secs(publish_timeout_secs?),
+            webhook,
+            webhook_ip_filter,
+            webhook_ip_rate_limit,
+            admin_chat_ids,
+            disabled_commands,
+            session_ttl: Duration::from_secs(session_ttl_secs?),
+            unknown_command_interval: Duration::from_secs(
+                layer.unknown_command_interval_secs.unwrap_or_default(),
+            ),
+            rpc_timeout: Duration::from_secs(rpc_timeout_secs?),
+            broadcast_interval: Duration::from_millis(
+                layer.broadcast_interval_ms.unwrap_or_default(),
+            ),
+            queues: queues?,
+            tiers: tiers?,
+            cron_jobs: cron_jobs?,
+            unroutable_queue,
+            timeout_fallback_queues,
+            scrubber: scrubber?,
+            payload_cipher: payload_cipher?,
+            message_signer: message_signer?,
+            statsd,
+            circuit_breaker: CircuitBreakerConfig {
+                failure_rate: circuit_failure_rate,
+                min_requests: circuit_min_requests? as u32,
+                window: Duration::from_secs(circuit_window_secs?),
+                open_for: Duration::from_secs(circuit_open_secs?),
+            },
+            flood: FloodConfig {
+                max_messages: flood_max_messages? as usize,
+                window: Duration::from_secs(flood_window_secs?),
+                mute_for: Duration::from_secs(flood_mute_secs?),
+            },
+            cooldowns,
+            bots,
+            hmac_verifiers,
+            ws_allowed_queues,
+            routes: routes?,
+            config_file,
+            reminders_file: layer
+                .reminders_file
+                .filter(|file| !file.is_empty())
+                .map(PathBuf::from),
+            settings_file: layer
+                .settings_file
+                .filter(|file| !file.is_empty())
+                .map(PathBuf::from),
+        })
+    }
+}
+
+fn as_u64(value: usize) -> u64 {
+    value as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(overrides: ConfigLayer) -> Result<Config, ConfigError> {
+        Config::load(ConfigArgs {
+            config: None,
+            overrides: ConfigLayer {
+                rabbit_address: Some("amqp://localhost:5672".to_string()),
+                ..overrides
+            },
+        })
+    }
+
+    #[test]
+    fn statsd_settings_are_validated() {
+        let config = load(ConfigLayer {
+            statsd_address: Some("localhost:8125".to_string()),
+            statsd_tags: Some("env:prod, service:bot,".to_string()),
+            ..ConfigLayer::default()
+        })
+        .unwrap();
+        assert_eq!(
+            config.statsd,
+            Some(StatsdConfig {
+                address: "localhost:8125".to_string(),
+                prefix: "rustin_bot_publisher.".to_string(),
+                tags: vec!["env:prod".to_string(), "service:bot".to_string()],
+                interval: Duration::from_secs(10),
+            })
+        );
+
+        let problems = load(ConfigLayer {
+            statsd_address: Some("localhost".to_string()),
+            statsd_interval_secs: Some(0),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(
+            problems,
+            vec![
+                "statsd_interval_secs: must be greater than zero",
+                "statsd_address: \"localhost\" is not host:port",
+            ]
+        );
+
+        // The same parser reads STATSD_INTERVAL_SECS; a name of its own keeps other tests'
+        // environment untouched
+        let mut problems = Vec::new();
+        env::set_var("TEST_STATSD_INTERVAL_SECS", "abc");
+        assert_eq!(
+            env_parse::<u64>("TEST_STATSD_INTERVAL_SECS", &mut problems),
+            None
+        );
+        env::remove_var("TEST_STATSD_INTERVAL_SECS");
+        assert_eq!(
+            problems,
+            vec!["TEST_STATSD_INTERVAL_SECS: cannot parse \"abc\": invalid digit found in string"]
+        );
+    }
+
+    #[test]
+    fn payload_encryption_key_is_validated_on_load() {
+        let problems = |key: &str, key_id: Option<&str>| {
+            load(ConfigLayer {
+                payload_encryption_key: Some(key.to_string()),
+                payload_encryption_key_id: key_id.map(str::to_string),
+                ..ConfigLayer::default()
+            })
+            .unwrap_err()
+            .0
+        };
+        assert_eq!(
+            problems("no hex!!", Some("2026-10")),
+            vec!["payload_encryption_key: not hex: Invalid character 'n' at position 0"]
+        );
+        assert_eq!(
+            problems(&"07".repeat(32), None),
+            vec!["payload_encryption_key_id: must name the key, so consumers can pick theirs"]
+        );
+        assert_eq!(
+            problems(&"07".repeat(16), Some("2026-10")),
+            vec!["payload_encryption_key: must be 32 bytes for AES-256-GCM, not 16"]
+        );
+    }
+
+    #[test]
+    fn message_signing_key_is_validated_on_load() {
+        let problems = |key: &str, key_id: Option<&str>| {
+            load(ConfigLayer {
+                message_signing_key: Some(key.to_string()),
+                message_signing_key_id: key_id.map(str::to_string),
+                ..ConfigLayer::default()
+            })
+            .unwrap_err()
+            .0
+        };
+        assert_eq!(
+            problems("no hex!!", Some("publisher-1")),
+            vec!["message_signing_key: not hex: Invalid character 'n' at position 0"]
+        );
+        assert_eq!(
+            problems(&"03".repeat(32), None),
+            vec!["message_signing_key_id: must name the key, so consumers can pick theirs"]
+        );
+        assert_eq!(
+            problems(&"03".repeat(16), Some("publisher-1")),
+            vec!["message_signing_key: must be a 32 byte seed for Ed25519, not 16"]
+        );
+    }
+
+    #[test]
+    fn audit_database_url_is_validated_on_load() {
+        let problems = |url: &str| {
+            load(ConfigLayer {
+                audit_database_url: Some(url.to_string()),
+                ..ConfigLayer::default()
+            })
+            .map(|config| config.audit_database_url)
+            .map_err(|e| e.0)
+        };
+        assert_eq!(
+            problems("mysql://db/audit"),
+            Err(vec![
+                "audit_database_url: must start with postgres:// or postgresql://".to_string()
+            ])
+        );
+        assert_eq!(
+            problems("not a url"),
+            Err(vec![
+                "audit_database_url: relative URL without a base".to_string()
+            ])
+        );
+        if cfg!(feature = "audit") {
+            assert!(problems("postgres://audit@db/audit?sslmode=sometimes").is_err());
+            assert_eq!(
+                problems("postgres://audit@db/audit"),
+                Ok(Some("postgres://audit@db/audit".to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn timeout_fallback_queues_are_validated() {
+        let problems = load(ConfigLayer {
+            timeout_fallback_queues: Some(HashMap::from([
+                ("songlinks".to_string(), "SlowMusic".to_string()),
+                ("/help".to_string(), "slow help".to_string()),
+            ])),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(problems.len(), 2);
+        assert!(problems
+            .contains(&"timeout_fallback_queues: \"songlinks\" is not a /command".to_string()));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("timeout_fallback_queues.\"/help\": ")));
+
+        let config = load(ConfigLayer {
+            timeout_fallback_queues: Some(HashMap::from([(
+                "/songlinks".to_string(),
+                "SlowMusic".to_string(),
+            )])),
+            ..ConfigLayer::default()
+        })
+        .unwrap();
+        assert_eq!(
+            config.timeout_fallback_queues,
+            HashMap::from([("/songlinks".to_string(), "SlowMusic".to_string())])
+        );
+    }
+
+    #[test]
+    fn layers_override_defaults_then_file_then_env_then_flags() {
+        let file = env::temp_dir().join(format!("config-layers-{}.toml", std::process::id()));
+        fs::write(
+            &file,
+            "flood_max_messages = 5\nflood_window_secs = 20\nflood_mute_secs = 30\n",
+        )
+        .unwrap();
+        env::set_var("FLOOD_WINDOW_SECS", "21");
+        env::set_var("FLOOD_MUTE_SECS", "31");
+        let config = Config::load(ConfigArgs {
+            config: Some(file.clone()),
+            overrides: ConfigLayer {
+                rabbit_address: Some("amqp://localhost:5672".to_string()),
+                flood_mute_secs: Some(32),
+                ..ConfigLayer::default()
+            },
+        });
+        env::remove_var("FLOOD_WINDOW_SECS");
+        env::remove_var("FLOOD_MUTE_SECS");
+        fs::remove_file(&file).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(
+            config.flood,
+            FloodConfig {
+                max_messages: 5,
+                window: Duration::from_secs(21),
+                mute_for: Duration::from_secs(32),
+            }
+        );
+        assert_eq!(
+            config.circuit_breaker.open_for,
+            CircuitBreakerConfig::default().open_for
+        );
+    }
+
+    #[test]
+    fn breaker_flood_and_cooldown_settings_are_validated() {
+        let problems = load(ConfigLayer {
+            circuit_failure_rate: Some(1.5),
+            circuit_min_requests: Some(0),
+            flood_window_secs: Some(0),
+            cooldowns: Some(HashMap::from([("readimage".to_string(), 30)])),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(
+            problems,
+            vec![
+                "circuit_min_requests: must be greater than zero",
+                "flood_window_secs: must be greater than zero",
+                "circuit_failure_rate: must be greater than 0 and at most 1",
+                "cooldowns: \"readimage\" is not a /command",
+            ]
+        );
+
+        let config = load(ConfigLayer {
+            cooldowns: Some(HashMap::from([("/readimage".to_string(), 30)])),
+            ..ConfigLayer::default()
+        })
+        .unwrap();
+        assert_eq!(
+            config.cooldowns,
+            HashMap::from([("/readimage".to_string(), Duration::from_secs(30))])
+        );
+    }
+
+    #[test]
+    fn tls_settings_are_validated() {
+        let problems = load(ConfigLayer {
+            tls_cert_path: Some("cert.pem".to_string()),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(
+            problems,
+            vec!["tls_cert_path and tls_key_path: must be set together"]
+        );
+
+        let problems = load(ConfigLayer {
+            tls_self_signed_domain: Some("bot.example.com".to_string()),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(
+            problems,
+            vec!["tls_self_signed_domain: needs tls_cert_path and tls_key_path"]
+        );
+
+        let problems = load(ConfigLayer {
+            amqp_ca_bundle: Some("ca.pem".to_string()),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(
+            problems,
+            vec![
+                "rabbit_address: AMQP TLS settings are configured but the address does not use \
+                 amqps://"
+            ]
+        );
+    }
+
+    #[test]
+    fn hmac_settings_build_verifiers_and_are_validated() {
+        let settings = |secret: Option<&str>, header: Option<&str>| HmacSettings {
+            secret: secret.map(str::to_string),
+            header: header.map(str::to_string),
+        };
+        let config = load(ConfigLayer {
+            hmac: Some(HashMap::from([
+                ("GitHub".to_string(), settings(Some("s1"), None)),
+                (
+                    "rpc".to_string(),
+                    settings(Some("s2"), Some("X-Rpc-Signature")),
+                ),
+                ("admin".to_string(), settings(None, None)),
+            ])),
+            ws_allowed_queues: Some("Events, Alerts".to_string()),
+            ..ConfigLayer::default()
+        })
+        .unwrap();
+        let header = |name: &str| {
+            config
+                .hmac_verifier(name)
+                .map(|verifier| verifier.header_name().to_string())
+        };
+        assert_eq!(
+            header(github::SOURCE).as_deref(),
+            Some(github::SIGNATURE_HEADER)
+        );
+        assert_eq!(header(rpc::SOURCE).as_deref(), Some("X-Rpc-Signature"));
+        assert_eq!(header(admin::SOURCE), None);
+        assert_eq!(
+            config.ws_allowed_queues,
+            Some(HashSet::from(["Events".to_string(), "Alerts".to_string()]))
+        );
+
+        let mut problems = load(ConfigLayer {
+            hmac: Some(HashMap::from([
+                ("stripe".to_string(), settings(Some("s1"), None)),
+                ("ws".to_string(), settings(None, Some("X-Ws-Signature"))),
+                (
+                    "admin".to_string(),
+                    settings(Some("s2"), Some("bad header")),
+                ),
+            ])),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        problems.sort();
+        assert_eq!(
+            problems,
+            vec![
+                "hmac.admin.header: \"bad header\" is not a header name",
+                "hmac.stripe: nothing is signed under this name, expected one of webhook, \
+                 github, rpc, ws, admin",
+                "hmac.ws.header: set without a secret",
+            ]
+        );
+    }
+
+    #[test]
+    fn bot_settings_fall_back_to_shared_ones_and_are_validated() {
+        let file = env::temp_dir().join(format!("config-bots-{}.toml", std::process::id()));
+        fs::write(
+            &file,
+            "bot_ids = \"music, news\"\n\
+             [bot_defaults]\nack_text = \"On it\"\nsonglinks_max_lines = 5\n\
+             [bots.music]\nqueue_prefix = \"music.\"\nsonglinks_max_lines = 3\n",
+        )
+        .unwrap();
+        env::set_var("BOT_MUSIC_SONGLINKS_MAX_LINES", "4");
+        env::set_var("BOT_MUSIC_USERNAME", "@music_bot");
+        let config = Config::load(ConfigArgs {
+            config: Some(file.clone()),
+            overrides: ConfigLayer {
+                rabbit_address: Some("amqp://localhost:5672".to_string()),
+                ..ConfigLayer::default()
+            },
+        });
+        env::remove_var("BOT_MUSIC_SONGLINKS_MAX_LINES");
+        env::remove_var("BOT_MUSIC_USERNAME");
+        fs::remove_file(&file).unwrap();
+
+        let mut bots = config.unwrap().bots;
+        bots.sort_by(|a, b| a.id.cmp(&b.id));
+        let [music, news] = &bots[..] else {
+            panic!("expected two bots, got {:?}", bots);
+        };
+        assert_eq!(music.queue_prefix, "music.");
+        assert_eq!(music.songlinks_limits.max_lines, 4);
+        assert_eq!(music.username.as_deref(), Some("music_bot"));
+        assert_eq!(music.acknowledgment_text.as_deref(), Some("On it"));
+        assert_eq!(news.queue_prefix, "");
+        assert_eq!(news.songlinks_limits.max_lines, 5);
+
+        let problems = load(ConfigLayer {
+            bot_ids: Some(" , ".to_string()),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(problems, vec!["bot_ids: must name at least one bot"]);
+
+        let problems = load(ConfigLayer {
+            bots: Some(HashMap::from([(
+                "default".to_string(),
+                BotOverrides {
+                    songlinks_max_chars: Some(0),
+                    ..BotOverrides::default()
+                },
+            )])),
+            ..ConfigLayer::default()
+        })
+        .unwrap_err()
+        .0;
+        assert_eq!(
+            problems,
+            vec!["bots.default.songlinks_max_chars: must be greater than zero"]
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    shared_state::{InMemorySharedState, SharedState},
+    tiers::Tier,
+};
+
+// Minimum time between two uses of the same command by the same user
+pub struct CommandCooldowns {
+    durations: HashMap<String, Duration>,
+    // Holds a claim per user and command until they may use it again
+    state: Arc<dyn SharedState>,
+}
+
+impl CommandCooldowns {
+    // A single cooldown, for throttling something other than a user's commands
+    pub fn single(key: &str, duration: Duration) -> Self {
+        Self::new([(key.to_string(), duration)].into())
+    }
+
+    pub fn new(durations: HashMap<String, Duration>) -> Self {
+        Self {
+            durations,
+            state: Arc::new(InMemorySharedState::default()),
+        }
+    }
+
+    // Shares the cooldowns with other replicas
+    pub fn with_state(mut self, state: Arc<dyn SharedState>) -> Self {
+        self.state = state;
+        self
+    }
+
+    // Records the use and returns Ok, or returns how long the user still has to wait. The
+    // user's tier, if any, replaces the command's cooldown.
+    pub async fn check(
+        &self,
+        bot_id: &str,
+        user_id: i64,
+        command: &str,
+        tier: Option<&Tier>,
+    ) -> Result<(), Duration> {
+        let cooldown = tier
+            .and_then(|tier| tier.cooldown(command))
+            .or_else(|| self.durations.get(command).copied());
+        let Some(cooldown) = cooldown.filter(|cooldown| !cooldown.is_zero()) else {
+            return Ok(());
+        };
+        let key = format!("cooldown:{}:{}:{}", bot_id, user_id, command);
+        self.state.claim(&key, cooldown).await
+    }
+}
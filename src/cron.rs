@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Datelike, Days, TimeDelta, Timelike, Utc};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    bots::{BotRegistry, DEFAULT_BOT_ID},
+    metrics::Metrics,
+    publisher::{MessagePublisher, RabbitMessage},
+};
+
+pub const EVENT: &str = "cron";
+
+// One [cron.<name>] table of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CronJobConfig {
+    // Five fields (minute hour day-of-month month day-of-week, in UTC) or @hourly, @daily, ...
+    pub schedule: String,
+    // Queue name before the bot's prefix, e.g. "Music"
+    pub queue: String,
+    pub text: String,
+    pub bot: Option<String>,
+    // A disabled job keeps its schedule, but every run is counted as skipped
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+// A parsed cron expression; each field is a bit set of the values it matches
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Restricted day-of-month and day-of-week fields match when either does, as in cron
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(spec: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step in {:?}", part)),
+            },
+            None => (part, 1),
+        };
+        let value = |text: &str| match text.parse::<u32>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!("{:?} is not between {} and {}", text, min, max)),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // "5/15" means from 5 to the end, every 15
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if first > last {
+            return Err(format!("{:?} is an empty range", range));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let spec = match spec.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            spec => spec,
+        };
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields, got {}", fields.len()));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Schedule {
+    fn matches_day(&self, time: &DateTime<Utc>) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.months & (1 << time.month()) != 0
+    }
+
+    // The first minute strictly after `after` that matches, within the next five years
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let after = after.with_second(0)?.with_nanosecond(0)?;
+        let mut time = after + TimeDelta::minutes(1);
+        let limit = after.checked_add_days(Days::new(5 * 366))?;
+        while time < limit {
+            if !self.matches_day(&time) {
+                time = time
+                    .checked_add_days(Days::new(1))?
+                    .with_hour(0)?
+                    .with_minute(0)?;
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.with_minute(0)? + TimeDelta::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += TimeDelta::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CronJob {
+    pub name: String,
+    pub schedule: Schedule,
+    // As written in the config file
+    pub schedule_spec: String,
+    pub bot_id: String,
+    pub queue: String,
+    pub text: String,
+    pub enabled: bool,
+}
+
+// Every job of the config file, checked once at startup
+#[derive(Debug, Clone, Default)]
+pub struct CronJobs {
+    jobs: Vec<CronJob>,
+}
+
+impl CronJobs {
+    pub fn new(configs: HashMap<String, CronJobConfig>) -> Result<Self, Vec<String>> {
+        let mut jobs = Vec::new();
+        let mut problems = Vec::new();
+        for (name, config) in configs {
+            let schedule = match config.schedule.parse::<Schedule>() {
+                Ok(schedule) => schedule,
+                Err(e) => {
+                    problems.push(format!("cron.{}.schedule: {}", name, e));
+                    continue;
+                }
+            };
+            if config.queue.trim().is_empty() {
+                problems.push(format!("cron.{}.queue: must not be empty", name));
+                continue;
+            }
+            jobs.push(CronJob {
+                name,
+                schedule,
+                schedule_spec: config.schedule,
+                bot_id: config.bot.unwrap_or_else(|| DEFAULT_BOT_ID.to_string()),
+                queue: config.queue,
+                text: config.text,
+                enabled: config.enabled,
+            });
+        }
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+        if problems.is_empty() {
+            Ok(Self { jobs })
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CronJob> {
+        self.jobs.iter()
+    }
+
+    // One task per job; runs missed while the process was down are not caught up
+    pub fn spawn(
+        &self,
+        publisher: Arc<dyn MessagePublisher>,
+        bots: Arc<BotRegistry>,
+        metrics: Arc<Metrics>,
+    ) {
+        for job in self.jobs.iter().cloned() {
+            let Some(bot) = bots.get(&job.bot_id) else {
+                warn!(
+                    "Cron job '{}' belongs to unknown bot '{}', not scheduling it.",
+                    job.name, job.bot_id
+                );
+                continue;
+            };
+            let queue = bot.queue_name(&job.queue);
+            let publisher = Arc::clone(&publisher);
+            let metrics = Arc::clone(&metrics);
+            tokio::spawn(async move {
+                let now = || DateTime::<Utc>::from(SystemTime::now());
+                while let Some(next) = job.schedule.next_after(now()) {
+                    let wait = (next - now()).to_std().unwrap_or(Duration::ZERO);
+                    tokio::time::sleep(wait).await;
+                    if !job.enabled {
+                        metrics.record_cron(&job.name, false);
+                        continue;
+                    }
+                    let message =
+                        RabbitMessage::bot(&job.bot_id, job.text.as_str()).with_event(EVENT);
+                    match publisher.publish(&queue, &message).await {
+                        Ok(()) => {
+                            info!("Cron job '{}' published to {}.", job.name, queue);
+                            metrics.record_cron(&job.name, true);
+                        }
+                        Err(e) => {
+                            warn!("Cron job '{}' skipped a run: {}", job.name, e);
+                            metrics.record_cron(&job.name, false);
+                        }
+                    }
+                }
+                warn!("Cron job '{}' will never run again.", job.name);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn next_run_follows_the_expression() {
+        let nightly: Schedule = "0 3 * * *".parse().unwrap();
+        assert_eq!(
+            nightly.next_after(at(2024, 5, 10, 3, 0)),
+            Some(at(2024, 5, 11, 3, 0))
+        );
+
+        let quarter: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // Saturday evening -> Monday morning
+        assert_eq!(
+            quarter.next_after(at(2024, 5, 11, 18, 7)),
+            Some(at(2024, 5, 13, 9, 0))
+        );
+        assert_eq!(
+            quarter.next_after(at(2024, 5, 13, 9, 1)),
+            Some(at(2024, 5, 13, 9, 15))
+        );
+
+        let leap: Schedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            leap.next_after(at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+    }
+
+    #[test]
+    fn invalid_jobs_are_reported() {
+        let job = |schedule: &str| CronJobConfig {
+            schedule: schedule.to_string(),
+            queue: "Music".to_string(),
+            text: "digest".to_string(),
+            bot: None,
+            enabled: true,
+        };
+        let configs = [
+            ("digest".to_string(), job("@daily")),
+            ("broken".to_string(), job("60 * * * *")),
+            ("short".to_string(), job("* * *")),
+        ]
+        .into();
+
+        let problems = CronJobs::new(configs).unwrap_err();
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().any(|p| p.starts_with("cron.broken")));
+    }
+}
@@ -0,0 +1,11 @@
+use async_trait::async_trait;
+
+// The event of the message /deleteme publishes to the Deletion queue
+pub const DELETION_REQUEST_EVENT: &str = "deletion_request";
+
+// Records this service keeps about users, which /deleteme purges along with publishing the request
+#[async_trait]
+pub trait UserRecords: Send + Sync {
+    // How many records were deleted
+    async fn purge_user(&self, bot_id: &str, user_id: i64) -> Result<u64, String>;
+}
@@ -0,0 +1,289 @@
+use std::{fmt, str::FromStr};
+
+use crate::publisher::{PublishError, RabbitMessage};
+
+// How RabbitMessage is serialized onto the queue; consumers tell them apart by content type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadEncoding {
+    #[default]
+    Json,
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl FromStr for PayloadEncoding {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(PayloadEncoding::Json),
+            #[cfg(feature = "protobuf")]
+            "protobuf" => Ok(PayloadEncoding::Protobuf),
+            #[cfg(not(feature = "protobuf"))]
+            "protobuf" => Err("protobuf encoding needs the \"protobuf\" cargo feature".to_string()),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Ok(PayloadEncoding::MessagePack),
+            #[cfg(not(feature = "msgpack"))]
+            "msgpack" => Err("msgpack encoding needs the \"msgpack\" cargo feature".to_string()),
+            other => Err(format!("unknown payload encoding {:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for PayloadEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadEncoding::Json => write!(f, "json"),
+            #[cfg(feature = "protobuf")]
+            PayloadEncoding::Protobuf => write!(f, "protobuf"),
+            #[cfg(feature = "msgpack")]
+            PayloadEncoding::MessagePack => write!(f, "msgpack"),
+        }
+    }
+}
+
+impl PayloadEncoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadEncoding::Json => "application/json",
+            #[cfg(feature = "protobuf")]
+            PayloadEncoding::Protobuf => "application/x-protobuf",
+            #[cfg(feature = "msgpack")]
+            PayloadEncoding::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn encode(&self, message: &RabbitMessage) -> Result<Vec<u8>, PublishError> {
+        match self {
+            PayloadEncoding::Json => {
+                serde_json::to_vec(message).map_err(|e| PublishError::Serialization(e.to_string()))
+            }
+            #[cfg(feature = "protobuf")]
+            PayloadEncoding::Protobuf => {
+                use prost::Message;
+                Ok(proto::QueueMessage::from(message).encode_to_vec())
+            }
+            // Maps keyed by field name, so consumers see the same shape as the JSON payload
+            #[cfg(feature = "msgpack")]
+            PayloadEncoding::MessagePack => rmp_serde::to_vec_named(message)
+                .map_err(|e| PublishError::Serialization(e.to_string())),
+        }
+    }
+}
+
+// The content encoding of a payload gzipped by `compress`
+pub const GZIP: &str = "gzip";
+
+// Gzips payloads of at least `over_bytes`; returns whether it did
+pub fn compress(
+    payload: Vec<u8>,
+    over_bytes: Option<usize>,
+) -> Result<(Vec<u8>, bool), PublishError> {
+    match over_bytes {
+        Some(over_bytes) if payload.len() >= over_bytes => {
+            gzip(&payload).map(|payload| (payload, true))
+        }
+        _ => Ok((payload, false)),
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip(payload: &[u8]) -> Result<Vec<u8>, PublishError> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(payload)
+        .and_then(|()| encoder.finish())
+        .map_err(|e| PublishError::Serialization(e.to_string()))
+}
+
+// Queue settings asking for compression are refused without the feature
+#[cfg(not(feature = "gzip"))]
+fn gzip(_payload: &[u8]) -> Result<Vec<u8>, PublishError> {
+    Err(PublishError::Serialization(
+        "compression needs the \"gzip\" cargo feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "protobuf")]
+pub mod proto {
+    use crate::{
+        publisher::{RabbitMessage, ReplyContext as Reply},
+        settings::ChatSettings as Settings,
+    };
+
+    // Mirrors QueueMessage in proto/message.proto
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QueueMessage {
+        #[prost(uint32, tag = "1")]
+        pub schema_version: u32,
+        #[prost(string, tag = "2")]
+        pub source: String,
+        #[prost(string, optional, tag = "3")]
+        pub bot_id: Option<String>,
+        #[prost(int64, optional, tag = "4")]
+        pub chat_id: Option<i64>,
+        #[prost(string, tag = "5")]
+        pub text: String,
+        #[prost(string, optional, tag = "6")]
+        pub event: Option<String>,
+        #[prost(string, optional, tag = "7")]
+        pub data_json: Option<String>,
+        #[prost(message, optional, tag = "8")]
+        pub reply_to: Option<ReplyContext>,
+        #[prost(int64, optional, tag = "9")]
+        pub from_id: Option<i64>,
+        #[prost(string, optional, tag = "10")]
+        pub from_username: Option<String>,
+        #[prost(int64, optional, tag = "11")]
+        pub message_id: Option<i64>,
+        #[prost(int64, optional, tag = "12")]
+        pub date: Option<i64>,
+        #[prost(string, optional, tag = "13")]
+        pub language_code: Option<String>,
+        #[prost(string, optional, tag = "14")]
+        pub command: Option<String>,
+        #[prost(message, optional, tag = "15")]
+        pub settings: Option<ChatSettings>,
+        #[prost(string, optional, tag = "16")]
+        pub command_language: Option<String>,
+    }
+
+    // Mirrors ReplyContext in proto/message.proto
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReplyContext {
+        #[prost(int64, tag = "1")]
+        pub message_id: i64,
+        #[prost(string, optional, tag = "2")]
+        pub text: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub caption: Option<String>,
+        #[prost(string, repeated, tag = "4")]
+        pub photo_file_ids: Vec<String>,
+    }
+
+    // Mirrors ChatSettings in proto/message.proto
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ChatSettings {
+        #[prost(string, optional, tag = "1")]
+        pub language: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub verbosity: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub song_format: Option<String>,
+    }
+
+    impl From<&Settings> for ChatSettings {
+        fn from(settings: &Settings) -> Self {
+            Self {
+                language: settings.language.clone(),
+                verbosity: settings.verbosity.clone(),
+                song_format: settings.song_format.clone(),
+            }
+        }
+    }
+
+    impl From<&Reply> for ReplyContext {
+        fn from(reply: &Reply) -> Self {
+            Self {
+                message_id: reply.message_id,
+                text: reply.text.clone(),
+                caption: reply.caption.clone(),
+                photo_file_ids: reply.photo_file_ids.clone(),
+            }
+        }
+    }
+
+    impl From<&RabbitMessage> for QueueMessage {
+        fn from(message: &RabbitMessage) -> Self {
+            let metadata = &message.metadata;
+            Self {
+                schema_version: message.schema_version,
+                source: message.source.clone(),
+                bot_id: message.bot_id.clone(),
+                chat_id: message.chat_id,
+                text: message.text.clone(),
+                event: message.event.clone(),
+                data_json: message.data.as_ref().map(|data| data.to_string()),
+                reply_to: message.reply_to.as_ref().map(ReplyContext::from),
+                from_id: metadata.from_id,
+                from_username: metadata.from_username.clone(),
+                message_id: metadata.message_id,
+                date: metadata.date,
+                language_code: metadata.language_code.clone(),
+                command: metadata.command.clone(),
+                settings: message.settings.as_ref().map(ChatSettings::from),
+                command_language: metadata.command_language.clone(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_encoding_names() {
+        assert_eq!("json".parse(), Ok(PayloadEncoding::Json));
+        assert!("xml".parse::<PayloadEncoding>().is_err());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn only_payloads_over_the_threshold_are_gzipped() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let payload = b"Bohemian Rhapsody ".repeat(20);
+        assert_eq!(
+            compress(payload.clone(), None).unwrap(),
+            (payload.clone(), false)
+        );
+        assert!(!compress(payload.clone(), Some(1024)).unwrap().1);
+
+        let (compressed, gzipped) = compress(payload.clone(), Some(256)).unwrap();
+        assert!(gzipped && compressed.len() < payload.len());
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_round_trips_through_the_schema() {
+        use prost::Message;
+        use serde_json::json;
+
+        let message = RabbitMessage::chat("default", 42, "file-1")
+            .with_event("sticker")
+            .with_data(json!({ "emoji": "🎸" }));
+        let bytes = PayloadEncoding::Protobuf.encode(&message).unwrap();
+
+        let decoded = proto::QueueMessage::decode(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.chat_id, Some(42));
+        assert_eq!(decoded.text, "file-1");
+        assert_eq!(decoded.event.as_deref(), Some("sticker"));
+        assert_eq!(decoded.data_json.as_deref(), Some(r#"{"emoji":"🎸"}"#));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_keeps_the_json_shape() {
+        use serde_json::{json, Value};
+
+        let message = RabbitMessage::chat("default", 42, "file-1")
+            .with_event("sticker")
+            .with_data(json!({ "emoji": "🎸" }));
+        let bytes = PayloadEncoding::MessagePack.encode(&message).unwrap();
+
+        let decoded: Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, serde_json::to_value(&message).unwrap());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+
+#[cfg(feature = "encryption")]
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key,
+};
+
+use crate::publisher::PublishError;
+
+// Message headers telling consumers how the body was encrypted and with which of their keys
+pub const ALGORITHM_HEADER: &str = "x-encryption";
+pub const KEY_ID_HEADER: &str = "x-encryption-key-id";
+pub const ALGORITHM: &str = "AES-256-GCM";
+
+const KEY_BYTES: usize = 32;
+#[cfg(feature = "encryption")]
+const NONCE_BYTES: usize = 12;
+
+// Encrypts published bodies so only consumers holding the key can read users' text
+pub struct PayloadCipher {
+    key_id: String,
+    #[cfg(feature = "encryption")]
+    cipher: Aes256Gcm,
+}
+
+// Names the key only, so printing the config never shows it
+impl fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadCipher")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PayloadCipher {
+    pub fn new(key_id: &str, key: &[u8]) -> Result<Self, String> {
+        if key.len() != KEY_BYTES {
+            return Err(format!(
+                "payload_encryption_key: must be {} bytes for {}, not {}",
+                KEY_BYTES,
+                ALGORITHM,
+                key.len()
+            ));
+        }
+        #[cfg(feature = "encryption")]
+        return Ok(Self {
+            key_id: key_id.to_string(),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        });
+        #[cfg(not(feature = "encryption"))]
+        {
+            let _ = key_id;
+            Err("payload_encryption_key: encryption needs the \"encryption\" cargo feature".into())
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    // A fresh random nonce, then the ciphertext with its authentication tag
+    #[cfg(feature = "encryption")]
+    pub fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, PublishError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|e| PublishError::Serialization(format!("encryption failed: {}", e)))?;
+        let mut sealed = Vec::with_capacity(NONCE_BYTES + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // Never constructed without the feature
+    #[cfg(not(feature = "encryption"))]
+    pub fn encrypt(&self, _payload: &[u8]) -> Result<Vec<u8>, PublishError> {
+        unreachable!("PayloadCipher::new fails without the \"encryption\" feature")
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+    use aes_gcm::Nonce;
+
+    #[test]
+    fn bodies_decrypt_with_the_key_only() {
+        let key = [7u8; KEY_BYTES];
+        let cipher = PayloadCipher::new("2026-10", &key).unwrap();
+
+        let sealed = cipher.encrypt(b"Bohemian Rhapsody").unwrap();
+        let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+        let consumer = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        assert_eq!(
+            consumer
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .unwrap(),
+            b"Bohemian Rhapsody"
+        );
+        let other = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[8u8; KEY_BYTES]));
+        assert!(other.decrypt(Nonce::from_slice(nonce), ciphertext).is_err());
+        // Nonces are never reused
+        assert_ne!(cipher.encrypt(b"Bohemian Rhapsody").unwrap(), sealed);
+        assert!(PayloadCipher::new("short", &key[..16]).is_err());
+    }
+}
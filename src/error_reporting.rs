@@ -0,0 +1,37 @@
+use std::env;
+
+use log::info;
+
+// Starts the Sentry client when SENTRY_DSN is set; the guard flushes pending events on drop
+pub fn init() -> Option<sentry::ClientInitGuard> {
+    let dsn = env::var("SENTRY_DSN").ok().filter(|dsn| !dsn.is_empty())?;
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: env::var("SENTRY_ENVIRONMENT").ok().map(Into::into),
+            ..Default::default()
+        },
+    ));
+    info!("Reporting errors to Sentry.");
+    Some(guard)
+}
+
+// Reports a failed publish with enough context to find the affected chat and command
+pub fn capture_publish_failure(
+    bot_id: &str,
+    chat_id: Option<i64>,
+    command: Option<&str>,
+    queue: &str,
+    error: &str,
+) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("bot_id", bot_id);
+            scope.set_tag("queue", queue);
+            scope.set_tag("command", command.unwrap_or("none"));
+            scope.set_extra("chat_id", chat_id.into());
+        },
+        || sentry::capture_message(error, sentry::Level::Error),
+    );
+}
@@ -0,0 +1,104 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{error, info};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::publisher::PublishError;
+
+// Error returned to webhook callers as {"error": ..., "message": ..., "field": ...}
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    // Stable machine-readable category, e.g. "missing_field"
+    #[schema(value_type = String, example = "missing_field")]
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, example = "message.chat.id")]
+    pub field: Option<&'static str>,
+}
+
+impl WebhookError {
+    pub fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error,
+            message: message.into(),
+            field: None,
+        }
+    }
+
+    pub fn with_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    pub fn unknown_bot(bot_id: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "unknown_bot",
+            format!("no bot is configured with id '{}'", bot_id),
+        )
+    }
+
+    pub fn unauthorized(reason: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", reason)
+    }
+
+    pub fn forbidden(reason: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", reason)
+    }
+
+    pub fn invalid_json(reason: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_json", reason)
+    }
+
+    pub fn missing_field(field: &'static str) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "missing_field",
+            format!("{} is missing or has the wrong type", field),
+        )
+        .with_field(field)
+    }
+
+    pub fn payload_too_large(limit: usize) -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            format!("request body exceeds {} bytes", limit),
+        )
+    }
+
+    pub fn broker_unavailable() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "broker_unavailable",
+            "not connected to RabbitMQ yet",
+        )
+    }
+
+    pub fn publish_failed(queue: &str, err: &PublishError) -> Self {
+        Self::new(
+            err.status_code(),
+            "publish_failed",
+            format!("could not publish to {}: {}", queue, err),
+        )
+    }
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            error!("Webhook failed [{}]: {}", self.error, self.message);
+        } else {
+            info!("Webhook rejected [{}]: {}", self.error, self.message);
+        }
+        (self.status, Json(self)).into_response()
+    }
+}
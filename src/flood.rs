@@ -0,0 +1,73 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::shared_state::{InMemorySharedState, SharedState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloodConfig {
+    // More than this many messages within `window` mutes the chat
+    pub max_messages: usize,
+    pub window: Duration,
+    pub mute_for: Duration,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 20,
+            window: Duration::from_secs(10),
+            mute_for: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloodVerdict {
+    Allow,
+    // The chat crossed the threshold with this message; warn the user once
+    JustMuted,
+    // The chat is still muted; drop the message silently
+    Muted,
+}
+
+// Tracks message frequency per chat and temporarily ignores chats that burst past the limit
+pub struct FloodGuard {
+    config: FloodConfig,
+    // Each chat's recent messages, and a claim on muted chats until the mute is over
+    state: Arc<dyn SharedState>,
+}
+
+impl FloodGuard {
+    pub fn new(config: FloodConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(InMemorySharedState::default()),
+        }
+    }
+
+    // Shares message counts and mutes with other replicas
+    pub fn with_state(mut self, state: Arc<dyn SharedState>) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn mute_duration(&self) -> Duration {
+        self.config.mute_for
+    }
+
+    pub async fn observe(&self, bot_id: &str, chat_id: i64) -> FloodVerdict {
+        let muted = format!("flood_muted:{}:{}", bot_id, chat_id);
+        if self.state.is_claimed(&muted).await {
+            return FloodVerdict::Muted;
+        }
+        let recent = format!("flood:{}:{}", bot_id, chat_id);
+        if self.state.hit(&recent, self.config.window).await > self.config.max_messages {
+            self.state.reset(&recent).await;
+            // Another replica may have muted the chat in the meantime; only one warns the user
+            return match self.state.claim(&muted, self.config.mute_for).await {
+                Ok(()) => FloodVerdict::JustMuted,
+                Err(_) => FloodVerdict::Muted,
+            };
+        }
+        FloodVerdict::Allow
+    }
+}
@@ -0,0 +1,126 @@
+use std::{collections::HashSet, env, net::SocketAddr, sync::Arc};
+
+use log::{error, info};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::publisher::{validate_destination, MessagePublisher, PublishError, RabbitMessage};
+
+include!(concat!(
+    env!("OUT_DIR"),
+    "/rustin_bot_publisher.v1.Publisher.rs"
+));
+
+use publisher_server::{Publisher, PublisherServer};
+
+// Mirrors PublishRequest in proto/publisher.proto
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishRequest {
+    #[prost(string, tag = "1")]
+    pub destination: String,
+    #[prost(string, tag = "2")]
+    pub source: String,
+    #[prost(int64, optional, tag = "3")]
+    pub chat_id: Option<i64>,
+    #[prost(string, tag = "4")]
+    pub text: String,
+    #[prost(string, optional, tag = "5")]
+    pub event: Option<String>,
+}
+
+// Mirrors PublishResponse in proto/publisher.proto
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishResponse {
+    #[prost(bool, tag = "1")]
+    pub accepted: bool,
+}
+
+pub struct GrpcPublisher {
+    publisher: Arc<dyn MessagePublisher>,
+    // None allows publishing to any valid destination
+    allowed_destinations: Option<HashSet<String>>,
+}
+
+impl GrpcPublisher {
+    // GRPC_ALLOWED_QUEUES restricts which queues internal producers may target
+    pub fn from_env(publisher: Arc<dyn MessagePublisher>) -> Self {
+        let allowed_destinations = env::var("GRPC_ALLOWED_QUEUES").ok().map(|queues| {
+            queues
+                .split(',')
+                .map(|q| q.trim().to_string())
+                .filter(|q| !q.is_empty())
+                .collect()
+        });
+        Self {
+            publisher,
+            allowed_destinations,
+        }
+    }
+
+    // tonic::Status is large, but it is what every handler returns anyway
+    #[allow(clippy::result_large_err)]
+    fn validate(&self, request: &PublishRequest) -> Result<(), Status> {
+        validate_destination(&request.destination)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(allowed) = &self.allowed_destinations {
+            if !allowed.contains(&request.destination) {
+                return Err(Status::permission_denied(format!(
+                    "destination {} is not allowed",
+                    request.destination
+                )));
+            }
+        }
+        if request.source.is_empty() {
+            return Err(Status::invalid_argument("source must be set"));
+        }
+        if request.text.is_empty() {
+            return Err(Status::invalid_argument("text must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Publisher for GrpcPublisher {
+    async fn publish_message(
+        &self,
+        request: Request<PublishRequest>,
+    ) -> Result<Response<PublishResponse>, Status> {
+        let request = request.into_inner();
+        self.validate(&request)?;
+
+        let message = RabbitMessage {
+            source: request.source,
+            chat_id: request.chat_id,
+            text: request.text,
+            event: request.event,
+            ..RabbitMessage::default()
+        };
+        match self.publisher.publish(&request.destination, &message).await {
+            Ok(()) => {
+                info!("Published gRPC message to {} queue.", request.destination);
+                Ok(Response::new(PublishResponse { accepted: true }))
+            }
+            Err(PublishError::InvalidDestination(name)) => Err(Status::invalid_argument(format!(
+                "invalid destination {}",
+                name
+            ))),
+            Err(PublishError::Unroutable(name)) => {
+                Err(Status::not_found(format!("no queue is bound to {}", name)))
+            }
+            Err(e) => {
+                error!("Publishing to {} failed: {}", request.destination, e);
+                Err(Status::unavailable(e.to_string()))
+            }
+        }
+    }
+}
+
+// Serves the gRPC endpoint until the process exits
+pub async fn serve(address: SocketAddr, publisher: Arc<dyn MessagePublisher>) {
+    info!("gRPC listening on {}", address);
+    Server::builder()
+        .add_service(PublisherServer::new(GrpcPublisher::from_env(publisher)))
+        .serve(address)
+        .await
+        .expect("Error serving gRPC endpoint");
+}
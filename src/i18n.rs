@@ -0,0 +1,84 @@
+use std::{collections::HashMap, env};
+
+use log::warn;
+
+pub const FALLBACK_LANGUAGE: &str = "en";
+
+// Translations compiled into the binary, one JSON object of key -> text per language
+const BUNDLED: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.json")),
+    ("ro", include_str!("../locales/ro.json")),
+    ("ru", include_str!("../locales/ru.json")),
+];
+
+pub struct Translations {
+    catalogs: HashMap<String, HashMap<String, String>>,
+    fallback: String,
+}
+
+impl Translations {
+    pub fn new(catalogs: HashMap<String, HashMap<String, String>>, fallback: &str) -> Self {
+        Self {
+            catalogs,
+            fallback: fallback.to_string(),
+        }
+    }
+
+    // Loads the bundled catalogs; DEFAULT_LANGUAGE picks the fallback for unknown languages
+    pub fn bundled() -> Self {
+        let catalogs = BUNDLED
+            .iter()
+            .map(|(language, json)| {
+                let catalog = serde_json::from_str(json)
+                    .unwrap_or_else(|e| panic!("Invalid bundled locale {}: {}", language, e));
+                (language.to_string(), catalog)
+            })
+            .collect();
+        let fallback =
+            env::var("DEFAULT_LANGUAGE").unwrap_or_else(|_| FALLBACK_LANGUAGE.to_string());
+        Self::new(catalogs, &fallback)
+    }
+
+    // The languages replies can be translated to, sorted
+    pub fn languages(&self) -> Vec<&str> {
+        let mut languages: Vec<&str> = self.catalogs.keys().map(String::as_str).collect();
+        languages.sort();
+        languages
+    }
+
+    // Resolves "pt-br" to "pt-br", then "pt", then the fallback language
+    pub fn resolve_language(&self, language_code: Option<&str>) -> &str {
+        if let Some(code) = language_code.map(str::to_lowercase) {
+            if let Some((language, _)) = self.catalogs.get_key_value(&code) {
+                return language;
+            }
+            let primary = code.split(['-', '_']).next().unwrap_or_default();
+            if let Some((language, _)) = self.catalogs.get_key_value(primary) {
+                return language;
+            }
+        }
+        &self.fallback
+    }
+
+    // Looks up a key and fills "{name}" placeholders; missing keys fall back to the default language
+    pub fn text(&self, language_code: Option<&str>, key: &str, args: &[(&str, String)]) -> String {
+        let language = self.resolve_language(language_code);
+        let template = self
+            .catalogs
+            .get(language)
+            .and_then(|catalog| catalog.get(key))
+            .or_else(|| {
+                self.catalogs
+                    .get(&self.fallback)
+                    .and_then(|catalog| catalog.get(key))
+            });
+        let Some(template) = template else {
+            warn!("Missing translation for key '{}'.", key);
+            return key.to_string();
+        };
+
+        args.iter().fold(template.clone(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+    }
+}
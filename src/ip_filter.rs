@@ -0,0 +1,143 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+
+use crate::errors::WebhookError;
+
+// Where Telegram sends webhooks from, per https://core.telegram.org/bots/webhooks
+pub const TELEGRAM_RANGES: &[&str] = &["149.154.160.0/20", "91.108.4.0/22"];
+
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+// Accepts requests whose client address is in one of the allowed ranges
+#[derive(Debug, Clone)]
+pub struct IpFilter {
+    allowed: Vec<IpNet>,
+    // Proxies whose X-Forwarded-For entries are believed
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(allowed: Vec<IpNet>, trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            allowed,
+            trusted_proxies,
+        }
+    }
+
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        client_ip(peer, headers, &self.trusted_proxies)
+    }
+
+    pub fn allows(&self, peer: IpAddr, headers: &HeaderMap) -> bool {
+        let client = self.client_ip(peer, headers);
+        self.allowed.iter().any(|net| net.contains(&client))
+    }
+}
+
+// Walks X-Forwarded-For from the right for as long as each hop is a trusted proxy, so a client
+// cannot spoof its address by sending the header itself
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let mut client = peer;
+    let forwarded = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.iter().rev() {
+        if !trusted_proxies.iter().any(|net| net.contains(&client)) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+// "149.154.160.0/20, 91.108.4.0/22" -> ranges; a bare address is a single-host range
+pub fn parse_ranges(ranges: &str) -> Result<Vec<IpNet>, String> {
+    ranges
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            range
+                .parse::<IpNet>()
+                .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("{:?} is not an IP address or CIDR range", range))
+        })
+        .collect()
+}
+
+// Middleware answering 403 to requests that do not come from an allowed address
+pub async fn filter_source_ip(
+    State(filter): State<Arc<IpFilter>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, WebhookError> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    match peer {
+        Some(peer) if filter.allows(peer, request.headers()) => Ok(next.run(request).await),
+        Some(peer) => Err(WebhookError::forbidden(format!(
+            "requests from {} are not accepted",
+            filter.client_ip(peer, request.headers())
+        ))),
+        None => Err(WebhookError::forbidden("unknown source address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telegram_filter(trusted_proxies: &str) -> IpFilter {
+        IpFilter::new(
+            parse_ranges(&TELEGRAM_RANGES.join(",")).unwrap(),
+            parse_ranges(trusted_proxies).unwrap(),
+        )
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR_HEADER, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn direct_requests_are_checked_by_peer_address() {
+        let filter = telegram_filter("");
+
+        assert!(filter.allows("149.154.167.99".parse().unwrap(), &HeaderMap::new()));
+        assert!(!filter.allows("203.0.113.9".parse().unwrap(), &HeaderMap::new()));
+        // Without a trusted proxy the header is ignored
+        assert!(!filter.allows(
+            "203.0.113.9".parse().unwrap(),
+            &forwarded_for("149.154.167.99")
+        ));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_believed_from_trusted_proxies() {
+        let filter = telegram_filter("10.0.0.0/8");
+        let proxy = "10.0.0.2".parse().unwrap();
+
+        assert!(filter.allows(proxy, &forwarded_for("149.154.167.99")));
+        // A spoofed entry to the left of the real client does not help
+        assert!(!filter.allows(proxy, &forwarded_for("149.154.167.99, 203.0.113.9")));
+    }
+}
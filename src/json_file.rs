@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+
+// A JSON file rewritten whole on every change. Saves run one at a time and take their snapshot
+// once their turn comes, so the newest state is always written last; a temporary file and
+// rename keep the file intact if we crash mid-write.
+pub struct JsonFile {
+    path: PathBuf,
+    saving: Mutex<()>,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            saving: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // None when the file does not exist yet
+    pub fn load<T: DeserializeOwned>(&self) -> Result<Option<T>, String> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let bytes = std::fs::read(&self.path).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn save<T: Serialize>(&self, snapshot: impl FnOnce() -> T) -> Result<(), String> {
+        let _saving = self.saving.lock().await;
+        let bytes = serde_json::to_vec(&snapshot()).map_err(|e| e.to_string())?;
+        let temporary = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary, bytes)
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_saves_leave_the_newest_state() {
+        let path = std::env::temp_dir().join(format!("json-file-{}.json", std::process::id()));
+        let file = Arc::new(JsonFile::new(path.clone()));
+        let state = Arc::new(AtomicU64::new(0));
+
+        let saves: Vec<_> = (0..20)
+            .map(|_| {
+                let (file, state) = (Arc::clone(&file), Arc::clone(&state));
+                tokio::spawn(async move {
+                    state.fetch_add(1, Ordering::SeqCst);
+                    file.save(|| state.load(Ordering::SeqCst)).await.unwrap();
+                })
+            })
+            .collect();
+        for save in saves {
+            save.await.unwrap();
+        }
+
+        assert_eq!(file.load::<u64>(), Ok(Some(20)));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.load::<u64>(), Ok(None));
+    }
+}
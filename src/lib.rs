@@ -0,0 +1,70 @@
+// The webhook server as a library: App::builder() assembles it, the binary only parses the CLI
+pub mod access_log;
+pub mod adapters;
+pub mod admin;
+pub mod amqp_tls;
+pub mod app;
+#[cfg(feature = "audit")]
+pub mod audit;
+pub mod bots;
+pub mod broadcast;
+pub mod broker;
+pub mod channel_pool;
+pub mod circuit_breaker;
+pub mod cli;
+pub mod config;
+pub mod cooldowns;
+pub mod cron;
+pub mod deletion;
+pub mod encoding;
+pub mod encryption;
+#[cfg(feature = "sentry")]
+pub mod error_reporting;
+pub mod errors;
+pub mod flood;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod i18n;
+pub mod ip_filter;
+pub mod json_file;
+pub mod limits;
+pub mod loadtest;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod openapi;
+pub mod polling;
+pub mod publisher;
+pub mod queues;
+pub mod rate_limit;
+#[cfg(feature = "redis")]
+pub mod redis_state;
+pub mod reminders;
+#[cfg(feature = "audit")]
+pub mod replay;
+pub mod routing;
+pub mod rpc;
+pub mod scrubbing;
+pub mod sessions;
+pub mod settings;
+pub mod shadow;
+pub mod shared_state;
+pub mod signature;
+pub mod signing;
+#[cfg(feature = "sqs")]
+pub mod sqs;
+pub mod statsd;
+pub mod telegram_api;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(test)]
+pub mod testing;
+pub mod tiers;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod update_filter;
+pub mod validation;
+pub mod version;
+pub mod webhook_handler;
+pub mod websocket;
@@ -0,0 +1,83 @@
+use axum::{http::StatusCode, BoxError};
+use tower::{load_shed::error::Overloaded, timeout::error::Elapsed};
+
+use crate::errors::WebhookError;
+
+// Turns the errors of the webhook load-shedding and timeout layers into 503s, which Telegram
+// retries later, instead of letting requests queue up behind a stalled broker
+pub async fn reject_overload(error: BoxError) -> WebhookError {
+    if error.is::<Overloaded>() {
+        WebhookError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "too many webhook requests in flight",
+        )
+    } else if error.is::<Elapsed>() {
+        WebhookError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "timeout",
+            "the update was not handled in time",
+        )
+    } else {
+        WebhookError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            error.to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        body::Body, error_handling::HandleErrorLayer, http::Request, routing::post, Router,
+    };
+    use tokio::sync::Semaphore;
+    use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder, ServiceExt};
+
+    use super::*;
+
+    fn slow_router(permits: usize, timeout: Duration) -> Router {
+        Router::new()
+            .route(
+                "/webhook",
+                post(|| async { tokio::time::sleep(Duration::from_millis(200)).await }),
+            )
+            .route_layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(reject_overload))
+                    .load_shed()
+                    .layer(GlobalConcurrencyLimitLayer::with_semaphore(Arc::new(
+                        Semaphore::new(permits),
+                    )))
+                    .timeout(timeout),
+            )
+    }
+
+    fn request() -> Request<Body> {
+        Request::post("/webhook").body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out_with_503() {
+        let router = slow_router(1, Duration::from_millis(10));
+
+        let response = router.oneshot(request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_limit_are_shed() {
+        let router = slow_router(1, Duration::from_secs(5));
+
+        let first = tokio::spawn(router.clone().oneshot(request()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = router.oneshot(request()).await.unwrap();
+
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use axum::http::{HeaderMap, HeaderValue};
+use serde_json::{json, Value};
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+
+use crate::{app::App, webhook_handler::SECRET_TOKEN_HEADER};
+
+// Far above real chat ids, so generated chats are easy to tell apart in the consumers' logs
+const FIRST_CHAT_ID: i64 = 9_000_000_000;
+
+// Synthetic updates fed straight to the dispatcher, skipping HTTP, at a steady rate
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    // Updates per second
+    pub rate: u32,
+    pub duration: Duration,
+    // Updates are spread over this many chats, so per-chat flood limits are not what is measured
+    pub chats: i64,
+    // Sent as "<command>\nLoad test <n>"
+    pub command: String,
+    pub bot_id: String,
+}
+
+#[derive(Debug)]
+pub struct LoadTestReport {
+    pub accepted: u64,
+    pub failed: u64,
+    pub elapsed: Duration,
+    // Of every update, fastest first
+    pub latencies: Vec<Duration>,
+    pub publishes: u64,
+    pub channel_pool_size: Option<usize>,
+    // Checkouts during the run and how many of them shared a busy channel; None without a pool
+    pub contention: Option<(u64, u64)>,
+}
+
+impl LoadTest {
+    fn update(&self, n: u64) -> Value {
+        let chat_id = FIRST_CHAT_ID + (n as i64 % self.chats.max(1));
+        json!({
+            "update_id": n + 1,
+            "message": {
+                "message_id": n + 1,
+                "date": 1_700_000_000,
+                "chat": { "id": chat_id, "type": "private", "first_name": "Load" },
+                "from": { "id": chat_id, "is_bot": false, "first_name": "Load" },
+                "text": format!("{}\nLoad test {}", self.command, n)
+            }
+        })
+    }
+
+    pub async fn run(&self, app: &App) -> LoadTestReport {
+        // Generated updates are ours, so they pass the bot's secret check like Telegram's do
+        let mut headers = HeaderMap::new();
+        if let Some(secret) = app
+            .dispatcher()
+            .bots
+            .get(&self.bot_id)
+            .and_then(|bot| bot.secret_token.as_deref())
+            .and_then(|secret| HeaderValue::from_str(secret).ok())
+        {
+            headers.insert(SECRET_TOKEN_HEADER, secret);
+        }
+        let headers = Arc::new(headers);
+        let pool = app.broker().channel_pool().cloned();
+        let contention_before = pool.as_ref().map(|pool| pool.contention());
+        let publishes_before = app.metrics().publishes.load(Ordering::Relaxed);
+
+        let total = (self.rate as f64 * self.duration.as_secs_f64()) as u64;
+        let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / self.rate as f64));
+        // A stalled handler must not lower the offered rate; late updates are sent at once
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        let started = Instant::now();
+        let mut sent = JoinSet::new();
+        for n in 0..total {
+            ticker.tick().await;
+            let dispatcher = Arc::clone(app.dispatcher());
+            let headers = Arc::clone(&headers);
+            let bot_id = self.bot_id.clone();
+            let update = self.update(n);
+            sent.spawn(async move {
+                let start = Instant::now();
+                let result = dispatcher.dispatch(&bot_id, &headers, &update).await;
+                (start.elapsed(), result.is_ok())
+            });
+        }
+
+        let mut report = LoadTestReport {
+            accepted: 0,
+            failed: 0,
+            elapsed: Duration::ZERO,
+            latencies: Vec::with_capacity(total as usize),
+            publishes: 0,
+            channel_pool_size: pool.as_ref().map(|pool| pool.size()),
+            contention: None,
+        };
+        while let Some(outcome) = sent.join_next().await {
+            let Ok((latency, accepted)) = outcome else {
+                report.failed += 1;
+                continue;
+            };
+            report.latencies.push(latency);
+            if accepted {
+                report.accepted += 1;
+            } else {
+                report.failed += 1;
+            }
+        }
+        report.elapsed = started.elapsed();
+        report.latencies.sort();
+        report.publishes = app.metrics().publishes.load(Ordering::Relaxed) - publishes_before;
+        report.contention = pool.zip(contention_before).map(|(pool, before)| {
+            let after = pool.contention();
+            (after.0 - before.0, after.1 - before.1)
+        });
+        report
+    }
+}
+
+impl LoadTestReport {
+    // Nearest-rank percentile, 0 < p <= 100
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Sent {} updates in {:.1}s: {} accepted, {} failed",
+            self.accepted + self.failed,
+            self.elapsed.as_secs_f64(),
+            self.accepted,
+            self.failed
+        );
+        println!(
+            "  publish throughput = {:.1} messages/s ({} published)",
+            self.publishes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON),
+            self.publishes
+        );
+        println!(
+            "  latency            = p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last().copied().unwrap_or_default()
+        );
+        if let (Some(size), Some((checkouts, contended))) =
+            (self.channel_pool_size, self.contention)
+        {
+            println!(
+                "  channel pool       = {} channels, {} of {} checkouts ({:.1}%) shared a busy channel",
+                size,
+                contended,
+                checkouts,
+                contended as f64 * 100.0 / checkouts.max(1) as f64
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bots::{BotConfig, BotRegistry, DEFAULT_BOT_ID},
+        config::{Config, ConfigArgs, ConfigLayer},
+        testing::RecordingPublisher,
+    };
+
+    #[tokio::test]
+    async fn every_update_is_dispatched_and_timed() {
+        let config = Config::load(ConfigArgs {
+            config: None,
+            overrides: ConfigLayer {
+                rabbit_address: Some("amqp://localhost:5672".to_string()),
+                ..ConfigLayer::default()
+            },
+        })
+        .unwrap();
+        let publisher = Arc::new(RecordingPublisher::default());
+        let app = App::builder()
+            .config(config)
+            .publisher(Arc::clone(&publisher) as _)
+            .bots(BotRegistry::new(vec![BotConfig::new(DEFAULT_BOT_ID)]))
+            .build()
+            .await
+            .unwrap();
+        let load_test = LoadTest {
+            rate: 200,
+            duration: Duration::from_millis(100),
+            chats: 20,
+            command: "/songlinks".to_string(),
+            bot_id: DEFAULT_BOT_ID.to_string(),
+        };
+
+        let report = load_test.run(&app).await;
+
+        assert_eq!((report.accepted, report.failed), (20, 0));
+        assert_eq!(report.latencies.len(), 20);
+        assert_eq!(report.publishes, 20);
+        assert!(publisher
+            .texts_for("Music")
+            .contains(&"Load test 0".to_string()));
+        assert_eq!(report.percentile(100.0), report.latencies[19]);
+        assert_eq!(report.contention, None);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    env,
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use log::{
+    kv::{Error as KvError, Key, Value as KvValue, VisitSource},
+    Log, Metadata, Record,
+};
+use serde_json::{Map, Value};
+
+// LOG_FORMAT=json switches to one JSON object per line, otherwise pretty text logs are used
+pub fn init() {
+    if env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json")) {
+        let filter = FilterBuilder::from_env("RUST_LOG").build();
+        log::set_max_level(filter.filter());
+        log::set_boxed_logger(Box::new(JsonLogger { filter })).expect("Logger already initialized");
+    } else {
+        pretty_env_logger::init();
+    }
+}
+
+// Writes records as JSON lines, including structured key-values passed to the log macros
+struct JsonLogger {
+    filter: Filter,
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), KvError> {
+        let json = if let Some(n) = value.to_i64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_u64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_f64() {
+            Value::from(n)
+        } else if let Some(b) = value.to_bool() {
+            Value::from(b)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        let mut fields = Map::new();
+        fields.insert("timestamp_ms".into(), timestamp_ms.into());
+        fields.insert("level".into(), record.level().as_str().into());
+        fields.insert("target".into(), record.target().into());
+        fields.insert("message".into(), record.args().to_string().into());
+        let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", Value::Object(fields));
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
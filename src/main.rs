@@ -1,56 +1,79 @@
-use std::{env, sync::Arc};
+use std::{process, time::Duration};
 
-use axum::{
-    response::IntoResponse,
-    routing::{get, post},
-    Extension, Router,
-};
+use clap::Parser;
 use dotenvy::dotenv;
-use lapin::{Connection, ConnectionProperties};
-use webhook_handler::{receive_message, ChannelPool};
-pub mod webhook_handler;
+use rustin_bot_publisher::{
+    app::App,
+    cli::{self, Cli, Command},
+    config::Config,
+    loadtest::LoadTest,
+    logging,
+    publisher::RabbitMessage,
+};
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
-    dotenv().expect("Failed to load .env file");
-    let server_address = env::var("SERVER_ADDRESS").expect("SERVER_ADDRESS must be set");
-
-    let rabbit_addr = env::var("RABBIT_ADDRESS").expect("RABBIT_ADDRESS must be set");
+    // A .env file is optional; settings can also come from a config file, the environment or flags
+    dotenv().ok();
+    logging::init();
+    let cli = Cli::parse();
+    let config = Config::load(cli.config).unwrap_or_else(|e| {
+        eprint!("{}", e);
+        process::exit(2);
+    });
 
-    let connection = Connection::connect(&rabbit_addr, ConnectionProperties::default())
-        .await
-        .expect("Failed to connect to RabbitMQ");
-
-    // Create a pool of RabbitMQ channels (e.g., 5 channels)
-    let mut channels = Vec::new();
-    for _ in 0..5 {
-        let channel = Arc::new(
-            connection
-                .create_channel()
-                .await
-                .expect("Failed to create channel"),
-        );
-        channels.push(channel);
+    let result = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            serve(config).await;
+            Ok(())
+        }
+        Command::CheckConfig => cli::check_config(&config).await,
+        Command::DeclareTopology { durable } => cli::declare_topology(&config, durable).await,
+        Command::SendTest {
+            queue,
+            text,
+            bot_id,
+            chat_id,
+        } => {
+            let message = RabbitMessage::chat(&bot_id, chat_id, text);
+            cli::send_test(&config, &queue, message).await
+        }
+        Command::Loadtest {
+            rate,
+            duration_secs,
+            chats,
+            command,
+            bot_id,
+        } => {
+            let load_test = LoadTest {
+                rate,
+                duration: Duration::from_secs(duration_secs),
+                chats,
+                command,
+                bot_id,
+            };
+            cli::load_test(config, load_test).await
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
     }
+}
 
-    // Create the channel pool using the cycling iterator
-    let channel_pool = Arc::new(ChannelPool::new(channels));
-
-    let app = Router::new()
-        .route("/", get(hello))
-        .route("/webhook", post(receive_message))
-        .layer(Extension(Arc::clone(&channel_pool)));
-    let listener = tokio::net::TcpListener::bind(server_address)
-        .await
-        .expect("Could not bind to address");
-
-    println!("Listening on {}", listener.local_addr().unwrap());
-
-    axum::serve(listener, app)
+async fn serve(config: Config) {
+    #[cfg(feature = "otel")]
+    let _tracer_provider = rustin_bot_publisher::telemetry::init_tracing();
+    #[cfg(feature = "sentry")]
+    let _sentry_guard = rustin_bot_publisher::error_reporting::init();
+    // Shared state is connected while building, so an unreachable Redis stops the server here
+    let app = App::builder()
+        .config(config)
+        .build()
         .await
-        .expect("Error serving application");
-}
-async fn hello() -> impl IntoResponse {
-    "Hello"
+        .unwrap_or_else(|e| {
+            eprint!("{}", e);
+            process::exit(2);
+        });
+    app.serve().await;
 }
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    publisher::{MessagePublisher, PublishError, RabbitMessage},
+    update_filter::UpdateClass,
+};
+
+// Handler latencies kept until an exporter takes them; later ones are dropped
+const MAX_LATENCY_SAMPLES: usize = 10_000;
+
+// Process-wide counters shared by every ingestion path (HTTP, gRPC, ...)
+pub struct Metrics {
+    started: Instant,
+    // Telegram updates dispatched, whatever their outcome
+    pub updates: AtomicU64,
+    // The same updates by UpdateClass, and the group chatter among them dropped unhandled
+    pub command_updates: AtomicU64,
+    pub media_with_caption_updates: AtomicU64,
+    pub other_updates: AtomicU64,
+    pub filtered_updates: AtomicU64,
+    // Group commands ignored because they were not addressed to the bot
+    pub unaddressed_commands: AtomicU64,
+    pub publishes: AtomicU64,
+    pub publish_failures: AtomicU64,
+    pub flood_mutes: AtomicU64,
+    pub flood_dropped: AtomicU64,
+    // Webhook requests answered 429 because their source address was over its quota
+    pub throttled_requests: AtomicU64,
+    // Publishes the broker returned because no queue was bound to the destination
+    pub unroutable: AtomicU64,
+    // Channels currently in the pool; changes when the pool autoscales
+    pub channel_pool_size: AtomicU64,
+    // Publishes mirrored to the shadow broker, and those that failed or were dropped
+    pub shadow_publishes: AtomicU64,
+    pub shadow_failures: AtomicU64,
+    per_queue: Mutex<HashMap<String, u64>>,
+    // Cron job -> runs fired and skipped (disabled or failed to publish)
+    per_cron_job: Mutex<HashMap<String, CronRuns>>,
+    // How long each update took to handle, since the last exporter flush
+    update_latencies: Mutex<Vec<Duration>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CronRuns {
+    pub fired: u64,
+    pub skipped: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            updates: AtomicU64::default(),
+            command_updates: AtomicU64::default(),
+            media_with_caption_updates: AtomicU64::default(),
+            other_updates: AtomicU64::default(),
+            filtered_updates: AtomicU64::default(),
+            unaddressed_commands: AtomicU64::default(),
+            publishes: AtomicU64::default(),
+            publish_failures: AtomicU64::default(),
+            flood_mutes: AtomicU64::default(),
+            flood_dropped: AtomicU64::default(),
+            throttled_requests: AtomicU64::default(),
+            unroutable: AtomicU64::default(),
+            channel_pool_size: AtomicU64::default(),
+            shadow_publishes: AtomicU64::default(),
+            shadow_failures: AtomicU64::default(),
+            per_queue: Mutex::default(),
+            per_cron_job: Mutex::default(),
+            update_latencies: Mutex::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn record_publish(&self, destination: &str, success: bool) {
+        if success {
+            self.publishes.fetch_add(1, Ordering::Relaxed);
+            let mut per_queue = self.per_queue.lock().expect("metrics lock poisoned");
+            *per_queue.entry(destination.to_string()).or_default() += 1;
+        } else {
+            self.publish_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_update_class(&self, class: UpdateClass) {
+        let counter = match class {
+            UpdateClass::Command => &self.command_updates,
+            UpdateClass::MediaWithCaption => &self.media_with_caption_updates,
+            UpdateClass::Other => &self.other_updates,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_update_latency(&self, latency: Duration) {
+        let mut latencies = self.update_latencies.lock().expect("metrics lock poisoned");
+        if latencies.len() < MAX_LATENCY_SAMPLES {
+            latencies.push(latency);
+        }
+    }
+
+    // The latencies recorded since the last call
+    pub fn take_update_latencies(&self) -> Vec<Duration> {
+        std::mem::take(&mut *self.update_latencies.lock().expect("metrics lock poisoned"))
+    }
+
+    pub fn record_cron(&self, job: &str, fired: bool) {
+        let mut per_job = self.per_cron_job.lock().expect("metrics lock poisoned");
+        let runs = per_job.entry(job.to_string()).or_default();
+        if fired {
+            runs.fired += 1;
+        } else {
+            runs.skipped += 1;
+        }
+    }
+
+    pub fn cron_runs(&self) -> HashMap<String, CronRuns> {
+        self.per_cron_job
+            .lock()
+            .expect("metrics lock poisoned")
+            .clone()
+    }
+
+    pub fn publishes_per_queue(&self) -> HashMap<String, u64> {
+        self.per_queue
+            .lock()
+            .expect("metrics lock poisoned")
+            .clone()
+    }
+}
+
+// Wraps another publisher and records the outcome of every publish
+pub struct MeteredPublisher {
+    inner: Arc<dyn MessagePublisher>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredPublisher {
+    pub fn new(inner: Arc<dyn MessagePublisher>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for MeteredPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        let result = self.inner.publish(destination, message).await;
+        self.metrics.record_publish(destination, result.is_ok());
+        result
+    }
+}
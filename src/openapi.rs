@@ -0,0 +1,175 @@
+use axum::{routing::get, Json, Router};
+use serde_json::Value;
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        OpenApi as OpenApiSpec,
+    },
+    IntoResponses, Modify, OpenApi, ToSchema,
+};
+
+use crate::errors::WebhookError;
+
+// Name of the security scheme for routes signed with HMAC_<NAME>_SECRET, as the paths refer to it
+pub const HMAC_SCHEME: &str = "hmac";
+
+// The parts of a Telegram Update the dispatcher looks at; anything else is accepted and ignored.
+// See https://core.telegram.org/bots/api#update
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct TelegramUpdate {
+    update_id: i64,
+    message: Option<Value>,
+    edited_message: Option<Value>,
+    callback_query: Option<Value>,
+    inline_query: Option<Value>,
+    pre_checkout_query: Option<Value>,
+    message_reaction: Option<Value>,
+    poll: Option<Value>,
+    poll_answer: Option<Value>,
+}
+
+// What both webhook routes can answer
+#[derive(IntoResponses)]
+#[allow(dead_code)]
+pub enum WebhookResponses {
+    #[response(
+        status = 200,
+        description = "Published, or nothing to publish for this update"
+    )]
+    Accepted,
+    #[response(
+        status = 400,
+        description = "Not a JSON update, or a required field is missing"
+    )]
+    Invalid(WebhookError),
+    #[response(
+        status = 401,
+        description = "Wrong secret token or HMAC signature, or an address not allowed"
+    )]
+    Unauthorized(WebhookError),
+    #[response(status = 404, description = "No bot is configured with this id")]
+    UnknownBot(WebhookError),
+    #[response(status = 413, description = "The body is over webhook_max_body_bytes")]
+    TooLarge(WebhookError),
+    #[response(status = 429, description = "Too many requests from this address")]
+    RateLimited(WebhookError),
+    #[response(
+        status = 503,
+        description = "The broker is not connected or the server is overloaded; retry later"
+    )]
+    Unavailable(WebhookError),
+}
+
+struct HmacSecurity;
+
+impl Modify for HmacSecurity {
+    fn modify(&self, openapi: &mut OpenApiSpec) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            HMAC_SCHEME,
+            // The header name is the default; HMAC_<NAME>_HEADER may change it
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Signature-256",
+                "Hex HMAC-SHA256 of the raw body, optionally prefixed with \"sha256=\"",
+            ))),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "rustin_bot_publisher",
+        description = "Receives Telegram updates and other events and publishes them to the \
+                       configured queues. Admin and RPC routes are only served when their \
+                       secret is set."
+    ),
+    paths(
+        crate::webhook_handler::receive_message,
+        crate::webhook_handler::receive_bot_message,
+        crate::broker::readyz,
+        crate::version::version,
+        crate::admin::list_commands,
+        crate::admin::switch_command,
+        crate::rpc::call_queue,
+    ),
+    modifiers(&HmacSecurity)
+)]
+struct ApiDoc;
+
+#[cfg(feature = "audit")]
+#[derive(OpenApi)]
+#[openapi(paths(crate::replay::replay))]
+struct ReplayApiDoc;
+
+// The spec of every route this build can serve
+pub fn spec() -> OpenApiSpec {
+    #[allow(unused_mut)]
+    let mut spec = ApiDoc::openapi();
+    #[cfg(feature = "audit")]
+    spec.merge(ReplayApiDoc::openapi());
+    spec
+}
+
+#[cfg(feature = "swagger-ui")]
+const SWAGGER_UI_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>rustin_bot_publisher API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+// GET /openapi.json, and Swagger UI at /docs when built with the "swagger-ui" feature
+pub fn routes() -> Router {
+    let spec = spec();
+    let router = Router::new().route("/openapi.json", get(move || async move { Json(spec) }));
+    #[cfg(feature = "swagger-ui")]
+    let router = router.route(
+        "/docs",
+        get(|| async { axum::response::Html(SWAGGER_UI_PAGE) }),
+    );
+    router
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_the_webhook_and_admin_routes() {
+        let spec = serde_json::to_value(spec()).unwrap();
+
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/webhook",
+            "/webhook/{bot_id}",
+            "/readyz",
+            "/version",
+            "/admin/commands",
+            "/admin/commands/{command}",
+            "/rpc/{queue}",
+        ] {
+            assert!(paths.contains_key(path), "{} is not documented", path);
+        }
+        let webhook = &paths["/webhook/{bot_id}"]["post"];
+        assert_eq!(
+            webhook["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/TelegramUpdate"
+        );
+        assert_eq!(
+            webhook["responses"]["400"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/WebhookError"
+        );
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("WebhookError"));
+        assert!(schemas.contains_key("CommandSwitch"));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use axum::http::{HeaderMap, HeaderValue};
+use log::{info, warn};
+use serde_json::{json, Value};
+use teloxide::{payloads::DeleteWebhookSetters, requests::Requester, Bot};
+
+use crate::{
+    bots::{BotConfig, BotRegistry},
+    broker::Broker,
+    webhook_handler::{Dispatcher, SECRET_TOKEN_HEADER},
+};
+
+// Pause after Telegram or the broker failed, before asking for the same updates again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+// Time a getUpdates call may take on top of its long-polling timeout
+const REQUEST_MARGIN: Duration = Duration::from_secs(10);
+
+// How updates reach the dispatcher
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UpdateMode {
+    // Telegram posts them to /webhook
+    #[default]
+    Webhook,
+    // We fetch them with getUpdates, for development and hosts without a public HTTPS endpoint
+    Polling,
+}
+
+impl FromStr for UpdateMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "webhook" => Ok(UpdateMode::Webhook),
+            "polling" => Ok(UpdateMode::Polling),
+            other => Err(format!(
+                "unknown mode {:?}, expected webhook or polling",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for UpdateMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateMode::Webhook => write!(f, "webhook"),
+            UpdateMode::Polling => write!(f, "polling"),
+        }
+    }
+}
+
+// One long-polling loop per bot with an API token, feeding the same dispatcher as the webhook
+pub struct UpdatePoller {
+    pub dispatcher: Arc<Dispatcher>,
+    pub broker: Arc<Broker>,
+    // How long one getUpdates call waits for an update to arrive
+    pub timeout: Duration,
+}
+
+impl UpdatePoller {
+    pub fn spawn(self, bots: &BotRegistry) {
+        let poller = Arc::new(self);
+        for bot in bots.iter() {
+            let Some(api_token) = &bot.api_token else {
+                warn!(
+                    "Bot {} has no API token, its updates are not polled.",
+                    bot.id
+                );
+                continue;
+            };
+            let api = Bot::new(api_token);
+            let bot = bot.clone();
+            let poller = Arc::clone(&poller);
+            tokio::spawn(async move { poller.poll(api, bot).await });
+        }
+    }
+
+    async fn poll(&self, api: Bot, bot: BotConfig) {
+        // getUpdates is refused while a webhook is set; pending updates are kept for us
+        if let Err(e) = api.delete_webhook().drop_pending_updates(false).await {
+            warn!("Could not delete the webhook of bot {}: {}", bot.id, e);
+        }
+        // Updates fetched with the bot's own token are genuine, so they carry its secret through
+        // the same check webhook deliveries pass
+        let mut headers = HeaderMap::new();
+        if let Some(secret) = bot
+            .secret_token
+            .as_deref()
+            .and_then(|secret| HeaderValue::from_str(secret).ok())
+        {
+            headers.insert(SECRET_TOKEN_HEADER, secret);
+        }
+        info!("Polling updates for bot {}.", bot.id);
+
+        // Telegram forgets every update below the offset of the next call
+        let mut offset = 0;
+        loop {
+            // Like the webhook's 503, leave updates at Telegram until they can be published
+            if !self.broker.is_ready() {
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            let updates = match self.get_updates(&api, offset).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    warn!("Could not fetch updates for bot {}: {}", bot.id, e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            for update in updates {
+                let Some(update_id) = update["update_id"].as_i64() else {
+                    warn!("Skipping an update without update_id for bot {}.", bot.id);
+                    continue;
+                };
+                match self.dispatcher.dispatch(&bot.id, &headers, &update).await {
+                    // Telegram would redeliver this one to a webhook, so fetch it again later
+                    Err(e) if e.status.is_server_error() => {
+                        warn!(
+                            "Update {} of bot {} failed, retrying: {}",
+                            update_id, bot.id, e.message
+                        );
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        break;
+                    }
+                    Err(e) => warn!(
+                        "Dropped update {} of bot {}: {}",
+                        update_id, bot.id, e.message
+                    ),
+                    Ok(_) => {}
+                }
+                offset = update_id + 1;
+            }
+        }
+    }
+
+    // The bot's own HTTP client, but raw JSON rather than teloxide's typed updates, which drop
+    // fields they do not know: the dispatcher sees exactly what a webhook would have been sent
+    async fn get_updates(&self, api: &Bot, offset: i64) -> Result<Vec<Value>, String> {
+        let url = api
+            .api_url()
+            .join(&format!("bot{}/getUpdates", api.token()))
+            .map_err(|e| e.to_string())?;
+        // Errors name the URL, and with it the token, unless it is stripped
+        let response: Value = api
+            .client()
+            .post(url)
+            .json(&json!({ "offset": offset, "timeout": self.timeout.as_secs() }))
+            .timeout(self.timeout + REQUEST_MARGIN)
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?
+            .json()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if response["ok"] != true {
+            return Err(response["description"]
+                .as_str()
+                .unwrap_or("getUpdates failed")
+                .to_string());
+        }
+        Ok(response["result"].as_array().cloned().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dispatcher, fixtures, RecordingPublisher};
+    use axum::{routing::post, Json, Router};
+
+    #[tokio::test]
+    async fn updates_are_fetched_as_telegram_sent_them() {
+        let update = fixtures::with_command_entity(fixtures::text_message("/help"), 0, 5);
+        let sent = update.clone();
+        let api = Router::new().route(
+            "/botTOKEN/getUpdates",
+            post(move || async move { Json(json!({ "ok": true, "result": [sent] })) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, api).await });
+        let poller = UpdatePoller {
+            dispatcher: Arc::new(dispatcher(Arc::new(RecordingPublisher::default()))),
+            broker: Arc::new(Broker::default()),
+            timeout: Duration::from_secs(1),
+        };
+
+        let api = Bot::new("TOKEN").set_api_url(api_url.parse().unwrap());
+        let updates = poller.get_updates(&api, 0).await.unwrap();
+
+        assert_eq!(updates, vec![update]);
+    }
+}
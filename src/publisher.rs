@@ -0,0 +1,810 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use lapin::{
+    options::BasicPublishOptions,
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldTable, LongString},
+    BasicProperties,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    broker::Broker,
+    encoding,
+    encryption::{self, PayloadCipher},
+    metrics::Metrics,
+    queues::{QueueOptions, QueueSettings},
+    scrubbing,
+    settings::ChatSettings,
+    signing::{self, MessageSigner},
+};
+
+pub const TELEGRAM_SOURCE: &str = "telegram";
+
+// Channel checkout, publish and broker confirm together
+pub const DEFAULT_PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Bumped when a field is removed or changes meaning; new optional fields keep the version.
+// Version 1 was the original {chat_id, text} message.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RabbitMessage {
+    pub schema_version: u32,
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<i64>,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    // The message the user replied to, when the update was a reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyContext>,
+    #[serde(flatten)]
+    pub metadata: MessageMetadata,
+    // The chat's /settings, so consumers can honour them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<ChatSettings>,
+    // AMQP message priority; a delivery property, not part of the payload
+    #[serde(skip)]
+    pub priority: Option<u8>,
+    // Whether personal data was scrubbed from the text; a header, not part of the payload
+    #[serde(skip)]
+    pub redacted: bool,
+}
+
+impl Default for RabbitMessage {
+    fn default() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            source: String::new(),
+            bot_id: None,
+            chat_id: None,
+            text: String::new(),
+            event: None,
+            data: None,
+            reply_to: None,
+            metadata: MessageMetadata::default(),
+            settings: None,
+            priority: None,
+            redacted: false,
+        }
+    }
+}
+
+// Who sent the update and which Telegram message it came from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct MessageMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i64>,
+    // Unix time the message was sent, as reported by Telegram
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_code: Option<String>,
+    // The "/command" that produced this message, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    // The language of the localized alias the command was sent as, e.g. "ro" for /ajutor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_language: Option<String>,
+    // The Telegram update this came from; recorded in the audit log and in the idempotency key,
+    // not in the payload
+    #[serde(skip)]
+    pub update_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ReplyContext {
+    pub message_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    // Every size Telegram offers, smallest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub photo_file_ids: Vec<String>,
+}
+
+impl RabbitMessage {
+    // A message for a Telegram chat handled by one of our bots
+    pub fn chat(bot_id: &str, chat_id: i64, text: impl Into<String>) -> Self {
+        Self {
+            source: TELEGRAM_SOURCE.to_string(),
+            bot_id: Some(bot_id.to_string()),
+            chat_id: Some(chat_id),
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    // An update for one of our bots that belongs to no chat (e.g. a poll's new vote counts)
+    pub fn bot(bot_id: &str, text: impl Into<String>) -> Self {
+        Self {
+            source: TELEGRAM_SOURCE.to_string(),
+            bot_id: Some(bot_id.to_string()),
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    // The same for every delivery of one update, so consumers can drop the work they already did;
+    // update ids are only unique per bot, and one update can produce several commands' messages
+    pub fn idempotency_key(&self) -> Option<String> {
+        let update_id = self.metadata.update_id?;
+        let mut key = format!(
+            "{}:{}",
+            self.bot_id.as_deref().unwrap_or_default(),
+            update_id
+        );
+        if let Some(command) = &self.metadata.command {
+            key.push(':');
+            key.push_str(command);
+        }
+        Some(key)
+    }
+
+    pub fn with_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    // Attach structured details (e.g. file metadata) alongside the text
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Option<u8>) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: MessageMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_reply_to(mut self, reply_to: Option<ReplyContext>) -> Self {
+        self.reply_to = reply_to;
+        self
+    }
+
+    // A normalized event received from a non-Telegram webhook producer
+    pub fn event(source: &str, event: &str, text: impl Into<String>, data: Value) -> Self {
+        Self {
+            source: source.to_string(),
+            text: text.into(),
+            event: Some(event.to_string()),
+            data: Some(data),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    InvalidDestination(String),
+    Serialization(String),
+    Broker(String),
+    CircuitOpen,
+    NotConnected,
+    // The broker returned the message: no queue is bound to the routing key
+    Unroutable(String),
+    Timeout(Duration),
+}
+
+impl PublishError {
+    // Broker-side problems are reported as 503 so webhook producers retry later
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::CircuitOpen | PublishError::NotConnected | PublishError::Timeout(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::InvalidDestination(name) => {
+                write!(f, "invalid destination name: {:?}", name)
+            }
+            PublishError::Serialization(err) => write!(f, "failed to serialize message: {}", err),
+            PublishError::Broker(err) => write!(f, "broker rejected publish: {}", err),
+            PublishError::CircuitOpen => write!(f, "circuit breaker is open"),
+            PublishError::NotConnected => write!(f, "not connected to the broker yet"),
+            PublishError::Unroutable(name) => write!(f, "no queue is bound to {:?}", name),
+            PublishError::Timeout(timeout) => {
+                write!(f, "broker did not confirm within {}s", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+// Where messages are published; RabbitMQ unless the config picks another backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    RabbitMq,
+    #[cfg(feature = "sqs")]
+    Sqs,
+    #[cfg(feature = "mqtt")]
+    Mqtt,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "rabbitmq" => Ok(Backend::RabbitMq),
+            #[cfg(feature = "sqs")]
+            "sqs" => Ok(Backend::Sqs),
+            #[cfg(not(feature = "sqs"))]
+            "sqs" => Err("the sqs backend needs the \"sqs\" cargo feature".to_string()),
+            #[cfg(feature = "mqtt")]
+            "mqtt" => Ok(Backend::Mqtt),
+            #[cfg(not(feature = "mqtt"))]
+            "mqtt" => Err("the mqtt backend needs the \"mqtt\" cargo feature".to_string()),
+            other => Err(format!(
+                "unknown backend {:?}, expected rabbitmq, sqs or mqtt",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::RabbitMq => write!(f, "rabbitmq"),
+            #[cfg(feature = "sqs")]
+            Backend::Sqs => write!(f, "sqs"),
+            #[cfg(feature = "mqtt")]
+            Backend::Mqtt => write!(f, "mqtt"),
+        }
+    }
+}
+
+// AMQP routing keys are short strings, so names must be non-empty and at most 255 bytes
+pub fn validate_destination(destination: &str) -> Result<(), PublishError> {
+    if destination.is_empty()
+        || destination.len() > 255
+        || destination.chars().any(char::is_whitespace)
+    {
+        return Err(PublishError::InvalidDestination(destination.to_string()));
+    }
+    Ok(())
+}
+
+// Anything that can deliver a RabbitMessage to a named destination (queue, topic, ...)
+#[async_trait]
+pub trait MessagePublisher: Send + Sync {
+    async fn publish(&self, destination: &str, message: &RabbitMessage)
+        -> Result<(), PublishError>;
+}
+
+// Publishes to RabbitMQ through the default exchange, using the destination as routing key
+pub struct RabbitPublisher {
+    broker: Arc<Broker>,
+    queues: QueueSettings,
+    metrics: Arc<Metrics>,
+    // Where messages the broker returned as unroutable are republished, if anywhere
+    unroutable_queue: Option<String>,
+    // Command -> where its messages are republished when a publish times out
+    timeout_fallback_queues: HashMap<String, String>,
+    publish_timeout: Duration,
+    cipher: Option<Arc<PayloadCipher>>,
+    signer: Option<Arc<MessageSigner>>,
+}
+
+impl RabbitPublisher {
+    pub fn new(broker: Arc<Broker>, queues: QueueSettings, metrics: Arc<Metrics>) -> Self {
+        Self {
+            broker,
+            queues,
+            metrics,
+            unroutable_queue: None,
+            timeout_fallback_queues: HashMap::new(),
+            publish_timeout: DEFAULT_PUBLISH_TIMEOUT,
+            cipher: None,
+            signer: None,
+        }
+    }
+
+    pub fn with_unroutable_queue(mut self, queue: Option<String>) -> Self {
+        self.unroutable_queue = queue;
+        self
+    }
+
+    pub fn with_timeout_fallback_queues(mut self, queues: HashMap<String, String>) -> Self {
+        self.timeout_fallback_queues = queues;
+        self
+    }
+
+    pub fn with_publish_timeout(mut self, timeout: Duration) -> Self {
+        self.publish_timeout = timeout;
+        self
+    }
+
+    // Bodies are encrypted after any compression; headers stay readable for routing
+    pub fn with_cipher(mut self, cipher: Option<Arc<PayloadCipher>>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    // Signs the body as published, so after encryption; consumers verify before decrypting
+    pub fn with_signer(mut self, signer: Option<Arc<MessageSigner>>) -> Self {
+        self.signer = signer;
+        self
+    }
+}
+
+// AMQP delivery modes
+const TRANSIENT: u8 = 1;
+const PERSISTENT: u8 = 2;
+
+// Lets consumers filter and route on the message without decoding the payload
+fn message_headers(options: &QueueOptions, message: &RabbitMessage) -> FieldTable {
+    let mut headers = FieldTable::default();
+    let mut insert = |key: &str, value: &str| {
+        headers.insert(
+            key.into(),
+            AMQPValue::LongString(LongString::from(value.as_bytes())),
+        )
+    };
+    for (key, value) in &options.headers {
+        insert(key, value);
+    }
+    insert("source", &message.source);
+    if let Some(bot_id) = &message.bot_id {
+        insert("bot_id", bot_id);
+    }
+    if let Some(command) = &message.metadata.command {
+        insert("command", command);
+    }
+    if let Some(key) = message.idempotency_key() {
+        insert("x-idempotency-key", &key);
+    }
+    if message.redacted {
+        insert(scrubbing::REDACTED_HEADER, "true");
+    }
+    headers
+}
+
+fn add_headers(properties: BasicProperties, added: &[(&str, &str)]) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    for (key, value) in added {
+        headers.insert(
+            (*key).into(),
+            AMQPValue::LongString(LongString::from(value.as_bytes())),
+        );
+    }
+    properties.with_headers(headers)
+}
+
+fn message_properties(options: &QueueOptions, message: &RabbitMessage) -> BasicProperties {
+    let delivery_mode = if options.persistent {
+        PERSISTENT
+    } else {
+        TRANSIENT
+    };
+    let properties = BasicProperties::default()
+        .with_content_type(options.encoding.content_type().into())
+        .with_delivery_mode(delivery_mode)
+        .with_headers(message_headers(options, message));
+    match message.priority {
+        Some(priority) => properties.with_priority(priority),
+        None => properties,
+    }
+}
+
+impl RabbitPublisher {
+    // A stalled broker fails the publish after `publish_timeout` instead of holding the request
+    async fn basic_publish(
+        &self,
+        destination: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), PublishError> {
+        tokio::time::timeout(
+            self.publish_timeout,
+            self.publish_confirmed(destination, payload, properties),
+        )
+        .await
+        .map_err(|_| PublishError::Timeout(self.publish_timeout))?
+    }
+
+    async fn publish_confirmed(
+        &self,
+        destination: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), PublishError> {
+        let Some(channel_pool) = self.broker.channel_pool() else {
+            return Err(PublishError::NotConnected);
+        };
+        let channel = channel_pool
+            .get_channel_for(destination)
+            .await
+            .map_err(|e| PublishError::Broker(e.to_string()))?;
+        // Mandatory makes the broker return, rather than drop, messages no queue is bound for
+        let options = BasicPublishOptions {
+            mandatory: true,
+            ..BasicPublishOptions::default()
+        };
+        let confirmation = channel
+            .basic_publish(
+                "",          // Exchange
+                destination, // Queue name
+                options,
+                payload,
+                properties,
+            )
+            .await
+            .map_err(|e| PublishError::Broker(e.to_string()))?
+            .await
+            .map_err(|e| PublishError::Broker(e.to_string()))?;
+        match confirmation {
+            Confirmation::Ack(Some(returned)) => {
+                warn!(
+                    "Broker returned the message published to {}: {} {}",
+                    destination, returned.reply_code, returned.reply_text
+                );
+                Err(PublishError::Unroutable(destination.to_string()))
+            }
+            Confirmation::Nack(_) => Err(PublishError::Broker(format!(
+                "broker did not accept the message for {}",
+                destination
+            ))),
+            Confirmation::Ack(None) | Confirmation::NotRequested => Ok(()),
+        }
+    }
+
+    // Counts the returned message and republishes it to the unroutable queue when one is set
+    async fn redirect_unroutable(
+        &self,
+        destination: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), PublishError> {
+        self.metrics.unroutable.fetch_add(1, Ordering::Relaxed);
+        let Some(unroutable_queue) = &self.unroutable_queue else {
+            return Err(PublishError::Unroutable(destination.to_string()));
+        };
+        let unroutable_queue = &self.queues.broker_name(unroutable_queue);
+        warn!(
+            "Redirecting the message for {} to {}.",
+            destination, unroutable_queue
+        );
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        headers.insert(
+            "original_queue".into(),
+            AMQPValue::LongString(LongString::from(destination.as_bytes())),
+        );
+        self.basic_publish(unroutable_queue, payload, properties.with_headers(headers))
+            .await
+    }
+
+    // The fallback queue for a message whose publish timed out, and the properties to republish
+    // it with, if its command has one
+    fn timeout_fallback(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+        properties: BasicProperties,
+    ) -> Option<(String, BasicProperties)> {
+        let fallback_queue = message
+            .metadata
+            .command
+            .as_deref()
+            .and_then(|command| self.timeout_fallback_queues.get(command))?;
+        Some((
+            self.queues.broker_name(fallback_queue),
+            add_headers(properties, &[("original_queue", destination)]),
+        ))
+    }
+
+    // Republishes a message whose publish timed out to its command's fallback queue, if it has
+    // one. The broker may still have taken the first publish, so consumers should dedupe on
+    // x-idempotency-key.
+    async fn redirect_timed_out(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+        payload: &[u8],
+        properties: BasicProperties,
+        timeout: PublishError,
+    ) -> Result<(), PublishError> {
+        let Some((fallback_queue, properties)) =
+            self.timeout_fallback(destination, message, properties)
+        else {
+            return Err(timeout);
+        };
+        warn!(
+            "Publishing to {} timed out; redirecting the message to {}.",
+            destination, fallback_queue
+        );
+        self.basic_publish(&fallback_queue, payload, properties)
+            .await
+    }
+}
+
+impl RabbitPublisher {
+    // Publishes with the queue's settings, letting `extend` add properties such as RPC reply routing
+    pub async fn publish_with_properties(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+        extend: impl FnOnce(BasicProperties) -> BasicProperties + Send,
+    ) -> Result<(), PublishError> {
+        let options = self.queues.for_queue(destination);
+        let destination = &self.queues.broker_name(destination);
+        validate_destination(destination)?;
+        let (serialized_message, compressed) = encoding::compress(
+            options.encoding.encode(message)?,
+            options.compress_over_bytes,
+        )?;
+        let mut properties = extend(message_properties(options, message));
+        if compressed {
+            properties = properties.with_content_encoding(encoding::GZIP.into());
+        }
+        let serialized_message = match &self.cipher {
+            Some(cipher) => {
+                properties = add_headers(
+                    properties,
+                    &[
+                        (encryption::ALGORITHM_HEADER, encryption::ALGORITHM),
+                        (encryption::KEY_ID_HEADER, cipher.key_id()),
+                    ],
+                );
+                cipher.encrypt(&serialized_message)?
+            }
+            None => serialized_message,
+        };
+        if let Some(signer) = &self.signer {
+            properties = add_headers(
+                properties,
+                &[
+                    (signing::ALGORITHM_HEADER, signing::ALGORITHM),
+                    (signing::KEY_ID_HEADER, signer.key_id()),
+                    (signing::SIGNATURE_HEADER, &signer.sign(&serialized_message)),
+                ],
+            );
+        }
+
+        // Carry the trace context across the queue so consumers can continue the trace
+        #[cfg(feature = "otel")]
+        let (properties, publish_cx) = {
+            let cx = crate::telemetry::start_publish_span(destination);
+            let headers = properties.headers().clone().unwrap_or_default();
+            let headers = crate::telemetry::inject_context(&cx, headers);
+            (properties.with_headers(headers), cx)
+        };
+
+        let result = match self
+            .basic_publish(destination, &serialized_message, properties.clone())
+            .await
+        {
+            Err(PublishError::Unroutable(_)) => {
+                self.redirect_unroutable(destination, &serialized_message, properties)
+                    .await
+            }
+            Err(timeout @ PublishError::Timeout(_)) => {
+                self.redirect_timed_out(
+                    destination,
+                    message,
+                    &serialized_message,
+                    properties,
+                    timeout,
+                )
+                .await
+            }
+            result => result,
+        };
+
+        #[cfg(feature = "otel")]
+        crate::telemetry::end_span(&publish_cx, result.as_ref().err().map(|e| e.to_string()));
+
+        result
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for RabbitPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        self.publish_with_properties(destination, message, |properties| properties)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serializes_a_flat_versioned_message() {
+        let message = RabbitMessage::chat("default", 42, "hi").with_metadata(MessageMetadata {
+            from_id: Some(7),
+            from_username: Some("ana".to_string()),
+            command: Some("/help".to_string()),
+            ..MessageMetadata::default()
+        });
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "schema_version": SCHEMA_VERSION,
+                "source": "telegram",
+                "bot_id": "default",
+                "chat_id": 42,
+                "text": "hi",
+                "from_id": 7,
+                "from_username": "ana",
+                "command": "/help"
+            })
+        );
+    }
+
+    #[test]
+    fn messages_round_trip_through_json() {
+        let message = RabbitMessage::chat("default", 42, "hi")
+            .with_data(json!({ "file_id": "photo-1" }))
+            .with_reply_to(Some(ReplyContext {
+                message_id: 3,
+                ..ReplyContext::default()
+            }));
+
+        let json = serde_json::to_string(&message).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<RabbitMessage>(&json).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn properties_carry_routing_headers_and_persistence() {
+        let message = RabbitMessage::chat("default", 42, "hi").with_metadata(MessageMetadata {
+            command: Some("/songlinks".to_string()),
+            update_id: Some(7),
+            ..MessageMetadata::default()
+        });
+        let options = QueueOptions {
+            headers: [("team".to_string(), "music".to_string())].into(),
+            ..QueueOptions::default()
+        };
+
+        let properties = message_properties(&options, &message);
+
+        assert_eq!(
+            properties.content_type().as_ref().map(|t| t.as_str()),
+            Some("application/json")
+        );
+        assert_eq!(*properties.delivery_mode(), Some(PERSISTENT));
+        let headers = properties.headers().clone().unwrap();
+        let header = |key: &str| match headers.inner().get(key) {
+            Some(AMQPValue::LongString(value)) => Some(value.to_string()),
+            _ => None,
+        };
+        assert_eq!(header("source").as_deref(), Some(TELEGRAM_SOURCE));
+        assert_eq!(header("bot_id").as_deref(), Some("default"));
+        assert_eq!(header("command").as_deref(), Some("/songlinks"));
+        assert_eq!(header("team").as_deref(), Some("music"));
+        assert_eq!(
+            header("x-idempotency-key").as_deref(),
+            Some("default:7:/songlinks")
+        );
+        assert_eq!(header(scrubbing::REDACTED_HEADER), None);
+        let redacted = RabbitMessage {
+            redacted: true,
+            ..message.clone()
+        };
+        let properties = message_properties(&options, &redacted);
+        let headers = properties.headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get(scrubbing::REDACTED_HEADER),
+            Some(&AMQPValue::LongString("true".into()))
+        );
+        let unkeyed = message_properties(&options, &RabbitMessage::bot("default", "hi"));
+        assert!(!unkeyed
+            .headers()
+            .as_ref()
+            .is_some_and(|headers| headers.inner().contains_key("x-idempotency-key")));
+    }
+
+    #[tokio::test]
+    async fn timed_out_publishes_go_to_their_command_fallback_queue() {
+        let queues = QueueSettings::new(QueueOptions::default(), HashMap::new())
+            .unwrap()
+            .with_name_affixes("staging.".to_string(), String::new());
+        let publisher = RabbitPublisher::new(
+            Arc::new(Broker::unused()),
+            queues,
+            Arc::new(Metrics::default()),
+        )
+        .with_timeout_fallback_queues(HashMap::from([(
+            "/songlinks".to_string(),
+            "SlowMusic".to_string(),
+        )]));
+        let timeout = Duration::from_secs(5);
+        let message = |command: &str| {
+            RabbitMessage::chat("default", 42, "hi").with_metadata(MessageMetadata {
+                command: Some(command.to_string()),
+                ..MessageMetadata::default()
+            })
+        };
+
+        let (queue, properties) = publisher
+            .timeout_fallback(
+                "staging.Music",
+                &message("/songlinks"),
+                BasicProperties::default(),
+            )
+            .unwrap();
+        assert_eq!(queue, "staging.SlowMusic");
+        assert_eq!(
+            properties
+                .headers()
+                .as_ref()
+                .unwrap()
+                .inner()
+                .get("original_queue"),
+            Some(&AMQPValue::LongString("staging.Music".into()))
+        );
+        // Mapped, so the fallback publish is attempted (and fails, nothing is connected)
+        assert!(matches!(
+            publisher
+                .redirect_timed_out(
+                    "staging.Music",
+                    &message("/songlinks"),
+                    b"{}",
+                    BasicProperties::default(),
+                    PublishError::Timeout(timeout),
+                )
+                .await,
+            Err(PublishError::NotConnected)
+        ));
+
+        assert!(matches!(
+            publisher
+                .redirect_timed_out(
+                    "staging.Help",
+                    &message("/help"),
+                    b"{}",
+                    BasicProperties::default(),
+                    PublishError::Timeout(timeout),
+                )
+                .await,
+            Err(PublishError::Timeout(after)) if after == timeout
+        ));
+    }
+}
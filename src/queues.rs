@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, HashMap};
+
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use serde::Deserialize;
+
+use crate::{encoding::PayloadEncoding, publisher::validate_destination};
+
+const QUEUE_TYPES: &[&str] = &["classic", "quorum", "stream"];
+const OVERFLOW_BEHAVIORS: &[&str] = &["drop-head", "reject-publish", "reject-publish-dlx"];
+// Appended to the broker name of a queue to name the queue its dead letters land in
+const DEAD_LETTER_SUFFIX: &str = ".DeadLetter";
+
+// One [queues.<name>] table of the config file; unset fields keep the global setting
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueOverrides {
+    pub encoding: Option<String>,
+    pub persistent: Option<bool>,
+    pub durable: Option<bool>,
+    pub auto_delete: Option<bool>,
+    // x-queue-type, x-max-length and x-overflow
+    pub queue_type: Option<String>,
+    pub max_length: Option<u64>,
+    pub overflow: Option<String>,
+    // x-max-priority; messages' priority is only honoured by queues declared with one
+    pub max_priority: Option<u8>,
+    // x-dead-letter-exchange and x-dead-letter-routing-key; declare-topology also declares the
+    // exchange and a queue bound to it
+    pub dead_letter_exchange: Option<String>,
+    pub dead_letter_routing_key: Option<String>,
+    // Bodies of at least this many bytes are gzipped, with content_encoding set to "gzip"
+    pub compress_over_bytes: Option<usize>,
+    // Added to the standard headers of every message published to the queue
+    pub headers: Option<BTreeMap<String, String>>,
+    pub channels: Option<usize>,
+}
+
+// How messages are published to one queue
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueOptions {
+    pub encoding: PayloadEncoding,
+    // Persistent messages survive a broker restart (on durable queues)
+    pub persistent: bool,
+    // Declaration flags used by declare-topology
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub queue_type: Option<String>,
+    pub max_length: Option<u64>,
+    pub overflow: Option<String>,
+    pub max_priority: Option<u8>,
+    pub dead_letter_exchange: Option<String>,
+    // Without one, dead letters keep the routing key they were published with: the queue name
+    pub dead_letter_routing_key: Option<String>,
+    // Only applies to RabbitMQ publishes, the one backend with a content encoding property
+    pub compress_over_bytes: Option<usize>,
+    pub headers: BTreeMap<String, String>,
+    // Channels reserved for this queue so its traffic never waits behind other queues'; 0
+    // publishes over the shared pool
+    pub channels: usize,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            encoding: PayloadEncoding::default(),
+            persistent: true,
+            durable: true,
+            auto_delete: false,
+            queue_type: None,
+            max_length: None,
+            overflow: None,
+            max_priority: None,
+            dead_letter_exchange: None,
+            dead_letter_routing_key: None,
+            compress_over_bytes: None,
+            headers: BTreeMap::new(),
+            channels: 0,
+        }
+    }
+}
+
+impl QueueOptions {
+    // Arguments for queue.declare; they must match those of an existing queue
+    pub fn declare_arguments(&self) -> FieldTable {
+        let mut arguments = FieldTable::default();
+        let mut insert = |name: &str, value: AMQPValue| {
+            arguments.insert(ShortString::from(name), value);
+        };
+        if let Some(queue_type) = &self.queue_type {
+            insert(
+                "x-queue-type",
+                AMQPValue::LongString(LongString::from(queue_type.as_str())),
+            );
+        }
+        if let Some(max_length) = self.max_length {
+            insert("x-max-length", AMQPValue::LongLongInt(max_length as i64));
+        }
+        if let Some(max_priority) = self.max_priority {
+            insert("x-max-priority", AMQPValue::ShortShortUInt(max_priority));
+        }
+        if let Some(overflow) = &self.overflow {
+            insert(
+                "x-overflow",
+                AMQPValue::LongString(LongString::from(overflow.as_str())),
+            );
+        }
+        if let Some(exchange) = &self.dead_letter_exchange {
+            insert(
+                "x-dead-letter-exchange",
+                AMQPValue::LongString(LongString::from(exchange.as_str())),
+            );
+        }
+        if let Some(routing_key) = &self.dead_letter_routing_key {
+            insert(
+                "x-dead-letter-routing-key",
+                AMQPValue::LongString(LongString::from(routing_key.as_str())),
+            );
+        }
+        arguments
+    }
+}
+
+// Where the messages a queue rejects or expires are routed, as declared by declare-topology
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetterRoute {
+    // A durable direct exchange
+    pub exchange: String,
+    pub routing_key: String,
+    // Bound to the exchange with the routing key
+    pub queue: String,
+}
+
+// Publish options keyed by the queue name with bot queue prefixes, but before the environment's
+// prefix and suffix
+#[derive(Debug, Clone, Default)]
+pub struct QueueSettings {
+    default: QueueOptions,
+    queues: HashMap<String, QueueOptions>,
+    // Around every queue name on the broker, so environments sharing it do not collide
+    name_prefix: String,
+    name_suffix: String,
+}
+
+impl QueueSettings {
+    pub fn new(
+        default: QueueOptions,
+        overrides: HashMap<String, QueueOverrides>,
+    ) -> Result<Self, Vec<String>> {
+        let mut queues = HashMap::new();
+        let mut problems = Vec::new();
+        for (queue, queue_overrides) in overrides {
+            if let Err(e) = validate_destination(&queue) {
+                problems.push(format!("queues: {}", e));
+                continue;
+            }
+            let mut options = default.clone();
+            if let Some(encoding) = queue_overrides.encoding {
+                match encoding.parse() {
+                    Ok(encoding) => options.encoding = encoding,
+                    Err(e) => problems.push(format!("queues.{}.encoding: {}", queue, e)),
+                }
+            }
+            if let Some(persistent) = queue_overrides.persistent {
+                options.persistent = persistent;
+            }
+            if let Some(durable) = queue_overrides.durable {
+                options.durable = durable;
+            }
+            if let Some(auto_delete) = queue_overrides.auto_delete {
+                options.auto_delete = auto_delete;
+            }
+            let mut one_of = |field: &str, value: Option<String>, allowed: &[&str]| {
+                value.filter(|value| {
+                    let valid = allowed.contains(&value.as_str());
+                    if !valid {
+                        problems.push(format!(
+                            "queues.{}.{}: expected one of {}, got {:?}",
+                            queue,
+                            field,
+                            allowed.join(", "),
+                            value
+                        ));
+                    }
+                    valid
+                })
+            };
+            options.queue_type = one_of("queue_type", queue_overrides.queue_type, QUEUE_TYPES);
+            options.overflow = one_of("overflow", queue_overrides.overflow, OVERFLOW_BEHAVIORS);
+            options.max_length = queue_overrides.max_length;
+            options.max_priority = queue_overrides.max_priority;
+            options.dead_letter_routing_key = queue_overrides.dead_letter_routing_key;
+            if queue_overrides.compress_over_bytes.is_some() && !cfg!(feature = "gzip") {
+                problems.push(format!(
+                    "queues.{}.compress_over_bytes: compression needs the \"gzip\" cargo feature",
+                    queue
+                ));
+            }
+            options.compress_over_bytes = queue_overrides.compress_over_bytes;
+            match queue_overrides.dead_letter_exchange {
+                // The default exchange cannot be bound to, so its dead letters would be lost
+                Some(exchange) if exchange.is_empty() => problems.push(format!(
+                    "queues.{}.dead_letter_exchange: must not be empty",
+                    queue
+                )),
+                Some(exchange) => options.dead_letter_exchange = Some(exchange),
+                None if options.dead_letter_routing_key.is_some() => problems.push(format!(
+                    "queues.{}.dead_letter_routing_key: needs a dead_letter_exchange",
+                    queue
+                )),
+                None => {}
+            }
+            // Quorum and stream queues are replicated and always durable
+            if options
+                .queue_type
+                .as_deref()
+                .is_some_and(|t| t != "classic")
+                && (!options.durable || options.auto_delete)
+            {
+                problems.push(format!(
+                    "queues.{}: {} queues must be durable and cannot be auto-deleted",
+                    queue,
+                    options.queue_type.as_deref().unwrap_or_default()
+                ));
+            }
+            options
+                .headers
+                .extend(queue_overrides.headers.unwrap_or_default());
+            if let Some(channels) = queue_overrides.channels {
+                options.channels = channels;
+            }
+            queues.insert(queue, options);
+        }
+        if problems.is_empty() {
+            Ok(Self {
+                default,
+                queues,
+                name_prefix: String::new(),
+                name_suffix: String::new(),
+            })
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn with_name_affixes(mut self, prefix: String, suffix: String) -> Self {
+        self.name_prefix = prefix;
+        self.name_suffix = suffix;
+        self
+    }
+
+    // The queue's name on the broker
+    pub fn broker_name(&self, queue: &str) -> String {
+        format!("{}{}{}", self.name_prefix, queue, self.name_suffix)
+    }
+
+    pub fn default_options(&self) -> &QueueOptions {
+        &self.default
+    }
+
+    pub fn for_queue(&self, queue: &str) -> &QueueOptions {
+        self.queues.get(queue).unwrap_or(&self.default)
+    }
+
+    // The dead-letter exchange of a queue, its routing key and the queue collecting its dead
+    // letters, all by broker name
+    pub fn dead_letter_route(&self, queue: &str) -> Option<DeadLetterRoute> {
+        let options = self.for_queue(queue);
+        let exchange = options.dead_letter_exchange.clone()?;
+        let queue = self.broker_name(queue);
+        Some(DeadLetterRoute {
+            exchange,
+            routing_key: options
+                .dead_letter_routing_key
+                .clone()
+                .unwrap_or_else(|| queue.clone()),
+            queue: format!("{}{}", queue, DEAD_LETTER_SUFFIX),
+        })
+    }
+
+    // Queues that get their own channels, by broker name, and how many
+    pub fn dedicated_channels(&self) -> HashMap<String, usize> {
+        self.queues
+            .iter()
+            .filter(|(_, options)| options.channels > 0)
+            .map(|(queue, options)| (self.broker_name(queue), options.channels))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlisted_queues_use_the_default() {
+        let overrides = [(
+            "Music".to_string(),
+            QueueOverrides {
+                persistent: Some(false),
+                durable: Some(false),
+                ..QueueOverrides::default()
+            },
+        )]
+        .into();
+        let settings = QueueSettings::new(QueueOptions::default(), overrides).unwrap();
+
+        assert!(!settings.for_queue("Music").persistent);
+        assert!(!settings.for_queue("Music").durable);
+        assert!(settings.for_queue("Reply").durable);
+        assert!(settings.for_queue("Reply").persistent);
+        assert_eq!(settings.for_queue("Reply"), settings.default_options());
+        assert!(settings.dedicated_channels().is_empty());
+    }
+
+    #[test]
+    fn only_queues_with_channels_are_dedicated() {
+        let overrides = [
+            (
+                "ImageToText".to_string(),
+                QueueOverrides {
+                    channels: Some(2),
+                    ..QueueOverrides::default()
+                },
+            ),
+            ("Reply".to_string(), QueueOverrides::default()),
+        ]
+        .into();
+        let settings = QueueSettings::new(QueueOptions::default(), overrides).unwrap();
+
+        assert_eq!(
+            settings.dedicated_channels(),
+            [("ImageToText".to_string(), 2)].into()
+        );
+
+        let staging = settings.with_name_affixes("staging.".to_string(), String::new());
+        assert_eq!(staging.broker_name("Reply"), "staging.Reply");
+        assert_eq!(
+            staging.dedicated_channels(),
+            [("staging.ImageToText".to_string(), 2)].into()
+        );
+        assert_eq!(staging.for_queue("ImageToText").channels, 2);
+    }
+
+    #[test]
+    fn quorum_queues_declare_their_arguments() {
+        let overrides = [(
+            "Music".to_string(),
+            QueueOverrides {
+                queue_type: Some("quorum".to_string()),
+                max_length: Some(1000),
+                overflow: Some("reject-publish".to_string()),
+                ..QueueOverrides::default()
+            },
+        )]
+        .into();
+        let settings = QueueSettings::new(QueueOptions::default(), overrides).unwrap();
+
+        let arguments = settings.for_queue("Music").declare_arguments();
+        let arguments = arguments.inner();
+        assert_eq!(
+            arguments.get("x-queue-type"),
+            Some(&AMQPValue::LongString("quorum".into()))
+        );
+        assert_eq!(
+            arguments.get("x-max-length"),
+            Some(&AMQPValue::LongLongInt(1000))
+        );
+        assert!(settings
+            .for_queue("Reply")
+            .declare_arguments()
+            .inner()
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_overrides_are_all_reported() {
+        let overrides = [
+            ("two words".to_string(), QueueOverrides::default()),
+            (
+                "Music".to_string(),
+                QueueOverrides {
+                    encoding: Some("xml".to_string()),
+                    ..QueueOverrides::default()
+                },
+            ),
+            (
+                "Reply".to_string(),
+                QueueOverrides {
+                    queue_type: Some("quorum".to_string()),
+                    auto_delete: Some(true),
+                    overflow: Some("drop-tail".to_string()),
+                    ..QueueOverrides::default()
+                },
+            ),
+        ]
+        .into();
+
+        let problems = QueueSettings::new(QueueOptions::default(), overrides).unwrap_err();
+
+        assert_eq!(problems.len(), 4);
+    }
+
+    #[test]
+    fn dead_letters_are_routed_to_their_own_queue() {
+        let overrides = [(
+            "Music".to_string(),
+            QueueOverrides {
+                dead_letter_exchange: Some("dlx".to_string()),
+                ..QueueOverrides::default()
+            },
+        )]
+        .into();
+        let settings = QueueSettings::new(QueueOptions::default(), overrides)
+            .unwrap()
+            .with_name_affixes("staging.".to_string(), String::new());
+
+        assert_eq!(
+            settings.dead_letter_route("Music"),
+            Some(DeadLetterRoute {
+                exchange: "dlx".to_string(),
+                routing_key: "staging.Music".to_string(),
+                queue: "staging.Music.DeadLetter".to_string(),
+            })
+        );
+        assert_eq!(
+            settings
+                .for_queue("Music")
+                .declare_arguments()
+                .inner()
+                .get("x-dead-letter-exchange"),
+            Some(&AMQPValue::LongString("dlx".into()))
+        );
+        assert_eq!(settings.dead_letter_route("Reply"), None);
+
+        let orphan_key = [(
+            "Music".to_string(),
+            QueueOverrides {
+                dead_letter_routing_key: Some("music".to_string()),
+                ..QueueOverrides::default()
+            },
+        )]
+        .into();
+        assert!(QueueSettings::new(QueueOptions::default(), orphan_key).is_err());
+    }
+}
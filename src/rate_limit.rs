@@ -0,0 +1,115 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use log::warn;
+
+use crate::{
+    errors::WebhookError, ip_filter::client_ip, metrics::Metrics, shared_state::SharedState,
+};
+
+// Webhook requests each client address may make, whatever chats they are for
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpRateLimit {
+    pub per_second: u32,
+    // Requests an idle client may send at once
+    pub burst: u32,
+    // Proxies whose X-Forwarded-For entries are believed, as for the IP filter
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+// A token bucket per client address
+pub struct IpRateLimiter {
+    limit: IpRateLimit,
+    buckets: Arc<dyn SharedState>,
+    metrics: Arc<Metrics>,
+}
+
+impl IpRateLimiter {
+    pub fn new(limit: IpRateLimit, buckets: Arc<dyn SharedState>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            limit,
+            buckets,
+            metrics,
+        }
+    }
+
+    async fn allows(&self, client: IpAddr) -> bool {
+        self.buckets
+            .take_token(
+                &format!("ip:{}", client),
+                self.limit.per_second,
+                self.limit.burst,
+            )
+            .await
+    }
+}
+
+// Middleware answering 429 to client addresses over their quota
+pub async fn limit_source_ip(
+    State(limiter): State<Arc<IpRateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, WebhookError> {
+    let Some(peer) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip())
+    else {
+        return Ok(next.run(request).await);
+    };
+    let client = client_ip(peer, request.headers(), &limiter.limit.trusted_proxies);
+    if limiter.allows(client).await {
+        return Ok(next.run(request).await);
+    }
+    limiter
+        .metrics
+        .throttled_requests
+        .fetch_add(1, Ordering::Relaxed);
+    warn!("Throttled a webhook request from {}.", client);
+    Err(WebhookError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited",
+        format!("too many requests from {}", client),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::shared_state::InMemorySharedState;
+
+    #[tokio::test]
+    async fn each_address_gets_its_own_bucket() {
+        let limiter = IpRateLimiter::new(
+            IpRateLimit {
+                per_second: 10,
+                burst: 2,
+                trusted_proxies: Vec::new(),
+            },
+            Arc::new(InMemorySharedState::default()),
+            Arc::default(),
+        );
+        let noisy = "203.0.113.9".parse().unwrap();
+        let quiet = "198.51.100.1".parse().unwrap();
+
+        assert!(limiter.allows(noisy).await);
+        assert!(limiter.allows(noisy).await);
+        assert!(!limiter.allows(noisy).await);
+        assert!(limiter.allows(quiet).await);
+        // One token back every 100ms
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(limiter.allows(noisy).await);
+        assert!(!limiter.allows(noisy).await);
+    }
+}
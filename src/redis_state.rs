@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+
+use crate::{
+    sessions::{ExpectedStep, SessionKey, SessionStore},
+    shared_state::SharedState,
+};
+
+// Every key we keep starts with this, so the server can be shared with other services
+const KEY_PREFIX: &str = "rustin_bot_publisher:";
+
+// Times come from the server's clock so replicas with drifting clocks still agree. Claims
+// answer 0 when taken, otherwise the milliseconds left on the current one.
+const CLAIM_SCRIPT: &str = r"
+if redis.call('SET', KEYS[1], 1, 'NX', 'PX', ARGV[1]) then
+  return 0
+end
+return math.max(redis.call('PTTL', KEYS[1]), 1)
+";
+
+// A sorted set of hit times in microseconds; KEYS[2] numbers the key's own hits so two in the
+// same microsecond are both counted, and expires along with it
+const HIT_SCRIPT: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local window = tonumber(ARGV[1])
+local ttl = math.ceil(window / 1000)
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+redis.call('ZADD', KEYS[1], now, redis.call('INCR', KEYS[2]))
+redis.call('PEXPIRE', KEYS[1], ttl)
+redis.call('PEXPIRE', KEYS[2], ttl)
+return redis.call('ZCARD', KEYS[1])
+";
+
+const TOKEN_SCRIPT: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local rate, burst = tonumber(ARGV[1]), tonumber(ARGV[2])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or burst
+local at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - at) * rate)
+local allowed = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', string.format('%.6f', tokens), 'at', string.format('%.6f', now))
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / math.max(rate, 1) * 1000) + 1000)
+return allowed
+";
+
+// Connects at startup, so a wrong URL stops the service instead of every replica silently
+// keeping its own state; the connection reconnects by itself afterwards
+pub async fn connect(url: &str) -> Result<ConnectionManager, String> {
+    let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+    ConnectionManager::new(client)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// The name is a hash tag, so a script touching a key and its helpers stays in one cluster slot
+fn key(name: &str) -> String {
+    format!("{}{{{}}}", KEY_PREFIX, name)
+}
+
+fn sequence_key(name: &str) -> String {
+    format!("{}:seq", key(name))
+}
+
+// Cooldowns, flood counts and IP rate limits shared by all replicas. If Redis cannot be reached
+// they fail open: updates are let through rather than dropped.
+pub struct RedisSharedState {
+    connection: ConnectionManager,
+    claim: Script,
+    hit: Script,
+    token: Script,
+}
+
+impl RedisSharedState {
+    pub fn new(connection: ConnectionManager) -> Self {
+        Self {
+            connection,
+            claim: Script::new(CLAIM_SCRIPT),
+            hit: Script::new(HIT_SCRIPT),
+            token: Script::new(TOKEN_SCRIPT),
+        }
+    }
+}
+
+#[async_trait]
+impl SharedState for RedisSharedState {
+    async fn claim(&self, name: &str, ttl: Duration) -> Result<(), Duration> {
+        let millis = (ttl.as_millis() as u64).max(1);
+        let left: Result<u64, _> = self
+            .claim
+            .key(key(name))
+            .arg(millis)
+            .invoke_async(&mut self.connection.clone())
+            .await;
+        match left {
+            Ok(0) => Ok(()),
+            Ok(left) => Err(Duration::from_millis(left)),
+            Err(e) => {
+                warn!("Could not claim {} in Redis: {}", name, e);
+                Ok(())
+            }
+        }
+    }
+
+    async fn is_claimed(&self, name: &str) -> bool {
+        let exists: Result<bool, _> = self.connection.clone().exists(key(name)).await;
+        exists.unwrap_or_else(|e| {
+            warn!("Could not look up {} in Redis: {}", name, e);
+            false
+        })
+    }
+
+    async fn hit(&self, name: &str, window: Duration) -> usize {
+        let hits: Result<usize, _> = self
+            .hit
+            .key(key(name))
+            .key(sequence_key(name))
+            .arg(window.as_micros() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await;
+        hits.unwrap_or_else(|e| {
+            warn!("Could not count a hit on {} in Redis: {}", name, e);
+            0
+        })
+    }
+
+    async fn reset(&self, name: &str) {
+        let deleted: Result<(), _> = self
+            .connection
+            .clone()
+            .del(&[key(name), sequence_key(name)])
+            .await;
+        if let Err(e) = deleted {
+            warn!("Could not reset {} in Redis: {}", name, e);
+        }
+    }
+
+    async fn take_token(&self, name: &str, per_second: u32, burst: u32) -> bool {
+        let allowed: Result<bool, _> = self
+            .token
+            .key(key(name))
+            .arg(per_second)
+            .arg(burst)
+            .invoke_async(&mut self.connection.clone())
+            .await;
+        allowed.unwrap_or_else(|e| {
+            warn!("Could not take a token for {} in Redis: {}", name, e);
+            true
+        })
+    }
+}
+
+// Pending multi-step commands, so the follow-up message may reach any replica. GETDEL needs
+// Redis 6.2 or later.
+pub struct RedisSessionStore {
+    connection: ConnectionManager,
+    ttl: Duration,
+}
+
+impl RedisSessionStore {
+    pub fn new(connection: ConnectionManager, ttl: Duration) -> Self {
+        Self { connection, ttl }
+    }
+
+    fn key(key: &SessionKey) -> String {
+        self::key(&format!("session:{}:{}", key.bot_id, key.chat_id))
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn set(&self, key: &SessionKey, step: ExpectedStep) {
+        let step = serde_json::to_string(&step).expect("session steps serialize to JSON");
+        let stored: Result<(), _> = self
+            .connection
+            .clone()
+            .set_ex(Self::key(key), step, self.ttl.as_secs().max(1))
+            .await;
+        if let Err(e) = stored {
+            warn!("Could not store the session of chat {}: {}", key.chat_id, e);
+        }
+    }
+
+    async fn take(&self, key: &SessionKey) -> Option<ExpectedStep> {
+        let step: Result<Option<String>, _> = self.connection.clone().get_del(Self::key(key)).await;
+        match step {
+            Ok(step) => step.and_then(|step| serde_json::from_str(&step).ok()),
+            Err(e) => {
+                warn!("Could not take the session of chat {}: {}", key.chat_id, e);
+                None
+            }
+        }
+    }
+
+    async fn is_pending(&self, key: &SessionKey) -> bool {
+        let exists: Result<bool, _> = self.connection.clone().exists(Self::key(key)).await;
+        exists.unwrap_or_else(|e| {
+            warn!(
+                "Could not look up the session of chat {}: {}",
+                key.chat_id, e
+            );
+            false
+        })
+    }
+
+    async fn clear(&self, key: &SessionKey) {
+        let deleted: Result<(), _> = self.connection.clone().del(Self::key(key)).await;
+        if let Err(e) = deleted {
+            warn!("Could not clear the session of chat {}: {}", key.chat_id, e);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{
+    bots::BotRegistry,
+    json_file::JsonFile,
+    publisher::{MessagePublisher, RabbitMessage},
+    routing::REPLY_QUEUE,
+};
+
+// Reminders further out than this are refused
+pub const MAX_DELAY: Duration = Duration::from_secs(365 * 24 * 3600);
+pub const MAX_PENDING_PER_CHAT: usize = 20;
+// How long a reminder that could not be published waits before the next attempt
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reminder {
+    pub bot_id: String,
+    pub chat_id: i64,
+    pub text: String,
+    // Unix seconds
+    pub due_at: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScheduleError {
+    TooMany,
+}
+
+// Pending reminders keyed by (due time, id), so the next one due is always first
+type Jobs = BTreeMap<(u64, u64), Reminder>;
+
+// Keeps reminders in memory and in a JSON file, and publishes each to its chat's Reply queue
+// once it is due
+pub struct ReminderScheduler {
+    jobs: Mutex<Jobs>,
+    next_id: AtomicU64,
+    // None keeps reminders in memory only
+    file: Option<JsonFile>,
+    changed: Notify,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl ReminderScheduler {
+    // Reloads the reminders still pending when the process last stopped
+    pub fn load(file: Option<PathBuf>) -> Self {
+        let file = file.map(JsonFile::new);
+        let reminders: Vec<Reminder> = match &file {
+            Some(file) => file.load().unwrap_or_else(|e| {
+                warn!(
+                    "Could not load reminders from {}: {}",
+                    file.path().display(),
+                    e
+                );
+                None
+            }),
+            None => None,
+        }
+        .unwrap_or_default();
+        if !reminders.is_empty() {
+            info!("Loaded {} pending reminders.", reminders.len());
+        }
+        let count = reminders.len() as u64;
+        let jobs = reminders
+            .into_iter()
+            .enumerate()
+            .map(|(id, reminder)| ((reminder.due_at, id as u64), reminder))
+            .collect();
+        Self {
+            jobs: Mutex::new(jobs),
+            next_id: AtomicU64::new(count),
+            file,
+            changed: Notify::new(),
+        }
+    }
+
+    fn jobs(&self) -> std::sync::MutexGuard<'_, Jobs> {
+        self.jobs.lock().expect("reminders lock poisoned")
+    }
+
+    pub async fn schedule(
+        &self,
+        bot_id: &str,
+        chat_id: i64,
+        delay: Duration,
+        text: &str,
+    ) -> Result<(), ScheduleError> {
+        {
+            let mut jobs = self.jobs();
+            let pending = jobs
+                .values()
+                .filter(|job| job.bot_id == bot_id && job.chat_id == chat_id)
+                .count();
+            if pending >= MAX_PENDING_PER_CHAT {
+                return Err(ScheduleError::TooMany);
+            }
+            let reminder = Reminder {
+                bot_id: bot_id.to_string(),
+                chat_id,
+                text: text.to_string(),
+                due_at: now() + delay.as_secs(),
+            };
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            jobs.insert((reminder.due_at, id), reminder);
+        }
+        self.persist().await;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    async fn persist(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let snapshot = || -> Vec<Reminder> { self.jobs().values().cloned().collect() };
+        if let Err(e) = file.save(snapshot).await {
+            warn!(
+                "Could not save reminders to {}: {}",
+                file.path().display(),
+                e
+            );
+        }
+    }
+
+    // Due reminders, removed from the schedule, and how long until the next one
+    fn take_due(&self) -> (Vec<Reminder>, Option<Duration>) {
+        let now = now();
+        let mut jobs = self.jobs();
+        let pending = jobs.split_off(&(now + 1, 0));
+        let due = std::mem::replace(&mut *jobs, pending);
+        let next = jobs
+            .keys()
+            .next()
+            .map(|(due_at, _)| Duration::from_secs(due_at - now));
+        (due.into_values().collect(), next)
+    }
+
+    fn reschedule(&self, reminder: Reminder) {
+        let due_at = now() + RETRY_DELAY.as_secs();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.jobs()
+            .insert((due_at, id), Reminder { due_at, ..reminder });
+    }
+
+    pub fn spawn(self: &Arc<Self>, publisher: Arc<dyn MessagePublisher>, bots: Arc<BotRegistry>) {
+        let scheduler = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let (due, next) = scheduler.take_due();
+                let sent = !due.is_empty();
+                for reminder in due {
+                    let Some(bot) = bots.get(&reminder.bot_id) else {
+                        warn!("Dropping a reminder for unknown bot '{}'.", reminder.bot_id);
+                        continue;
+                    };
+                    let message =
+                        RabbitMessage::chat(&bot.id, reminder.chat_id, reminder.text.as_str())
+                            .with_event("reminder");
+                    let queue = bot.queue_name(REPLY_QUEUE);
+                    if let Err(e) = publisher.publish(&queue, &message).await {
+                        warn!(
+                            "Could not publish a reminder for chat {}, retrying: {}",
+                            reminder.chat_id, e
+                        );
+                        scheduler.reschedule(reminder);
+                    }
+                }
+                if sent {
+                    scheduler.persist().await;
+                    continue;
+                }
+                match next {
+                    Some(wait) => {
+                        let _ = tokio::time::timeout(wait, scheduler.changed.notified()).await;
+                    }
+                    None => scheduler.changed.notified().await,
+                }
+            }
+        });
+    }
+}
+
+// "2h", "90m", "1d12h" or "45s" -> the delay
+pub fn parse_delay(spec: &str) -> Option<Duration> {
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in spec.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return None,
+        };
+        let amount: u64 = number.parse().ok()?;
+        total = total.checked_add(amount.checked_mul(unit)?)?;
+        number.clear();
+    }
+    let delay = Duration::from_secs(total);
+    (number.is_empty() && total > 0 && delay <= MAX_DELAY).then_some(delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bots::BotConfig, testing::RecordingPublisher};
+
+    #[test]
+    fn delays_combine_units() {
+        assert_eq!(parse_delay("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_delay("1d12h"), Some(Duration::from_secs(129_600)));
+        assert_eq!(parse_delay("90"), None);
+        assert_eq!(parse_delay("0m"), None);
+        assert_eq!(parse_delay("2w"), None);
+        assert_eq!(parse_delay("400d"), None);
+    }
+
+    #[tokio::test]
+    async fn pending_reminders_survive_a_restart() {
+        let file = std::env::temp_dir().join(format!("reminders-{}.json", std::process::id()));
+        let scheduler = ReminderScheduler::load(Some(file.clone()));
+        scheduler
+            .schedule("default", 42, Duration::from_secs(3600), "take a break")
+            .await
+            .unwrap();
+
+        let reloaded = ReminderScheduler::load(Some(file.clone()));
+        std::fs::remove_file(&file).unwrap();
+
+        let (due, next) = reloaded.take_due();
+        assert!(due.is_empty());
+        assert!(next.unwrap() > Duration::from_secs(3500));
+        assert_eq!(
+            reloaded.jobs().values().next().unwrap().text,
+            "take a break"
+        );
+    }
+
+    #[tokio::test]
+    async fn reminders_scheduled_concurrently_are_all_saved() {
+        let file = std::env::temp_dir().join(format!("reminders-many-{}.json", std::process::id()));
+        let scheduler = Arc::new(ReminderScheduler::load(Some(file.clone())));
+
+        let scheduled: Vec<_> = (0..10)
+            .map(|chat_id| {
+                let scheduler = Arc::clone(&scheduler);
+                tokio::spawn(async move {
+                    scheduler
+                        .schedule("default", chat_id, Duration::from_secs(3600), "stretch")
+                        .await
+                })
+            })
+            .collect();
+        for schedule in scheduled {
+            schedule.await.unwrap().unwrap();
+        }
+
+        let reloaded = ReminderScheduler::load(Some(file.clone()));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(reloaded.jobs().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn due_reminders_are_published_to_the_reply_queue() {
+        let scheduler = Arc::new(ReminderScheduler::load(None));
+        scheduler
+            .schedule("default", 42, Duration::ZERO, "stretch")
+            .await
+            .unwrap();
+        let publisher = Arc::new(RecordingPublisher::default());
+        let bots = Arc::new(BotRegistry::new(vec![BotConfig::new("default")]));
+
+        scheduler.spawn(Arc::clone(&publisher) as _, bots);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(publisher.texts_for("Reply"), vec!["stretch"]);
+        assert!(scheduler.jobs().is_empty());
+    }
+}
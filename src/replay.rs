@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use axum::{body::Bytes, http::StatusCode, Extension, Json};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::AuditLog,
+    errors::WebhookError,
+    publisher::{MessagePublisher, RabbitMessage},
+};
+
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 10_000;
+
+// Which audit records to re-publish; times are Unix seconds
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ReplayFilter {
+    since: i64,
+    until: Option<i64>,
+    queue: Option<String>,
+    chat_id: Option<i64>,
+    #[serde(default)]
+    failed_only: bool,
+    limit: Option<i64>,
+}
+
+struct AuditedMessage {
+    destination: String,
+    message: RabbitMessage,
+}
+
+async fn find_messages(
+    pool: &PgPool,
+    filter: &ReplayFilter,
+) -> Result<Vec<AuditedMessage>, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new(
+        "SELECT destination, update_id, payload::text AS payload FROM publish_audit \
+         WHERE payload IS NOT NULL AND published_at >= to_timestamp(",
+    );
+    query.push_bind(filter.since).push(")");
+    if let Some(until) = filter.until {
+        query
+            .push(" AND published_at < to_timestamp(")
+            .push_bind(until)
+            .push(")");
+    }
+    if let Some(queue) = &filter.queue {
+        query.push(" AND destination = ").push_bind(queue.clone());
+    }
+    if let Some(chat_id) = filter.chat_id {
+        query.push(" AND chat_id = ").push_bind(chat_id);
+    }
+    if filter.failed_only {
+        query.push(" AND outcome = 'failed'");
+    }
+    query
+        .push(" ORDER BY id LIMIT ")
+        .push_bind(filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT));
+
+    let mut messages = Vec::new();
+    for row in query.build().fetch_all(pool).await? {
+        let payload: String = row.try_get("payload")?;
+        let mut message: RabbitMessage = match serde_json::from_str(&payload) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Skipping an audit record that is not a message: {}", e);
+                continue;
+            }
+        };
+        message.metadata.update_id = row.try_get("update_id")?;
+        messages.push(AuditedMessage {
+            destination: row.try_get("destination")?,
+            message,
+        });
+    }
+    Ok(messages)
+}
+
+// POST /admin/replay with a ReplayFilter; re-publishes the matching messages in their original
+// order. Replays go through the normal publisher, so they are audited again.
+#[utoipa::path(
+    post,
+    path = "/admin/replay",
+    tag = "admin",
+    security(("hmac" = [])),
+    request_body = ReplayFilter,
+    responses(
+        (status = 200, description = "How many messages matched and were republished", body = Object,
+            example = json!({ "matched": 2, "republished": 2, "failed": 0 })),
+        (status = 400, description = "Not a filter", body = WebhookError),
+        (status = 401, description = "Missing or wrong signature", body = WebhookError),
+        (status = 503, description = "The audit log cannot be read", body = WebhookError),
+    )
+)]
+pub async fn replay(
+    Extension(audit): Extension<Arc<AuditLog>>,
+    Extension(publisher): Extension<Arc<dyn MessagePublisher>>,
+    body: Bytes,
+) -> Result<Json<Value>, WebhookError> {
+    let filter: ReplayFilter =
+        serde_json::from_slice(&body).map_err(|e| WebhookError::invalid_json(e.to_string()))?;
+    let messages = find_messages(&audit.pool, &filter).await.map_err(|e| {
+        WebhookError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "audit_unavailable",
+            format!("cannot read the audit log: {}", e),
+        )
+    })?;
+
+    let mut republished = 0;
+    let mut failed = 0;
+    for AuditedMessage {
+        destination,
+        message,
+    } in &messages
+    {
+        match publisher.publish(destination, message).await {
+            Ok(()) => republished += 1,
+            Err(e) => {
+                warn!("Could not replay a message to {}: {}", destination, e);
+                failed += 1;
+            }
+        }
+    }
+    info!(
+        "Replayed {} of {} audited messages ({} failed).",
+        republished,
+        messages.len(),
+        failed
+    );
+    Ok(Json(json!({
+        "matched": messages.len(),
+        "republished": republished,
+        "failed": failed,
+    })))
+}
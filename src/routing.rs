@@ -0,0 +1,388 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use log::{info, warn};
+
+use crate::{config, publisher::validate_destination};
+
+// Replies to the user always go here; only command output and attachments are routable
+pub const REPLY_QUEUE: &str = "Reply";
+
+// Non-command updates that are forwarded as they arrive, routed by their kind
+pub const MESSAGE_KINDS: &[&str] = &[
+    "audio",
+    "moderation",
+    "payments",
+    "polls",
+    "reactions",
+    "stickers",
+];
+
+// What a caption command can be sent with; "/command:kind" routes pick the queue for each
+pub const ATTACHMENT_KINDS: &[&str] = &["photo", "video", "animation", "document"];
+
+const DEFAULT_ROUTES: &[(&str, &str)] = &[
+    ("/deleteme", "Deletion"),
+    ("/readimage", "ImageToText"),
+    ("/songlinks", "Music"),
+    ("audio", "AudioIn"),
+    ("moderation", "Moderation"),
+    ("payments", "Payments"),
+    ("polls", "Polls"),
+    ("reactions", "Reactions"),
+    ("stickers", "Stickers"),
+];
+
+// Which queue each command's (or message kind's) output is published to, before bot queue
+// prefixes are applied, and which commands other command strings stand for
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingTable {
+    routes: HashMap<String, String>,
+    // Alias -> command, e.g. /song -> /songlinks
+    aliases: HashMap<String, String>,
+    // Alias -> (language, command) for aliases in the users' own language, e.g. /ajutor ->
+    // (ro, /help)
+    localized_aliases: HashMap<String, (String, String)>,
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        let routes = DEFAULT_ROUTES
+            .iter()
+            .map(|(command, queue)| (command.to_string(), queue.to_string()))
+            .collect();
+        Self {
+            routes,
+            aliases: HashMap::new(),
+            localized_aliases: HashMap::new(),
+        }
+    }
+}
+
+impl RoutingTable {
+    // The defaults with `overrides` (command or message kind -> queue) applied on top, and
+    // `aliases` (alias -> command)
+    pub fn with_overrides(
+        overrides: HashMap<String, String>,
+        aliases: HashMap<String, String>,
+    ) -> Result<Self, Vec<String>> {
+        let mut table = Self::default();
+        let mut problems = Vec::new();
+        for (command, queue) in overrides {
+            let attachment_kind = command.split_once(':').map(|(_, kind)| kind);
+            if !command.starts_with('/') && !MESSAGE_KINDS.contains(&command.as_str()) {
+                problems.push(format!(
+                    "routes: {:?} is neither a /command nor one of {:?}",
+                    command, MESSAGE_KINDS
+                ));
+            } else if attachment_kind.is_some_and(|kind| !ATTACHMENT_KINDS.contains(&kind)) {
+                problems.push(format!(
+                    "routes: {:?} must end in one of {:?}",
+                    command, ATTACHMENT_KINDS
+                ));
+            } else if let Err(e) = validate_destination(&queue) {
+                problems.push(format!("routes.{:?}: {}", command, e));
+            } else {
+                table.routes.insert(command, queue);
+            }
+        }
+        for (alias, command) in &aliases {
+            if !alias.starts_with('/') || alias.contains(char::is_whitespace) {
+                problems.push(format!("aliases: {:?} is not a /command", alias));
+            } else if !command.starts_with('/') || command.contains(char::is_whitespace) {
+                problems.push(format!(
+                    "aliases.{:?}: {:?} is not a /command",
+                    alias, command
+                ));
+            } else if alias == command || aliases.contains_key(command) {
+                problems.push(format!(
+                    "aliases.{:?}: {:?} is itself an alias",
+                    alias, command
+                ));
+            }
+        }
+        table.aliases = aliases;
+        if problems.is_empty() {
+            Ok(table)
+        } else {
+            Err(problems)
+        }
+    }
+
+    // Adds `localized` (language -> alias -> command). Localized aliases work whatever the
+    // sender's language; the language only says which users they are meant for.
+    pub fn with_localized_aliases(
+        mut self,
+        localized: HashMap<String, HashMap<String, String>>,
+    ) -> Result<Self, Vec<String>> {
+        let mut problems = Vec::new();
+        // Sorted so a clash is always reported against the same language
+        let mut languages: Vec<_> = localized.into_iter().collect();
+        languages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (language, aliases) in languages {
+            if language.is_empty() || language.contains(char::is_whitespace) {
+                problems.push(format!(
+                    "localized_aliases: {:?} is not a language code",
+                    language
+                ));
+                continue;
+            }
+            for (alias, command) in aliases {
+                let name = format!("localized_aliases.{}.{:?}", language, alias);
+                if !alias.starts_with('/') || alias.contains(char::is_whitespace) {
+                    problems.push(format!("{}: {:?} is not a /command", name, alias));
+                } else if !command.starts_with('/') || command.contains(char::is_whitespace) {
+                    problems.push(format!("{}: {:?} is not a /command", name, command));
+                } else if alias == command || self.aliases.contains_key(&command) {
+                    problems.push(format!("{}: {:?} is itself an alias", name, command));
+                } else if self.aliases.contains_key(&alias) {
+                    problems.push(format!("{}: already an alias in [aliases]", name));
+                } else if let Some((other, _)) = self.localized_aliases.get(&alias) {
+                    problems.push(format!(
+                        "{}: already an alias in localized_aliases.{}",
+                        name, other
+                    ));
+                } else {
+                    self.localized_aliases
+                        .insert(alias, (language.clone(), command));
+                }
+            }
+        }
+        // Checked once all are in, as an alias may point at one of any language
+        for (alias, (language, command)) in &self.localized_aliases {
+            if self.localized_aliases.contains_key(command) {
+                problems.push(format!(
+                    "localized_aliases.{}.{:?}: {:?} is itself an alias",
+                    language, alias, command
+                ));
+            }
+        }
+        if problems.is_empty() {
+            Ok(self)
+        } else {
+            Err(problems)
+        }
+    }
+
+    // The command an alias stands for; anything else is returned as it is
+    pub fn resolve<'a>(&'a self, command: &'a str) -> &'a str {
+        if let Some((_, localized)) = self.localized_aliases.get(command) {
+            return localized;
+        }
+        self.aliases.get(command).map_or(command, String::as_str)
+    }
+
+    // The language of a localized alias, e.g. "ro" for /ajutor
+    pub fn alias_language(&self, command: &str) -> Option<&str> {
+        self.localized_aliases
+            .get(command)
+            .map(|(language, _)| language.as_str())
+    }
+
+    // Command -> its aliases, both sorted, for the help text; localized aliases are only listed
+    // for users of their language
+    pub fn aliases_by_command(&self, language: Option<&str>) -> BTreeMap<&str, Vec<&str>> {
+        let mut by_command: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (alias, command) in &self.aliases {
+            by_command.entry(command).or_default().push(alias);
+        }
+        for (alias, (alias_language, command)) in &self.localized_aliases {
+            if Some(alias_language.as_str()) == language {
+                by_command.entry(command).or_default().push(alias);
+            }
+        }
+        by_command.values_mut().for_each(|aliases| aliases.sort());
+        by_command
+    }
+
+    // The routed /commands, sorted; message kinds and caption routes are left out
+    pub fn commands(&self) -> Vec<&str> {
+        let mut commands: Vec<&str> = self
+            .routes
+            .keys()
+            .map(String::as_str)
+            .filter(|command| command.starts_with('/') && !command.contains(':'))
+            .collect();
+        commands.sort();
+        commands
+    }
+
+    pub fn queue_for(&self, command: &str) -> Option<&str> {
+        self.routes.get(command).map(String::as_str)
+    }
+
+    // Where a caption command sends an attachment of this kind, if it takes such attachments
+    pub fn queue_for_attachment(&self, command: &str, kind: &str) -> Option<&str> {
+        self.queue_for(&format!("{}:{}", command, kind))
+    }
+
+    // Every queue a bot may publish to under this table, including the reply queue
+    pub fn queues(&self) -> Vec<&str> {
+        let mut queues: Vec<&str> = self.routes.values().map(String::as_str).collect();
+        queues.push(REPLY_QUEUE);
+        queues.sort();
+        queues.dedup();
+        queues
+    }
+}
+
+// The live routing table; a reload swaps it while in-flight updates keep the snapshot they took
+pub struct Routes {
+    current: RwLock<Arc<RoutingTable>>,
+}
+
+impl Routes {
+    pub fn new(table: RoutingTable) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(table)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<RoutingTable> {
+        Arc::clone(&self.current.read().expect("routes lock poisoned"))
+    }
+
+    pub fn replace(&self, table: RoutingTable) {
+        *self.current.write().expect("routes lock poisoned") = Arc::new(table);
+    }
+
+    // Re-reads the [routes] section of the config file; a broken file keeps the old table
+    pub fn reload(&self, config_file: &Path) {
+        match config::load_routes(config_file) {
+            Ok(table) => {
+                info!("Reloaded routing table from {}.", config_file.display());
+                self.replace(table);
+            }
+            Err(problems) => warn!(
+                "Keeping the current routing table, {} is invalid: {}",
+                config_file.display(),
+                problems.join("; ")
+            ),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn spawn_reload_on_sighup(self: &Arc<Self>, config_file: PathBuf) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let routes = Arc::clone(self);
+        let mut hangups = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                routes.reload(&config_file);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_replace_default_routes() {
+        let overrides = [("/readimage".to_string(), "Ocr".to_string())].into();
+        let table = RoutingTable::with_overrides(overrides, HashMap::new()).unwrap();
+
+        assert_eq!(table.queue_for("/readimage"), Some("Ocr"));
+        assert_eq!(table.queue_for("/songlinks"), Some("Music"));
+        let queues = table.queues();
+        assert!(queues.contains(&"Ocr") && queues.contains(&REPLY_QUEUE));
+        assert!(!queues.contains(&"ImageToText"));
+    }
+
+    #[test]
+    fn invalid_overrides_are_all_reported() {
+        let overrides = [
+            ("readimage".to_string(), "Ocr".to_string()),
+            ("/songlinks".to_string(), "two words".to_string()),
+            ("/transcribe:sticker".to_string(), "Transcribe".to_string()),
+        ]
+        .into();
+
+        let aliases = [
+            ("/song".to_string(), "/songs".to_string()),
+            ("/songs".to_string(), "/songlinks".to_string()),
+            ("links".to_string(), "/songlinks".to_string()),
+        ]
+        .into();
+
+        let problems = RoutingTable::with_overrides(overrides, aliases).unwrap_err();
+
+        assert_eq!(problems.len(), 5);
+    }
+
+    #[test]
+    fn aliases_expand_to_their_command() {
+        let aliases = [
+            ("/song".to_string(), "/songlinks".to_string()),
+            ("/links".to_string(), "/songlinks".to_string()),
+        ]
+        .into();
+        let table = RoutingTable::with_overrides(HashMap::new(), aliases).unwrap();
+
+        assert_eq!(table.resolve("/links"), "/songlinks");
+        assert_eq!(table.resolve("/songs"), "/songs");
+        assert_eq!(
+            table.aliases_by_command(None)["/songlinks"],
+            vec!["/links", "/song"]
+        );
+    }
+
+    #[test]
+    fn localized_aliases_resolve_and_report_clashes() {
+        let localized = HashMap::from([
+            (
+                "ro".to_string(),
+                HashMap::from([("/ajutor".to_string(), "/help".to_string())]),
+            ),
+            (
+                "ru".to_string(),
+                HashMap::from([("/помощь".to_string(), "/help".to_string())]),
+            ),
+        ]);
+        let table = RoutingTable::default()
+            .with_localized_aliases(localized)
+            .unwrap();
+
+        assert_eq!(table.resolve("/помощь"), "/help");
+        assert_eq!(table.alias_language("/ajutor"), Some("ro"));
+        assert_eq!(table.alias_language("/help"), None);
+        assert_eq!(
+            table.aliases_by_command(Some("ro"))["/help"],
+            vec!["/ajutor"]
+        );
+        assert!(table.aliases_by_command(Some("en")).is_empty());
+
+        let aliases = [("/song".to_string(), "/songlinks".to_string())].into();
+        let clashing = HashMap::from([
+            (
+                "ro".to_string(),
+                HashMap::from([
+                    ("/song".to_string(), "/songlinks".to_string()),
+                    ("/melodie".to_string(), "/song".to_string()),
+                ]),
+            ),
+            (
+                "es".to_string(),
+                HashMap::from([("/ayuda".to_string(), "/help".to_string())]),
+            ),
+            (
+                "it".to_string(),
+                HashMap::from([("/ayuda".to_string(), "/help".to_string())]),
+            ),
+        ]);
+        let problems = RoutingTable::with_overrides(HashMap::new(), aliases)
+            .unwrap()
+            .with_localized_aliases(clashing)
+            .unwrap_err();
+
+        assert_eq!(problems.len(), 3);
+        assert!(problems
+            .iter()
+            .any(|problem| problem.contains("localized_aliases.es")));
+    }
+}
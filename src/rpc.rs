@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::StreamExt;
+use lapin::{
+    options::{BasicConsumeOptions, QueueDeclareOptions},
+    types::FieldTable,
+    Channel,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::{oneshot, Mutex as AsyncMutex};
+use utoipa::ToSchema;
+
+use crate::{
+    broker::Broker,
+    channel_pool::ChannelPool,
+    errors::WebhookError,
+    publisher::{PublishError, RabbitMessage, RabbitPublisher},
+};
+
+pub const SOURCE: &str = "rpc";
+
+// What the consumer sent back to the reply queue
+pub struct RpcReply {
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Publish(PublishError),
+    ReplyQueue(String),
+    Timeout(Duration),
+}
+
+// Calls waiting on one reply queue, by correlation id
+type Pending = Arc<Mutex<HashMap<String, oneshot::Sender<RpcReply>>>>;
+
+struct ReplyQueue {
+    name: String,
+    channel: Channel,
+    pending: Pending,
+}
+
+// Forgets the call however it ends, including when the request awaiting it is dropped
+struct PendingCall {
+    pending: Pending,
+    correlation_id: String,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .expect("rpc lock poisoned")
+            .remove(&self.correlation_id);
+    }
+}
+
+// Hands the reply to the call waiting for its correlation id; false when none is
+fn deliver(pending: &Pending, correlation_id: Option<&str>, reply: RpcReply) -> bool {
+    let sender =
+        correlation_id.and_then(|id| pending.lock().expect("rpc lock poisoned").remove(id));
+    match sender {
+        Some(sender) => {
+            let _ = sender.send(reply);
+            true
+        }
+        None => false,
+    }
+}
+
+// Publishes requests with reply_to/correlation_id set and waits for the matching reply
+pub struct RpcClient {
+    broker: Arc<Broker>,
+    publisher: Arc<RabbitPublisher>,
+    timeout: Duration,
+    reply_queue: AsyncMutex<Option<ReplyQueue>>,
+    next_correlation_id: AtomicU64,
+}
+
+impl RpcClient {
+    pub fn new(broker: Arc<Broker>, publisher: Arc<RabbitPublisher>, timeout: Duration) -> Self {
+        Self {
+            broker,
+            publisher,
+            timeout,
+            reply_queue: AsyncMutex::new(None),
+            next_correlation_id: AtomicU64::new(1),
+        }
+    }
+
+    pub async fn call(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<RpcReply, RpcError> {
+        let (reply_to, pending) = self.reply_queue().await?;
+        // Only this process consumes the reply queue, so a counter is unique enough
+        let correlation_id = self
+            .next_correlation_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string();
+        let (sender, receiver) = oneshot::channel();
+        pending
+            .lock()
+            .expect("rpc lock poisoned")
+            .insert(correlation_id.clone(), sender);
+        let _call = PendingCall {
+            pending,
+            correlation_id: correlation_id.clone(),
+        };
+
+        let published = self
+            .publisher
+            .publish_with_properties(destination, message, |properties| {
+                properties
+                    .with_reply_to(reply_to.as_str().into())
+                    .with_correlation_id(correlation_id.as_str().into())
+            })
+            .await;
+        if let Err(e) = published {
+            return Err(RpcError::Publish(e));
+        }
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(RpcError::ReplyQueue(
+                "the reply consumer stopped".to_string(),
+            )),
+            Err(_) => Err(RpcError::Timeout(self.timeout)),
+        }
+    }
+
+    // Declared on first use, and again once its channel is gone (the queue goes with it)
+    async fn reply_queue(&self) -> Result<(String, Pending), RpcError> {
+        let mut reply_queue = self.reply_queue.lock().await;
+        if let Some(queue) = reply_queue
+            .as_ref()
+            .filter(|queue| queue.channel.status().connected())
+        {
+            return Ok((queue.name.clone(), Arc::clone(&queue.pending)));
+        }
+        let pool = self
+            .broker
+            .channel_pool()
+            .ok_or(RpcError::Publish(PublishError::NotConnected))?;
+        let queue = open_reply_queue(pool)
+            .await
+            .map_err(|e| RpcError::ReplyQueue(e.to_string()))?;
+        info!("Declared RPC reply queue {}.", queue.name);
+        let opened = (queue.name.clone(), Arc::clone(&queue.pending));
+        *reply_queue = Some(queue);
+        Ok(opened)
+    }
+}
+
+async fn open_reply_queue(pool: &ChannelPool) -> Result<ReplyQueue, lapin::Error> {
+    let channel = pool.dedicated_channel().await?;
+    let options = QueueDeclareOptions {
+        exclusive: true,
+        auto_delete: true,
+        ..QueueDeclareOptions::default()
+    };
+    // An empty name makes the broker generate a unique one
+    let queue = channel
+        .queue_declare("", options, FieldTable::default())
+        .await?;
+    let name = queue.name().to_string();
+    let mut consumer = channel
+        .basic_consume(
+            &name,
+            "rpc-replies",
+            BasicConsumeOptions {
+                no_ack: true,
+                ..BasicConsumeOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    let pending = Pending::default();
+    let calls = Arc::clone(&pending);
+    tokio::spawn(async move {
+        while let Some(delivery) = consumer.next().await {
+            let delivery = match delivery {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    warn!("RPC reply consumer stopped: {}", e);
+                    break;
+                }
+            };
+            let properties = &delivery.properties;
+            let reply = RpcReply {
+                content_type: properties.content_type().as_ref().map(|t| t.to_string()),
+                body: delivery.data,
+            };
+            let correlation_id = properties.correlation_id().as_ref().map(|id| id.as_str());
+            if !deliver(&calls, correlation_id, reply) {
+                warn!("Dropping an RPC reply with an unknown or expired correlation id.");
+            }
+        }
+        // No reply can arrive any more; the calls still waiting fail now instead of timing out
+        calls.lock().expect("rpc lock poisoned").clear();
+    });
+
+    Ok(ReplyQueue {
+        name,
+        channel,
+        pending,
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RpcRequest {
+    text: String,
+    #[serde(default)]
+    data: Option<Value>,
+}
+
+// POST /rpc/:queue with {"text": ..., "data": ...}; answers with the consumer's reply as-is
+#[utoipa::path(
+    post,
+    path = "/rpc/{queue}",
+    tag = "rpc",
+    security(("hmac" = [])),
+    params(("queue" = String, Path, description = "The queue whose consumer answers")),
+    request_body = RpcRequest,
+    responses(
+        (status = 200, description = "The consumer's reply, with its content type"),
+        (status = 400, description = "Not an RPC request", body = WebhookError),
+        (status = 401, description = "Missing or wrong signature", body = WebhookError),
+        (status = 503, description = "The broker is not connected", body = WebhookError),
+        (status = 504, description = "The consumer did not reply within rpc_timeout", body = WebhookError),
+    )
+)]
+pub async fn call_queue(
+    Extension(rpc): Extension<Arc<RpcClient>>,
+    Path(queue): Path<String>,
+    body: Bytes,
+) -> Result<Response, WebhookError> {
+    let request: RpcRequest =
+        serde_json::from_slice(&body).map_err(|e| WebhookError::invalid_json(e.to_string()))?;
+    let message = RabbitMessage {
+        source: SOURCE.to_string(),
+        text: request.text,
+        data: request.data,
+        ..RabbitMessage::default()
+    };
+
+    let reply = rpc.call(&queue, &message).await.map_err(|e| match e {
+        RpcError::Publish(e) => WebhookError::publish_failed(&queue, &e),
+        RpcError::ReplyQueue(reason) => WebhookError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "rpc_unavailable",
+            format!("cannot receive replies: {}", reason),
+        ),
+        RpcError::Timeout(timeout) => WebhookError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "rpc_timeout",
+            format!("{} did not reply within {}s", queue, timeout.as_secs()),
+        ),
+    })?;
+
+    let content_type = reply
+        .content_type
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    Ok(([(header::CONTENT_TYPE, content_type)], reply.body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(body: &str) -> RpcReply {
+        RpcReply {
+            content_type: None,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_reach_the_call_with_their_correlation_id() {
+        let pending = Pending::default();
+        let (first, first_reply) = oneshot::channel();
+        let (second, second_reply) = oneshot::channel();
+        pending.lock().unwrap().insert("1".to_string(), first);
+        pending.lock().unwrap().insert("2".to_string(), second);
+
+        assert!(deliver(&pending, Some("2"), reply("second")));
+        assert!(!deliver(&pending, Some("2"), reply("again")));
+        assert!(!deliver(&pending, Some("3"), reply("unknown")));
+        assert!(!deliver(&pending, None, reply("uncorrelated")));
+        assert_eq!(second_reply.await.unwrap().body, b"second");
+
+        // A dropped call is forgotten, so its late reply is dropped too
+        drop(PendingCall {
+            pending: Arc::clone(&pending),
+            correlation_id: "1".to_string(),
+        });
+        assert!(pending.lock().unwrap().is_empty());
+        assert!(!deliver(&pending, Some("1"), reply("first")));
+        assert!(first_reply.await.is_err());
+    }
+}
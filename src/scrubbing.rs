@@ -0,0 +1,262 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::publisher::{MessagePublisher, PublishError, RabbitMessage};
+
+// Set to "true" on messages that had something redacted
+pub const REDACTED_HEADER: &str = "x-pii-redacted";
+
+// Applied in this order, so the "@example.com" of an email is never taken for a username
+const BUILTIN_RULES: [(&str, &str); 3] = [
+    ("email", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+    (
+        "phone",
+        r"(?:\+\d{1,3}[\s.-]?)?\(?\d{2,4}\)?[\s.-]?\d{3,4}[\s.-]?\d{3,4}",
+    ),
+    // Telegram usernames are 5 to 32 characters
+    ("username", r"\B@[A-Za-z][A-Za-z0-9_]{4,31}\b"),
+];
+
+// The [pii_scrubbing] table of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrubbingConfig {
+    // Which of "email", "phone" and "username" to redact; all of them when unset
+    pub builtin: Option<Vec<String>>,
+    // Name -> regex of anything else to redact, applied after the built-in rules
+    #[serde(default)]
+    pub rules: HashMap<String, String>,
+}
+
+// Replaces personal data in the text of messages with "[<rule name>]"
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    rules: Vec<(String, Regex)>,
+}
+
+impl Scrubber {
+    pub fn new(config: ScrubbingConfig) -> Result<Self, Vec<String>> {
+        let mut rules = Vec::new();
+        let mut problems = Vec::new();
+        let builtin = config.builtin.unwrap_or_else(|| {
+            BUILTIN_RULES
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect()
+        });
+        for name in &builtin {
+            if !BUILTIN_RULES.iter().any(|(builtin, _)| builtin == name) {
+                problems.push(format!(
+                    "pii_scrubbing.builtin: {:?} is not one of email, phone, username",
+                    name
+                ));
+            }
+        }
+        for (name, pattern) in BUILTIN_RULES {
+            if builtin.iter().any(|enabled| enabled == name) {
+                let regex = Regex::new(pattern).expect("built-in rules are valid");
+                rules.push((name.to_string(), regex));
+            }
+        }
+        // Sorted so the order they apply in does not depend on the map
+        let mut custom: Vec<_> = config.rules.into_iter().collect();
+        custom.sort();
+        for (name, pattern) in custom {
+            match Regex::new(&pattern) {
+                Ok(regex) => rules.push((name, regex)),
+                Err(e) => problems.push(format!("pii_scrubbing.rules.{}: {}", name, e)),
+            }
+        }
+        if problems.is_empty() {
+            Ok(Self { rules })
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn rule_names(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|(name, _)| name.as_str())
+    }
+
+    // Whether anything in the text was redacted
+    fn scrub_text(&self, text: &mut String) -> bool {
+        let mut redacted = false;
+        for (name, regex) in &self.rules {
+            if regex.is_match(text) {
+                *text = regex
+                    .replace_all(text, format!("[{}]", name).as_str())
+                    .into_owned();
+                redacted = true;
+            }
+        }
+        redacted
+    }
+
+    // Telegram gives usernames without the "@" in fields of their own, which the rule would not
+    // match; those are replaced whole
+    fn redacts_usernames(&self) -> bool {
+        self.rules.iter().any(|(name, _)| name == "username")
+    }
+
+    // Every string in the data except identifiers, e.g. captions, titles and names
+    fn scrub_data(&self, key: &str, value: &mut Value) -> bool {
+        match value {
+            Value::String(username) if key == "username" && self.redacts_usernames() => {
+                *username = "[username]".to_string();
+                true
+            }
+            Value::String(_) if key == "id" || key.ends_with("_id") => false,
+            Value::String(text) => self.scrub_text(text),
+            Value::Array(values) => values.iter_mut().fold(false, |redacted, value| {
+                self.scrub_data(key, value) | redacted
+            }),
+            Value::Object(fields) => fields.iter_mut().fold(false, |redacted, (key, value)| {
+                self.scrub_data(key, value) | redacted
+            }),
+            _ => false,
+        }
+    }
+
+    // The user's text, the text of the message they replied to, the sender's username and the
+    // strings of structured data
+    pub fn scrub(&self, message: &mut RabbitMessage) -> bool {
+        let mut redacted = self.scrub_text(&mut message.text);
+        if let Some(reply_to) = &mut message.reply_to {
+            for text in [&mut reply_to.text, &mut reply_to.caption]
+                .into_iter()
+                .flatten()
+            {
+                redacted |= self.scrub_text(text);
+            }
+        }
+        if let Some(data) = &mut message.data {
+            redacted |= self.scrub_data("", data);
+        }
+        if self.redacts_usernames() {
+            if let Some(username) = &mut message.metadata.from_username {
+                *username = "[username]".to_string();
+                redacted = true;
+            }
+        }
+        message.redacted |= redacted;
+        redacted
+    }
+}
+
+// Scrubs every message before the publishers behind it serialize, record or mirror it
+pub struct ScrubbingPublisher {
+    inner: Arc<dyn MessagePublisher>,
+    scrubber: Scrubber,
+}
+
+impl ScrubbingPublisher {
+    pub fn new(inner: Arc<dyn MessagePublisher>, scrubber: Scrubber) -> Self {
+        Self { inner, scrubber }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for ScrubbingPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        let mut scrubbed = message.clone();
+        self.scrubber.scrub(&mut scrubbed);
+        self.inner.publish(destination, &scrubbed).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::{
+        publisher::{MessageMetadata, ReplyContext},
+        testing::RecordingPublisher,
+    };
+
+    #[tokio::test]
+    async fn redacts_builtin_and_configured_rules() {
+        let recorded = Arc::new(RecordingPublisher::default());
+        let scrubber = Scrubber::new(ScrubbingConfig {
+            builtin: None,
+            rules: HashMap::from([("order".to_string(), r"ORD-\d+".to_string())]),
+        })
+        .unwrap();
+        let publisher = ScrubbingPublisher::new(Arc::clone(&recorded) as _, scrubber);
+
+        let message = RabbitMessage::chat(
+            "default",
+            42,
+            "Mail ana@example.com or call +40 721 234 567 about ORD-1234, says @ana_fan",
+        )
+        .with_reply_to(Some(ReplyContext {
+            message_id: 6,
+            text: Some("Released 1975-10-31".to_string()),
+            ..ReplyContext::default()
+        }))
+        .with_metadata(MessageMetadata {
+            from_username: Some("ana_fan".to_string()),
+            ..MessageMetadata::default()
+        });
+        publisher.publish("Music", &message).await.unwrap();
+        let answer = RabbitMessage::chat("default", 42, "").with_data(json!({
+            "poll_id": "5432109876543210",
+            "username": "ana_fan",
+            "caption": "Lyrics from ana@example.com",
+        }));
+        publisher.publish("Polls", &answer).await.unwrap();
+        publisher
+            .publish(
+                "Music",
+                &RabbitMessage::chat("default", 42, "Bohemian Rhapsody"),
+            )
+            .await
+            .unwrap();
+
+        let published = recorded.published();
+        assert_eq!(
+            published[0].1.text,
+            "Mail [email] or call [phone] about [order], says [username]"
+        );
+        assert_eq!(
+            published[0].1.reply_to.as_ref().unwrap().text.as_deref(),
+            Some("Released 1975-10-31")
+        );
+        assert_eq!(
+            published[0].1.metadata.from_username.as_deref(),
+            Some("[username]")
+        );
+        assert!(published[0].1.redacted);
+        assert_eq!(
+            published[1].1.data,
+            Some(json!({
+                "poll_id": "5432109876543210",
+                "username": "[username]",
+                "caption": "Lyrics from [email]",
+            }))
+        );
+        assert!(published[1].1.redacted);
+        assert!(!published[2].1.redacted);
+    }
+
+    #[test]
+    fn unknown_rules_and_bad_patterns_are_problems() {
+        let problems = Scrubber::new(ScrubbingConfig {
+            builtin: Some(vec!["iban".to_string()]),
+            rules: HashMap::from([("broken".to_string(), "(".to_string())]),
+        })
+        .unwrap_err();
+
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("\"iban\""));
+        assert!(problems[1].starts_with("pii_scrubbing.rules.broken"));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+// What the next message in a chat is expected to contain after a multi-step command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExpectedStep {
+    // e.g. "/readimage" sent without a photo: the next photo is read
+    Photo { command: String },
+    // e.g. "/songlinks" sent without titles: the next text message holds them
+    Text { command: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub bot_id: String,
+    pub chat_id: i64,
+}
+
+impl SessionKey {
+    pub fn new(bot_id: &str, chat_id: i64) -> Self {
+        Self {
+            bot_id: bot_id.to_string(),
+            chat_id,
+        }
+    }
+}
+
+// Storage for per-chat conversation state; implementations expire entries after their TTL
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn set(&self, key: &SessionKey, step: ExpectedStep);
+    // Returns the pending step and removes it, so each step is consumed exactly once
+    async fn take(&self, key: &SessionKey) -> Option<ExpectedStep>;
+    // Whether a step is pending, without consuming it
+    async fn is_pending(&self, key: &SessionKey) -> bool;
+    async fn clear(&self, key: &SessionKey);
+}
+
+pub struct InMemorySessionStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<SessionKey, (ExpectedStep, Instant)>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn set(&self, key: &SessionKey, step: ExpectedStep) {
+        let mut entries = self.entries.lock().expect("session lock poisoned");
+        let now = Instant::now();
+        // Expired sessions are dropped lazily whenever a new one is stored
+        entries.retain(|_, (_, expires_at)| *expires_at > now);
+        entries.insert(key.clone(), (step, now + self.ttl));
+    }
+
+    async fn take(&self, key: &SessionKey) -> Option<ExpectedStep> {
+        let mut entries = self.entries.lock().expect("session lock poisoned");
+        entries
+            .remove(key)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(step, _)| step)
+    }
+
+    async fn is_pending(&self, key: &SessionKey) -> bool {
+        let entries = self.entries.lock().expect("session lock poisoned");
+        entries
+            .get(key)
+            .is_some_and(|(_, expires_at)| *expires_at > Instant::now())
+    }
+
+    async fn clear(&self, key: &SessionKey) {
+        let mut entries = self.entries.lock().expect("session lock poisoned");
+        entries.remove(key);
+    }
+}
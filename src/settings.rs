@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    json_file::JsonFile,
+    publisher::{MessagePublisher, PublishError, RabbitMessage},
+};
+
+// Every setting /settings knows, in the order it lists them
+pub const SETTING_NAMES: &[&str] = &["language", "verbosity", "song_format"];
+pub const VERBOSITY_VALUES: &[&str] = &["short", "normal", "detailed"];
+pub const SONG_FORMAT_VALUES: &[&str] = &["mp3", "m4a", "opus", "flac"];
+// Given as the value, puts a setting back to its default
+pub const DEFAULT_VALUE: &str = "default";
+
+// Preferences a chat picked with /settings; unset ones leave the choice to the consumers
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatSettings {
+    // Replaces the sender's Telegram language for replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // How much consumers should say in their replies: short, normal or detailed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verbosity: Option<String>,
+    // The format /songlinks consumers should link to, e.g. mp3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub song_format: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SettingError {
+    UnknownSetting,
+    // The values the setting accepts
+    InvalidValue(Vec<String>),
+}
+
+impl ChatSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "language" => self.language.as_deref(),
+            "verbosity" => self.verbosity.as_deref(),
+            "song_format" => self.song_format.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Sets one setting, or clears it when the value is "default"; `languages` are those replies
+    // can be translated to
+    pub fn apply(
+        &mut self,
+        name: &str,
+        value: &str,
+        languages: &[&str],
+    ) -> Result<(), SettingError> {
+        let (setting, allowed) = match name {
+            "language" => (&mut self.language, languages),
+            "verbosity" => (&mut self.verbosity, VERBOSITY_VALUES),
+            "song_format" => (&mut self.song_format, SONG_FORMAT_VALUES),
+            _ => return Err(SettingError::UnknownSetting),
+        };
+        let value = value.to_lowercase();
+        if value == DEFAULT_VALUE {
+            *setting = None;
+        } else if allowed.contains(&value.as_str()) {
+            *setting = Some(value);
+        } else {
+            return Err(SettingError::InvalidValue(
+                allowed.iter().map(|value| value.to_string()).collect(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Where per-chat settings are kept
+#[async_trait]
+pub trait SettingsStore: Send + Sync {
+    // The defaults for chats that never changed a setting
+    async fn get(&self, bot_id: &str, chat_id: i64) -> ChatSettings;
+    async fn set(&self, bot_id: &str, chat_id: i64, settings: ChatSettings);
+}
+
+// One chat's settings as saved in the file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SavedSettings {
+    bot_id: String,
+    chat_id: i64,
+    settings: ChatSettings,
+}
+
+// Keeps settings in memory and in a JSON file
+pub struct FileSettingsStore {
+    settings: Mutex<HashMap<(String, i64), ChatSettings>>,
+    // None keeps settings in memory only
+    file: Option<JsonFile>,
+}
+
+impl FileSettingsStore {
+    // Reloads the settings saved when the process last stopped
+    pub fn load(file: Option<PathBuf>) -> Self {
+        let file = file.map(JsonFile::new);
+        let saved: Vec<SavedSettings> = match &file {
+            Some(file) => file.load().unwrap_or_else(|e| {
+                warn!(
+                    "Could not load chat settings from {}: {}",
+                    file.path().display(),
+                    e
+                );
+                None
+            }),
+            None => None,
+        }
+        .unwrap_or_default();
+        if !saved.is_empty() {
+            info!("Loaded the settings of {} chats.", saved.len());
+        }
+        let settings = saved
+            .into_iter()
+            .map(|saved| ((saved.bot_id, saved.chat_id), saved.settings))
+            .collect();
+        Self {
+            settings: Mutex::new(settings),
+            file,
+        }
+    }
+
+    fn settings(&self) -> std::sync::MutexGuard<'_, HashMap<(String, i64), ChatSettings>> {
+        self.settings.lock().expect("settings lock poisoned")
+    }
+
+    async fn persist(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let snapshot = || -> Vec<SavedSettings> {
+            self.settings()
+                .iter()
+                .map(|((bot_id, chat_id), settings)| SavedSettings {
+                    bot_id: bot_id.clone(),
+                    chat_id: *chat_id,
+                    settings: settings.clone(),
+                })
+                .collect()
+        };
+        if let Err(e) = file.save(snapshot).await {
+            warn!(
+                "Could not save chat settings to {}: {}",
+                file.path().display(),
+                e
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl SettingsStore for FileSettingsStore {
+    async fn get(&self, bot_id: &str, chat_id: i64) -> ChatSettings {
+        self.settings()
+            .get(&(bot_id.to_string(), chat_id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn set(&self, bot_id: &str, chat_id: i64, settings: ChatSettings) {
+        {
+            let mut saved = self.settings();
+            let key = (bot_id.to_string(), chat_id);
+            if settings.is_empty() {
+                saved.remove(&key);
+            } else {
+                saved.insert(key, settings);
+            }
+        }
+        self.persist().await;
+    }
+}
+
+// Attaches the chat's settings to every message for a chat of one of our bots, whichever part
+// of the service publishes it (replies, reminders, broadcasts, ...)
+pub struct SettingsPublisher {
+    inner: Arc<dyn MessagePublisher>,
+    store: Arc<dyn SettingsStore>,
+}
+
+impl SettingsPublisher {
+    pub fn new(inner: Arc<dyn MessagePublisher>, store: Arc<dyn SettingsStore>) -> Self {
+        Self { inner, store }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for SettingsPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        let (Some(bot_id), Some(chat_id)) = (&message.bot_id, message.chat_id) else {
+            return self.inner.publish(destination, message).await;
+        };
+        let settings = self.store.get(bot_id, chat_id).await;
+        if settings.is_empty() {
+            return self.inner.publish(destination, message).await;
+        }
+        let mut message = message.clone();
+        message.settings = Some(settings);
+        self.inner.publish(destination, &message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingPublisher;
+
+    #[test]
+    fn only_known_values_are_accepted() {
+        let mut settings = ChatSettings::default();
+
+        assert_eq!(settings.apply("verbosity", "Short", &[]), Ok(()));
+        assert_eq!(settings.apply("language", "ro", &["en", "ro"]), Ok(()));
+        assert_eq!(
+            settings.apply("song_format", "wav", &[]),
+            Err(SettingError::InvalidValue(
+                SONG_FORMAT_VALUES.iter().map(|v| v.to_string()).collect()
+            ))
+        );
+        assert_eq!(
+            settings.apply("theme", "dark", &[]),
+            Err(SettingError::UnknownSetting)
+        );
+        assert_eq!(settings.get("verbosity"), Some("short"));
+        settings.apply("verbosity", DEFAULT_VALUE, &[]).unwrap();
+        assert_eq!(settings.get("verbosity"), None);
+        assert_eq!(settings.get("language"), Some("ro"));
+    }
+
+    #[tokio::test]
+    async fn settings_survive_a_restart_and_ride_along_with_messages() {
+        let file = std::env::temp_dir().join(format!("settings-{}.json", std::process::id()));
+        let store = FileSettingsStore::load(Some(file.clone()));
+        let settings = ChatSettings {
+            song_format: Some("flac".to_string()),
+            ..ChatSettings::default()
+        };
+        store.set("default", 42, settings.clone()).await;
+
+        let store = Arc::new(FileSettingsStore::load(Some(file.clone())));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(store.get("default", 42).await, settings);
+
+        let recorded = Arc::new(RecordingPublisher::default());
+        let publisher = SettingsPublisher::new(Arc::clone(&recorded) as _, store as _);
+        for chat_id in [42, 43] {
+            let message = RabbitMessage::chat("default", chat_id, "Bohemian Rhapsody");
+            publisher.publish("Music", &message).await.unwrap();
+        }
+        let published = recorded.published();
+        assert_eq!(published[0].1.settings.as_ref(), Some(&settings));
+        assert_eq!(published[1].1.settings, None);
+    }
+}
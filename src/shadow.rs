@@ -0,0 +1,116 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::sync::Semaphore;
+
+use crate::{
+    metrics::Metrics,
+    publisher::{MessagePublisher, PublishError, RabbitMessage},
+};
+
+// Mirrored publishes waiting on the shadow broker; beyond this they are dropped, so a slow
+// shadow cannot pile up tasks
+const MAX_IN_FLIGHT: usize = 1000;
+
+// Mirrors every publish to a second publisher in the background; callers only ever see the
+// primary's outcome
+pub struct ShadowPublisher {
+    primary: Arc<dyn MessagePublisher>,
+    shadow: Arc<dyn MessagePublisher>,
+    in_flight: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+}
+
+impl ShadowPublisher {
+    pub fn new(
+        primary: Arc<dyn MessagePublisher>,
+        shadow: Arc<dyn MessagePublisher>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            primary,
+            shadow,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            metrics,
+        }
+    }
+
+    fn mirror(&self, destination: &str, message: &RabbitMessage) {
+        let Ok(permit) = Arc::clone(&self.in_flight).try_acquire_owned() else {
+            debug!(
+                "Dropped the shadow publish to {}: too many in flight.",
+                destination
+            );
+            self.metrics.shadow_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let shadow = Arc::clone(&self.shadow);
+        let metrics = Arc::clone(&self.metrics);
+        let destination = destination.to_string();
+        let message = message.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            match shadow.publish(&destination, &message).await {
+                Ok(()) => metrics.shadow_publishes.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    debug!("Shadow publish to {} failed: {}", destination, e);
+                    metrics.shadow_failures.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+        });
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for ShadowPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        // Mirrored whatever the primary's outcome, so the new cluster sees all the traffic
+        self.mirror(destination, message);
+        self.primary.publish(destination, message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingPublisher;
+
+    #[tokio::test]
+    async fn shadow_failures_are_counted_but_not_returned() {
+        let primary = Arc::new(RecordingPublisher::default());
+        let shadow = Arc::new(RecordingPublisher::default());
+        let metrics = Arc::new(Metrics::default());
+        let publisher = ShadowPublisher::new(
+            Arc::clone(&primary) as _,
+            Arc::clone(&shadow) as _,
+            Arc::clone(&metrics),
+        );
+        let message = RabbitMessage::chat("default", 42, "Bohemian Rhapsody");
+        // Mirrors have finished once every permit is back
+        let settled = || async {
+            drop(
+                publisher
+                    .in_flight
+                    .acquire_many(MAX_IN_FLIGHT as u32)
+                    .await
+                    .unwrap(),
+            )
+        };
+
+        publisher.publish("Music", &message).await.unwrap();
+        settled().await;
+        shadow.fail_with(|| PublishError::NotConnected);
+        publisher.publish("Music", &message).await.unwrap();
+        settled().await;
+
+        assert_eq!(primary.queues(), vec!["Music", "Music"]);
+        assert_eq!(shadow.queues(), vec!["Music"]);
+        assert_eq!(metrics.shadow_publishes.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.shadow_failures.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+// How often buckets that have refilled completely are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Counters and expiring keys the cooldowns, flood guard and IP rate limit keep. In memory each
+// replica only sees its own traffic; with Redis all replicas behind a load balancer share them.
+#[async_trait]
+pub trait SharedState: Send + Sync {
+    // Claims the key for `ttl` and returns Ok, or returns how long the current claim lasts
+    async fn claim(&self, key: &str, ttl: Duration) -> Result<(), Duration>;
+    async fn is_claimed(&self, key: &str) -> bool;
+    // Records a hit and returns how many the key had within the last `window`, this one included
+    async fn hit(&self, key: &str, window: Duration) -> usize;
+    // Forgets everything kept under the key
+    async fn reset(&self, key: &str);
+    // Takes a token from the key's bucket, which refills at `per_second` up to `burst`
+    async fn take_token(&self, key: &str, per_second: u32, burst: u32) -> bool;
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    // After this long untouched the bucket is full again, as good as a new one
+    full_after: Duration,
+}
+
+// State for a single replica; stale entries are dropped lazily whenever their kind is touched,
+// buckets at most every PRUNE_INTERVAL as every request takes a token
+pub struct InMemorySharedState {
+    claims: Mutex<HashMap<String, Instant>>,
+    hits: Mutex<HashMap<String, (Duration, VecDeque<Instant>)>>,
+    buckets: Mutex<(HashMap<String, Bucket>, Instant)>,
+}
+
+impl Default for InMemorySharedState {
+    fn default() -> Self {
+        Self {
+            claims: Mutex::default(),
+            hits: Mutex::default(),
+            buckets: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+}
+
+#[async_trait]
+impl SharedState for InMemorySharedState {
+    async fn claim(&self, key: &str, ttl: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut claims = self.claims.lock().expect("shared state lock poisoned");
+        claims.retain(|_, expires_at| *expires_at > now);
+        if let Some(expires_at) = claims.get(key) {
+            return Err(*expires_at - now);
+        }
+        claims.insert(key.to_string(), now + ttl);
+        Ok(())
+    }
+
+    async fn is_claimed(&self, key: &str) -> bool {
+        let claims = self.claims.lock().expect("shared state lock poisoned");
+        claims
+            .get(key)
+            .is_some_and(|expires_at| *expires_at > Instant::now())
+    }
+
+    async fn hit(&self, key: &str, window: Duration) -> usize {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().expect("shared state lock poisoned");
+        // Keys without a hit in their window are forgotten
+        hits.retain(|_, (window, recent)| {
+            recent
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < *window)
+        });
+        let (_, recent) = hits
+            .entry(key.to_string())
+            .or_insert_with(|| (window, VecDeque::new()));
+        while recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= window)
+        {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+        recent.len()
+    }
+
+    async fn reset(&self, key: &str) {
+        self.claims
+            .lock()
+            .expect("shared state lock poisoned")
+            .remove(key);
+        self.hits
+            .lock()
+            .expect("shared state lock poisoned")
+            .remove(key);
+        self.buckets
+            .lock()
+            .expect("shared state lock poisoned")
+            .0
+            .remove(key);
+    }
+
+    async fn take_token(&self, key: &str, per_second: u32, burst: u32) -> bool {
+        let now = Instant::now();
+        let burst = burst as f64;
+        let mut guard = self.buckets.lock().expect("shared state lock poisoned");
+        let (buckets, pruned_at) = &mut *guard;
+        if now.saturating_duration_since(*pruned_at) >= PRUNE_INTERVAL {
+            buckets.retain(|_, bucket| {
+                now.saturating_duration_since(bucket.refilled_at) < bucket.full_after
+            });
+            *pruned_at = now;
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+            full_after: Duration::from_secs_f64(burst / per_second.max(1) as f64),
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_second as f64).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn claims_hold_until_they_expire_or_are_reset() {
+        let state = InMemorySharedState::default();
+        let long = Duration::from_secs(60);
+
+        assert_eq!(state.claim("cooldown:a", long).await, Ok(()));
+        assert!(state.claim("cooldown:a", long).await.is_err());
+        assert!(state.is_claimed("cooldown:a").await);
+        assert_eq!(state.claim("cooldown:b", long).await, Ok(()));
+        state.reset("cooldown:a").await;
+        assert!(!state.is_claimed("cooldown:a").await);
+
+        assert_eq!(state.claim("short", Duration::from_millis(1)).await, Ok(()));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(state.claim("short", long).await, Ok(()));
+
+        assert_eq!(state.hit("flood:a", long).await, 1);
+        assert_eq!(state.hit("flood:a", long).await, 2);
+        assert_eq!(state.hit("flood:b", long).await, 1);
+        state.reset("flood:a").await;
+        assert_eq!(state.hit("flood:a", long).await, 1);
+
+        assert!(state.take_token("ip:a", 1, 1).await);
+        assert!(!state.take_token("ip:a", 1, 1).await);
+    }
+}
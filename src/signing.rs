@@ -0,0 +1,105 @@
+use std::fmt;
+
+#[cfg(feature = "signing")]
+use ed25519_dalek::{Signer, SigningKey};
+
+// Message headers carrying the signature of the body as published, and which key made it
+pub const SIGNATURE_HEADER: &str = "x-signature";
+pub const KEY_ID_HEADER: &str = "x-signature-key-id";
+pub const ALGORITHM_HEADER: &str = "x-signature-algorithm";
+pub const ALGORITHM: &str = "Ed25519";
+
+const SEED_BYTES: usize = 32;
+
+// Signs published bodies so consumers can tell our messages from ones injected into the broker
+pub struct MessageSigner {
+    key_id: String,
+    #[cfg(feature = "signing")]
+    key: SigningKey,
+}
+
+// Names the key only, so printing the config never shows it
+impl fmt::Debug for MessageSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageSigner")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MessageSigner {
+    pub fn new(key_id: &str, seed: &[u8]) -> Result<Self, String> {
+        let Ok(seed) = <[u8; SEED_BYTES]>::try_from(seed) else {
+            return Err(format!(
+                "message_signing_key: must be a {} byte seed for {}, not {}",
+                SEED_BYTES,
+                ALGORITHM,
+                seed.len()
+            ));
+        };
+        #[cfg(feature = "signing")]
+        return Ok(Self {
+            key_id: key_id.to_string(),
+            key: SigningKey::from_bytes(&seed),
+        });
+        #[cfg(not(feature = "signing"))]
+        {
+            let _ = (key_id, seed);
+            Err("message_signing_key: signing needs the \"signing\" cargo feature".into())
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    // Hex, for the operators who hand it to consumers
+    #[cfg(feature = "signing")]
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    #[cfg(not(feature = "signing"))]
+    pub fn public_key(&self) -> String {
+        unreachable!("MessageSigner::new fails without the \"signing\" feature")
+    }
+
+    // The hex encoded signature of exactly the bytes published
+    #[cfg(feature = "signing")]
+    pub fn sign(&self, body: &[u8]) -> String {
+        hex::encode(self.key.sign(body).to_bytes())
+    }
+
+    // Never constructed without the feature
+    #[cfg(not(feature = "signing"))]
+    pub fn sign(&self, _body: &[u8]) -> String {
+        unreachable!("MessageSigner::new fails without the \"signing\" feature")
+    }
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    #[test]
+    fn consumers_verify_with_the_public_key() {
+        let signer = MessageSigner::new("publisher-1", &[3u8; SEED_BYTES]).unwrap();
+        let public_key: [u8; 32] = hex::decode(signer.public_key())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let verifying_key = VerifyingKey::from_bytes(&public_key).unwrap();
+
+        let signature: [u8; 64] = hex::decode(signer.sign(b"Bohemian Rhapsody"))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = Signature::from_bytes(&signature);
+        assert!(verifying_key
+            .verify(b"Bohemian Rhapsody", &signature)
+            .is_ok());
+        assert!(verifying_key.verify(b"Injected", &signature).is_err());
+        assert!(MessageSigner::new("short", &[3u8; 16]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use aws_sdk_sqs::{error::DisplayErrorContext, types::MessageAttributeValue, Client};
+
+use crate::{
+    config::Config,
+    publisher::{validate_destination, MessagePublisher, PublishError, RabbitMessage},
+    queues::QueueSettings,
+};
+
+// Publishes to Amazon SQS, e.g. for consumers running on Lambda; credentials and region come
+// from the usual AWS environment (AWS_REGION, AWS_PROFILE, instance roles, ...)
+pub struct SqsPublisher {
+    client: Client,
+    queues: QueueSettings,
+    // Queue -> URL, checked before the prefix
+    queue_urls: HashMap<String, String>,
+    // The broker name of the queue is appended to it
+    queue_url_prefix: Option<String>,
+    publish_timeout: Duration,
+}
+
+impl SqsPublisher {
+    pub async fn from_config(config: &Config) -> Self {
+        let aws = aws_config::load_from_env().await;
+        Self {
+            client: Client::new(&aws),
+            queues: config.queues.clone(),
+            queue_urls: config.sqs_queue_urls.clone(),
+            queue_url_prefix: config.sqs_queue_url_prefix.clone(),
+            publish_timeout: config.publish_timeout,
+        }
+    }
+
+    fn queue_url(&self, destination: &str) -> Option<String> {
+        if let Some(url) = self.queue_urls.get(destination) {
+            return Some(url.clone());
+        }
+        let prefix = self.queue_url_prefix.as_deref()?;
+        Some(format!(
+            "{}{}",
+            prefix,
+            self.queues.broker_name(destination)
+        ))
+    }
+}
+
+// Consumers can filter on these without parsing the body, like the AMQP headers
+fn message_attributes(
+    message: &RabbitMessage,
+    content_type: &str,
+) -> Result<HashMap<String, MessageAttributeValue>, PublishError> {
+    let mut attributes = HashMap::new();
+    let mut insert = |name: &str, data_type: &str, value: String| {
+        let value = MessageAttributeValue::builder()
+            .data_type(data_type)
+            .string_value(value)
+            .build()
+            .map_err(|e| PublishError::Serialization(e.to_string()))?;
+        attributes.insert(name.to_string(), value);
+        Ok::<_, PublishError>(())
+    };
+    insert("content_type", "String", content_type.to_string())?;
+    insert("source", "String", message.source.clone())?;
+    if let Some(bot_id) = &message.bot_id {
+        insert("bot_id", "String", bot_id.clone())?;
+    }
+    if let Some(chat_id) = message.chat_id {
+        insert("chat_id", "Number", chat_id.to_string())?;
+    }
+    if let Some(command) = &message.metadata.command {
+        insert("command", "String", command.clone())?;
+    }
+    Ok(attributes)
+}
+
+#[async_trait]
+impl MessagePublisher for SqsPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        validate_destination(destination)?;
+        let Some(queue_url) = self.queue_url(destination) else {
+            return Err(PublishError::Unroutable(destination.to_string()));
+        };
+        let options = self.queues.for_queue(destination);
+        // SQS bodies are text, so only the JSON encoding fits
+        let body = String::from_utf8(options.encoding.encode(message)?).map_err(|_| {
+            PublishError::Serialization(format!(
+                "{} payloads cannot be sent as SQS message bodies",
+                options.encoding
+            ))
+        })?;
+        let request = self
+            .client
+            .send_message()
+            .queue_url(&queue_url)
+            .message_body(body)
+            .set_message_attributes(Some(message_attributes(
+                message,
+                options.encoding.content_type(),
+            )?))
+            .send();
+        tokio::time::timeout(self.publish_timeout, request)
+            .await
+            .map_err(|_| PublishError::Timeout(self.publish_timeout))?
+            .map_err(|e| PublishError::Broker(DisplayErrorContext(e).to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_carry_chat_and_command() {
+        let mut message = RabbitMessage::chat("default", 42, "Song");
+        message.metadata.command = Some("/songlinks".to_string());
+
+        let attributes = message_attributes(&message, "application/json").unwrap();
+
+        assert_eq!(attributes["chat_id"].data_type(), "Number");
+        assert_eq!(attributes["chat_id"].string_value(), Some("42"));
+        assert_eq!(attributes["command"].string_value(), Some("/songlinks"));
+        assert_eq!(attributes["bot_id"].string_value(), Some("default"));
+    }
+}
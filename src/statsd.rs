@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::net::UdpSocket;
+
+use crate::metrics::Metrics;
+
+// Keeps packets within one Ethernet frame, as the Datadog agent recommends for UDP
+const MAX_PACKET_BYTES: usize = 1432;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdConfig {
+    // host:port of the StatsD server or Datadog agent
+    pub address: String,
+    // Put in front of every metric name, e.g. "bot_publisher."
+    pub prefix: String,
+    // DogStatsD tags added to every metric, e.g. ["env:prod", "service:bot"]
+    pub tags: Vec<String>,
+    pub interval: Duration,
+}
+
+// The counters as last pushed, so each flush sends what happened since
+#[derive(Default)]
+struct Pushed {
+    counters: HashMap<&'static str, u64>,
+    per_queue: HashMap<String, u64>,
+}
+
+// Pushes the process metrics to StatsD every interval, as counters, gauges and timers
+pub struct StatsdExporter {
+    config: StatsdConfig,
+    metrics: Arc<Metrics>,
+    pushed: Pushed,
+}
+
+impl StatsdExporter {
+    pub fn new(config: StatsdConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            metrics,
+            pushed: Pushed::default(),
+        }
+    }
+
+    fn line(
+        &self,
+        name: &str,
+        value: impl std::fmt::Display,
+        kind: &str,
+        tags: &[String],
+    ) -> String {
+        let mut line = format!("{}{}:{}|{}", self.config.prefix, name, value, kind);
+        let tags: Vec<&str> = self
+            .config
+            .tags
+            .iter()
+            .chain(tags)
+            .map(String::as_str)
+            .collect();
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+
+    // One line per metric that changed since the last call
+    fn lines(&mut self) -> Vec<String> {
+        let metrics = &self.metrics;
+        let counters = [
+            ("updates", &metrics.updates),
+            ("updates.command", &metrics.command_updates),
+            (
+                "updates.media_with_caption",
+                &metrics.media_with_caption_updates,
+            ),
+            ("updates.other", &metrics.other_updates),
+            ("updates.filtered", &metrics.filtered_updates),
+            (
+                "updates.unaddressed_commands",
+                &metrics.unaddressed_commands,
+            ),
+            ("publish_failures", &metrics.publish_failures),
+            ("unroutable", &metrics.unroutable),
+            ("flood.mutes", &metrics.flood_mutes),
+            ("flood.dropped", &metrics.flood_dropped),
+            ("throttled_requests", &metrics.throttled_requests),
+            ("shadow.publishes", &metrics.shadow_publishes),
+            ("shadow.failures", &metrics.shadow_failures),
+        ];
+        let mut lines = Vec::new();
+        for (name, counter) in counters {
+            let total = counter.load(Ordering::Relaxed);
+            let pushed = self.pushed.counters.insert(name, total).unwrap_or_default();
+            if total > pushed {
+                lines.push(self.line(name, total - pushed, "c", &[]));
+            }
+        }
+        let mut per_queue: Vec<_> = metrics.publishes_per_queue().into_iter().collect();
+        per_queue.sort();
+        for (queue, total) in per_queue {
+            let pushed = self
+                .pushed
+                .per_queue
+                .insert(queue.clone(), total)
+                .unwrap_or_default();
+            if total > pushed {
+                lines.push(self.line(
+                    "publishes",
+                    total - pushed,
+                    "c",
+                    &[format!("queue:{}", queue)],
+                ));
+            }
+        }
+        lines.push(self.line(
+            "channel_pool.size",
+            metrics.channel_pool_size.load(Ordering::Relaxed),
+            "g",
+            &[],
+        ));
+        for latency in metrics.take_update_latencies() {
+            lines.push(self.line("update.latency", latency.as_millis(), "ms", &[]));
+        }
+        lines
+    }
+
+    pub fn spawn(mut self) {
+        tokio::spawn(async move {
+            let socket = match UdpSocket::bind("0.0.0.0:0").await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("Could not open a socket for StatsD: {}", e);
+                    return;
+                }
+            };
+            info!("Pushing metrics to StatsD at {}.", self.config.address);
+            let mut ticker = tokio::time::interval(self.config.interval);
+            loop {
+                ticker.tick().await;
+                for packet in packets(self.lines()) {
+                    // Metrics are best effort; an agent that is down must not stall anything
+                    if let Err(e) = socket
+                        .send_to(packet.as_bytes(), &self.config.address)
+                        .await
+                    {
+                        warn!("Could not push metrics to StatsD: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Newline-separated lines, as many per packet as fit
+fn packets(lines: Vec<String>) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_BYTES => {
+                packet.push('\n');
+                packet.push_str(&line);
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flushes_send_what_changed_since_the_last_one() {
+        let metrics = Arc::new(Metrics::default());
+        let mut exporter = StatsdExporter::new(
+            StatsdConfig {
+                address: "localhost:8125".to_string(),
+                prefix: "bot.".to_string(),
+                tags: vec!["env:test".to_string()],
+                interval: Duration::from_secs(10),
+            },
+            Arc::clone(&metrics),
+        );
+        metrics.record_publish("Music", true);
+        metrics.record_publish("Music", true);
+        metrics.record_publish("Music", false);
+        metrics.record_update_latency(Duration::from_millis(12));
+
+        assert_eq!(
+            exporter.lines(),
+            vec![
+                "bot.publish_failures:1|c|#env:test",
+                "bot.publishes:2|c|#env:test,queue:Music",
+                "bot.channel_pool.size:0|g|#env:test",
+                "bot.update.latency:12|ms|#env:test",
+            ]
+        );
+        metrics.record_publish("Music", true);
+        assert_eq!(
+            exporter.lines(),
+            vec![
+                "bot.publishes:1|c|#env:test,queue:Music",
+                "bot.channel_pool.size:0|g|#env:test",
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_are_packed_into_packets() {
+        let line = "x".repeat(1000);
+
+        let packets = packets(vec![line.clone(), "a".into(), "b".into(), line.clone()]);
+
+        assert_eq!(packets, vec![format!("{}\na\nb", line), line]);
+    }
+}
@@ -0,0 +1,132 @@
+use log::{error, info, warn};
+use teloxide::{
+    payloads::{DeleteWebhookSetters, SetWebhookSetters},
+    requests::Requester,
+    Bot,
+};
+use url::Url;
+
+use crate::bots::{BotRegistry, DEFAULT_BOT_ID};
+
+// setWebhook parameters shared by every bot
+#[derive(Debug, Clone)]
+pub struct WebhookSettings {
+    // Public base URL of this server, e.g. https://bots.example.com
+    pub public_url: Url,
+    pub max_connections: Option<u8>,
+    pub drop_pending_updates: bool,
+}
+
+struct BotWebhook {
+    bot_id: String,
+    api: Bot,
+    url: Url,
+    secret_token: Option<String>,
+}
+
+// Points Telegram at this server on startup and detaches it on shutdown, for bots with an API token
+pub struct WebhookRegistrar {
+    settings: WebhookSettings,
+    webhooks: Vec<BotWebhook>,
+}
+
+impl WebhookRegistrar {
+    pub fn new(settings: WebhookSettings, bots: &BotRegistry) -> Self {
+        let mut webhooks = Vec::new();
+        for bot in bots.iter() {
+            let Some(api_token) = &bot.api_token else {
+                warn!(
+                    "Bot {} has no API token, its webhook is not registered.",
+                    bot.id
+                );
+                continue;
+            };
+            webhooks.push(BotWebhook {
+                bot_id: bot.id.clone(),
+                api: Bot::new(api_token),
+                url: webhook_url(&settings.public_url, &bot.id),
+                secret_token: bot.secret_token.clone(),
+            });
+        }
+        Self { settings, webhooks }
+    }
+
+    // Failures are logged rather than fatal: the server still works with a webhook set by hand
+    pub async fn register(&self) {
+        for webhook in &self.webhooks {
+            let mut request = webhook
+                .api
+                .set_webhook(webhook.url.clone())
+                .drop_pending_updates(self.settings.drop_pending_updates);
+            if let Some(max_connections) = self.settings.max_connections {
+                request = request.max_connections(max_connections);
+            }
+            if let Some(secret_token) = &webhook.secret_token {
+                request = request.secret_token(secret_token);
+            }
+            match request.await {
+                Ok(_) => info!(
+                    "Registered the webhook of bot {} at {}.",
+                    webhook.bot_id, webhook.url
+                ),
+                Err(e) => error!(
+                    "Could not register the webhook of bot {}: {}",
+                    webhook.bot_id, e
+                ),
+            }
+        }
+    }
+
+    // Pending updates are kept so whichever instance registers next can still receive them
+    pub async fn unregister(&self) {
+        for webhook in &self.webhooks {
+            match webhook
+                .api
+                .delete_webhook()
+                .drop_pending_updates(false)
+                .await
+            {
+                Ok(_) => info!("Deleted the webhook of bot {}.", webhook.bot_id),
+                Err(e) => warn!(
+                    "Could not delete the webhook of bot {}: {}",
+                    webhook.bot_id, e
+                ),
+            }
+        }
+    }
+}
+
+// The default bot is served on /webhook, every other bot on /webhook/<bot_id>
+fn webhook_url(public_url: &Url, bot_id: &str) -> Url {
+    let path = if bot_id == DEFAULT_BOT_ID {
+        "webhook".to_string()
+    } else {
+        format!("webhook/{}", bot_id)
+    };
+    let mut base = public_url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join(&path)
+        .expect("a bot id is a valid URL path segment")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_urls_extend_the_public_path() {
+        let root = Url::parse("https://bots.example.com").unwrap();
+        let nested = Url::parse("https://example.com/telegram").unwrap();
+
+        assert_eq!(
+            webhook_url(&root, DEFAULT_BOT_ID).as_str(),
+            "https://bots.example.com/webhook"
+        );
+        assert_eq!(
+            webhook_url(&nested, "music").as_str(),
+            "https://example.com/telegram/webhook/music"
+        );
+    }
+}
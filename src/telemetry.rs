@@ -0,0 +1,117 @@
+use std::env;
+
+use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use log::info;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::TracerProvider};
+
+const TRACER_NAME: &str = "rustin_bot_publisher";
+
+// Installs the OTLP exporter when OTEL_EXPORTER_OTLP_ENDPOINT is set.
+// Keep the returned provider alive for the lifetime of the process.
+pub fn init_tracing() -> Option<TracerProvider> {
+    env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .expect("Failed to build OTLP span exporter");
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .build();
+
+    global::set_tracer_provider(provider.clone());
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    info!("Exporting traces over OTLP.");
+    Some(provider)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct FieldTableInjector<'a>(&'a mut FieldTable);
+
+impl Injector for FieldTableInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(
+            ShortString::from(key.to_string()),
+            AMQPValue::LongString(LongString::from(value)),
+        );
+    }
+}
+
+// Middleware giving every HTTP request its own server span, continuing an incoming traceparent
+pub async fn trace_request(request: Request, next: Next) -> Response {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(format!("{} {}", request.method(), request.uri().path()))
+        .with_kind(SpanKind::Server)
+        .with_attributes([
+            KeyValue::new("http.request.method", request.method().to_string()),
+            KeyValue::new("url.path", request.uri().path().to_string()),
+        ])
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let response = next.run(request).with_context(cx.clone()).await;
+
+    let span = cx.span();
+    let status = response.status();
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(status.as_u16()),
+    ));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+    span.end();
+    response
+}
+
+// Starts a producer span for a publish as a child of the current request span
+pub fn start_publish_span(destination: &str) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(format!("{} publish", destination))
+        .with_kind(SpanKind::Producer)
+        .with_attributes([
+            KeyValue::new("messaging.system", "rabbitmq"),
+            KeyValue::new("messaging.destination.name", destination.to_string()),
+        ])
+        .start(&tracer);
+    Context::current_with_span(span)
+}
+
+// Writes the W3C traceparent/tracestate of `cx` into AMQP headers for downstream consumers
+pub fn inject_context(cx: &Context, mut headers: FieldTable) -> FieldTable {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(cx, &mut FieldTableInjector(&mut headers))
+    });
+    headers
+}
+
+pub fn end_span(cx: &Context, error: Option<String>) {
+    let span = cx.span();
+    if let Some(error) = error {
+        span.set_status(Status::error(error));
+    }
+    span.end();
+}
@@ -0,0 +1,224 @@
+// Representative Telegram updates, trimmed to the fields the handlers read
+use serde_json::{json, Value};
+
+pub const CHAT_ID: i64 = 424242;
+pub const USER_ID: i64 = 1001;
+pub const GROUP_ID: i64 = -100_777;
+
+fn message(fields: Value) -> Value {
+    let mut message = json!({
+        "message_id": 7,
+        "date": 1_700_000_000,
+        "chat": { "id": CHAT_ID, "type": "private", "first_name": "Ana" },
+        "from": {
+            "id": USER_ID,
+            "is_bot": false,
+            "first_name": "Ana",
+            "language_code": "en"
+        }
+    });
+    for (key, value) in fields.as_object().expect("message fields are an object") {
+        message[key] = value.clone();
+    }
+    json!({ "update_id": 900_001, "message": message })
+}
+
+pub fn text_message(text: &str) -> Value {
+    message(json!({ "text": text }))
+}
+
+// A photo in three sizes, as Telegram sends them; the largest is "photo-large"
+pub fn photo_message(caption: Option<&str>) -> Value {
+    let mut fields = json!({
+        "photo": [
+            { "file_id": "photo-small", "file_unique_id": "s", "width": 90, "height": 67 },
+            { "file_id": "photo-large", "file_unique_id": "l", "width": 1280, "height": 960 },
+            { "file_id": "photo-medium", "file_unique_id": "m", "width": 320, "height": 240 }
+        ]
+    });
+    if let Some(caption) = caption {
+        fields["caption"] = json!(caption);
+    }
+    message(fields)
+}
+
+// A video, animation or document; animations also carry a document field, as Telegram sends them
+pub fn attachment_message(kind: &str, caption: Option<&str>) -> Value {
+    let file = json!({
+        "file_id": format!("{}-1", kind),
+        "file_unique_id": "f1",
+        "mime_type": "video/mp4"
+    });
+    let mut fields = json!({ kind: file });
+    if kind == "animation" {
+        fields["document"] = file;
+    }
+    if let Some(caption) = caption {
+        fields["caption"] = json!(caption);
+    }
+    message(fields)
+}
+
+// A music file with the tags Telegram extracts from it
+pub fn audio_message() -> Value {
+    message(json!({
+        "audio": {
+            "file_id": "audio-1",
+            "file_unique_id": "a1",
+            "duration": 215,
+            "performer": "Queen",
+            "title": "Bohemian Rhapsody",
+            "file_name": "bohemian.mp3",
+            "mime_type": "audio/mpeg",
+            "file_size": 5_160_000
+        }
+    }))
+}
+
+// Sent before the charge; the bot has 10 seconds to answer it
+pub fn pre_checkout_query() -> Value {
+    json!({
+        "update_id": 900_003,
+        "pre_checkout_query": {
+            "id": "pcq-1",
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ana" },
+            "currency": "EUR",
+            "total_amount": 499,
+            "invoice_payload": "premium-1m"
+        }
+    })
+}
+
+pub fn successful_payment() -> Value {
+    message(json!({
+        "successful_payment": {
+            "currency": "EUR",
+            "total_amount": 499,
+            "invoice_payload": "premium-1m",
+            "telegram_payment_charge_id": "tg-charge-1",
+            "provider_payment_charge_id": "provider-charge-1"
+        }
+    }))
+}
+
+// Vote counts of a poll the bot sent; poll updates carry no chat or user
+pub fn poll() -> Value {
+    json!({
+        "update_id": 900_004,
+        "poll": {
+            "id": "poll-1",
+            "question": "Best album?",
+            "options": [
+                { "text": "A Night at the Opera", "voter_count": 5 },
+                { "text": "News of the World", "voter_count": 3 }
+            ],
+            "total_voter_count": 8,
+            "is_closed": false,
+            "is_anonymous": false,
+            "type": "regular",
+            "allows_multiple_answers": false
+        }
+    })
+}
+
+pub fn poll_answer(option_ids: &[u32]) -> Value {
+    json!({
+        "update_id": 900_005,
+        "poll_answer": {
+            "poll_id": "poll-1",
+            "user": { "id": USER_ID, "is_bot": false, "first_name": "Ana", "username": "ana" },
+            "option_ids": option_ids
+        }
+    })
+}
+
+pub fn sticker_message() -> Value {
+    message(json!({
+        "sticker": {
+            "file_id": "sticker-1",
+            "file_unique_id": "st1",
+            "type": "regular",
+            "width": 512,
+            "height": 512,
+            "is_animated": false,
+            "is_video": false,
+            "emoji": "🎸",
+            "set_name": "RockPack"
+        }
+    }))
+}
+
+fn group_message(fields: Value) -> Value {
+    let mut update = message(fields);
+    update["message"]["chat"] =
+        json!({ "id": GROUP_ID, "type": "supergroup", "title": "Rock fans" });
+    update
+}
+
+// Ana writing in the group, e.g. plain chatter when the bot's privacy mode is off
+pub fn group_text_message(text: &str) -> Value {
+    group_message(json!({ "text": text }))
+}
+
+// Ana added two people to the group
+pub fn new_chat_members() -> Value {
+    group_message(json!({
+        "new_chat_members": [
+            { "id": 2001, "is_bot": false, "first_name": "Alice" },
+            { "id": 2002, "is_bot": false, "first_name": "Bob", "username": "bob" }
+        ]
+    }))
+}
+
+pub fn left_chat_member() -> Value {
+    group_message(json!({
+        "left_chat_member": { "id": 2002, "is_bot": false, "first_name": "Bob", "username": "bob" }
+    }))
+}
+
+// Ana swapped a thumbs up for a fire reaction, next to a custom emoji
+pub fn message_reaction() -> Value {
+    json!({
+        "update_id": 900_006,
+        "message_reaction": {
+            "chat": { "id": GROUP_ID, "type": "supergroup", "title": "Rock fans" },
+            "message_id": 55,
+            "user": { "id": USER_ID, "is_bot": false, "first_name": "Ana" },
+            "date": 1_700_000_200,
+            "old_reaction": [{ "type": "emoji", "emoji": "👍" }],
+            "new_reaction": [
+                { "type": "emoji", "emoji": "🔥" },
+                { "type": "custom_emoji", "custom_emoji_id": "5368324170671202286" }
+            ]
+        }
+    })
+}
+
+pub fn with_language(mut update: Value, language_code: &str) -> Value {
+    update["message"]["from"]["language_code"] = json!(language_code);
+    update
+}
+
+// Marks a bot_command entity on the text; offset and length count UTF-16 code units
+pub fn with_command_entity(mut update: Value, offset: usize, length: usize) -> Value {
+    update["message"]["entities"] =
+        json!([{ "type": "bot_command", "offset": offset, "length": length }]);
+    update
+}
+
+// An update type the handlers do not understand (no message.chat.id)
+pub fn edited_channel_post() -> Value {
+    json!({
+        "update_id": 900_002,
+        "edited_channel_post": {
+            "message_id": 8,
+            "date": 1_700_000_100,
+            "chat": { "id": -100123, "type": "channel" },
+            "text": "edited"
+        }
+    })
+}
+
+pub fn to_body(update: &Value) -> axum::body::Bytes {
+    axum::body::Bytes::from(serde_json::to_vec(update).expect("fixtures serialize"))
+}
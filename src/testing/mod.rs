@@ -0,0 +1,117 @@
+// Test doubles for exercising the webhook pipeline without a broker
+pub mod fixtures;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    bots::{BotConfig, BotRegistry, DEFAULT_BOT_ID},
+    broadcast::{Broadcaster, InMemoryChatDirectory},
+    cooldowns::CommandCooldowns,
+    flood::{FloodConfig, FloodGuard},
+    i18n::Translations,
+    metrics::Metrics,
+    publisher::{MessagePublisher, PublishError, RabbitMessage},
+    reminders::ReminderScheduler,
+    routing::{Routes, RoutingTable},
+    sessions::InMemorySessionStore,
+    settings::FileSettingsStore,
+    tiers::UserTiers,
+    webhook_handler::{Dispatcher, UNKNOWN_COMMAND},
+};
+
+// Records every publish instead of talking to RabbitMQ
+#[derive(Default)]
+pub struct RecordingPublisher {
+    published: Mutex<Vec<(String, RabbitMessage)>>,
+    fail_with: Mutex<Option<fn() -> PublishError>>,
+}
+
+impl RecordingPublisher {
+    // Makes every following publish fail with the error built by `error`
+    pub fn fail_with(&self, error: fn() -> PublishError) {
+        *self.fail_with.lock().unwrap() = Some(error);
+    }
+
+    // Makes publishes succeed again after `fail_with`
+    pub fn succeed(&self) {
+        *self.fail_with.lock().unwrap() = None;
+    }
+
+    pub fn published(&self) -> Vec<(String, RabbitMessage)> {
+        self.published.lock().unwrap().clone()
+    }
+
+    pub fn queues(&self) -> Vec<String> {
+        self.published()
+            .into_iter()
+            .map(|(queue, _)| queue)
+            .collect()
+    }
+
+    pub fn texts_for(&self, queue: &str) -> Vec<String> {
+        self.published()
+            .into_iter()
+            .filter(|(published_to, _)| published_to == queue)
+            .map(|(_, message)| message.text)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for RecordingPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        if let Some(error) = *self.fail_with.lock().unwrap() {
+            return Err(error());
+        }
+        self.published
+            .lock()
+            .unwrap()
+            .push((destination.to_string(), message.clone()));
+        Ok(())
+    }
+}
+
+// A dispatcher for the default bot with production defaults and no cooldowns
+pub fn dispatcher(publisher: Arc<RecordingPublisher>) -> Dispatcher {
+    dispatcher_with_bots(publisher, vec![BotConfig::new(DEFAULT_BOT_ID)])
+}
+
+pub fn dispatcher_with_bots(
+    publisher: Arc<RecordingPublisher>,
+    bots: Vec<BotConfig>,
+) -> Dispatcher {
+    Dispatcher {
+        publisher,
+        bots: Arc::new(BotRegistry::new(bots)),
+        translations: Arc::new(Translations::bundled()),
+        sessions: Arc::new(InMemorySessionStore::new(Duration::from_secs(300))),
+        cooldowns: Arc::new(CommandCooldowns::new(HashMap::new())),
+        flood: Arc::new(FloodGuard::new(FloodConfig::default())),
+        metrics: Arc::new(Metrics::default()),
+        routes: Arc::new(Routes::new(RoutingTable::default())),
+        tiers: Arc::new(UserTiers::default()),
+        admin_chat_ids: Arc::default(),
+        broadcaster: Arc::new(Broadcaster {
+            chats: Arc::new(InMemoryChatDirectory::default()),
+            interval: Duration::ZERO,
+        }),
+        reminders: Arc::new(ReminderScheduler::load(None)),
+        command_switches: Arc::default(),
+        unknown_command_replies: Arc::new(CommandCooldowns::single(
+            UNKNOWN_COMMAND,
+            Duration::from_secs(60),
+        )),
+        user_records: None,
+        settings: Arc::new(FileSettingsStore::load(None)),
+    }
+}
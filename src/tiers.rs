@@ -0,0 +1,147 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::Deserialize;
+
+use crate::bots::SonglinksLimits;
+
+// One [tiers.<name>] table of the config file; unset limits keep the bot's own
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TierConfig {
+    #[serde(default)]
+    pub users: Vec<i64>,
+    pub songlinks_max_lines: Option<usize>,
+    pub songlinks_max_chars: Option<usize>,
+    // Command -> seconds, replacing the COOLDOWN_<COMMAND>_SECS setting
+    pub cooldowns: Option<HashMap<String, u64>>,
+    // AMQP priority of the user's messages; the queue needs x-max-priority to honour it
+    pub priority: Option<u8>,
+}
+
+// What users of one tier get instead of the defaults
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tier {
+    pub name: String,
+    pub songlinks_max_lines: Option<usize>,
+    pub songlinks_max_chars: Option<usize>,
+    pub cooldowns: HashMap<String, Duration>,
+    pub priority: Option<u8>,
+}
+
+impl Tier {
+    pub fn songlinks_limits(&self, defaults: &SonglinksLimits) -> SonglinksLimits {
+        SonglinksLimits {
+            max_lines: self.songlinks_max_lines.unwrap_or(defaults.max_lines),
+            max_line_chars: self.songlinks_max_chars.unwrap_or(defaults.max_line_chars),
+        }
+    }
+
+    pub fn cooldown(&self, command: &str) -> Option<Duration> {
+        self.cooldowns.get(command).copied()
+    }
+}
+
+// Which tier each user id belongs to; users in no tier get the defaults
+#[derive(Debug, Clone, Default)]
+pub struct UserTiers {
+    tiers: HashMap<String, Tier>,
+    users: HashMap<i64, String>,
+}
+
+impl UserTiers {
+    pub fn new(configs: HashMap<String, TierConfig>) -> Result<Self, Vec<String>> {
+        let mut tiers = HashMap::new();
+        let mut users = HashMap::new();
+        let mut problems = Vec::new();
+        for (name, config) in configs {
+            for (field, value) in [
+                ("songlinks_max_lines", config.songlinks_max_lines),
+                ("songlinks_max_chars", config.songlinks_max_chars),
+            ] {
+                if value == Some(0) {
+                    problems.push(format!(
+                        "tiers.{}.{}: must be greater than zero",
+                        name, field
+                    ));
+                }
+            }
+            let mut cooldowns = HashMap::new();
+            for (command, secs) in config.cooldowns.unwrap_or_default() {
+                if !command.starts_with('/') {
+                    problems.push(format!(
+                        "tiers.{}.cooldowns: {:?} is not a /command",
+                        name, command
+                    ));
+                    continue;
+                }
+                cooldowns.insert(command, Duration::from_secs(secs));
+            }
+            for user in config.users {
+                if let Some(other) = users.insert(user, name.clone()) {
+                    problems.push(format!(
+                        "tiers.{}.users: user {} is already in tier {}",
+                        name, user, other
+                    ));
+                }
+            }
+            let tier = Tier {
+                name: name.clone(),
+                songlinks_max_lines: config.songlinks_max_lines,
+                songlinks_max_chars: config.songlinks_max_chars,
+                cooldowns,
+                priority: config.priority,
+            };
+            tiers.insert(name, tier);
+        }
+        if problems.is_empty() {
+            Ok(Self { tiers, users })
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn for_user(&self, user_id: i64) -> Option<&Tier> {
+        self.tiers.get(self.users.get(&user_id)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn premium() -> TierConfig {
+        TierConfig {
+            users: vec![42],
+            songlinks_max_lines: Some(30),
+            cooldowns: Some([("/readimage".to_string(), 5)].into()),
+            priority: Some(5),
+            ..TierConfig::default()
+        }
+    }
+
+    #[test]
+    fn tier_members_get_its_limits() {
+        let tiers = UserTiers::new([("premium".to_string(), premium())].into()).unwrap();
+
+        let tier = tiers.for_user(42).unwrap();
+        let limits = tier.songlinks_limits(&SonglinksLimits::default());
+        assert_eq!(limits.max_lines, 30);
+        assert_eq!(
+            limits.max_line_chars,
+            SonglinksLimits::default().max_line_chars
+        );
+        assert_eq!(tier.cooldown("/readimage"), Some(Duration::from_secs(5)));
+        assert!(tiers.for_user(7).is_none());
+    }
+
+    #[test]
+    fn a_user_belongs_to_one_tier() {
+        let configs = [
+            ("premium".to_string(), premium()),
+            ("gold".to_string(), premium()),
+        ]
+        .into();
+
+        assert_eq!(UserTiers::new(configs).unwrap_err().len(), 1);
+    }
+}
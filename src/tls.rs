@@ -0,0 +1,85 @@
+use std::{fs, future::Future, net::SocketAddr, path::PathBuf, time::Duration};
+
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use log::info;
+
+// How long in-flight requests may finish once shutdown starts
+const GRACEFUL_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Certificate settings for terminating HTTPS in-process instead of behind nginx
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    // When set, a self-signed certificate for this domain (or IP) is generated if missing.
+    // Telegram accepts such certificates when the PEM is uploaded with setWebhook.
+    self_signed_domain: Option<String>,
+}
+
+impl TlsSettings {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf, self_signed_domain: Option<String>) -> Self {
+        Self {
+            cert_path,
+            key_path,
+            self_signed_domain,
+        }
+    }
+
+    pub async fn rustls_config(&self) -> RustlsConfig {
+        if let Some(domain) = &self.self_signed_domain {
+            if !self.cert_path.exists() || !self.key_path.exists() {
+                self.generate_self_signed(domain);
+            }
+        }
+        RustlsConfig::from_pem_file(&self.cert_path, &self.key_path)
+            .await
+            .expect("Failed to load TLS certificate and key")
+    }
+
+    fn generate_self_signed(&self, domain: &str) {
+        let certified = rcgen::generate_simple_self_signed(vec![domain.to_string()])
+            .expect("Failed to generate self-signed certificate");
+        fs::write(&self.cert_path, certified.cert.pem())
+            .expect("Failed to write self-signed certificate");
+        fs::write(&self.key_path, certified.key_pair.serialize_pem())
+            .expect("Failed to write self-signed key");
+        info!(
+            "Generated self-signed certificate for {} at {}; upload it to Telegram with setWebhook.",
+            domain,
+            self.cert_path.display()
+        );
+    }
+}
+
+// Serves the application over HTTPS until the process exits
+pub async fn serve(
+    address: &str,
+    app: Router,
+    settings: TlsSettings,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    let address: SocketAddr = address
+        .parse()
+        .expect("server_address is validated with the config");
+    let config = settings.rustls_config().await;
+
+    println!("Listening on https://{}", address);
+
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(Some(GRACEFUL_SHUTDOWN_TIMEOUT));
+    });
+
+    axum_server::bind_rustls(address, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .expect("Error serving application");
+}
@@ -0,0 +1,61 @@
+use serde_json::Value;
+
+// What an update is, as far as the dispatcher cares, decided from a few fields only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateClass {
+    // Text or caption starting with a /command
+    Command,
+    // A photo, document or other file with a caption but no command
+    MediaWithCaption,
+    Other,
+}
+
+fn starts_with_command(text: &Value) -> bool {
+    text.as_str().is_some_and(|text| text.starts_with('/'))
+}
+
+pub fn classify(payload: &Value) -> UpdateClass {
+    let message = &payload["message"];
+    if starts_with_command(&message["text"]) || starts_with_command(&message["caption"]) {
+        UpdateClass::Command
+    } else if message["caption"].is_string() {
+        UpdateClass::MediaWithCaption
+    } else {
+        UpdateClass::Other
+    }
+}
+
+// Plain text in a group that mentions no command: with privacy mode off, most of what a bot in
+// a busy group receives, and nothing the dispatcher acts on unless the chat is answering a
+// prompt. Media, service messages and every other update type are left to the dispatcher.
+pub fn is_group_chatter(payload: &Value, class: UpdateClass) -> bool {
+    let message = &payload["message"];
+    class == UpdateClass::Other
+        && matches!(
+            message["chat"]["type"].as_str(),
+            Some("group" | "supergroup")
+        )
+        && message["text"].is_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures;
+
+    #[test]
+    fn only_plain_group_text_is_chatter() {
+        let chatter = fixtures::group_text_message("anyone up for lunch?");
+        let command = fixtures::group_text_message("/songlinks\nBohemian Rhapsody");
+        let private_text = fixtures::text_message("anyone up for lunch?");
+        let captioned = fixtures::photo_message(Some("look at this"));
+
+        assert_eq!(classify(&command), UpdateClass::Command);
+        assert_eq!(classify(&captioned), UpdateClass::MediaWithCaption);
+        assert_eq!(classify(&chatter), UpdateClass::Other);
+        assert!(is_group_chatter(&chatter, classify(&chatter)));
+        assert!(!is_group_chatter(&command, classify(&command)));
+        assert!(!is_group_chatter(&private_text, classify(&private_text)));
+        assert!(!is_group_chatter(&captioned, classify(&captioned)));
+    }
+}
@@ -0,0 +1,49 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::errors::WebhookError;
+
+// Cheap structural check done on the raw bytes, before the JSON is parsed
+pub fn looks_like_update(body: &[u8]) -> bool {
+    let starts_with_object = body
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_some_and(|byte| *byte == b'{');
+    starts_with_object && contains(body, b"\"update_id\"")
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+// Middleware rejecting oversized bodies with 413 and non-update bodies with 400
+pub async fn validate_update(
+    State(max_body_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Result<Response, WebhookError> {
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, max_body_bytes)
+        .await
+        .map_err(|_| WebhookError::payload_too_large(max_body_bytes))?;
+
+    if !looks_like_update(&bytes) {
+        return Err(WebhookError::new(
+            StatusCode::BAD_REQUEST,
+            "not_an_update",
+            "body must be a JSON object containing update_id",
+        )
+        .with_field("update_id"));
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json};
+use chrono::{DateTime, SecondsFormat};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{publisher::Backend, webhook_handler::Dispatcher};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Both set by build.rs
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+// The optional cargo features compiled in
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "audit")]
+    "audit",
+    #[cfg(feature = "encryption")]
+    "encryption",
+    #[cfg(feature = "grpc")]
+    "grpc",
+    #[cfg(feature = "gzip")]
+    "gzip",
+    #[cfg(feature = "mqtt")]
+    "mqtt",
+    #[cfg(feature = "msgpack")]
+    "msgpack",
+    #[cfg(feature = "otel")]
+    "otel",
+    #[cfg(feature = "protobuf")]
+    "protobuf",
+    #[cfg(feature = "redis")]
+    "redis",
+    #[cfg(feature = "sentry")]
+    "sentry",
+    #[cfg(feature = "signing")]
+    "signing",
+    #[cfg(feature = "sqs")]
+    "sqs",
+    #[cfg(feature = "swagger-ui")]
+    "swagger-ui",
+    #[cfg(feature = "tls")]
+    "tls",
+];
+
+// What is running: the build, and the parts of the config that decide what it does
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BuildInfo {
+    #[schema(example = "0.1.0")]
+    pub version: &'static str,
+    #[schema(example = "5bd1b1d0c2a4")]
+    pub git_commit: &'static str,
+    // RFC 3339, UTC
+    #[schema(example = "2026-10-15T09:30:00Z")]
+    pub build_timestamp: String,
+    pub features: &'static [&'static str],
+    #[schema(example = "rabbitmq")]
+    pub backend: String,
+    // Routed commands not switched off through the admin API
+    #[schema(example = json!(["/songlinks", "/readimage"]))]
+    pub commands: Vec<String>,
+}
+
+pub fn build_timestamp() -> String {
+    BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|| BUILD_TIMESTAMP.to_string())
+}
+
+fn build_info(dispatcher: &Dispatcher, backend: Backend) -> BuildInfo {
+    let routing = dispatcher.routes.current();
+    BuildInfo {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        build_timestamp: build_timestamp(),
+        features: FEATURES,
+        backend: backend.to_string(),
+        commands: routing
+            .commands()
+            .into_iter()
+            .filter(|command| dispatcher.command_switches.is_enabled(command))
+            .map(str::to_string)
+            .collect(),
+    }
+}
+
+// GET /version
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "health",
+    responses((status = 200, description = "The build and its configuration", body = BuildInfo))
+)]
+pub async fn version(
+    Extension(dispatcher): Extension<Arc<Dispatcher>>,
+    Extension(backend): Extension<Backend>,
+) -> Json<BuildInfo> {
+    Json(build_info(&dispatcher, backend))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dispatcher, RecordingPublisher};
+
+    #[test]
+    fn switched_off_commands_are_not_listed() {
+        let dispatcher = dispatcher(Arc::new(RecordingPublisher::default()));
+        dispatcher.command_switches.set_enabled("/readimage", false);
+
+        let info = build_info(&dispatcher, Backend::RabbitMq);
+
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_commit.is_empty());
+        assert!(info.build_timestamp.ends_with('Z'));
+        assert_eq!(info.backend, "rabbitmq");
+        assert!(info.commands.contains(&"/songlinks".to_string()));
+        assert!(!info.commands.contains(&"/readimage".to_string()));
+    }
+}
@@ -1,66 +1,945 @@
-use axum::{debug_handler, http::StatusCode, Extension, Json};
-use lapin::{options::BasicPublishOptions, BasicProperties, Channel};
-use log::info;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::{iter::Cycle, sync::Arc, vec::IntoIter};
-use tokio::sync::Mutex;
+use axum::{
+    body::Bytes,
+    debug_handler,
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    Extension,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Instant, SystemTime},
+};
 
-pub struct ChannelPool {
-    channels: Mutex<Cycle<IntoIter<Arc<Channel>>>>,
+use crate::{
+    access_log,
+    admin::CommandSwitches,
+    bots::{BotConfig, BotRegistry, SonglinksLimits, DEFAULT_BOT_ID},
+    broadcast::Broadcaster,
+    cooldowns::CommandCooldowns,
+    deletion::{UserRecords, DELETION_REQUEST_EVENT},
+    errors::WebhookError,
+    flood::{FloodGuard, FloodVerdict},
+    i18n::Translations,
+    metrics::Metrics,
+    openapi::{TelegramUpdate, WebhookResponses},
+    publisher::{MessageMetadata, MessagePublisher, RabbitMessage, ReplyContext},
+    reminders::{parse_delay, ReminderScheduler, ScheduleError, MAX_PENDING_PER_CHAT},
+    routing::{Routes, RoutingTable, REPLY_QUEUE},
+    sessions::{ExpectedStep, SessionKey, SessionStore},
+    settings::{SettingError, SettingsStore, DEFAULT_VALUE, SETTING_NAMES},
+    tiers::{Tier, UserTiers},
+    update_filter::{self, UpdateClass},
+};
+
+pub const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+// Producers other than Telegram may also sign updates, with HMAC_WEBHOOK_SECRET
+pub const SOURCE: &str = "webhook";
+// The cooldown key of the unknown-command reply, checked per chat
+pub const UNKNOWN_COMMAND: &str = "unknown";
+
+// Everything needed to turn a Telegram update into queue messages
+pub struct Dispatcher {
+    pub publisher: Arc<dyn MessagePublisher>,
+    pub bots: Arc<BotRegistry>,
+    pub translations: Arc<Translations>,
+    pub sessions: Arc<dyn SessionStore>,
+    pub cooldowns: Arc<CommandCooldowns>,
+    pub flood: Arc<FloodGuard>,
+    pub metrics: Arc<Metrics>,
+    pub routes: Arc<Routes>,
+    pub tiers: Arc<UserTiers>,
+    // Chats allowed to use admin commands
+    pub admin_chat_ids: Arc<HashSet<i64>>,
+    pub broadcaster: Arc<Broadcaster>,
+    pub reminders: Arc<ReminderScheduler>,
+    pub command_switches: Arc<CommandSwitches>,
+    // Keeps the unknown-command reply from answering every message of a chat
+    pub unknown_command_replies: Arc<CommandCooldowns>,
+    // What /deleteme purges locally; None when nothing is kept about users
+    pub user_records: Option<Arc<dyn UserRecords>>,
+    pub settings: Arc<dyn SettingsStore>,
+}
+
+// Per-update data shared by the command handlers
+struct UpdateContext<'a> {
+    bot: &'a BotConfig,
+    // None for updates that belong to no chat, such as poll state changes
+    chat_id: Option<i64>,
+    command: Option<&'a str>,
+    language_code: Option<&'a str>,
+    reply_to: Option<ReplyContext>,
+    metadata: MessageMetadata,
+    // The sender's tier, if they are in one
+    tier: Option<&'a Tier>,
+    publisher: &'a dyn MessagePublisher,
+    translations: &'a Translations,
+    routing: &'a RoutingTable,
+    // Queues published to while handling this update, for the summary log line
+    published_queues: &'a Mutex<Vec<String>>,
 }
 
-impl ChannelPool {
-    pub fn new(channels: Vec<Arc<Channel>>) -> Self {
-        let channel_iter = channels.into_iter().cycle();
-        Self {
-            channels: Mutex::new(channel_iter),
+impl UpdateContext<'_> {
+    // Reply text in the sender's language
+    fn text(&self, key: &str, args: &[(&str, String)]) -> String {
+        self.translations.text(self.language_code, key, args)
+    }
+
+    fn songlinks_limits(&self) -> SonglinksLimits {
+        match self.tier {
+            Some(tier) => tier.songlinks_limits(&self.bot.songlinks_limits),
+            None => self.bot.songlinks_limits.clone(),
         }
     }
 
-    pub async fn get_next_channel(&self) -> Arc<Channel> {
-        let mut channels = self.channels.lock().await;
-        channels.next().expect("Channel pool should never be empty")
+    // The queue a built-in command publishes its output to
+    fn route(&self, command: &str) -> &str {
+        self.routing
+            .queue_for(command)
+            .expect("built-in commands always have a route")
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct RabbitMessage {
-    chat_id: i64,
-    text: String,
+    // Publish a message for this chat to one of the bot's queues
+    async fn publish(&self, queue: &str, text: impl Into<String>) -> Result<(), WebhookError> {
+        self.publish_message(queue, self.message(text)).await
+    }
+
+    // Lets the user know a long-running command was queued, if the bot acknowledges it. The
+    // work is already published, so a failed acknowledgment must not make Telegram retry it.
+    async fn acknowledge(&self, command: &str) {
+        if !self.bot.acknowledged_commands.contains(command) {
+            return;
+        }
+        let text = match &self.bot.acknowledgment_text {
+            Some(text) => text.clone(),
+            None => self.text("processing", &[]),
+        };
+        if let Err(e) = self.publish(REPLY_QUEUE, text).await {
+            warn!("Could not acknowledge {}: {}", command, e.message);
+        }
+    }
+
+    fn message(&self, text: impl Into<String>) -> RabbitMessage {
+        let message = match self.chat_id {
+            Some(chat_id) => RabbitMessage::chat(&self.bot.id, chat_id, text),
+            None => RabbitMessage::bot(&self.bot.id, text),
+        };
+        message
+            .with_metadata(self.metadata.clone())
+            .with_reply_to(self.reply_to.clone())
+            .with_priority(self.tier.and_then(|tier| tier.priority))
+    }
+
+    async fn publish_message(
+        &self,
+        queue: &str,
+        message: RabbitMessage,
+    ) -> Result<(), WebhookError> {
+        let queue_name = self.bot.queue_name(queue);
+        let result = publish_to_queue(&queue_name, message, self.publisher).await;
+        if result.is_ok() {
+            self.published_queues
+                .lock()
+                .expect("published queues lock poisoned")
+                .push(queue_name.clone());
+        }
+
+        #[cfg(feature = "sentry")]
+        if let Err(err) = &result {
+            crate::error_reporting::capture_publish_failure(
+                &self.bot.id,
+                self.chat_id,
+                self.command,
+                &queue_name,
+                &err.message,
+            );
+        }
+
+        result
+    }
 }
 
+// Webhook for the default bot, kept for single-bot deployments
+#[utoipa::path(
+    post,
+    path = "/webhook",
+    tag = "webhook",
+    request_body = TelegramUpdate,
+    params(
+        ("X-Telegram-Bot-Api-Secret-Token" = Option<String>, Header,
+            description = "The bot's secret token, required when one is configured"),
+    ),
+    responses(WebhookResponses)
+)]
 #[debug_handler]
 pub async fn receive_message(
-    Extension(channel_pool): Extension<Arc<ChannelPool>>,
-    Json(payload): Json<Value>,
-) -> Result<StatusCode, StatusCode> {
-    info!("Received message payload: {:?}", payload);
-
-    if let Some(chat_id) = extract_chat_id(&payload) {
-        if let Some(command) = extract_caption(&payload) {
-            match command {
-                "/readimage" => handle_readimage(chat_id, &payload, &channel_pool).await?,
-                _ => return Ok(StatusCode::OK),
+    Extension(dispatcher): Extension<Arc<Dispatcher>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, WebhookError> {
+    let payload = parse_update(&body)?;
+    dispatcher
+        .dispatch(DEFAULT_BOT_ID, &headers, &payload)
+        .await
+}
+
+// Webhook for a specific bot, registered with Telegram as /webhook/<bot_id>
+#[utoipa::path(
+    post,
+    path = "/webhook/{bot_id}",
+    tag = "webhook",
+    request_body = TelegramUpdate,
+    params(
+        ("bot_id" = String, Path, description = "One of BOT_IDS"),
+        ("X-Telegram-Bot-Api-Secret-Token" = Option<String>, Header,
+            description = "The bot's secret token, required when one is configured"),
+    ),
+    responses(WebhookResponses)
+)]
+#[debug_handler]
+pub async fn receive_bot_message(
+    Path(bot_id): Path<String>,
+    Extension(dispatcher): Extension<Arc<Dispatcher>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, WebhookError> {
+    let payload = parse_update(&body)?;
+    dispatcher.dispatch(&bot_id, &headers, &payload).await
+}
+
+fn parse_update(body: &[u8]) -> Result<Value, WebhookError> {
+    serde_json::from_slice(body).map_err(|e| WebhookError::invalid_json(e.to_string()))
+}
+
+impl Dispatcher {
+    // Remember what the chat's next message should be and tell the user what to send
+    async fn expect_step(
+        &self,
+        ctx: &UpdateContext<'_>,
+        session_key: &SessionKey,
+        step: ExpectedStep,
+        prompt_key: &str,
+    ) -> Result<(), WebhookError> {
+        self.sessions.set(session_key, step).await;
+        let prompt = ctx.text(
+            prompt_key,
+            &[("max_lines", ctx.songlinks_limits().max_lines.to_string())],
+        );
+        ctx.publish(REPLY_QUEUE, prompt).await?;
+        info!("Published '{}' prompt to Reply queue.", prompt_key);
+        Ok(())
+    }
+
+    // Announces `text` to every known chat of the bot and tells the admin how many that is
+    async fn handle_broadcast_command(
+        &self,
+        ctx: &UpdateContext<'_>,
+        text: &str,
+    ) -> Result<(), WebhookError> {
+        let text = text.trim();
+        let reply = if text.is_empty() {
+            "Usage: /broadcast <text>".to_string()
+        } else {
+            match self
+                .broadcaster
+                .start(Arc::clone(&self.publisher), ctx.bot, text)
+                .await
+            {
+                Ok(count) => format!("Broadcasting to {} chats.", count),
+                Err(e) => {
+                    warn!("Could not look up the chats to broadcast to: {}", e);
+                    "Could not look up the chats to broadcast to.".to_string()
+                }
             }
-        } else if let Some(text) = extract_text(&payload) {
-            if text == "/help" {
-                handle_help_command(chat_id, &channel_pool).await?;
-            } else if text.starts_with("/songlinks") {
-                handle_songlinks(chat_id, text, &channel_pool).await?;
+        };
+        ctx.publish(REPLY_QUEUE, reply).await?;
+        info!("Handled /broadcast for bot '{}'.", ctx.bot.id);
+        Ok(())
+    }
+
+    // "/remind 2h take a break": schedules the text to be sent back to the chat after the delay
+    async fn handle_remind_command(
+        &self,
+        ctx: &UpdateContext<'_>,
+        chat_id: i64,
+        args: &str,
+    ) -> Result<(), WebhookError> {
+        let (spec, text) = args
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((args, ""));
+        let (spec, text) = (spec.trim(), text.trim());
+        let reply = match parse_delay(spec).filter(|_| !text.is_empty()) {
+            None => ctx.text("remind.usage", &[]),
+            Some(delay) => match self
+                .reminders
+                .schedule(&ctx.bot.id, chat_id, delay, text)
+                .await
+            {
+                Ok(()) => ctx.text("remind.scheduled", &[("delay", spec.to_string())]),
+                Err(ScheduleError::TooMany) => ctx.text(
+                    "remind.too_many",
+                    &[("max", MAX_PENDING_PER_CHAT.to_string())],
+                ),
+            },
+        };
+        ctx.publish(REPLY_QUEUE, reply).await?;
+        info!("Handled /remind for chat {}.", chat_id);
+        Ok(())
+    }
+
+    // Asks the consumers of the Deletion queue to erase the user's data, then purges what this
+    // service keeps. The request is published first, so Telegram retries if it cannot be, and
+    // the purge comes last, so it also covers the records of the request and the reply.
+    async fn handle_deleteme(
+        &self,
+        ctx: &UpdateContext<'_>,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<(), WebhookError> {
+        let data = json!({
+            "chat_id": chat_id,
+            "user_id": user_id,
+            "requested_at": DateTime::<Utc>::from(SystemTime::now())
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+        });
+        let message = ctx
+            .message(user_id.to_string())
+            .with_event(DELETION_REQUEST_EVENT)
+            .with_data(data);
+        let queue = ctx.route("/deleteme");
+        ctx.publish_message(queue, message).await?;
+        info!(
+            "Published deletion request of user {} to {} queue.",
+            user_id, queue
+        );
+
+        ctx.publish(REPLY_QUEUE, ctx.text("deleteme.requested", &[]))
+            .await?;
+
+        if let Some(records) = &self.user_records {
+            match records.purge_user(&ctx.bot.id, user_id).await {
+                Ok(purged) => info!("Purged {} stored records of user {}.", purged, user_id),
+                Err(e) => warn!(
+                    "Could not purge the stored records of user {}: {}",
+                    user_id, e
+                ),
             }
         }
-    } else {
-        info!("No valid chat_id found in the message payload.");
-        return Err(StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    // "/settings" lists the chat's settings, "/settings verbosity short" changes one and
+    // "/settings verbosity default" resets it
+    async fn handle_settings_command(
+        &self,
+        ctx: &UpdateContext<'_>,
+        chat_id: i64,
+        args: &str,
+    ) -> Result<(), WebhookError> {
+        let mut settings = self.settings.get(&ctx.bot.id, chat_id).await;
+        let usage = || ctx.text("settings.usage", &[("names", SETTING_NAMES.join(", "))]);
+        let mut words = args.split_whitespace();
+        let reply = match (words.next(), words.next()) {
+            (None, _) => {
+                let current = SETTING_NAMES
+                    .iter()
+                    .map(|name| {
+                        format!("{}: {}", name, settings.get(name).unwrap_or(DEFAULT_VALUE))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                ctx.text("settings.current", &[("settings", current)])
+            }
+            (Some(_), None) => usage(),
+            (Some(name), Some(value)) => {
+                match settings.apply(name, value, &ctx.translations.languages()) {
+                    Ok(()) => {
+                        let value = settings.get(name).unwrap_or(DEFAULT_VALUE).to_string();
+                        // Confirmed in the language the chat gets from now on
+                        let language = settings
+                            .language
+                            .clone()
+                            .or_else(|| ctx.metadata.language_code.clone());
+                        self.settings.set(&ctx.bot.id, chat_id, settings).await;
+                        info!("Set {} to {} for chat {}.", name, value, chat_id);
+                        ctx.translations.text(
+                            language.as_deref(),
+                            "settings.updated",
+                            &[("name", name.to_string()), ("value", value)],
+                        )
+                    }
+                    Err(SettingError::UnknownSetting) => usage(),
+                    Err(SettingError::InvalidValue(allowed)) => ctx.text(
+                        "settings.invalid",
+                        &[("name", name.to_string()), ("values", allowed.join(", "))],
+                    ),
+                }
+            }
+        };
+        ctx.publish(REPLY_QUEUE, reply).await
+    }
+
+    // Points the user to /help, at most once per cooldown per chat
+    async fn handle_unknown_command(
+        &self,
+        ctx: &UpdateContext<'_>,
+        chat_id: i64,
+        command: &str,
+    ) -> Result<(), WebhookError> {
+        if self
+            .unknown_command_replies
+            .check(&ctx.bot.id, chat_id, UNKNOWN_COMMAND, None)
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+        ctx.publish(
+            REPLY_QUEUE,
+            ctx.text("unknown_command", &[("command", command.to_string())]),
+        )
+        .await?;
+        info!(
+            "Replied to unknown command {} in chat {}.",
+            command, chat_id
+        );
+        Ok(())
+    }
+
+    pub async fn dispatch(
+        &self,
+        bot_id: &str,
+        headers: &HeaderMap,
+        payload: &Value,
+    ) -> Result<StatusCode, WebhookError> {
+        let started = Instant::now();
+        self.metrics.updates.fetch_add(1, Ordering::Relaxed);
+        let class = update_filter::classify(payload);
+        self.metrics.record_update_class(class);
+        let bot = self.authenticate(bot_id, headers);
+        if let Ok(bot) = bot {
+            if self.is_chatter(bot, payload, class).await {
+                self.metrics
+                    .filtered_updates
+                    .fetch_add(1, Ordering::Relaxed);
+                return Ok(StatusCode::OK);
+            }
+        }
+        let published_queues = Mutex::new(Vec::new());
+        let result = match bot {
+            Ok(bot) => self.handle_update(bot, payload, &published_queues).await,
+            Err(err) => Err(err),
+        };
+
+        // One structured line per handled update (fields show up as JSON keys with LOG_FORMAT=json)
+        let queues = published_queues
+            .into_inner()
+            .expect("published queues lock poisoned")
+            .join(",");
+        let status = match &result {
+            Ok(status) => status.as_u16(),
+            Err(err) => err.status.as_u16(),
+        };
+        let latency = started.elapsed();
+        self.metrics.record_update_latency(latency);
+        let latency_ms = latency.as_millis() as u64;
+        let chat_id = extract_chat_id(payload).unwrap_or_default();
+        let command = extract_command(payload).unwrap_or_default();
+        info!(
+            bot_id, chat_id, command, queue = queues.as_str(), status, latency_ms;
+            "Handled update for chat {} ({}) with status {} in {}ms",
+            chat_id, command, status, latency_ms
+        );
+        result
+    }
+
+    // The bot the update is for, once the request proves it comes from Telegram
+    fn authenticate(&self, bot_id: &str, headers: &HeaderMap) -> Result<&BotConfig, WebhookError> {
+        let Some(bot) = self.bots.get(bot_id) else {
+            return Err(WebhookError::unknown_bot(bot_id));
+        };
+
+        let provided_secret = headers
+            .get(SECRET_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if !bot.verify_secret(provided_secret) {
+            return Err(WebhookError::unauthorized(format!(
+                "invalid {} for bot '{}'",
+                SECRET_TOKEN_HEADER, bot_id
+            )));
+        }
+        Ok(bot)
     }
 
-    Ok(StatusCode::OK)
+    // Group chatter is dropped before any other work or logging, unless the chat is answering a
+    // prompt. It no longer counts towards flood limits or records the chat for broadcasts.
+    async fn is_chatter(&self, bot: &BotConfig, payload: &Value, class: UpdateClass) -> bool {
+        if !update_filter::is_group_chatter(payload, class) {
+            return false;
+        }
+        let Some(chat_id) = extract_chat_id(payload) else {
+            return false;
+        };
+        !self
+            .sessions
+            .is_pending(&SessionKey::new(&bot.id, chat_id))
+            .await
+    }
+
+    async fn handle_update(
+        &self,
+        bot: &BotConfig,
+        payload: &Value,
+        published_queues: &Mutex<Vec<String>>,
+    ) -> Result<StatusCode, WebhookError> {
+        // Snapshot the routing table so a reload mid-update cannot mix two tables
+        let routing = self.routes.current();
+        // Mentions and aliases are resolved here, so handlers and consumers only ever see the
+        // real command
+        let mut command_language = None;
+        let command = match extract_command(payload) {
+            Some(command) => match bot.own_command(command) {
+                // In busy groups only "/help@username", mentions and replies to the bot count
+                Some(own)
+                    if own == command
+                        && bot.requires_address(payload["message"]["chat"]["type"].as_str())
+                        && !is_addressed(&payload["message"], bot) =>
+                {
+                    self.metrics
+                        .unaddressed_commands
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Ok(StatusCode::OK);
+                }
+                Some(command) => {
+                    command_language = routing.alias_language(command);
+                    Some(routing.resolve(command))
+                }
+                // Addressed to another bot in the same group
+                None => return Ok(StatusCode::OK),
+            },
+            None => None,
+        };
+        if let Some(command) = command {
+            access_log::record_command(command);
+        }
+        let mut metadata = extract_metadata(payload);
+        metadata.command = command.map(str::to_string);
+        metadata.command_language = command_language.map(str::to_string);
+        let chat_id = extract_chat_id(payload);
+        // A language picked with /settings wins over that of a localized alias, which wins over
+        // the sender's
+        let settings = match chat_id {
+            Some(chat_id) => self.settings.get(&bot.id, chat_id).await,
+            None => Default::default(),
+        };
+        let ctx = UpdateContext {
+            bot,
+            chat_id,
+            command,
+            language_code: settings
+                .language
+                .as_deref()
+                .or(command_language)
+                .or_else(|| extract_language_code(payload)),
+            reply_to: extract_reply_context(payload),
+            metadata,
+            tier: extract_user_id(payload).and_then(|user_id| self.tiers.for_user(user_id)),
+            publisher: self.publisher.as_ref(),
+            translations: &self.translations,
+            routing: &routing,
+            published_queues,
+        };
+
+        // Payments are never muted or rate limited; a dropped pre-checkout query fails the payment
+        if let Some(query) = payload.get("pre_checkout_query") {
+            handle_pre_checkout_query(&ctx, query).await?;
+            return Ok(StatusCode::OK);
+        }
+        if let Some(payment) = payload["message"].get("successful_payment") {
+            handle_successful_payment(&ctx, payment, extract_user_id(payload)).await?;
+            return Ok(StatusCode::OK);
+        }
+        // Joins arrive in bursts, so membership events bypass the flood guard too
+        if let Some(event) = membership_event(&payload["message"]) {
+            handle_membership(&ctx, &payload["message"], event).await?;
+            return Ok(StatusCode::OK);
+        }
+        if let Some(reaction) = payload.get("message_reaction") {
+            handle_message_reaction(&ctx, reaction).await?;
+            return Ok(StatusCode::OK);
+        }
+        if let Some(poll) = payload.get("poll") {
+            handle_poll(&ctx, poll).await?;
+            return Ok(StatusCode::OK);
+        }
+        if let Some(answer) = payload.get("poll_answer") {
+            handle_poll_answer(&ctx, answer).await?;
+            return Ok(StatusCode::OK);
+        }
+
+        // Nothing below can answer without a chat, but the update is still valid
+        let Some(chat_id) = ctx.chat_id else {
+            debug!("Ignoring update for bot '{}' without a chat.", bot.id);
+            return Ok(StatusCode::OK);
+        };
+
+        match self.flood.observe(&bot.id, chat_id).await {
+            FloodVerdict::Allow => {}
+            FloodVerdict::JustMuted => {
+                self.metrics.flood_mutes.fetch_add(1, Ordering::Relaxed);
+                let seconds = self.flood.mute_duration().as_secs().to_string();
+                ctx.publish(
+                    REPLY_QUEUE,
+                    ctx.text("flood_muted", &[("seconds", seconds)]),
+                )
+                .await?;
+                info!("Muted chat {} of bot '{}' for flooding.", chat_id, bot.id);
+                return Ok(StatusCode::OK);
+            }
+            FloodVerdict::Muted => {
+                self.metrics.flood_dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(StatusCode::OK);
+            }
+        }
+
+        self.broadcaster.chats.record(&bot.id, chat_id).await;
+        let session_key = SessionKey::new(&bot.id, chat_id);
+
+        // Tell the user rather than queue work nobody is going to pick up
+        if let Some(command) = ctx
+            .command
+            .filter(|command| bot.command_enabled(command))
+            .filter(|command| !self.command_switches.is_enabled(command))
+        {
+            ctx.publish(
+                REPLY_QUEUE,
+                ctx.text("command_unavailable", &[("command", command.to_string())]),
+            )
+            .await?;
+            info!("Refused {}, it is switched off.", command);
+            return Ok(StatusCode::OK);
+        }
+
+        if let Some(command) = ctx.command {
+            // Private chats have the user's id as chat id, so fall back to it
+            let user_id = extract_user_id(payload).unwrap_or(chat_id);
+            if let Err(remaining) = self
+                .cooldowns
+                .check(&bot.id, user_id, command, ctx.tier)
+                .await
+            {
+                let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                ctx.publish(
+                    REPLY_QUEUE,
+                    ctx.text("rate_limited", &[("seconds", seconds.to_string())]),
+                )
+                .await?;
+                info!("User {} hit the {} cooldown.", user_id, command);
+                return Ok(StatusCode::OK);
+            }
+        }
+
+        if let Some(command) = extract_caption(payload).and(ctx.command) {
+            self.sessions.clear(&session_key).await;
+            let attachment = extract_attachment(payload)
+                .filter(|_| bot.command_enabled(command))
+                .and_then(|attachment| {
+                    let queue = routing.queue_for_attachment(command, attachment.kind)?;
+                    Some((attachment, queue))
+                });
+            if let Some((attachment, queue)) = attachment {
+                handle_attachment_command(&ctx, command, &attachment, queue).await?;
+            } else if command == "/readimage" && bot.command_enabled("/readimage") {
+                handle_readimage(&ctx, &payload["message"]).await?;
+            } else {
+                self.handle_unknown_command(&ctx, chat_id, command).await?;
+            }
+        } else if let Some(text) = extract_text(payload) {
+            if let Some(command) = ctx.command {
+                // Whatever follows the command on its line and below it
+                let args = extract_command_span(payload).map_or("", |span| span.args);
+                // A new command abandons whatever the chat was in the middle of
+                self.sessions.clear(&session_key).await;
+                if command == "/help" && bot.command_enabled("/help") {
+                    handle_help_command(&ctx).await?;
+                } else if command == "/stats"
+                    && bot.command_enabled("/stats")
+                    && self.admin_chat_ids.contains(&chat_id)
+                {
+                    handle_stats_command(&ctx, &self.metrics).await?;
+                } else if command == "/broadcast"
+                    && bot.command_enabled("/broadcast")
+                    && self.admin_chat_ids.contains(&chat_id)
+                {
+                    self.handle_broadcast_command(&ctx, args).await?;
+                } else if command == "/remind" && bot.command_enabled("/remind") {
+                    self.handle_remind_command(&ctx, chat_id, args).await?;
+                } else if command == "/deleteme" && bot.command_enabled("/deleteme") {
+                    // Private chats have the user's id as chat id
+                    let user_id = extract_user_id(payload).unwrap_or(chat_id);
+                    self.handle_deleteme(&ctx, chat_id, user_id).await?;
+                } else if command == "/settings" && bot.command_enabled("/settings") {
+                    self.handle_settings_command(&ctx, chat_id, args).await?;
+                } else if command == "/stickerinfo" && bot.command_enabled("/stickerinfo") {
+                    handle_stickerinfo(&ctx, payload).await?;
+                } else if command == "/readimage" && bot.command_enabled("/readimage") {
+                    // Replying to a photo sent earlier reads that photo
+                    let replied_to = &payload["message"]["reply_to_message"];
+                    if has_photo(replied_to) {
+                        handle_readimage(&ctx, replied_to).await?;
+                    } else {
+                        self.expect_step(
+                            &ctx,
+                            &session_key,
+                            photo_step("/readimage"),
+                            "readimage.awaiting",
+                        )
+                        .await?;
+                    }
+                } else if command == "/songlinks" && bot.command_enabled("/songlinks") {
+                    // Titles start on the line after the command
+                    if args.lines().skip(1).all(|line| line.trim().is_empty()) {
+                        self.expect_step(
+                            &ctx,
+                            &session_key,
+                            text_step("/songlinks"),
+                            "songlinks.awaiting",
+                        )
+                        .await?;
+                    } else {
+                        handle_songlinks(&ctx, args.lines().skip(1)).await?;
+                    }
+                } else {
+                    self.handle_unknown_command(&ctx, chat_id, command).await?;
+                }
+            } else if let Some(step) = self.sessions.take(&session_key).await {
+                if step == text_step("/songlinks") {
+                    handle_songlinks(&ctx, text.lines()).await?;
+                }
+            }
+        } else if has_photo(&payload["message"]) {
+            if let Some(step) = self.sessions.take(&session_key).await {
+                if step == photo_step("/readimage") {
+                    handle_readimage(&ctx, &payload["message"]).await?;
+                }
+            }
+        } else if let Some(audio) = extract_audio(payload) {
+            handle_audio(&ctx, audio, extract_caption(payload)).await?;
+        } else if let Some(sticker) = extract_sticker(&payload["message"]) {
+            if !bot.stickers_on_command {
+                publish_sticker(&ctx, sticker).await?;
+            }
+        }
+
+        Ok(StatusCode::OK)
+    }
 }
 
-// Extract chat_id from the payload
+// Extract chat_id from the payload; pre-checkout queries and poll answers have no chat, so their
+// sender's private chat (same id as the user) is used
 fn extract_chat_id(payload: &Value) -> Option<i64> {
-    payload["message"]["chat"]["id"].as_i64()
+    let answer = &payload["poll_answer"];
+    payload["message"]["chat"]["id"]
+        .as_i64()
+        .or_else(|| payload["message_reaction"]["chat"]["id"].as_i64())
+        .or_else(|| payload["pre_checkout_query"]["from"]["id"].as_i64())
+        .or_else(|| answer["user"]["id"].as_i64())
+        .or_else(|| answer["voter_chat"]["id"].as_i64())
+}
+
+// The user behind the update, wherever its type keeps it
+fn extract_sender(payload: &Value) -> &Value {
+    [
+        &payload["message"]["from"],
+        &payload["pre_checkout_query"]["from"],
+        &payload["poll_answer"]["user"],
+        &payload["message_reaction"]["user"],
+    ]
+    .into_iter()
+    .find(|user| user.is_object())
+    .unwrap_or(&Value::Null)
+}
+
+// Extract the sender's IETF language tag (e.g. "en", "pt-br") if Telegram provided one
+fn extract_language_code(payload: &Value) -> Option<&str> {
+    extract_sender(payload)["language_code"].as_str()
+}
+
+// Extract the sender's user id
+fn extract_user_id(payload: &Value) -> Option<i64> {
+    extract_sender(payload)["id"].as_i64()
+}
+
+fn extract_metadata(payload: &Value) -> MessageMetadata {
+    let sender = extract_sender(payload);
+    let message = &payload["message"];
+    MessageMetadata {
+        from_id: sender["id"].as_i64(),
+        from_username: sender["username"].as_str().map(str::to_string),
+        message_id: message["message_id"]
+            .as_i64()
+            .or_else(|| payload["message_reaction"]["message_id"].as_i64()),
+        date: message["date"]
+            .as_i64()
+            .or_else(|| payload["message_reaction"]["date"].as_i64()),
+        language_code: sender["language_code"].as_str().map(str::to_string),
+        command: extract_command(payload).map(str::to_string),
+        command_language: None,
+        update_id: payload["update_id"].as_i64(),
+    }
+}
+
+// What the message replies to, so consumers see the referenced text or photo
+fn extract_reply_context(payload: &Value) -> Option<ReplyContext> {
+    let reply = &payload["message"]["reply_to_message"];
+    let photo_file_ids = reply["photo"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|photo| photo["file_id"].as_str().map(str::to_string))
+        .collect();
+    Some(ReplyContext {
+        message_id: reply["message_id"].as_i64()?,
+        text: reply["text"].as_str().map(str::to_string),
+        caption: reply["caption"].as_str().map(str::to_string),
+        photo_file_ids,
+    })
+}
+
+// A command at the start of a message's text or caption, and what follows it
+struct CommandSpan<'a> {
+    // As sent, "@botusername" included
+    command: &'a str,
+    args: &'a str,
+}
+
+// Telegram marks commands with bot_command entities; the first one is the message's command if
+// only whitespace precedes it. Producers that leave entities out get the leading word instead.
+fn extract_command_span(payload: &Value) -> Option<CommandSpan<'_>> {
+    let message = &payload["message"];
+    let (text, entities) = match message["text"].as_str() {
+        Some(text) => (text, &message["entities"]),
+        None => (message["caption"].as_str()?, &message["caption_entities"]),
+    };
+    let first_word = || {
+        let start = text.len() - text.trim_start().len();
+        let end = text[start..]
+            .find(char::is_whitespace)
+            .map_or(text.len(), |len| start + len);
+        (start, end)
+    };
+    let (start, end) = match entities.as_array() {
+        Some(entities) => match entities
+            .iter()
+            .find(|entity| entity["type"] == "bot_command")
+        {
+            Some(entity) => {
+                let offset = entity["offset"].as_u64()? as usize;
+                let length = entity["length"].as_u64()? as usize;
+                (
+                    utf16_offset_to_byte(text, offset)?,
+                    utf16_offset_to_byte(text, offset + length)?,
+                )
+            }
+            // Telegram only marks commands of Latin letters, digits and underscores, so
+            // localized aliases such as /помощь are found by hand
+            None => {
+                let (start, end) = first_word();
+                if text[start..end].is_ascii() {
+                    return None;
+                }
+                (start, end)
+            }
+        },
+        None => first_word(),
+    };
+    let command = &text[start..end];
+    (text[..start].trim().is_empty() && command.starts_with('/')).then_some(CommandSpan {
+        command,
+        args: &text[end..],
+    })
+}
+
+// Entity offsets count UTF-16 code units; None if the offset is not on a character boundary
+fn utf16_offset_to_byte(text: &str, offset: usize) -> Option<usize> {
+    let mut units = 0;
+    for (index, c) in text.char_indices() {
+        if units == offset {
+            return Some(index);
+        }
+        units += c.len_utf16();
+    }
+    (units == offset).then_some(text.len())
+}
+
+// Whether a message mentions the bot or replies to one of its messages; "/help@username" is
+// recognised by BotConfig::own_command. Nothing is addressed to a bot without a username.
+fn is_addressed(message: &Value, bot: &BotConfig) -> bool {
+    let Some(own) = bot.username.as_deref() else {
+        return false;
+    };
+    let replied_to = &message["reply_to_message"]["from"];
+    if replied_to["is_bot"] == true
+        && replied_to["username"]
+            .as_str()
+            .is_some_and(|username| username.eq_ignore_ascii_case(own))
+    {
+        return true;
+    }
+    extract_mentions(message)
+        .iter()
+        .any(|mention| mention.eq_ignore_ascii_case(own))
+}
+
+// The usernames of a message's "@username" mention entities
+fn extract_mentions(message: &Value) -> Vec<&str> {
+    let (text, entities) = match message["text"].as_str() {
+        Some(text) => (text, &message["entities"]),
+        None => match message["caption"].as_str() {
+            Some(caption) => (caption, &message["caption_entities"]),
+            None => return Vec::new(),
+        },
+    };
+    entities
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter(|entity| entity["type"] == "mention")
+        .filter_map(|entity| {
+            let offset = entity["offset"].as_u64()? as usize;
+            let length = entity["length"].as_u64()? as usize;
+            let start = utf16_offset_to_byte(text, offset)?;
+            let end = utf16_offset_to_byte(text, offset + length)?;
+            text[start..end].strip_prefix('@')
+        })
+        .collect()
+}
+
+// The message's "/command", if it has one
+fn extract_command(payload: &Value) -> Option<&str> {
+    extract_command_span(payload).map(|span| span.command)
+}
+
+fn photo_step(command: &str) -> ExpectedStep {
+    ExpectedStep::Photo {
+        command: command.to_string(),
+    }
+}
+
+fn text_step(command: &str) -> ExpectedStep {
+    ExpectedStep::Text {
+        command: command.to_string(),
+    }
+}
+
+fn has_photo(message: &Value) -> bool {
+    message["photo"].is_array()
 }
 
 // Extract caption from the payload (used for commands like /readimage)
@@ -73,89 +952,1466 @@ fn extract_text(payload: &Value) -> Option<&str> {
     payload["message"]["text"].as_str()
 }
 
+// A file sent along with a message, as caption commands see it
+struct Attachment<'a> {
+    // One of ATTACHMENT_KINDS
+    kind: &'static str,
+    file_id: &'a str,
+    mime_type: Option<&'a str>,
+}
+
+fn extract_attachment(payload: &Value) -> Option<Attachment<'_>> {
+    if let Some(file_id) = extract_largest_image_file_id(&payload["message"]) {
+        return Some(Attachment {
+            kind: "photo",
+            file_id,
+            mime_type: None,
+        });
+    }
+    // Animations also carry a document field, so they are looked for first
+    ["video", "animation", "document"]
+        .into_iter()
+        .find_map(|kind| {
+            let file = &payload["message"][kind];
+            Some(Attachment {
+                kind,
+                file_id: file["file_id"].as_str()?,
+                mime_type: file["mime_type"].as_str(),
+            })
+        })
+}
+
+// Sends the attachment's file_id to the queue routed for "<command>:<kind>"
+async fn handle_attachment_command(
+    ctx: &UpdateContext<'_>,
+    command: &str,
+    attachment: &Attachment<'_>,
+    queue: &str,
+) -> Result<(), WebhookError> {
+    let message = ctx.message(attachment.file_id).with_data(json!({
+        "kind": attachment.kind,
+        "mime_type": attachment.mime_type,
+    }));
+    ctx.publish_message(queue, message).await?;
+    info!(
+        "Published {} {} to {} queue.",
+        command, attachment.kind, queue
+    );
+    ctx.acknowledge(command).await;
+    Ok(())
+}
+
 // Handle the /readimage command by sending the file_id to the ImageToText queue
-async fn handle_readimage(
-    chat_id: i64,
-    payload: &Value,
-    channel_pool: &Arc<ChannelPool>,
-) -> Result<(), StatusCode> {
-    if let Some(file_id) = extract_largest_image_file_id(payload) {
-        let rabbit_message = RabbitMessage {
-            chat_id,
-            text: file_id.to_string(),
-        };
-        publish_to_queue("ImageToText", rabbit_message, channel_pool).await?;
-        info!("Published 'readimage' message to ImageToText queue.");
+// `message` is the update's message, or the photo message it replies to
+async fn handle_readimage(ctx: &UpdateContext<'_>, message: &Value) -> Result<(), WebhookError> {
+    if let Some(file_id) = extract_largest_image_file_id(message) {
+        let queue = ctx.route("/readimage");
+        ctx.publish(queue, file_id).await?;
+        info!("Published 'readimage' message to {} queue.", queue);
+        ctx.acknowledge("/readimage").await;
         Ok(())
     } else {
-        info!("No valid file_id found in the photo.");
-        Err(StatusCode::BAD_REQUEST)
+        // Telling the user what is missing handles the update; rejecting it would not help them
+        ctx.publish(REPLY_QUEUE, ctx.text("readimage.missing_photo", &[]))
+            .await?;
+        Ok(())
     }
 }
 
 // Handle the /help command by sending a help message to the Reply queue
-async fn handle_help_command(
-    chat_id: i64,
-    channel_pool: &Arc<ChannelPool>,
-) -> Result<(), StatusCode> {
-    let help_message = RabbitMessage {
-        chat_id,
-        text: "Type /songlinks, followed by up to 10 lines of song titles to get download links.\n/readimage with an attached image, to get the text from the image.\n/donate to get a QR code."
-            .to_string(),
-    };
-    publish_to_queue("Reply", help_message, channel_pool).await?;
+async fn handle_help_command(ctx: &UpdateContext<'_>) -> Result<(), WebhookError> {
+    let mut help_text = ctx.text(
+        "help",
+        &[("max_lines", ctx.bot.songlinks_limits.max_lines.to_string())],
+    );
+    for (command, aliases) in ctx.routing.aliases_by_command(ctx.language_code) {
+        if ctx.bot.command_enabled(command) {
+            help_text.push('\n');
+            help_text.push_str(&ctx.text(
+                "help.aliases",
+                &[
+                    ("command", command.to_string()),
+                    ("aliases", aliases.join(", ")),
+                ],
+            ));
+        }
+    }
+    ctx.publish(REPLY_QUEUE, help_text).await?;
     info!("Published 'help' message to Reply queue.");
     Ok(())
 }
 
-// Extract the file_id of the largest image from the payload
-fn extract_largest_image_file_id(payload: &Value) -> Option<&str> {
-    payload["message"]["photo"]
+// Runtime counters for operators; only admin chats get here
+async fn handle_stats_command(
+    ctx: &UpdateContext<'_>,
+    metrics: &Metrics,
+) -> Result<(), WebhookError> {
+    ctx.publish(REPLY_QUEUE, stats_summary(metrics)).await?;
+    info!("Published 'stats' summary to Reply queue.");
+    Ok(())
+}
+
+fn stats_summary(metrics: &Metrics) -> String {
+    let uptime = metrics.uptime().as_secs();
+    let mut lines = vec![
+        format!(
+            "Uptime: {}d {}h {}m",
+            uptime / 86_400,
+            uptime % 86_400 / 3600,
+            uptime % 3600 / 60
+        ),
+        format!(
+            "Updates handled: {}",
+            metrics.updates.load(Ordering::Relaxed)
+        ),
+        format!(
+            "By class: {} command, {} media with caption, {} other ({} group chatter dropped)",
+            metrics.command_updates.load(Ordering::Relaxed),
+            metrics.media_with_caption_updates.load(Ordering::Relaxed),
+            metrics.other_updates.load(Ordering::Relaxed),
+            metrics.filtered_updates.load(Ordering::Relaxed)
+        ),
+        format!(
+            "Group commands not addressed to the bot: {}",
+            metrics.unaddressed_commands.load(Ordering::Relaxed)
+        ),
+        format!("Publishes: {}", metrics.publishes.load(Ordering::Relaxed)),
+        format!(
+            "Publish failures: {}",
+            metrics.publish_failures.load(Ordering::Relaxed)
+        ),
+        format!("Unroutable: {}", metrics.unroutable.load(Ordering::Relaxed)),
+        format!(
+            "Flood mutes: {}",
+            metrics.flood_mutes.load(Ordering::Relaxed)
+        ),
+        format!(
+            "Throttled requests: {}",
+            metrics.throttled_requests.load(Ordering::Relaxed)
+        ),
+    ];
+    let shadow_publishes = metrics.shadow_publishes.load(Ordering::Relaxed);
+    let shadow_failures = metrics.shadow_failures.load(Ordering::Relaxed);
+    if shadow_publishes + shadow_failures > 0 {
+        lines.push(format!(
+            "Shadow publishes: {} ({} failed)",
+            shadow_publishes, shadow_failures
+        ));
+    }
+    let mut per_queue: Vec<_> = metrics.publishes_per_queue().into_iter().collect();
+    per_queue.sort();
+    if !per_queue.is_empty() {
+        lines.push("Publishes per queue:".to_string());
+    }
+    lines.extend(
+        per_queue
+            .into_iter()
+            .map(|(queue, count)| format!("  {}: {}", queue, count)),
+    );
+    let mut cron_runs: Vec<_> = metrics.cron_runs().into_iter().collect();
+    cron_runs.sort_by(|a, b| a.0.cmp(&b.0));
+    if !cron_runs.is_empty() {
+        lines.push("Cron jobs (fired/skipped):".to_string());
+    }
+    lines.extend(
+        cron_runs
+            .into_iter()
+            .map(|(job, runs)| format!("  {}: {}/{}", job, runs.fired, runs.skipped)),
+    );
+    lines.join("\n")
+}
+
+// The audio attachment (a music file, not a voice note) if the message has one
+fn extract_audio(payload: &Value) -> Option<&Value> {
+    let audio = &payload["message"]["audio"];
+    audio["file_id"].is_string().then_some(audio)
+}
+
+// Forward an audio file with its ID3-style tags so downstream workers can tag or convert it
+async fn handle_audio(
+    ctx: &UpdateContext<'_>,
+    audio: &Value,
+    caption: Option<&str>,
+) -> Result<(), WebhookError> {
+    let file_id = audio["file_id"].as_str().unwrap_or_default();
+    let data = json!({
+        "file_id": file_id,
+        "file_unique_id": audio["file_unique_id"],
+        "performer": audio["performer"],
+        "title": audio["title"],
+        "duration": audio["duration"],
+        "mime_type": audio["mime_type"],
+        "file_name": audio["file_name"],
+        "file_size": audio["file_size"],
+        "caption": caption,
+    });
+    let queue = ctx.route("audio");
+    ctx.publish_message(queue, ctx.message(file_id).with_data(data))
+        .await?;
+    info!("Published audio message to {} queue.", queue);
+    Ok(())
+}
+
+// Forward a pre-checkout query; the Payments worker must answer it within 10 seconds
+async fn handle_pre_checkout_query(
+    ctx: &UpdateContext<'_>,
+    query: &Value,
+) -> Result<(), WebhookError> {
+    let data = json!({
+        "pre_checkout_query_id": query["id"],
+        "from_id": query["from"]["id"],
+        "currency": query["currency"],
+        "total_amount": query["total_amount"],
+        "invoice_payload": query["invoice_payload"],
+        "shipping_option_id": query["shipping_option_id"],
+        "order_info": query["order_info"],
+    });
+    publish_payment(ctx, "pre_checkout_query", query, data).await
+}
+
+async fn handle_successful_payment(
+    ctx: &UpdateContext<'_>,
+    payment: &Value,
+    from_id: Option<i64>,
+) -> Result<(), WebhookError> {
+    let data = json!({
+        "from_id": from_id,
+        "currency": payment["currency"],
+        "total_amount": payment["total_amount"],
+        "invoice_payload": payment["invoice_payload"],
+        "telegram_payment_charge_id": payment["telegram_payment_charge_id"],
+        "provider_payment_charge_id": payment["provider_payment_charge_id"],
+    });
+    publish_payment(ctx, "successful_payment", payment, data).await
+}
+
+async fn publish_payment(
+    ctx: &UpdateContext<'_>,
+    event: &str,
+    payment: &Value,
+    data: Value,
+) -> Result<(), WebhookError> {
+    let invoice_payload = payment["invoice_payload"].as_str().unwrap_or_default();
+    let message = ctx
+        .message(invoice_payload)
+        .with_event(event)
+        .with_data(data);
+    let queue = ctx.route("payments");
+    ctx.publish_message(queue, message).await?;
+    info!("Published '{}' message to {} queue.", event, queue);
+    Ok(())
+}
+
+// Service messages about users joining or leaving a group
+fn membership_event(message: &Value) -> Option<&'static str> {
+    if message["new_chat_members"].is_array() {
+        Some("new_chat_members")
+    } else if message["left_chat_member"].is_object() {
+        Some("left_chat_member")
+    } else {
+        None
+    }
+}
+
+fn user_info(user: &Value) -> Value {
+    json!({
+        "id": user["id"],
+        "is_bot": user["is_bot"],
+        "first_name": user["first_name"],
+        "last_name": user["last_name"],
+        "username": user["username"],
+        "language_code": user["language_code"],
+    })
+}
+
+// Publish joins (with who added them) and departures for welcome and ban bots
+async fn handle_membership(
+    ctx: &UpdateContext<'_>,
+    message: &Value,
+    event: &str,
+) -> Result<(), WebhookError> {
+    let members: Vec<Value> = match message["new_chat_members"].as_array() {
+        Some(members) => members.iter().map(user_info).collect(),
+        None => vec![user_info(&message["left_chat_member"])],
+    };
+    // The sender is the inviter (or remover); on self-joins it is the member themselves
+    let data = json!({
+        "chat_id": message["chat"]["id"],
+        "chat_title": message["chat"]["title"],
+        "chat_type": message["chat"]["type"],
+        "members": members,
+        "actor": user_info(&message["from"]),
+    });
+    let message = ctx.message(event).with_event(event).with_data(data);
+    let queue = ctx.route("moderation");
+    ctx.publish_message(queue, message).await?;
+    info!("Published '{}' message to {} queue.", event, queue);
+    Ok(())
+}
+
+fn extract_sticker(message: &Value) -> Option<&Value> {
+    let sticker = &message["sticker"];
+    sticker["file_id"].is_string().then_some(sticker)
+}
+
+// /stickerinfo sent as a reply to a sticker forwards that sticker
+async fn handle_stickerinfo(ctx: &UpdateContext<'_>, payload: &Value) -> Result<(), WebhookError> {
+    match extract_sticker(&payload["message"]["reply_to_message"]) {
+        Some(sticker) => publish_sticker(ctx, sticker).await,
+        None => {
+            ctx.publish(REPLY_QUEUE, ctx.text("stickerinfo.missing_sticker", &[]))
+                .await
+        }
+    }
+}
+
+async fn publish_sticker(ctx: &UpdateContext<'_>, sticker: &Value) -> Result<(), WebhookError> {
+    let file_id = sticker["file_id"].as_str().unwrap_or_default();
+    let data = json!({
+        "file_id": file_id,
+        "file_unique_id": sticker["file_unique_id"],
+        "emoji": sticker["emoji"],
+        "set_name": sticker["set_name"],
+        "type": sticker["type"],
+        "is_animated": sticker["is_animated"],
+        "is_video": sticker["is_video"],
+        "width": sticker["width"],
+        "height": sticker["height"],
+    });
+    let message = ctx.message(file_id).with_event("sticker").with_data(data);
+    let queue = ctx.route("stickers");
+    ctx.publish_message(queue, message).await?;
+    info!("Published sticker message to {} queue.", queue);
+    Ok(())
+}
+
+// A user changed their reactions to a message; both the old and new sets are forwarded
+async fn handle_message_reaction(
+    ctx: &UpdateContext<'_>,
+    reaction: &Value,
+) -> Result<(), WebhookError> {
+    let data = json!({
+        "chat_id": reaction["chat"]["id"],
+        "message_id": reaction["message_id"],
+        // Anonymous admins react as the chat, without a user
+        "user_id": reaction["user"]["id"],
+        "actor_chat_id": reaction["actor_chat"]["id"],
+        "old_reaction": reaction["old_reaction"],
+        "new_reaction": reaction["new_reaction"],
+        "new_emoji": reaction_emojis(&reaction["new_reaction"]),
+    });
+    let message_id = reaction["message_id"].to_string();
+    let message = ctx
+        .message(message_id)
+        .with_event("message_reaction")
+        .with_data(data);
+    let queue = ctx.route("reactions");
+    ctx.publish_message(queue, message).await?;
+    info!("Published 'message_reaction' message to {} queue.", queue);
+    Ok(())
+}
+
+// Plain emoji of a reaction list, skipping custom and paid reactions
+fn reaction_emojis(reactions: &Value) -> Vec<&str> {
+    reactions
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|reaction| reaction["emoji"].as_str())
+        .collect()
+}
+
+// Poll state changes (new vote counts, closing) for polls the bot sent
+async fn handle_poll(ctx: &UpdateContext<'_>, poll: &Value) -> Result<(), WebhookError> {
+    let options: Vec<Value> = poll["options"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .map(|(index, option)| {
+            json!({
+                "index": index,
+                "text": option["text"],
+                "voter_count": option["voter_count"],
+            })
+        })
+        .collect();
+    let data = json!({
+        "poll_id": poll["id"],
+        "question": poll["question"],
+        "type": poll["type"],
+        "is_anonymous": poll["is_anonymous"],
+        "is_closed": poll["is_closed"],
+        "total_voter_count": poll["total_voter_count"],
+        "correct_option_id": poll["correct_option_id"],
+        "options": options,
+    });
+    let poll_id = poll["id"].as_str().unwrap_or_default();
+    publish_poll_update(ctx, "poll", poll_id, data).await
+}
+
+// A vote in a non-anonymous poll; an empty option_ids list means the vote was retracted
+async fn handle_poll_answer(ctx: &UpdateContext<'_>, answer: &Value) -> Result<(), WebhookError> {
+    let data = json!({
+        "poll_id": answer["poll_id"],
+        "option_ids": answer["option_ids"],
+        "user_id": answer["user"]["id"],
+        "username": answer["user"]["username"],
+        "voter_chat_id": answer["voter_chat"]["id"],
+    });
+    let poll_id = answer["poll_id"].as_str().unwrap_or_default();
+    publish_poll_update(ctx, "poll_answer", poll_id, data).await
+}
+
+async fn publish_poll_update(
+    ctx: &UpdateContext<'_>,
+    event: &str,
+    poll_id: &str,
+    data: Value,
+) -> Result<(), WebhookError> {
+    let message = ctx.message(poll_id).with_event(event).with_data(data);
+    let queue = ctx.route("polls");
+    ctx.publish_message(queue, message).await?;
+    info!("Published '{}' message to {} queue.", event, queue);
+    Ok(())
+}
+
+// Extract the file_id of the largest image of a message
+fn extract_largest_image_file_id(message: &Value) -> Option<&str> {
+    message["photo"]
         .as_array()?
         .iter()
         .max_by_key(|p| p["width"].as_i64().unwrap_or(0))
         .and_then(|photo| photo["file_id"].as_str())
 }
 
-// Publish a RabbitMessage to the specified RabbitMQ queue
+// Publish a RabbitMessage to the specified queue through the injected publisher
 async fn publish_to_queue(
     queue_name: &str,
     message: RabbitMessage,
-    channel_pool: &Arc<ChannelPool>,
-) -> Result<(), StatusCode> {
-    let serialized_message = serde_json::to_vec(&message).expect("Failed to serialize message");
-    let channel = channel_pool.get_next_channel().await;
-    channel
-        .basic_publish(
-            "",         // Exchange
-            queue_name, // Queue name
-            BasicPublishOptions::default(),
-            &serialized_message, // Payload
-            BasicProperties::default(),
-        )
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(())
+    publisher: &dyn MessagePublisher,
+) -> Result<(), WebhookError> {
+    publisher
+        .publish(queue_name, &message)
+        .await
+        .map_err(|e| WebhookError::publish_failed(queue_name, &e))
 }
-async fn handle_songlinks(
-    chat_id: i64,
-    text: &str,
-    channel_pool: &Arc<ChannelPool>,
-) -> Result<(), StatusCode> {
-    // Extract song lines, skipping the /songlinks command
-    let truncated_songs: Vec<String> = text
-        .lines()
-        .skip(1) // Skip the /songlinks command itself
-        .take(10) // Limit to 10 lines
-        .map(|line| line.chars().take(50).collect()) // Truncate each line to 50 characters
+async fn handle_songlinks<'t>(
+    ctx: &UpdateContext<'_>,
+    lines: impl Iterator<Item = &'t str>,
+) -> Result<(), WebhookError> {
+    let limits = ctx.songlinks_limits();
+
+    // Extract song lines, skipping blank ones
+    let songs: Vec<&str> = lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
         .collect();
 
-    let song_message = RabbitMessage {
-        chat_id,
-        text: truncated_songs.join("\n"), // Join all truncated lines with newlines
-    };
+    if songs.is_empty() {
+        let notice = ctx.text(
+            "songlinks.empty",
+            &[("max_lines", limits.max_lines.to_string())],
+        );
+        ctx.publish(REPLY_QUEUE, notice).await?;
+        info!("Published empty 'songlinks' notice to Reply queue.");
+        return Ok(());
+    }
 
-    publish_to_queue("Music", song_message, channel_pool).await?;
-    info!("Published 'songlinks' message to Music queue.");
+    let dropped_lines = songs.len().saturating_sub(limits.max_lines);
+    let mut shortened_lines = 0;
+    let truncated_songs: Vec<String> = songs
+        .iter()
+        .take(limits.max_lines)
+        .map(|line| {
+            if line.chars().count() > limits.max_line_chars {
+                shortened_lines += 1;
+            }
+            line.chars().take(limits.max_line_chars).collect()
+        })
+        .collect();
+
+    // Join all truncated lines with newlines
+    let queue = ctx.route("/songlinks");
+    ctx.publish(queue, truncated_songs.join("\n")).await?;
+    info!("Published 'songlinks' message to {} queue.", queue);
+    ctx.acknowledge("/songlinks").await;
+
+    if let Some(notice) = truncation_notice(ctx, dropped_lines, shortened_lines) {
+        ctx.publish(REPLY_QUEUE, notice).await?;
+        info!("Published 'songlinks' truncation notice to Reply queue.");
+    }
     Ok(())
 }
+
+// Explain to the user what was cut from their /songlinks request, if anything
+fn truncation_notice(
+    ctx: &UpdateContext<'_>,
+    dropped_lines: usize,
+    shortened_lines: usize,
+) -> Option<String> {
+    let limits = ctx.songlinks_limits();
+    let mut notes = Vec::new();
+    if dropped_lines > 0 {
+        notes.push(ctx.text(
+            "songlinks.dropped",
+            &[
+                ("max_lines", limits.max_lines.to_string()),
+                ("dropped", dropped_lines.to_string()),
+            ],
+        ));
+    }
+    if shortened_lines > 0 {
+        notes.push(ctx.text(
+            "songlinks.shortened",
+            &[
+                ("shortened", shortened_lines.to_string()),
+                ("max_chars", limits.max_line_chars.to_string()),
+            ],
+        ));
+    }
+    if notes.is_empty() {
+        return None;
+    }
+    let separator = ctx.text("songlinks.note.separator", &[]);
+    Some(ctx.text("songlinks.note", &[("notes", notes.join(&separator))]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        bots::BotConfig,
+        flood::FloodConfig,
+        publisher::PublishError,
+        testing::{dispatcher, dispatcher_with_bots, fixtures, RecordingPublisher},
+        tiers::TierConfig,
+    };
+
+    async fn post(
+        dispatcher: &Arc<Dispatcher>,
+        headers: HeaderMap,
+        update: Value,
+    ) -> Result<StatusCode, WebhookError> {
+        receive_message(
+            Extension(Arc::clone(dispatcher)),
+            headers,
+            fixtures::to_body(&update),
+        )
+        .await
+    }
+
+    fn setup() -> (Arc<RecordingPublisher>, Arc<Dispatcher>) {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let dispatcher = Arc::new(dispatcher(Arc::clone(&publisher)));
+        (publisher, dispatcher)
+    }
+
+    #[derive(Default)]
+    struct PurgedUsers(Mutex<Vec<(String, i64)>>);
+
+    #[async_trait::async_trait]
+    impl UserRecords for PurgedUsers {
+        async fn purge_user(&self, bot_id: &str, user_id: i64) -> Result<u64, String> {
+            self.0.lock().unwrap().push((bot_id.to_string(), user_id));
+            Ok(3)
+        }
+    }
+
+    #[tokio::test]
+    async fn deleteme_publishes_the_request_and_purges_stored_records() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let purged = Arc::new(PurgedUsers::default());
+        let mut dispatcher = dispatcher(Arc::clone(&publisher));
+        dispatcher.user_records = Some(Arc::clone(&purged) as _);
+        let dispatcher = Arc::new(dispatcher);
+
+        let update = fixtures::group_text_message("/deleteme");
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        assert_eq!(publisher.queues(), vec!["Deletion", "Reply"]);
+        let (_, request) = &publisher.published()[0];
+        assert_eq!(request.event.as_deref(), Some(DELETION_REQUEST_EVENT));
+        let data = request.data.as_ref().unwrap();
+        assert_eq!(data["chat_id"], fixtures::GROUP_ID);
+        assert_eq!(data["user_id"], fixtures::USER_ID);
+        assert!(data["requested_at"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(
+            *purged.0.lock().unwrap(),
+            vec![(DEFAULT_BOT_ID.to_string(), fixtures::USER_ID)]
+        );
+    }
+
+    #[tokio::test]
+    async fn settings_are_stored_per_chat_and_pick_the_reply_language() {
+        let (publisher, dispatcher) = setup();
+
+        for text in [
+            "/settings language ro",
+            "/settings song_format wav",
+            "/settings verbosity short",
+            "/help",
+        ] {
+            post(&dispatcher, HeaderMap::new(), fixtures::text_message(text))
+                .await
+                .unwrap();
+        }
+
+        let replies = publisher.texts_for("Reply");
+        assert_eq!(replies[0], "language este acum ro.");
+        assert_eq!(
+            replies[1],
+            "song_format poate fi mp3, m4a, opus, flac sau default."
+        );
+        assert!(replies[3].starts_with("Scrie /songlinks"));
+        let settings = dispatcher
+            .settings
+            .get(DEFAULT_BOT_ID, fixtures::CHAT_ID)
+            .await;
+        assert_eq!(settings.verbosity.as_deref(), Some("short"));
+        assert_eq!(settings.song_format, None);
+    }
+
+    #[tokio::test]
+    async fn group_chatter_is_dropped_unless_answering_a_prompt() {
+        let (publisher, dispatcher) = setup();
+
+        for text in ["anyone up for lunch?", "/songlinks", "Bohemian Rhapsody"] {
+            let update = fixtures::group_text_message(text);
+            let status = post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let metrics = &dispatcher.metrics;
+        assert_eq!(metrics.command_updates.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.other_updates.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.filtered_updates.load(Ordering::Relaxed), 1);
+        // The title after the prompt still reached /songlinks
+        assert_eq!(publisher.texts_for("Music"), vec!["Bohemian Rhapsody"]);
+    }
+
+    #[tokio::test]
+    async fn help_replies_in_the_senders_language() {
+        let (publisher, dispatcher) = setup();
+        let update = fixtures::with_language(fixtures::text_message("/help"), "ro");
+
+        let status = post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        let replies = publisher.texts_for("Reply");
+        assert_eq!(
+            replies,
+            vec![Translations::bundled().text(Some("ro"), "help", &[("max_lines", "10".into())])]
+        );
+        let (_, message) = &publisher.published()[0];
+        assert_eq!(message.chat_id, Some(fixtures::CHAT_ID));
+        assert_eq!(message.bot_id.as_deref(), Some(DEFAULT_BOT_ID));
+        assert_eq!(message.metadata.from_id, Some(fixtures::USER_ID));
+        assert_eq!(message.metadata.message_id, Some(7));
+        assert_eq!(message.metadata.date, Some(1_700_000_000));
+        assert_eq!(message.metadata.language_code.as_deref(), Some("ro"));
+        assert_eq!(message.metadata.command.as_deref(), Some("/help"));
+    }
+
+    #[tokio::test]
+    async fn songlinks_publishes_titles_to_music() {
+        let (publisher, dispatcher) = setup();
+        let update = fixtures::text_message("/songlinks\nFirst song\n\n  Second song  ");
+
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        assert_eq!(publisher.queues(), vec!["Music"]);
+        assert_eq!(
+            publisher.texts_for("Music"),
+            vec!["First song\nSecond song"]
+        );
+    }
+
+    #[tokio::test]
+    async fn songlinks_truncation_is_explained() {
+        let (publisher, dispatcher) = setup();
+        let titles: Vec<String> = (1..=12).map(|n| format!("Song {}", n)).collect();
+        let update = fixtures::text_message(&format!("/songlinks\n{}", titles.join("\n")));
+
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        assert_eq!(publisher.texts_for("Music")[0].lines().count(), 10);
+        assert_eq!(publisher.queues(), vec!["Music", "Reply"]);
+    }
+
+    #[tokio::test]
+    async fn stats_are_only_sent_to_admin_chats() {
+        let (publisher, stranger_dispatcher) = setup();
+        post(
+            &stranger_dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/stats"),
+        )
+        .await
+        .unwrap();
+        // Strangers are told /stats does not exist
+        assert!(publisher.texts_for("Reply")[0].starts_with("I don't know /stats."));
+
+        let mut dispatcher = dispatcher(Arc::clone(&publisher));
+        dispatcher.admin_chat_ids = Arc::new([fixtures::CHAT_ID].into());
+        post(
+            &Arc::new(dispatcher),
+            HeaderMap::new(),
+            fixtures::text_message("/stats"),
+        )
+        .await
+        .unwrap();
+
+        let texts = publisher.texts_for("Reply");
+        assert_eq!(texts.len(), 2);
+        assert!(texts[1].contains("Updates handled: 1"));
+    }
+
+    #[tokio::test]
+    async fn switched_off_commands_are_refused_with_a_reply() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut dispatcher = dispatcher(Arc::clone(&publisher));
+        dispatcher.command_switches =
+            Arc::new(CommandSwitches::new(["/readimage".to_string()].into()));
+        let dispatcher = Arc::new(dispatcher);
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/readimage"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            publisher.texts_for("Reply"),
+            vec!["/readimage is temporarily unavailable. Please try again later."]
+        );
+
+        dispatcher.command_switches.set_enabled("/readimage", true);
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/readimage"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(publisher.texts_for("Reply").len(), 2);
+        assert_ne!(
+            publisher.texts_for("Reply")[1],
+            publisher.texts_for("Reply")[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_point_to_help_once_per_chat() {
+        let (publisher, dispatcher) = setup();
+
+        for text in ["/start", "/whatever", "/start@other_bot"] {
+            post(&dispatcher, HeaderMap::new(), fixtures::text_message(text))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            publisher.texts_for("Reply"),
+            vec!["I don't know /start. Try /help to see what I can do."]
+        );
+    }
+
+    #[tokio::test]
+    async fn group_commands_addressed_to_this_bot_are_handled() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut bot = BotConfig::new(DEFAULT_BOT_ID);
+        bot.username = Some("RustinBot".to_string());
+        let dispatcher = Arc::new(dispatcher_with_bots(Arc::clone(&publisher), vec![bot]));
+
+        for text in [
+            "/help@rustinbot",
+            "/help@OtherBot",
+            "/songlinks@RustinBot\nSong",
+        ] {
+            post(&dispatcher, HeaderMap::new(), fixtures::text_message(text))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(publisher.queues(), vec!["Reply", "Music"]);
+        assert_eq!(publisher.texts_for("Music"), vec!["Song"]);
+    }
+
+    #[tokio::test]
+    async fn addressed_groups_ignore_commands_meant_for_others() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut bot = BotConfig::new(DEFAULT_BOT_ID);
+        bot.username = Some("RustinBot".to_string());
+        bot.addressed_chat_types = HashSet::from(["supergroup".to_string()]);
+        let dispatcher = Arc::new(dispatcher_with_bots(Arc::clone(&publisher), vec![bot]));
+
+        let mut mention = fixtures::group_text_message("/help @rustinbot");
+        mention["message"]["entities"] = json!([
+            { "type": "bot_command", "offset": 0, "length": 5 },
+            { "type": "mention", "offset": 6, "length": 10 }
+        ]);
+        let mut reply = fixtures::group_text_message("/help");
+        reply["message"]["reply_to_message"] = json!({
+            "message_id": 6,
+            "from": { "id": 4242, "is_bot": true, "first_name": "Rustin", "username": "RustinBot" }
+        });
+        let mut other_mention = fixtures::group_text_message("/help @OtherBot");
+        other_mention["message"]["entities"] = json!([
+            { "type": "bot_command", "offset": 0, "length": 5 },
+            { "type": "mention", "offset": 6, "length": 9 }
+        ]);
+        for update in [
+            fixtures::group_text_message("/help"),
+            other_mention,
+            fixtures::group_text_message("/help@RustinBot"),
+            mention,
+            reply,
+            // Private chats are not in addressed_chat_types
+            fixtures::text_message("/help"),
+        ] {
+            post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+        }
+
+        assert_eq!(publisher.queues(), vec!["Reply"; 4]);
+        assert_eq!(
+            dispatcher
+                .metrics
+                .unaddressed_commands
+                .load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_are_found_through_their_entities() {
+        let (publisher, dispatcher) = setup();
+
+        let update =
+            fixtures::with_command_entity(fixtures::text_message("\n/songlinks\nSong"), 1, 10);
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+        let update = fixtures::with_command_entity(fixtures::text_message("  /help"), 2, 5);
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+        // A command in the middle of a sentence is not the message's command; the emoji is two
+        // UTF-16 code units
+        let update =
+            fixtures::with_command_entity(fixtures::text_message("\u{1F3B5} see /help"), 7, 5);
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        assert_eq!(publisher.texts_for("Music"), vec!["Song"]);
+        assert_eq!(publisher.queues(), vec!["Music", "Reply"]);
+    }
+
+    #[tokio::test]
+    async fn reminders_are_confirmed_or_explained() {
+        let (publisher, dispatcher) = setup();
+
+        for text in [
+            "/remind 2h take a break",
+            "/remind soon stretch",
+            "/remind 2h",
+        ] {
+            post(&dispatcher, HeaderMap::new(), fixtures::text_message(text))
+                .await
+                .unwrap();
+        }
+
+        let texts = publisher.texts_for("Reply");
+        assert_eq!(texts[0], "OK, I'll remind you in 2h.");
+        assert!(texts[1].starts_with("Usage: /remind"));
+        assert!(texts[2].starts_with("Usage: /remind"));
+    }
+
+    #[tokio::test]
+    async fn premium_users_get_more_lines_and_priority() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let premium = TierConfig {
+            users: vec![fixtures::USER_ID],
+            songlinks_max_lines: Some(20),
+            priority: Some(5),
+            ..TierConfig::default()
+        };
+        let mut dispatcher = dispatcher(Arc::clone(&publisher));
+        dispatcher.tiers =
+            Arc::new(UserTiers::new([("premium".to_string(), premium)].into()).unwrap());
+        let titles: Vec<String> = (1..=12).map(|n| format!("Song {}", n)).collect();
+        let update = fixtures::text_message(&format!("/songlinks\n{}", titles.join("\n")));
+
+        post(&Arc::new(dispatcher), HeaderMap::new(), update)
+            .await
+            .unwrap();
+
+        let published = publisher.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].1.text.lines().count(), 12);
+        assert_eq!(published[0].1.priority, Some(5));
+    }
+
+    #[tokio::test]
+    async fn songlinks_without_titles_waits_for_the_next_message() {
+        let (publisher, dispatcher) = setup();
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/songlinks"),
+        )
+        .await
+        .unwrap();
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("Song A\nSong B"),
+        )
+        .await
+        .unwrap();
+        // The step is consumed, so plain text afterwards is ignored
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("Song C"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(publisher.queues(), vec!["Reply", "Music"]);
+        assert_eq!(publisher.texts_for("Music"), vec!["Song A\nSong B"]);
+    }
+
+    #[tokio::test]
+    async fn readimage_caption_publishes_the_largest_photo() {
+        let (publisher, dispatcher) = setup();
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::photo_message(Some("/readimage")),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(publisher.texts_for("ImageToText"), vec!["photo-large"]);
+    }
+
+    #[tokio::test]
+    async fn readimage_as_a_reply_reads_the_replied_photo() {
+        let (publisher, dispatcher) = setup();
+        let mut update = fixtures::text_message("/readimage");
+        update["message"]["reply_to_message"] = fixtures::photo_message(None)["message"].clone();
+
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        assert_eq!(publisher.texts_for("ImageToText"), vec!["photo-large"]);
+        assert!(publisher.texts_for("Reply").is_empty());
+    }
+
+    #[tokio::test]
+    async fn caption_commands_route_each_attachment_kind() {
+        let (publisher, dispatcher) = setup();
+        let overrides = [
+            ("/transcribe:video".to_string(), "Transcribe".to_string()),
+            ("/transcribe:document".to_string(), "Files".to_string()),
+        ]
+        .into();
+        dispatcher
+            .routes
+            .replace(RoutingTable::with_overrides(overrides, HashMap::new()).unwrap());
+
+        for kind in ["video", "animation"] {
+            let update = fixtures::attachment_message(kind, Some("/transcribe please"));
+            post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+        }
+
+        let published = publisher.published();
+        assert_eq!(published[0].0, "Transcribe");
+        assert_eq!(published[0].1.text, "video-1");
+        assert_eq!(published[0].1.data.as_ref().unwrap()["kind"], "video");
+        // No route for animations, even though they carry a document
+        assert_eq!(published[1].0, "Reply");
+        assert_eq!(published.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn readimage_without_a_photo_replies_instead_of_failing() {
+        let (publisher, dispatcher) = setup();
+
+        let status = post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::attachment_message("video", Some("/readimage")),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            publisher.texts_for("Reply"),
+            vec!["Please attach a photo with /readimage as its caption."]
+        );
+        assert_eq!(publisher.published().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn readimage_command_then_photo() {
+        let (publisher, dispatcher) = setup();
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/readimage"),
+        )
+        .await
+        .unwrap();
+        post(&dispatcher, HeaderMap::new(), fixtures::photo_message(None))
+            .await
+            .unwrap();
+
+        assert_eq!(publisher.queues(), vec!["Reply", "ImageToText"]);
+    }
+
+    #[tokio::test]
+    async fn acknowledged_commands_reply_after_queueing_the_work() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut bot = BotConfig::new(DEFAULT_BOT_ID);
+        bot.acknowledged_commands = ["/readimage".to_string()].into();
+        let dispatcher = Arc::new(dispatcher_with_bots(Arc::clone(&publisher), vec![bot]));
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::photo_message(Some("/readimage")),
+        )
+        .await
+        .unwrap();
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/songlinks\nBohemian Rhapsody"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(publisher.queues(), vec!["ImageToText", "Reply", "Music"]);
+        assert_eq!(publisher.texts_for("Reply"), vec!["Working on it…"]);
+    }
+
+    #[tokio::test]
+    async fn audio_is_forwarded_with_its_tags() {
+        let (publisher, dispatcher) = setup();
+
+        post(&dispatcher, HeaderMap::new(), fixtures::audio_message())
+            .await
+            .unwrap();
+
+        let published = publisher.published();
+        let (queue, message) = &published[0];
+        assert_eq!(queue, "AudioIn");
+        assert_eq!(message.text, "audio-1");
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["performer"], "Queen");
+        assert_eq!(data["title"], "Bohemian Rhapsody");
+        assert_eq!(data["duration"], 215);
+    }
+
+    #[tokio::test]
+    async fn pre_checkout_queries_go_to_payments_even_when_muted() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut dispatcher = dispatcher(Arc::clone(&publisher));
+        dispatcher.flood = Arc::new(FloodGuard::new(FloodConfig {
+            max_messages: 0,
+            ..Default::default()
+        }));
+        let dispatcher = Arc::new(dispatcher);
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::pre_checkout_query(),
+        )
+        .await
+        .unwrap();
+
+        let published = publisher.published();
+        let (queue, message) = &published[0];
+        assert_eq!(queue, "Payments");
+        assert_eq!(message.event.as_deref(), Some("pre_checkout_query"));
+        assert_eq!(message.chat_id, Some(fixtures::USER_ID));
+        assert_eq!(message.text, "premium-1m");
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["currency"], "EUR");
+        assert_eq!(data["total_amount"], 499);
+        assert_eq!(data["from_id"], fixtures::USER_ID);
+    }
+
+    #[tokio::test]
+    async fn successful_payments_go_to_payments() {
+        let (publisher, dispatcher) = setup();
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::successful_payment(),
+        )
+        .await
+        .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Payments");
+        assert_eq!(message.event.as_deref(), Some("successful_payment"));
+        assert_eq!(message.data.as_ref().unwrap()["from_id"], fixtures::USER_ID);
+    }
+
+    #[tokio::test]
+    async fn poll_updates_have_no_chat() {
+        let (publisher, dispatcher) = setup();
+
+        post(&dispatcher, HeaderMap::new(), fixtures::poll())
+            .await
+            .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Polls");
+        assert_eq!(message.chat_id, None);
+        assert_eq!(message.event.as_deref(), Some("poll"));
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["poll_id"], "poll-1");
+        assert_eq!(data["options"][1]["index"], 1);
+        assert_eq!(data["options"][1]["voter_count"], 3);
+    }
+
+    #[tokio::test]
+    async fn poll_answers_identify_the_voter() {
+        let (publisher, dispatcher) = setup();
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::poll_answer(&[0, 2]),
+        )
+        .await
+        .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Polls");
+        assert_eq!(message.chat_id, Some(fixtures::USER_ID));
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["option_ids"], json!([0, 2]));
+        assert_eq!(data["user_id"], fixtures::USER_ID);
+    }
+
+    #[tokio::test]
+    async fn stickers_are_forwarded_with_their_metadata() {
+        let (publisher, dispatcher) = setup();
+
+        post(&dispatcher, HeaderMap::new(), fixtures::sticker_message())
+            .await
+            .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Stickers");
+        assert_eq!(message.text, "sticker-1");
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["emoji"], "🎸");
+        assert_eq!(data["set_name"], "RockPack");
+    }
+
+    #[tokio::test]
+    async fn gated_stickers_need_a_stickerinfo_reply() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut bot = BotConfig::new(DEFAULT_BOT_ID);
+        bot.stickers_on_command = true;
+        let dispatcher = Arc::new(dispatcher_with_bots(Arc::clone(&publisher), vec![bot]));
+
+        post(&dispatcher, HeaderMap::new(), fixtures::sticker_message())
+            .await
+            .unwrap();
+        assert!(publisher.published().is_empty());
+
+        let mut reply = fixtures::text_message("/stickerinfo");
+        reply["message"]["reply_to_message"] = fixtures::sticker_message()["message"].clone();
+        post(&dispatcher, HeaderMap::new(), reply).await.unwrap();
+        assert_eq!(publisher.texts_for("Stickers"), vec!["sticker-1"]);
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/stickerinfo"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(publisher.queues(), vec!["Stickers", "Reply"]);
+    }
+
+    #[tokio::test]
+    async fn new_members_go_to_moderation_with_their_inviter() {
+        let (publisher, dispatcher) = setup();
+
+        post(&dispatcher, HeaderMap::new(), fixtures::new_chat_members())
+            .await
+            .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Moderation");
+        assert_eq!(message.event.as_deref(), Some("new_chat_members"));
+        assert_eq!(message.chat_id, Some(fixtures::GROUP_ID));
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["members"][1]["username"], "bob");
+        assert_eq!(data["actor"]["id"], fixtures::USER_ID);
+    }
+
+    #[tokio::test]
+    async fn departures_go_to_moderation() {
+        let (publisher, dispatcher) = setup();
+
+        post(&dispatcher, HeaderMap::new(), fixtures::left_chat_member())
+            .await
+            .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Moderation");
+        assert_eq!(message.event.as_deref(), Some("left_chat_member"));
+        assert_eq!(message.data.as_ref().unwrap()["members"][0]["id"], 2002);
+    }
+
+    #[tokio::test]
+    async fn reactions_carry_old_and_new_sets() {
+        let (publisher, dispatcher) = setup();
+
+        post(&dispatcher, HeaderMap::new(), fixtures::message_reaction())
+            .await
+            .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Reactions");
+        assert_eq!(message.chat_id, Some(fixtures::GROUP_ID));
+        assert_eq!(message.text, "55");
+        let data = message.data.as_ref().unwrap();
+        assert_eq!(data["old_reaction"][0]["emoji"], "👍");
+        assert_eq!(data["new_emoji"], json!(["🔥"]));
+        assert_eq!(data["user_id"], fixtures::USER_ID);
+    }
+
+    #[tokio::test]
+    async fn replies_carry_the_referenced_message() {
+        let (publisher, dispatcher) = setup();
+        let mut update = fixtures::text_message("/songlinks\nSong");
+        update["message"]["reply_to_message"] =
+            fixtures::photo_message(Some("cover art"))["message"].clone();
+
+        post(&dispatcher, HeaderMap::new(), update).await.unwrap();
+
+        let (_, message) = &publisher.published()[0];
+        let reply_to = message.reply_to.as_ref().unwrap();
+        assert_eq!(reply_to.message_id, 7);
+        assert_eq!(reply_to.caption.as_deref(), Some("cover art"));
+        assert_eq!(reply_to.photo_file_ids.len(), 3);
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/songlinks\nSong"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(publisher.published()[1].1.reply_to, None);
+    }
+
+    #[tokio::test]
+    async fn reloaded_routes_apply_to_the_next_update() {
+        let (publisher, dispatcher) = setup();
+        let overrides = [("/songlinks".to_string(), "Tunes".to_string())].into();
+        dispatcher
+            .routes
+            .replace(RoutingTable::with_overrides(overrides, HashMap::new()).unwrap());
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/songlinks\nSong"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(publisher.queues(), vec!["Tunes"]);
+    }
+
+    #[tokio::test]
+    async fn aliases_dispatch_to_their_command_and_show_in_help() {
+        let (publisher, dispatcher) = setup();
+        let aliases = [
+            ("/song".to_string(), "/songlinks".to_string()),
+            ("/links".to_string(), "/songlinks".to_string()),
+        ]
+        .into();
+        dispatcher
+            .routes
+            .replace(RoutingTable::with_overrides(HashMap::new(), aliases).unwrap());
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/song\nSong"),
+        )
+        .await
+        .unwrap();
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/help"),
+        )
+        .await
+        .unwrap();
+
+        let (queue, message) = &publisher.published()[0];
+        assert_eq!(queue, "Music");
+        assert_eq!(message.metadata.command.as_deref(), Some("/songlinks"));
+        assert!(
+            publisher.texts_for("Reply")[0].ends_with("/links, /song also work for /songlinks.")
+        );
+    }
+
+    #[tokio::test]
+    async fn localized_aliases_reply_in_their_language_and_record_it() {
+        let (publisher, dispatcher) = setup();
+        let localized = HashMap::from([
+            (
+                "ro".to_string(),
+                HashMap::from([("/ajutor".to_string(), "/help".to_string())]),
+            ),
+            (
+                "ru".to_string(),
+                HashMap::from([("/песни".to_string(), "/songlinks".to_string())]),
+            ),
+        ]);
+        dispatcher.routes.replace(
+            RoutingTable::default()
+                .with_localized_aliases(localized)
+                .unwrap(),
+        );
+        // Telegram marks no bot_command in Cyrillic, only the mention
+        let mut songs = fixtures::text_message("/песни @ana_fan\nSong");
+        songs["message"]["entities"] = json!([{ "type": "mention", "offset": 7, "length": 8 }]);
+
+        post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/ajutor"),
+        )
+        .await
+        .unwrap();
+        post(&dispatcher, HeaderMap::new(), songs).await.unwrap();
+
+        let published = publisher.published();
+        let (queue, help) = &published[0];
+        assert_eq!(queue, "Reply");
+        assert!(help.text.ends_with("/ajutor funcționează și pentru /help."));
+        assert_eq!(help.metadata.command.as_deref(), Some("/help"));
+        assert_eq!(help.metadata.command_language.as_deref(), Some("ro"));
+        let (queue, songs) = &published[1];
+        assert_eq!(queue, "Music");
+        assert_eq!(songs.metadata.command.as_deref(), Some("/songlinks"));
+        assert_eq!(songs.metadata.command_language.as_deref(), Some("ru"));
+    }
+
+    #[tokio::test]
+    async fn ignores_updates_without_a_chat() {
+        let (publisher, dispatcher) = setup();
+
+        let status = post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::edited_channel_post(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(publisher.published().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_json() {
+        let (_, dispatcher) = setup();
+
+        let err = receive_message(
+            Extension(dispatcher),
+            HeaderMap::new(),
+            Bytes::from_static(b"{\"update_id\": 1,"),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.error, "invalid_json");
+    }
+
+    #[tokio::test]
+    async fn checks_the_bot_secret_token() {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let mut bot = BotConfig::new(DEFAULT_BOT_ID);
+        bot.secret_token = Some("s3cret".to_string());
+        let dispatcher = Arc::new(dispatcher_with_bots(Arc::clone(&publisher), vec![bot]));
+
+        let err = post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/help"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+
+        for wrong in ["s3cre", "s3cret!", "S3CRET"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(SECRET_TOKEN_HEADER, wrong.parse().unwrap());
+            let err = post(&dispatcher, headers, fixtures::text_message("/help"))
+                .await
+                .unwrap_err();
+            assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(SECRET_TOKEN_HEADER, "s3cret".parse().unwrap());
+        post(&dispatcher, headers, fixtures::text_message("/help"))
+            .await
+            .unwrap();
+        assert_eq!(publisher.queues(), vec!["Reply"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_bot_is_not_found() {
+        let (_, dispatcher) = setup();
+
+        let err = receive_bot_message(
+            Path("other".to_string()),
+            Extension(dispatcher),
+            HeaderMap::new(),
+            fixtures::to_body(&fixtures::text_message("/help")),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn broker_outage_is_reported_as_retryable() {
+        let (publisher, dispatcher) = setup();
+        publisher.fail_with(|| PublishError::CircuitOpen);
+
+        let err = post(
+            &dispatcher,
+            HeaderMap::new(),
+            fixtures::text_message("/help"),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.error, "publish_failed");
+    }
+}
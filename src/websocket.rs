@@ -0,0 +1,251 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Extension,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    errors::WebhookError,
+    publisher::{validate_destination, MessagePublisher, RabbitMessage},
+    signature::HmacVerifier,
+};
+
+// The handshake is signed with HMAC_WS_SECRET
+pub const SOURCE: &str = "ws";
+const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+// How old a signed handshake may be, so a captured one cannot be replayed later
+const TIMESTAMP_TOLERANCE_SECS: u64 = 300;
+// Producers stream small events; anything larger belongs on a real upload path
+const MAX_FRAME_BYTES: usize = 256 * 1024;
+
+// Streams of events from internal services, one JSON frame per message
+pub struct WsIngest {
+    verifier: HmacVerifier,
+    // None allows publishing to any valid destination
+    allowed_destinations: Option<HashSet<String>>,
+}
+
+// One frame; the id, if any, is echoed in its acknowledgement
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Frame {
+    #[serde(default)]
+    id: Option<Value>,
+    destination: String,
+    source: String,
+    #[serde(default)]
+    chat_id: Option<i64>,
+    text: String,
+    #[serde(default)]
+    event: Option<String>,
+    #[serde(default)]
+    data: Option<Value>,
+}
+
+impl WsIngest {
+    pub fn new(verifier: HmacVerifier, allowed_destinations: Option<HashSet<String>>) -> Self {
+        Self {
+            verifier,
+            allowed_destinations,
+        }
+    }
+
+    // The handshake has no body, so producers sign the unix timestamp they send alongside
+    fn verify_handshake(&self, headers: &HeaderMap, now: u64) -> Result<(), WebhookError> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let (Some(timestamp), Some(signature)) = (
+            header(TIMESTAMP_HEADER),
+            header(self.verifier.header_name()),
+        ) else {
+            return Err(WebhookError::unauthorized(format!(
+                "missing {} or {} header",
+                TIMESTAMP_HEADER,
+                self.verifier.header_name()
+            )));
+        };
+        let fresh = timestamp
+            .parse::<u64>()
+            .is_ok_and(|timestamp| now.abs_diff(timestamp) <= TIMESTAMP_TOLERANCE_SECS);
+        if !fresh || !self.verifier.verify(timestamp.as_bytes(), signature) {
+            return Err(WebhookError::unauthorized("handshake signature mismatch"));
+        }
+        Ok(())
+    }
+
+    fn validate(&self, frame: &Frame) -> Result<(), WebhookError> {
+        validate_destination(&frame.destination).map_err(|e| {
+            WebhookError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_destination",
+                e.to_string(),
+            )
+        })?;
+        if let Some(allowed) = &self.allowed_destinations {
+            if !allowed.contains(&frame.destination) {
+                return Err(WebhookError::forbidden(format!(
+                    "destination {} is not allowed",
+                    frame.destination
+                )));
+            }
+        }
+        if frame.source.is_empty() {
+            return Err(WebhookError::missing_field("source"));
+        }
+        if frame.text.is_empty() {
+            return Err(WebhookError::new(
+                StatusCode::BAD_REQUEST,
+                "empty_text",
+                "text must not be empty",
+            )
+            .with_field("text"));
+        }
+        Ok(())
+    }
+
+    // Publishes one frame and returns its acknowledgement
+    async fn publish(&self, frame: &[u8], publisher: &dyn MessagePublisher) -> Value {
+        let frame = match serde_json::from_slice::<Frame>(frame) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let error = WebhookError::invalid_json(e.to_string());
+                return json!({ "accepted": false, "error": error });
+            }
+        };
+        let published = async {
+            self.validate(&frame)?;
+            let message = RabbitMessage {
+                source: frame.source.clone(),
+                chat_id: frame.chat_id,
+                text: frame.text.clone(),
+                event: frame.event.clone(),
+                data: frame.data.clone(),
+                ..RabbitMessage::default()
+            };
+            publisher
+                .publish(&frame.destination, &message)
+                .await
+                .map_err(|e| WebhookError::publish_failed(&frame.destination, &e))
+        };
+        match published.await {
+            Ok(()) => json!({ "id": frame.id, "accepted": true }),
+            Err(error) => json!({ "id": frame.id, "accepted": false, "error": error }),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// GET /ws; every text or binary frame is a publish request, answered with one acknowledgement
+pub async fn upgrade(
+    Extension(ingest): Extension<Arc<WsIngest>>,
+    Extension(publisher): Extension<Arc<dyn MessagePublisher>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, WebhookError> {
+    ingest.verify_handshake(&headers, now())?;
+    Ok(ws
+        .max_message_size(MAX_FRAME_BYTES)
+        .on_upgrade(move |socket| stream(socket, ingest, publisher)))
+}
+
+async fn stream(
+    mut socket: WebSocket,
+    ingest: Arc<WsIngest>,
+    publisher: Arc<dyn MessagePublisher>,
+) {
+    info!("WebSocket producer connected.");
+    let mut published = 0;
+    while let Some(message) = socket.recv().await {
+        let frame = match message {
+            Ok(Message::Text(text)) => text.into_bytes(),
+            Ok(Message::Binary(bytes)) => bytes,
+            Ok(Message::Close(_)) => break,
+            // Pings are answered by axum itself
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("WebSocket producer stream failed: {}", e);
+                break;
+            }
+        };
+        let ack = ingest.publish(&frame, publisher.as_ref()).await;
+        if ack["accepted"] == true {
+            published += 1;
+        }
+        if socket.send(Message::Text(ack.to_string())).await.is_err() {
+            break;
+        }
+    }
+    info!(
+        "WebSocket producer disconnected after {} published messages.",
+        published
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingPublisher;
+
+    fn ingest(allowed: Option<&[&str]>) -> WsIngest {
+        WsIngest {
+            verifier: HmacVerifier::new("X-Signature-256", b"secret"),
+            allowed_destinations: allowed
+                .map(|queues| queues.iter().map(|q| q.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn handshakes_are_signed_and_fresh() {
+        let ingest = ingest(None);
+        // HMAC-SHA256 of "1700000000" with the key "secret"
+        let signature = {
+            use hmac::{Hmac, Mac};
+            let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(b"1700000000");
+            hex::encode(mac.finalize().into_bytes())
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, "1700000000".parse().unwrap());
+        headers.insert("X-Signature-256", signature.parse().unwrap());
+
+        assert!(ingest.verify_handshake(&headers, 1_700_000_100).is_ok());
+        assert!(ingest.verify_handshake(&headers, 1_700_001_000).is_err());
+        headers.insert(TIMESTAMP_HEADER, "1700000001".parse().unwrap());
+        assert!(ingest.verify_handshake(&headers, 1_700_000_100).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_are_validated_then_published() {
+        let ingest = ingest(Some(&["Events"]));
+        let publisher = RecordingPublisher::default();
+        let frame = |destination: &str| {
+            json!({ "id": 7, "destination": destination, "source": "billing", "text": "paid" })
+                .to_string()
+        };
+
+        let accepted = ingest.publish(frame("Events").as_bytes(), &publisher).await;
+        let forbidden = ingest.publish(frame("Music").as_bytes(), &publisher).await;
+        let garbled = ingest.publish(b"{\"destination\":", &publisher).await;
+
+        assert_eq!(accepted, json!({ "id": 7, "accepted": true }));
+        assert_eq!(forbidden["error"]["error"], "forbidden");
+        assert_eq!(garbled["error"]["error"], "invalid_json");
+        assert_eq!(publisher.texts_for("Events"), vec!["paid"]);
+        assert_eq!(publisher.queues(), vec!["Events"]);
+    }
+}
@@ -0,0 +1,134 @@
+// The server assembled through the public builder, as a service embedding it would
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use rustin_bot_publisher::{
+    app::App,
+    bots::{BotConfig, BotRegistry, DEFAULT_BOT_ID},
+    config::{Config, ConfigArgs, ConfigLayer},
+    publisher::{MessagePublisher, PublishError, RabbitMessage},
+    routing::RoutingTable,
+    signature::HmacSettings,
+};
+use serde_json::json;
+use tower::ServiceExt;
+
+#[derive(Default)]
+struct RecordingPublisher {
+    published: Mutex<Vec<(String, String)>>,
+}
+
+#[async_trait]
+impl MessagePublisher for RecordingPublisher {
+    async fn publish(
+        &self,
+        destination: &str,
+        message: &RabbitMessage,
+    ) -> Result<(), PublishError> {
+        self.published
+            .lock()
+            .unwrap()
+            .push((destination.to_string(), message.text.clone()));
+        Ok(())
+    }
+}
+
+fn config() -> Config {
+    Config::load(ConfigArgs {
+        config: None,
+        overrides: ConfigLayer {
+            rabbit_address: Some("amqp://localhost:5672".to_string()),
+            ..ConfigLayer::default()
+        },
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+async fn webhook_updates_reach_the_given_publisher() {
+    let publisher = Arc::new(RecordingPublisher::default());
+    let routes = RoutingTable::with_overrides(
+        [("/songlinks".to_string(), "Songs".to_string())].into(),
+        Default::default(),
+    )
+    .unwrap();
+    let app = App::builder()
+        .config(config())
+        .publisher(Arc::clone(&publisher) as _)
+        .routes(routes)
+        .bots(BotRegistry::new(vec![BotConfig::new(DEFAULT_BOT_ID)]))
+        .build()
+        .await
+        .unwrap();
+
+    let update = json!({
+        "update_id": 900_001,
+        "message": {
+            "message_id": 7,
+            "date": 1_700_000_000,
+            "chat": { "id": 424242, "type": "private", "first_name": "Ana" },
+            "from": { "id": 1001, "is_bot": false, "first_name": "Ana" },
+            "text": "/songlinks\nBohemian Rhapsody"
+        }
+    });
+    let request = Request::post("/webhook")
+        .header("content-type", "application/json")
+        .body(Body::from(update.to_string()))
+        .unwrap();
+    let response = app.router().oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        *publisher.published.lock().unwrap(),
+        vec![("Songs".to_string(), "Bohemian Rhapsody".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn signed_bodies_are_capped_at_the_webhook_body_limit() {
+    let config = Config::load(ConfigArgs {
+        config: None,
+        overrides: ConfigLayer {
+            rabbit_address: Some("amqp://localhost:5672".to_string()),
+            webhook_max_body_bytes: Some(64),
+            hmac: Some(HashMap::from([(
+                "admin".to_string(),
+                HmacSettings {
+                    secret: Some("secret".to_string()),
+                    header: None,
+                },
+            )])),
+            ..ConfigLayer::default()
+        },
+    })
+    .unwrap();
+    let app = App::builder()
+        .config(config)
+        .publisher(Arc::new(RecordingPublisher::default()) as _)
+        .bots(BotRegistry::new(vec![BotConfig::new(DEFAULT_BOT_ID)]))
+        .build()
+        .await
+        .unwrap();
+
+    let request = Request::post("/admin/commands/songlinks")
+        .header("content-type", "application/json")
+        .header("X-Signature-256", "sha256=00")
+        .body(Body::from(
+            json!({ "enabled": false, "padding": "x".repeat(64) }).to_string(),
+        ))
+        .unwrap();
+    let response = app.router().oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("request body exceeds 64 bytes"));
+}